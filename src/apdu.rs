@@ -30,17 +30,32 @@
 // (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
-use crate::{transaction::Transaction, Buffer, Result};
-use log::trace;
+use crate::{piv::SlotId, transaction::Transaction, yubikey::KEY_CARDMGM, Buffer, Result};
+use log::{log_enabled, trace, Level};
+use std::{cell::Cell, fmt, time::Duration};
 use zeroize::{Zeroize, Zeroizing};
 
+/// Length of a command header (CLA, INS, P1, P2).
+pub(crate) const HEADER_LEN: usize = 4;
+
+/// Class byte flag of commands chained to the next one.
+const CLA_CHAINING: u8 = 0x10;
+
+/// Tag list of GET DATA commands reading the PIN-protected data object, and
+/// prefix of PUT DATA commands writing it.
+const PROTECTED_OBJECT: &[u8] = &[0x5c, 0x03, 0x5f, 0xc1, 0x09];
+
 /// Maximum amount of command data that can be included in an APDU
 const APDU_DATA_MAX: usize = 0xFF;
 
+/// Maximum amount of command data that can be included in an extended length
+/// APDU
+const EXT_APDU_DATA_MAX: usize = 0xFFFF;
+
 /// Application Protocol Data Unit (APDU).
 ///
 /// These messages are packets used to communicate with the YubiKey.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub(crate) struct Apdu {
     /// Instruction class: indicates the type of command (e.g. inter-industry or proprietary)
     cla: u8,
@@ -56,6 +71,9 @@ pub(crate) struct Apdu {
 
     /// Command data to be sent (`lc` is calculated as `data.len()`)
     data: Vec<u8>,
+
+    /// Serialize as an extended length APDU
+    extended: bool,
 }
 
 impl Apdu {
@@ -67,6 +85,7 @@ impl Apdu {
             p1: 0,
             p2: 0,
             data: vec![],
+            extended: false,
         }
     }
 
@@ -82,12 +101,6 @@ impl Apdu {
         self
     }
 
-    /// Set this APDU's second parameter only
-    pub(crate) fn p2(&mut self, value: u8) -> &mut Self {
-        self.p2 = value;
-        self
-    }
-
     /// Set both parameters for this APDU
     pub fn params(&mut self, p1: u8, p2: u8) -> &mut Self {
         self.p1 = p1;
@@ -95,19 +108,34 @@ impl Apdu {
         self
     }
 
+    /// Serialize this APDU using extended length `Lc` and `Le` fields.
+    ///
+    /// Must be set before the command data.
+    pub fn extended(&mut self, value: bool) -> &mut Self {
+        assert!(self.data.is_empty(), "APDU command already set!");
+        self.extended = value;
+        self
+    }
+
     /// Set the command data for this APDU.
     ///
-    /// Panics if the byte slice is more than 255 bytes!
+    /// Panics if the byte slice is more than 255 bytes (or 65535 bytes for
+    /// extended length APDUs)!
     pub fn data(&mut self, bytes: impl AsRef<[u8]>) -> &mut Self {
         assert!(self.data.is_empty(), "APDU command already set!");
 
         let bytes = bytes.as_ref();
+        let max = if self.extended {
+            EXT_APDU_DATA_MAX
+        } else {
+            APDU_DATA_MAX
+        };
 
         assert!(
-            bytes.len() <= APDU_DATA_MAX,
+            bytes.len() <= max,
             "APDU command data too long: {} (max: {})",
             bytes.len(),
-            APDU_DATA_MAX
+            max
         );
 
         self.data.extend_from_slice(bytes);
@@ -116,25 +144,56 @@ impl Apdu {
 
     /// Transmit this APDU using the given card transaction
     pub fn transmit(&self, txn: &Transaction<'_>, recv_len: usize) -> Result<Response> {
-        trace!(">>> {:?}", self);
-        let response = Response::from(txn.transmit(&self.to_bytes(), recv_len)?);
-        trace!("<<< {:?}", &response);
-        Ok(response)
+        Ok(Response::from(txn.transmit(&self.to_bytes(), recv_len)?))
     }
 
     /// Serialize this APDU as a self-zeroizing byte buffer
     pub fn to_bytes(&self) -> Buffer {
-        let mut bytes = Vec::with_capacity(5 + self.data.len());
+        let mut bytes = Vec::with_capacity(9 + self.data.len());
         bytes.push(self.cla);
         bytes.push(self.ins.code());
         bytes.push(self.p1);
         bytes.push(self.p2);
-        bytes.push(self.data.len() as u8);
-        bytes.extend_from_slice(self.data.as_ref());
+
+        if self.extended {
+            // Extended length: `00 Lc1 Lc2 <data> Le1 Le2`, with `Lc` omitted
+            // when there is no data, and `Le` of `00 00` (i.e. 65536 bytes)
+            bytes.push(0);
+
+            if !self.data.is_empty() {
+                bytes.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(self.data.as_ref());
+            }
+
+            bytes.extend_from_slice(&[0, 0]);
+        } else {
+            bytes.push(self.data.len() as u8);
+            bytes.extend_from_slice(self.data.as_ref());
+        }
+
         Zeroizing::new(bytes)
     }
 }
 
+impl fmt::Debug for Apdu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Apdu");
+        debug
+            .field("cla", &self.cla)
+            .field("ins", &self.ins)
+            .field("p1", &self.p1)
+            .field("p2", &self.p2);
+
+        if is_secret(self.ins, self.p2, &self.data) {
+            debug.field("data", &format_args!("[redacted]"));
+        } else {
+            debug.field("data", &self.data);
+        }
+
+        debug.field("extended", &self.extended).finish()
+    }
+}
+
 impl Drop for Apdu {
     fn drop(&mut self) {
         self.zeroize();
@@ -204,6 +263,9 @@ pub enum Ins {
     /// Get slot metadata
     GetMetadata,
 
+    /// Move or delete a key
+    MoveKey,
+
     /// Management // Read Config
     ReadConfig,
 
@@ -213,6 +275,19 @@ pub enum Ins {
     /// Management // DeviceReset
     DeviceReset,
 
+    // Global Platform Security Domain instructions, used to open secure channels
+    /// Initialize update (SCP03)
+    InitializeUpdate,
+
+    /// External authenticate (SCP03)
+    ExternalAuthenticate,
+
+    /// Internal authenticate (SCP11b)
+    InternalAuthenticate,
+
+    /// Get data from the Security Domain
+    GetDataSd,
+
     /// Other/unrecognized instruction codes
     Other(u8),
 }
@@ -238,12 +313,19 @@ impl Ins {
             Ins::Attest => 0xf9,
             Ins::GetSerial => 0xf8,
             Ins::GetMetadata => 0xf7,
+            Ins::MoveKey => 0xf6,
 
             // Management
             Ins::ReadConfig => 0x1d,
             Ins::WriteConfig => 0x1c,
             Ins::DeviceReset => 0x1f,
 
+            // Security Domain
+            Ins::InitializeUpdate => 0x50,
+            Ins::ExternalAuthenticate => 0x82,
+            Ins::InternalAuthenticate => 0x88,
+            Ins::GetDataSd => 0xca,
+
             Ins::Other(code) => code,
         }
     }
@@ -257,6 +339,12 @@ impl From<u8> for Ins {
             0x1c => Ins::WriteConfig,
             0x1f => Ins::DeviceReset,
 
+            // Security Domain
+            0x50 => Ins::InitializeUpdate,
+            0x82 => Ins::ExternalAuthenticate,
+            0x88 => Ins::InternalAuthenticate,
+            0xca => Ins::GetDataSd,
+
             0x20 => Ins::Verify,
             0x24 => Ins::ChangeReference,
             0x2c => Ins::ResetRetry,
@@ -274,6 +362,7 @@ impl From<u8> for Ins {
             0xf9 => Ins::Attest,
             0xf8 => Ins::GetSerial,
             0xf7 => Ins::GetMetadata,
+            0xf6 => Ins::MoveKey,
             code => Ins::Other(code),
         }
     }
@@ -508,9 +597,336 @@ impl From<StatusWords> for u16 {
     }
 }
 
+/// Does a command with the given instruction, P2 and data carry secrets
+/// (PINs, PUKs, management keys or private keys) in its data?
+fn is_secret(ins: Ins, p2: u8, data: &[u8]) -> bool {
+    match ins {
+        Ins::Verify | Ins::ChangeReference | Ins::ResetRetry | Ins::SetMgmKey | Ins::ImportKey => {
+            true
+        }
+        Ins::Authenticate => p2 == KEY_CARDMGM,
+        Ins::PutData => data.starts_with(PROTECTED_OBJECT),
+        _ => false,
+    }
+}
+
+/// Does the given serialized command carry secrets in its data?
+///
+/// These are VERIFY, CHANGE REFERENCE, RESET RETRY, SET MGM KEY, IMPORT KEY,
+/// management key AUTHENTICATE, and PUT DATA of the PIN-protected data object
+/// (which may hold a PIN-protected management key).
+pub(crate) fn is_secret_command(command: &[u8]) -> bool {
+    match *command {
+        [_, ins, _, p2, ..] => is_secret(
+            Ins::from(ins),
+            p2,
+            command_data(command).unwrap_or_default(),
+        ),
+        _ => false,
+    }
+}
+
+/// Does the response to the given serialized command carry secrets?
+///
+/// This is the case when reading the PIN-protected data object, which may
+/// hold a PIN-protected management key, and for GENERAL AUTHENTICATE with a
+/// key slot, whose results may be decrypted data or a shared secret
+/// (management key authentication only returns challenges).
+pub(crate) fn is_secret_response(command: &[u8]) -> bool {
+    match command.get(1).map(|&ins| Ins::from(ins)) {
+        Some(Ins::GetData) => command_data(command) == Some(PROTECTED_OBJECT),
+        Some(Ins::Authenticate) => !is_mgm_auth(command),
+        _ => false,
+    }
+}
+
+/// Classification of the APDUs exchanged within a transaction as carrying
+/// secrets or not, used to redact them from the [`Trace`] and from
+/// transcripts ([`crate::transcript`]).
+///
+/// On top of [`is_secret_command`] and [`is_secret_response`], this follows
+/// secrets spread over several APDUs: the commands chained to a secret
+/// command, and the GET RESPONSE results following a secret response.
+#[derive(Debug, Default)]
+pub(crate) struct Secrets {
+    /// Is the next command chained to a secret command?
+    chained: Cell<bool>,
+
+    /// Is the response to the last command secret?
+    response: Cell<bool>,
+
+    /// Is the remainder of the last response secret?
+    remaining: Cell<bool>,
+}
+
+impl Secrets {
+    /// Classify a serialized command about to be sent to the card, returning
+    /// whether its data is secret.
+    pub fn command(&self, command: &[u8]) -> bool {
+        let secret = self.chained.get() || is_secret_command(command);
+        self.chained
+            .set(secret && command.first().is_some_and(|cla| cla & CLA_CHAINING != 0));
+
+        self.response
+            .set(match command.get(1).map(|&ins| Ins::from(ins)) {
+                Some(Ins::GetResponseApdu) => self.remaining.get(),
+                _ => is_secret_response(command),
+            });
+
+        secret
+    }
+
+    /// Classify the card's response to the last command (`None` if the
+    /// transport failed), returning whether its data is secret.
+    pub fn response(&self, response: Option<&[u8]>) -> bool {
+        let remaining = response.is_some_and(|response| {
+            matches!(status_words(response), StatusWords::BytesRemaining { .. })
+        });
+
+        if response.is_none() {
+            self.chained.set(false);
+        }
+
+        self.remaining.set(self.response.get() && remaining);
+        self.response.get()
+    }
+}
+
+/// Get the status words at the end of a serialized response.
+fn status_words(response: &[u8]) -> StatusWords {
+    match *response {
+        [.., sw1, sw2] => StatusWords::from(u16::from_be_bytes([sw1, sw2])),
+        _ => StatusWords::None,
+    }
+}
+
+/// Is the given serialized command an AUTHENTICATE with the management key?
+pub(crate) fn is_mgm_auth(command: &[u8]) -> bool {
+    command.get(1).map(|&ins| Ins::from(ins)) == Some(Ins::Authenticate)
+        && command.get(3) == Some(&KEY_CARDMGM)
+}
+
+/// Get the data of a serialized (short or extended length) command.
+pub(crate) fn command_data(command: &[u8]) -> Option<&[u8]> {
+    match command.get(HEADER_LEN..)? {
+        [0, lc1, lc2, data @ ..] if data.len() >= 2 => {
+            data.get(..usize::from(u16::from_be_bytes([*lc1, *lc2])))
+        }
+        [lc, data @ ..] => data.get(..usize::from(*lc)),
+        [] => None,
+    }
+}
+
+/// Get the key slot a command with the given instruction refers to.
+fn command_slot(ins: Ins, p1: u8, p2: u8) -> Option<SlotId> {
+    match ins {
+        Ins::Verify
+        | Ins::ChangeReference
+        | Ins::ResetRetry
+        | Ins::GenerateAsymmetric
+        | Ins::Authenticate
+        | Ins::ImportKey
+        | Ins::GetMetadata
+        | Ins::MoveKey => SlotId::try_from(p2).ok(),
+        Ins::Attest => SlotId::try_from(p1).ok(),
+        _ => None,
+    }
+}
+
+/// Trace of the APDUs exchanged within a transaction.
+///
+/// Every command and response is logged at `trace` level (with the
+/// `yubikey::apdu` target) as a single line, with its decoded fields also
+/// attached as structured key-values: the instruction, its parameters and the
+/// key slot it refers to for commands, and the status words and the time
+/// taken by the card to respond for responses.
+///
+/// Secret-bearing data is never logged, only its length (see [`Secrets`]).
+#[derive(Debug, Default)]
+pub(crate) struct Trace {
+    secrets: Secrets,
+}
+
+impl Trace {
+    /// Log a serialized command about to be sent to the card.
+    pub fn command(&self, command: &[u8]) {
+        let secret = self.secrets.command(command);
+
+        if !log_enabled!(Level::Trace) {
+            return;
+        }
+
+        let [cla, code, p1, p2, ..] = *command else {
+            trace!("> malformed command ({} bytes)", command.len());
+            return;
+        };
+
+        let ins = Ins::from(code);
+        let slot = command_slot(ins, p1, p2).map(|slot| slot.to_string());
+        let data = command_data(command).unwrap_or_default();
+        let data = redact(data, secret);
+
+        trace!(
+            cla,
+            ins = code,
+            name:? = ins,
+            p1,
+            p2,
+            slot = slot.as_deref(),
+            lc = data.len,
+            data = data.value.as_str();
+            "> {:?} (cla={:02x} ins={:02x} p1={:02x} p2={:02x}){} lc={} data={}",
+            ins,
+            cla,
+            code,
+            p1,
+            p2,
+            slot.as_deref()
+                .map(|slot| format!(" slot={slot}"))
+                .unwrap_or_default(),
+            data.len,
+            data.value
+        );
+    }
+
+    /// Log the card's response to a command, or the error the transport
+    /// failed with, and the time it took.
+    pub fn response(&self, response: &Result<Vec<u8>>, elapsed: Duration) {
+        let secret = self.secrets.response(response.as_deref().ok());
+
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                trace!(
+                    error:% = e,
+                    elapsed_us = elapsed.as_micros() as u64;
+                    "< error: {} (after {:?})",
+                    e,
+                    elapsed
+                );
+                return;
+            }
+        };
+
+        let data = &response[..response.len().saturating_sub(2)];
+        let sw = status_words(response);
+
+        if !log_enabled!(Level::Trace) {
+            return;
+        }
+
+        let data = redact(data, secret);
+
+        trace!(
+            sw = sw.code(),
+            status:? = sw,
+            len = data.len,
+            data = data.value.as_str(),
+            elapsed_us = elapsed.as_micros() as u64;
+            "< {:?} (sw={:04x}) len={} data={} (in {:?})",
+            sw,
+            sw.code(),
+            data.len,
+            data.value,
+            elapsed
+        );
+    }
+}
+
+/// Data field of a traced APDU.
+struct TracedData {
+    /// Length of the data
+    len: usize,
+
+    /// Hex encoded data, or a placeholder if it was redacted
+    value: String,
+}
+
+/// Prepare a data field for tracing, redacting it if it is secret.
+fn redact(data: &[u8], secret: bool) -> TracedData {
+    TracedData {
+        len: data.len(),
+        value: if secret && !data.is_empty() {
+            String::from("[redacted]")
+        } else {
+            hex::lower::encode_string(data)
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::StatusWords;
+    use super::{is_secret_command, is_secret_response, Apdu, Ins, Secrets, StatusWords, Trace};
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::{
+        sync::{Mutex, Once},
+        time::Duration,
+    };
+
+    /// Logger capturing the APDU trace.
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "yubikey::apdu"
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            if self.enabled(record.metadata()) {
+                let data = record.key_values().get("data".into());
+                self.0
+                    .lock()
+                    .expect("poisoned")
+                    .push(format!("{} {:?}", record.args(), data));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    fn capture_trace() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CAPTURE).expect("logger already set");
+            log::set_max_level(LevelFilter::Trace);
+        });
+        assert!(log::log_enabled!(target: "yubikey::apdu", Level::Trace));
+    }
+
+    #[test]
+    fn short_apdu_serialization() {
+        let bytes = Apdu::new(Ins::GetData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x01, 0x7e])
+            .to_bytes();
+
+        assert_eq!(
+            bytes.as_slice(),
+            &[0x00, 0xcb, 0x3f, 0xff, 0x03, 0x5c, 0x01, 0x7e]
+        );
+    }
+
+    #[test]
+    fn extended_apdu_serialization() {
+        let data = vec![0xab; 0x1234];
+        let bytes = Apdu::new(Ins::PutData)
+            .params(0x3f, 0xff)
+            .extended(true)
+            .data(&data)
+            .to_bytes();
+
+        assert_eq!(&bytes[..7], &[0x00, 0xdb, 0x3f, 0xff, 0x00, 0x12, 0x34]);
+        assert_eq!(&bytes[7..7 + data.len()], data.as_slice());
+        assert_eq!(&bytes[7 + data.len()..], &[0x00, 0x00]);
+
+        let bytes = Apdu::new(Ins::GetResponseApdu).extended(true).to_bytes();
+        assert_eq!(
+            bytes.as_slice(),
+            &[0x00, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
 
     #[test]
     fn status_words_round_trip() {
@@ -543,4 +959,171 @@ mod tests {
         round_trip(StatusWords::CommandAbortedError);
         round_trip(StatusWords::Other(0x1337));
     }
+
+    #[test]
+    fn secret_commands() {
+        let verify = Apdu::new(Ins::Verify)
+            .params(0x00, 0x80)
+            .data(b"123456\xff\xff")
+            .to_bytes();
+        assert!(is_secret_command(&verify));
+
+        let mgm_auth = Apdu::new(Ins::Authenticate)
+            .params(0x03, 0x9b)
+            .data([0x7c, 0x02, 0x80, 0x00])
+            .to_bytes();
+        assert!(is_secret_command(&mgm_auth));
+
+        let sign = Apdu::new(Ins::Authenticate)
+            .params(0x11, 0x9a)
+            .data([0x7c, 0x02, 0x82, 0x00])
+            .to_bytes();
+        assert!(!is_secret_command(&sign));
+
+        let protected = Apdu::new(Ins::GetData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x09])
+            .to_bytes();
+        assert!(!is_secret_command(&protected));
+        assert!(is_secret_response(&protected));
+
+        let put_protected = Apdu::new(Ins::PutData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x09, 0x53, 0x02, 0x88, 0x00])
+            .to_bytes();
+        assert!(is_secret_command(&put_protected));
+
+        let put_chuid = Apdu::new(Ins::PutData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x02, 0x53, 0x00])
+            .to_bytes();
+        assert!(!is_secret_command(&put_chuid));
+    }
+
+    #[test]
+    fn secrets_span_apdus() {
+        let secrets = Secrets::default();
+
+        // Commands chained to a secret command are secret too
+        let put_protected = Apdu::new(Ins::PutData)
+            .cla(0x10)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x09, 0x53, 0x02])
+            .to_bytes();
+        assert!(secrets.command(&put_protected));
+        assert!(!secrets.response(Some(&[0x90, 0x00])));
+
+        let last = Apdu::new(Ins::PutData)
+            .params(0x3f, 0xff)
+            .data([0x88, 0x00])
+            .to_bytes();
+        assert!(secrets.command(&last));
+        assert!(!secrets.response(Some(&[0x90, 0x00])));
+
+        let chuid = Apdu::new(Ins::PutData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x02, 0x53, 0x00])
+            .to_bytes();
+        assert!(!secrets.command(&chuid));
+        assert!(!secrets.response(Some(&[0x90, 0x00])));
+
+        // Decryption results are secret, including their remainder, while
+        // management key challenges aren't
+        let decrypt = Apdu::new(Ins::Authenticate)
+            .params(0x07, 0x9d)
+            .data([0x7c, 0x02, 0x81, 0x00])
+            .to_bytes();
+        assert!(!secrets.command(&decrypt));
+        assert!(secrets.response(Some(&[0x7c, 0x61, 0x08])));
+
+        let get_response = Apdu::new(Ins::GetResponseApdu).to_bytes();
+        assert!(!secrets.command(&get_response));
+        assert!(secrets.response(Some(&[0xab, 0x90, 0x00])));
+
+        let mgm_auth = Apdu::new(Ins::Authenticate)
+            .params(0x03, 0x9b)
+            .data([0x7c, 0x02, 0x80, 0x00])
+            .to_bytes();
+        assert!(secrets.command(&mgm_auth));
+        assert!(!secrets.response(Some(&[0x7c, 0x0a, 0x90, 0x00])));
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        // The PIN, as formatted by `Debug`: "49, 50, 51, 52, 53, 54"
+        let pin = format!("{:?}", b"123456");
+        let pin = pin.trim_start_matches('[').trim_end_matches(']');
+
+        let verify = Apdu::new(Ins::Verify)
+            .params(0x00, 0x80)
+            .data(b"123456\xff\xff")
+            .clone();
+        let debug = format!("{:?}", verify);
+        assert!(debug.contains("[redacted]"));
+        assert!(!debug.contains(pin));
+
+        let put_protected = Apdu::new(Ins::PutData)
+            .params(0x3f, 0xff)
+            .data(
+                [
+                    &[0x5c, 0x03, 0x5f, 0xc1, 0x09, 0x53, 0x08, 0x88, 0x06],
+                    &b"123456"[..],
+                ]
+                .concat(),
+            )
+            .clone();
+        let debug = format!("{:?}", put_protected);
+        assert!(debug.contains("[redacted]"));
+        assert!(!debug.contains(pin));
+    }
+
+    #[test]
+    fn trace_redacts_secrets() {
+        capture_trace();
+        let trace = Trace::default();
+        let elapsed = Duration::from_millis(1);
+
+        let verify = Apdu::new(Ins::Verify)
+            .params(0x00, 0x80)
+            .data(b"654321\xff\xff")
+            .to_bytes();
+        trace.command(&verify);
+        trace.response(&Ok(vec![0x90, 0x00]), elapsed);
+
+        // The response to reading the protected object is redacted, including
+        // its remainder
+        let protected = Apdu::new(Ins::GetData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x09])
+            .to_bytes();
+        trace.command(&protected);
+        trace.response(&Ok(vec![0x53, 0x0b, 0x61, 0x02]), elapsed);
+
+        let get_response = Apdu::new(Ins::GetResponseApdu).to_bytes();
+        trace.command(&get_response);
+        trace.response(&Ok(vec![0xab, 0xcd, 0x90, 0x00]), elapsed);
+
+        let chuid = Apdu::new(Ins::GetData)
+            .params(0x3f, 0xff)
+            .data([0x5c, 0x03, 0x5f, 0xc1, 0x02])
+            .to_bytes();
+        trace.command(&chuid);
+        trace.response(&Ok(vec![0x53, 0x01, 0xef, 0x90, 0x00]), elapsed);
+
+        let lines = CAPTURE.0.lock().expect("poisoned");
+        let line = |prefix: &str| {
+            lines
+                .iter()
+                .find(|line| line.starts_with(prefix))
+                .expect("line not traced")
+        };
+
+        assert!(line("> Verify").contains("slot=Pin lc=8 data=[redacted]"));
+        assert!(line("> GetData").contains("data=5c035fc109"));
+        assert!(line("< BytesRemaining").contains("(sw=6102) len=2 data=[redacted]"));
+        assert!(lines.iter().any(|line| line.contains("len=3 data=5301ef")));
+        assert!(!lines
+            .iter()
+            .any(|line| line.contains("363534") || line.contains("abcd")));
+    }
 }
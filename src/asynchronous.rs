@@ -0,0 +1,381 @@
+//! Async facade for using a YubiKey from an async executor (e.g. tokio).
+//!
+//! Card operations block the calling thread for as long as the card takes to
+//! answer, including while it waits for the user to touch it. To avoid
+//! stalling an executor, [`AsyncYubiKey`] moves the [`YubiKey`] onto a
+//! dedicated worker thread which performs all of its I/O, and returns a
+//! [`Operation`] future for each request. The futures don't depend on any
+//! particular runtime.
+//!
+//! ```no_run
+//! # async fn example() -> yubikey::Result<()> {
+//! use yubikey::{asynchronous::AsyncYubiKey, piv::{AlgorithmId, SlotId}};
+//!
+//! let yubikey = AsyncYubiKey::open().await?;
+//! yubikey.verify_pin(b"123456").await?;
+//!
+//! let signature = yubikey
+//!     .sign_data(&[0u8; 32], AlgorithmId::EccP256, SlotId::Signature)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Operations are performed in the order they were requested. Dropping an
+//! [`Operation`] before the worker starts it cancels it; operations which
+//! have already been sent to the card run to completion, and their result is
+//! discarded.
+
+use crate::{
+    certificate::{CertInfo, Certificate},
+    piv::{self, AlgorithmId, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    reader::Context,
+    touch::CancelHandle,
+    Buffer, Error, MgmKey, Result, Serial, Version, YubiKey,
+};
+use log::error;
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context as TaskContext, Poll, Waker},
+    thread,
+};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+
+/// Work item run on the worker thread.
+type Job = Box<dyn FnOnce(&mut YubiKey) + Send>;
+
+/// Handle to a [`YubiKey`] owned by a dedicated worker thread.
+///
+/// Clones share the same worker (and thus the same card session). The worker
+/// thread exits, disconnecting from the card, once all handles have been
+/// dropped and all queued operations have completed.
+#[derive(Clone)]
+pub struct AsyncYubiKey {
+    jobs: mpsc::Sender<Job>,
+    name: Arc<str>,
+    version: Version,
+    serial: Serial,
+    cancel: CancelHandle,
+}
+
+impl AsyncYubiKey {
+    /// Move an open [`YubiKey`] onto a new worker thread.
+    pub fn new(yubikey: YubiKey) -> Result<Self> {
+        let name = Arc::from(yubikey.name());
+        let version = yubikey.version();
+        let serial = yubikey.serial();
+        let cancel = yubikey.cancel_handle();
+        let (jobs, queue) = mpsc::channel::<Job>();
+
+        thread::Builder::new()
+            .name(format!("yubikey-{serial}"))
+            .spawn(move || {
+                let mut yubikey = yubikey;
+
+                for job in queue {
+                    job(&mut yubikey);
+                }
+            })
+            .map_err(|e| {
+                error!("error spawning YubiKey worker thread: {}", e);
+                Error::GenericError
+            })?;
+
+        Ok(Self {
+            jobs,
+            name,
+            version,
+            serial,
+            cancel,
+        })
+    }
+
+    /// Open a connection to a YubiKey (see [`YubiKey::open`]).
+    pub fn open() -> Operation<Self> {
+        spawn(|| YubiKey::open().and_then(Self::new))
+    }
+
+    /// Open a YubiKey with a specific serial number (see
+    /// [`YubiKey::open_by_serial`]).
+    pub fn open_by_serial(serial: Serial) -> Operation<Self> {
+        spawn(move || YubiKey::open_by_serial(serial).and_then(Self::new))
+    }
+
+    /// Open the YubiKey in the PC/SC reader with the given name.
+    pub fn open_reader(name: impl Into<String>) -> Operation<Self> {
+        let name = name.into();
+
+        spawn(move || {
+            let mut context = Context::open()?;
+
+            let reader = context
+                .iter()?
+                .find(|reader| reader.name() == name)
+                .ok_or_else(|| {
+                    error!("no reader named '{}'", name);
+                    Error::NotFound
+                })?;
+
+            reader.open().and_then(Self::new)
+        })
+    }
+
+    /// Get the name of the associated PC/SC card reader.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the YubiKey's PIV application version.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Get YubiKey device serial number.
+    pub fn serial(&self) -> Serial {
+        self.serial
+    }
+
+    /// Get a handle for cancelling the touch-gated operation in progress on
+    /// the worker thread (see [`crate::touch`]).
+    ///
+    /// Dropping an [`Operation`] doesn't stop it once it has started, so this
+    /// is the way to abort one waiting for the user to touch the YubiKey.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Run an arbitrary operation with the [`YubiKey`] on the worker thread.
+    ///
+    /// This allows using any part of the blocking API which doesn't have an
+    /// async counterpart here.
+    pub fn run<T, F>(&self, f: F) -> Operation<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut YubiKey) -> Result<T> + Send + 'static,
+    {
+        let (completion, operation) = oneshot();
+
+        let job: Job = Box::new(move |yubikey| {
+            if !completion.is_cancelled() {
+                completion.complete(f(yubikey));
+            }
+        });
+
+        // If the worker is gone, the job (and its completion) is dropped,
+        // which resolves the operation with an error.
+        let _ = self.jobs.send(job);
+        operation
+    }
+
+    /// Verify device PIN (see [`YubiKey::verify_pin`]).
+    pub fn verify_pin(&self, pin: &[u8]) -> Operation<()> {
+        let pin = Buffer::new(pin.to_vec());
+        self.run(move |yubikey| yubikey.verify_pin(&pin))
+    }
+
+    /// Authenticate to the card using the provided management key (see
+    /// [`YubiKey::authenticate`]).
+    pub fn authenticate(&self, mgm_key: MgmKey) -> Operation<()> {
+        self.run(move |yubikey| yubikey.authenticate(&mgm_key))
+    }
+
+    /// Get the number of PIN retries (see [`YubiKey::get_pin_retries`]).
+    pub fn get_pin_retries(&self) -> Operation<u8> {
+        self.run(YubiKey::get_pin_retries)
+    }
+
+    /// Generate a new key (see [`piv::generate`]).
+    pub fn generate(
+        &self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> Operation<SubjectPublicKeyInfoOwned> {
+        self.run(move |yubikey| piv::generate(yubikey, slot, algorithm, pin_policy, touch_policy))
+    }
+
+    /// Sign data using a PIV key (see [`piv::sign_data`]).
+    pub fn sign_data(
+        &self,
+        raw_in: &[u8],
+        algorithm: AlgorithmId,
+        slot: SlotId,
+    ) -> Operation<Buffer> {
+        let raw_in = Buffer::new(raw_in.to_vec());
+        self.run(move |yubikey| piv::sign_data(yubikey, &raw_in, algorithm, slot))
+    }
+
+    /// Read the certificate in the given slot (see [`Certificate::read`]).
+    pub fn read_certificate(&self, slot: SlotId) -> Operation<Certificate> {
+        self.run(move |yubikey| Certificate::read(yubikey, slot))
+    }
+
+    /// Write a certificate to the given slot (see [`Certificate::write`]).
+    pub fn write_certificate(
+        &self,
+        certificate: Certificate,
+        slot: SlotId,
+        certinfo: CertInfo,
+    ) -> Operation<()> {
+        self.run(move |yubikey| certificate.write(yubikey, slot, certinfo))
+    }
+}
+
+impl fmt::Debug for AsyncYubiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncYubiKey")
+            .field("name", &self.name)
+            .field("version", &self.version)
+            .field("serial", &self.serial)
+            .finish_non_exhaustive()
+    }
+}
+
+/// List the names of the available PC/SC readers.
+pub fn readers() -> Operation<Vec<String>> {
+    spawn(|| {
+        let mut context = Context::open()?;
+        let names = context
+            .iter()?
+            .map(|reader| reader.name().into_owned())
+            .collect();
+        Ok(names)
+    })
+}
+
+/// Run a blocking function on a new thread.
+fn spawn<T, F>(f: F) -> Operation<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let (completion, operation) = oneshot();
+
+    let spawned = thread::Builder::new()
+        .name("yubikey-open".into())
+        .spawn(move || {
+            if !completion.is_cancelled() {
+                completion.complete(f());
+            }
+        });
+
+    if let Err(e) = spawned {
+        error!("error spawning YubiKey worker thread: {}", e);
+    }
+
+    operation
+}
+
+/// Future resolving to the result of an operation performed on a worker
+/// thread.
+///
+/// Dropping it before the operation has started cancels the operation.
+#[must_use = "operations are cancelled when dropped"]
+pub struct Operation<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Operation<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<T>> {
+        let mut shared = lock(&self.shared);
+
+        if let Some(result) = shared.result.take() {
+            return Poll::Ready(result);
+        }
+
+        if shared.abandoned {
+            error!("YubiKey worker exited without completing operation");
+            return Poll::Ready(Err(Error::GenericError));
+        }
+
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Operation<T> {
+    fn drop(&mut self) {
+        lock(&self.shared).cancelled = true;
+    }
+}
+
+impl<T> fmt::Debug for Operation<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Operation").finish_non_exhaustive()
+    }
+}
+
+/// State shared between an [`Operation`] and its [`Completion`].
+struct Shared<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+    cancelled: bool,
+    abandoned: bool,
+}
+
+/// Sending half of an [`Operation`].
+struct Completion<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Completion<T> {
+    /// Has the operation been dropped?
+    fn is_cancelled(&self) -> bool {
+        lock(&self.shared).cancelled
+    }
+
+    /// Complete the operation with the given result.
+    fn complete(self, result: Result<T>) {
+        let waker = {
+            let mut shared = lock(&self.shared);
+            shared.result = Some(result);
+            shared.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut shared = lock(&self.shared);
+            shared.abandoned = true;
+            shared.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Create a connected [`Completion`] and [`Operation`] pair.
+fn oneshot<T>() -> (Completion<T>, Operation<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+        cancelled: false,
+        abandoned: false,
+    }));
+
+    (
+        Completion {
+            shared: shared.clone(),
+        },
+        Operation { shared },
+    )
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
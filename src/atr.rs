@@ -0,0 +1,123 @@
+//! Answer To Reset (ATR) parsing
+//!
+//! Only the card capabilities advertised in the historical bytes (see
+//! ISO/IEC 7816-4 section 8.1.1) are of interest here.
+
+/// ATR of a YubiKey 5 series device (CCID interface).
+#[cfg(feature = "emulator")]
+pub(crate) const YUBIKEY_5: &[u8] = &[
+    0x3b, 0xfd, 0x13, 0x00, 0x00, 0x81, 0x31, 0xfe, 0x15, 0x80, 0x73, 0xc0, 0x21, 0xc0, 0x57, 0x59,
+    0x75, 0x62, 0x69, 0x4b, 0x65, 0x79, 0x40,
+];
+
+/// ATR of a YubiKey 5 NFC, as reported by a contactless reader.
+#[cfg(test)]
+const YUBIKEY_5_NFC: &[u8] = &[
+    0x3b, 0x8d, 0x80, 0x01, 0x80, 0x73, 0xc0, 0x21, 0xc0, 0x57, 0x59, 0x75, 0x62, 0x69, 0x4b, 0x65,
+    0x79, 0xf9,
+];
+
+/// Category indicator for historical bytes consisting of COMPACT-TLV objects
+const CATEGORY_COMPACT_TLV: u8 = 0x80;
+
+/// COMPACT-TLV tag of the card capabilities object
+const TAG_CARD_CAPABILITIES: u8 = 0x7;
+
+/// "Extended Lc and Le fields" bit of the third software function table
+const EXTENDED_LENGTH: u8 = 0x40;
+
+/// Does the card with the given ATR support extended length APDUs?
+pub(crate) fn supports_extended_length(atr: &[u8]) -> bool {
+    card_capabilities(atr)
+        .and_then(|capabilities| capabilities.get(2))
+        .is_some_and(|&table| table & EXTENDED_LENGTH != 0)
+}
+
+/// Is the given ATR one constructed by a PC/SC reader for a contactless
+/// (ISO/IEC 14443-4) card?
+///
+/// See PC/SC part 3 section 3.1.3.2.3.
+pub(crate) fn is_contactless(atr: &[u8]) -> bool {
+    matches!(atr, [0x3b, t0, 0x80, 0x01, ..] if t0 & 0xf0 == 0x80)
+}
+
+/// Find the card capabilities object in the historical bytes of an ATR.
+fn card_capabilities(atr: &[u8]) -> Option<&[u8]> {
+    let mut historical = historical_bytes(atr)?;
+
+    if historical.first() != Some(&CATEGORY_COMPACT_TLV) {
+        return None;
+    }
+
+    historical = &historical[1..];
+
+    while let Some((&header, rest)) = historical.split_first() {
+        let (tag, len) = (header >> 4, usize::from(header & 0xf));
+        let value = rest.get(..len)?;
+
+        if tag == TAG_CARD_CAPABILITIES {
+            return Some(value);
+        }
+
+        historical = &rest[len..];
+    }
+
+    None
+}
+
+/// Skip the interface bytes of an ATR, returning its historical bytes.
+fn historical_bytes(atr: &[u8]) -> Option<&[u8]> {
+    // TS, then T0: `Y1` in the high nibble and `K` in the low nibble
+    let t0 = *atr.get(1)?;
+    let count = usize::from(t0 & 0xf);
+    let mut indicator = t0 >> 4;
+    let mut offset = 2;
+
+    loop {
+        // TAi, TBi and TCi are present if the corresponding bits are set
+        offset += (indicator & 0x7).count_ones() as usize;
+
+        // TDi, which indicates the presence of the next interface bytes
+        if indicator & 0x8 == 0 {
+            break;
+        }
+
+        indicator = *atr.get(offset)? >> 4;
+        offset += 1;
+    }
+
+    atr.get(offset..offset + count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_contactless, supports_extended_length, YUBIKEY_5, YUBIKEY_5_NFC};
+
+    #[test]
+    fn yubikey_5_extended_length() {
+        assert!(supports_extended_length(YUBIKEY_5));
+    }
+
+    #[test]
+    fn yubikey_neo_no_extended_length() {
+        // YubiKey NEO: no card capabilities in its historical bytes
+        let atr = [
+            0x3b, 0xfc, 0x13, 0x00, 0x00, 0x81, 0x31, 0xfe, 0x15, 0x59, 0x75, 0x62, 0x69, 0x6b,
+            0x65, 0x79, 0x4e, 0x45, 0x4f, 0x72, 0x33, 0xe1,
+        ];
+        assert!(!supports_extended_length(&atr));
+    }
+
+    #[test]
+    fn contactless() {
+        assert!(is_contactless(YUBIKEY_5_NFC));
+        assert!(supports_extended_length(YUBIKEY_5_NFC));
+        assert!(!is_contactless(YUBIKEY_5));
+    }
+
+    #[test]
+    fn truncated_atr() {
+        assert!(!supports_extended_length(&YUBIKEY_5[..12]));
+        assert!(!supports_extended_length(&[]));
+    }
+}
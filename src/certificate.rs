@@ -129,8 +129,8 @@ impl Certificate {
 
     /// Read a certificate from the given slot in the YubiKey
     pub fn read(yubikey: &mut YubiKey, slot: SlotId) -> Result<Self> {
-        let txn = yubikey.begin_transaction()?;
-        let buf = read_certificate(&txn, slot)?;
+        let buf =
+            yubikey.retrying(|yubikey| read_certificate(&yubikey.begin_transaction()?, slot))?;
 
         if buf.is_empty() {
             return Err(Error::InvalidObject);
@@ -241,6 +241,37 @@ pub(crate) fn read_certificate(txn: &Transaction<'_>, slot: SlotId) -> Result<Bu
     }
 }
 
+/// Read the certificate stored in the given slot, if any.
+///
+/// Unlike [`read_certificate`], errors other than the certificate being
+/// absent are reported: errors reading the object as the outer result, and
+/// errors parsing the certificate as the inner one.
+pub(crate) fn find_certificate(
+    txn: &Transaction<'_>,
+    slot: SlotId,
+) -> Result<Option<Result<Certificate>>> {
+    let buf = match txn.fetch_object(slot.object_id()) {
+        Ok(buf) => buf,
+        Err(Error::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let buf = match buf.first() {
+        None => return Ok(None),
+        Some(&TAG_CERT) => match Tlv::parse_single(buf, TAG_CERT) {
+            Ok(buf) => buf,
+            Err(e) => return Ok(Some(Err(e))),
+        },
+        Some(_) => buf,
+    };
+
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Certificate::from_bytes(buf)))
+}
+
 /// Write certificate
 pub(crate) fn write_certificate(
     txn: &Transaction<'_>,
@@ -251,36 +282,46 @@ pub(crate) fn write_certificate(
     let object_id = slot.object_id();
 
     if let Some(data) = data {
-        let mut buf = [0u8; CB_OBJ_MAX];
-        let mut offset = Tlv::write(&mut buf, TAG_CERT, data)?;
-
-        // write compression info and LRC trailer
-        offset += Tlv::write(&mut buf[offset..], TAG_CERT_COMPRESS, &[certinfo.into()])?;
-        offset += Tlv::write(&mut buf[offset..], TAG_CERT_LRC, &[])?;
-
-        txn.save_object(object_id, &buf[..offset])
+        txn.save_object(object_id, &certificate_object(data, certinfo)?)
     } else {
         txn.save_object(object_id, &[])
     }
 }
 
+/// Encode a certificate as stored in a slot's certificate object
+pub(crate) fn certificate_object(data: &[u8], certinfo: CertInfo) -> Result<Vec<u8>> {
+    let mut buf = [0u8; CB_OBJ_MAX];
+    let mut offset = Tlv::write(&mut buf, TAG_CERT, data)?;
+
+    // write compression info and LRC trailer
+    offset += Tlv::write(&mut buf[offset..], TAG_CERT_COMPRESS, &[certinfo.into()])?;
+    offset += Tlv::write(&mut buf[offset..], TAG_CERT_LRC, &[])?;
+
+    Ok(buf[..offset].to_vec())
+}
+
 pub mod yubikey_signer {
     //! Signer implementation for yubikey
 
     use crate::{
         error::{Error, Result},
-        piv::AlgorithmId,
-        piv::{sign_data, SlotId},
+        padding,
+        piv::{self, sign_data, AlgorithmId, SlotId},
         YubiKey,
     };
     use der::{
-        asn1::{Any, OctetString},
-        oid::db::rfc5912,
-        Encode, Sequence,
+        asn1::Any,
+        oid::{db::rfc5912, AssociatedOid},
+        referenced::OwnedToRef,
+        Document,
+    };
+    use rsa::{pkcs1::RsaPssParams, pkcs1v15::RsaSignatureAssociatedOid};
+    use sha2::{
+        digest::{FixedOutputReset, Update},
+        Digest, Sha256, Sha384,
     };
-    use sha2::{Digest, Sha256, Sha384, Sha512};
-    use signature::Keypair;
-    use std::{cell::RefCell, fmt, io::Write, marker::PhantomData};
+    use signature::{hazmat::PrehashSigner, DigestSigner, Keypair, Verifier};
+    use std::{cell::RefCell, fmt, marker::PhantomData};
     use x509_cert::spki::{
         self, AlgorithmIdentifierOwned, DynSignatureAlgorithmIdentifier, EncodePublicKey,
         SignatureBitStringEncoding, SubjectPublicKeyInfoRef,
@@ -288,7 +329,7 @@ pub mod yubikey_signer {
 
     type SigResult<T> = core::result::Result<T, signature::Error>;
 
-    /// Key to be used to sign certificates
+    /// Type of key used by a [`Signer`]
     pub trait KeyType {
         /// Error returned when working with signature
         type Error: Into<signature::Error> + fmt::Debug;
@@ -313,6 +354,16 @@ pub mod yubikey_signer {
         fn read_signature(input: &[u8]) -> SigResult<Self::Signature>;
     }
 
+    /// Key which signs a digest of the message, and can sign precomputed
+    /// digests
+    pub trait PrehashKeyType: KeyType {
+        /// Hash function used to digest messages
+        type Digest: Digest;
+
+        /// Prepare a message digest before submitting it for signature
+        fn prepare_prehash(prehash: &[u8]) -> SigResult<Vec<u8>>;
+    }
+
     impl KeyType for ed25519_dalek::SigningKey {
         const ALGORITHM: AlgorithmId = AlgorithmId::Ed25519;
         type Error = ed25519_dalek::SignatureError;
@@ -320,8 +371,9 @@ pub mod yubikey_signer {
         type VerifyingKey = ed25519_dalek::VerifyingKey;
         type PublicKey = ed25519_dalek::VerifyingKey;
 
+        /// Ed25519 keys sign the message itself
         fn prepare(input: &[u8]) -> SigResult<Vec<u8>> {
-            Ok(Sha512::digest(input).to_vec())
+            Ok(input.to_vec())
         }
 
         fn read_signature(input: &[u8]) -> SigResult<Self::Signature> {
@@ -337,7 +389,7 @@ pub mod yubikey_signer {
         type PublicKey = p256::ecdsa::VerifyingKey;
 
         fn prepare(input: &[u8]) -> SigResult<Vec<u8>> {
-            Ok(Sha256::digest(input).to_vec())
+            Self::prepare_prehash(&Sha256::digest(input))
         }
 
         fn read_signature(input: &[u8]) -> SigResult<Self::Signature> {
@@ -345,6 +397,15 @@ pub mod yubikey_signer {
         }
     }
 
+    impl PrehashKeyType for p256::NistP256 {
+        type Digest = Sha256;
+
+        /// Digests longer than the curve's order are truncated
+        fn prepare_prehash(prehash: &[u8]) -> SigResult<Vec<u8>> {
+            Ok(prehash[..prehash.len().min(32)].to_vec())
+        }
+    }
+
     impl KeyType for p384::NistP384 {
         const ALGORITHM: AlgorithmId = AlgorithmId::EccP384;
         type Error = ecdsa::Error;
@@ -353,7 +414,7 @@ pub mod yubikey_signer {
         type PublicKey = p384::ecdsa::VerifyingKey;
 
         fn prepare(input: &[u8]) -> SigResult<Vec<u8>> {
-            Ok(Sha384::digest(input).to_vec())
+            Self::prepare_prehash(&Sha384::digest(input))
         }
 
         fn read_signature(input: &[u8]) -> SigResult<Self::Signature> {
@@ -361,6 +422,15 @@ pub mod yubikey_signer {
         }
     }
 
+    impl PrehashKeyType for p384::NistP384 {
+        type Digest = Sha384;
+
+        /// Digests longer than the curve's order are truncated
+        fn prepare_prehash(prehash: &[u8]) -> SigResult<Vec<u8>> {
+            Ok(prehash[..prehash.len().min(48)].to_vec())
+        }
+    }
+
     /// Trait used to handle subtypes of RSA keys
     pub trait RsaLength {
         /// The length of the RSA key in bits
@@ -401,68 +471,151 @@ pub mod yubikey_signer {
         const ALGORITHM: AlgorithmId = AlgorithmId::Rsa4096;
     }
 
-    /// RSA keys used to sign certificates
-    pub struct YubiRsa<N: RsaLength> {
+    /// RSA keys used to sign with RSASSA-PKCS1-v1_5, using the digest `D`
+    /// (SHA-256 by default)
+    pub struct YubiRsa<N: RsaLength, D = Sha256> {
         _len: PhantomData<N>,
+        _digest: PhantomData<D>,
     }
 
-    impl<N: RsaLength> KeyType for YubiRsa<N> {
+    impl<N, D> KeyType for YubiRsa<N, D>
+    where
+        N: RsaLength,
+        D: Digest + AssociatedOid + RsaSignatureAssociatedOid,
+    {
         type Error = signature::Error;
         type Signature = rsa::pkcs1v15::Signature;
-        type VerifyingKey = rsa::pkcs1v15::VerifyingKey<Sha256>;
+        type VerifyingKey = rsa::pkcs1v15::VerifyingKey<D>;
         type PublicKey = rsa::RsaPublicKey;
         const ALGORITHM: AlgorithmId = N::ALGORITHM;
 
         fn prepare(input: &[u8]) -> SigResult<Vec<u8>> {
-            let hashed = Sha256::digest(input).to_vec();
+            Self::prepare_prehash(&D::digest(input))
+        }
+
+        fn read_signature(input: &[u8]) -> SigResult<Self::Signature> {
+            Self::Signature::try_from(input)
+        }
+    }
 
-            OctetString::new(hashed)
-                .map_err(|e| e.into())
-                .and_then(Self::emsa_pkcs1_1_5)
+    impl<N, D> PrehashKeyType for YubiRsa<N, D>
+    where
+        N: RsaLength,
+        D: Digest + AssociatedOid + RsaSignatureAssociatedOid,
+    {
+        type Digest = D;
+
+        fn prepare_prehash(prehash: &[u8]) -> SigResult<Vec<u8>> {
+            padding::emsa_pkcs1v15_encode::<D>(prehash, N::BIT_LENGTH / 8)
                 .map_err(signature::Error::from_source)
         }
+    }
+
+    /// RSA keys used to sign certificates with RSASSA-PSS, using the digest
+    /// `D` (SHA-256 by default) and a salt of the same length
+    pub struct YubiRsaPss<N: RsaLength, D = Sha256> {
+        _len: PhantomData<N>,
+        _digest: PhantomData<D>,
+    }
+
+    impl<N: RsaLength, D: Digest + AssociatedOid> KeyType for YubiRsaPss<N, D> {
+        type Error = signature::Error;
+        type Signature = rsa::pss::Signature;
+        type VerifyingKey = PssVerifyingKey<D>;
+        type PublicKey = rsa::RsaPublicKey;
+        const ALGORITHM: AlgorithmId = N::ALGORITHM;
+
+        fn prepare(input: &[u8]) -> SigResult<Vec<u8>> {
+            Self::prepare_prehash(&D::digest(input))
+        }
 
         fn read_signature(input: &[u8]) -> SigResult<Self::Signature> {
             Self::Signature::try_from(input)
         }
     }
 
-    impl<N: RsaLength> YubiRsa<N> {
-        /// https://www.rfc-editor.org/rfc/rfc8017#section-9.2
-        fn emsa_pkcs1_1_5(digest: OctetString) -> Result<Vec<u8>> {
-            /// https://www.rfc-editor.org/rfc/rfc8017#appendix-A.2.4
-            #[derive(Debug, Sequence)]
-            struct DigestInfo {
-                digest_algorithm: AlgorithmIdentifierOwned,
-                digest: OctetString,
+    impl<N: RsaLength, D: Digest + AssociatedOid> PrehashKeyType for YubiRsaPss<N, D> {
+        type Digest = D;
+
+        fn prepare_prehash(prehash: &[u8]) -> SigResult<Vec<u8>> {
+            padding::emsa_pss_encode::<D>(prehash, N::BIT_LENGTH - 1, <D as Digest>::output_size())
+                .map_err(signature::Error::from_source)
+        }
+    }
+
+    /// Public key of a [`YubiRsaPss`] key.
+    ///
+    /// Unlike [`rsa::pss::VerifyingKey`], this identifies the signature
+    /// algorithm (with its RSASSA-PSS parameters) when building certificates.
+    pub struct PssVerifyingKey<D: Digest> {
+        inner: rsa::pss::VerifyingKey<D>,
+    }
+
+    impl<D: Digest> AsRef<rsa::pss::VerifyingKey<D>> for PssVerifyingKey<D> {
+        fn as_ref(&self) -> &rsa::pss::VerifyingKey<D> {
+            &self.inner
+        }
+    }
+
+    impl<D: Digest> Clone for PssVerifyingKey<D> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
             }
+        }
+    }
 
-            let em_len = N::BIT_LENGTH / 8;
+    impl<D: Digest> fmt::Debug for PssVerifyingKey<D> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("PssVerifyingKey")
+                .field("salt_len", &self.inner.salt_len())
+                .finish_non_exhaustive()
+        }
+    }
 
-            let null = Any::null();
+    impl<D: Digest> From<rsa::RsaPublicKey> for PssVerifyingKey<D> {
+        fn from(key: rsa::RsaPublicKey) -> Self {
+            Self {
+                inner: rsa::pss::VerifyingKey::new(key),
+            }
+        }
+    }
 
-            let t = DigestInfo {
-                digest_algorithm: AlgorithmIdentifierOwned {
-                    oid: rfc5912::ID_SHA_256,
-                    parameters: Some(null),
-                },
-                digest,
-            };
-            let t = t.to_der()?;
+    impl<D: Digest> EncodePublicKey for PssVerifyingKey<D> {
+        fn to_public_key_der(&self) -> spki::Result<Document> {
+            self.inner.to_public_key_der()
+        }
+    }
 
-            let ps = vec![0xff; em_len - t.len() - 3];
-            assert!(ps.len() >= 8, "spec violation");
+    impl<D: Digest + AssociatedOid> DynSignatureAlgorithmIdentifier for PssVerifyingKey<D> {
+        fn signature_algorithm_identifier(&self) -> spki::Result<AlgorithmIdentifierOwned> {
+            let salt_len = <D as Digest>::output_size()
+                .try_into()
+                .map_err(|_| der::Error::from(der::Tag::Integer.value_error()))?;
+
+            Ok(AlgorithmIdentifierOwned {
+                oid: rfc5912::ID_RSASSA_PSS,
+                parameters: Some(Any::encode_from(&RsaPssParams::new::<D>(salt_len))?),
+            })
+        }
+    }
 
-            let mut out = Vec::with_capacity(em_len);
-            out.write(&[0x00, 0x01]).map_err(|_| Error::MemoryError)?;
-            out.write(&ps).map_err(|_| Error::MemoryError)?;
-            out.write(&[0x00]).map_err(|_| Error::MemoryError)?;
-            out.write(&t).map_err(|_| Error::MemoryError)?;
-            Ok(out)
+    impl<D: Digest + FixedOutputReset> Verifier<rsa::pss::Signature> for PssVerifyingKey<D> {
+        fn verify(&self, msg: &[u8], signature: &rsa::pss::Signature) -> SigResult<()> {
+            self.inner.verify(msg, signature)
         }
     }
 
-    /// The entrypoint to sign data with the yubikey.
+    /// Signer using a key stored in a slot of the YubiKey.
+    ///
+    /// This implements the [`signature`] traits ([`signature::Signer`],
+    /// [`Keypair`], and for keys signing a digest of the message
+    /// [`DigestSigner`] and [`PrehashSigner`]), and can be used to build
+    /// certificates. ECDSA signers produce both DER-encoded and fixed-size
+    /// signatures.
+    ///
+    /// If the key's touch policy requires it, signing waits for the user to
+    /// touch the YubiKey (see [`crate::touch`]).
     pub struct Signer<'y, KT: KeyType> {
         yubikey: RefCell<&'y mut YubiKey>,
         key: SlotId,
@@ -485,6 +638,28 @@ pub mod yubikey_signer {
                 public_key,
             })
         }
+
+        /// Create a new Signer for the key in the given slot.
+        ///
+        /// The public key is read from the slot's metadata (which also checks
+        /// the key's algorithm), or with firmware older than 5.3 from the
+        /// certificate stored in the slot.
+        pub fn from_slot(yubikey: &'y mut YubiKey, key: SlotId) -> Result<Self> {
+            let subject_pki = piv::slot_public_key(yubikey, key, KT::ALGORITHM)?;
+            Self::new(yubikey, key, subject_pki.owned_to_ref())
+        }
+
+        /// Sign data prepared for the key type
+        fn sign_prepared(&self, data: &[u8]) -> SigResult<KT::Signature> {
+            let out = sign_data(
+                &mut self.yubikey.borrow_mut(),
+                data,
+                KT::ALGORITHM,
+                self.key,
+            )
+            .map_err(signature::Error::from_source)?;
+            KT::read_signature(&out)
+        }
     }
 
     impl<KT: KeyType> Keypair for Signer<'_, KT> {
@@ -502,17 +677,70 @@ pub mod yubikey_signer {
 
     impl<KT: KeyType> signature::Signer<KT::Signature> for Signer<'_, KT> {
         fn try_sign(&self, msg: &[u8]) -> SigResult<KT::Signature> {
-            let data = KT::prepare(msg)?;
+            self.sign_prepared(&KT::prepare(msg)?)
+        }
+    }
 
-            let out = sign_data(
-                &mut self.yubikey.borrow_mut(),
-                &data,
-                KT::ALGORITHM,
-                self.key,
-            )
-            .map_err(signature::Error::from_source)?;
-            let out = KT::read_signature(&out)?;
-            Ok(out)
+    impl<KT: PrehashKeyType> PrehashSigner<KT::Signature> for Signer<'_, KT> {
+        fn sign_prehash(&self, prehash: &[u8]) -> SigResult<KT::Signature> {
+            self.sign_prepared(&KT::prepare_prehash(prehash)?)
+        }
+    }
+
+    impl<KT> DigestSigner<KT::Digest, KT::Signature> for Signer<'_, KT>
+    where
+        KT: PrehashKeyType,
+        KT::Digest: Update,
+    {
+        fn try_sign_digest<F: Fn(&mut KT::Digest) -> SigResult<()>>(
+            &self,
+            f: F,
+        ) -> SigResult<KT::Signature> {
+            let mut digest = KT::Digest::new();
+            f(&mut digest)?;
+            self.sign_prehash(&digest.finalize())
+        }
+    }
+
+    impl signature::Signer<p256::ecdsa::Signature> for Signer<'_, p256::NistP256> {
+        fn try_sign(&self, msg: &[u8]) -> SigResult<p256::ecdsa::Signature> {
+            signature::Signer::<p256::ecdsa::DerSignature>::try_sign(self, msg)?.try_into()
+        }
+    }
+
+    impl PrehashSigner<p256::ecdsa::Signature> for Signer<'_, p256::NistP256> {
+        fn sign_prehash(&self, prehash: &[u8]) -> SigResult<p256::ecdsa::Signature> {
+            PrehashSigner::<p256::ecdsa::DerSignature>::sign_prehash(self, prehash)?.try_into()
+        }
+    }
+
+    impl DigestSigner<Sha256, p256::ecdsa::Signature> for Signer<'_, p256::NistP256> {
+        fn try_sign_digest<F: Fn(&mut Sha256) -> SigResult<()>>(
+            &self,
+            f: F,
+        ) -> SigResult<p256::ecdsa::Signature> {
+            DigestSigner::<Sha256, p256::ecdsa::DerSignature>::try_sign_digest(self, f)?.try_into()
+        }
+    }
+
+    impl signature::Signer<p384::ecdsa::Signature> for Signer<'_, p384::NistP384> {
+        fn try_sign(&self, msg: &[u8]) -> SigResult<p384::ecdsa::Signature> {
+            signature::Signer::<p384::ecdsa::DerSignature>::try_sign(self, msg)?.try_into()
+        }
+    }
+
+    impl PrehashSigner<p384::ecdsa::Signature> for Signer<'_, p384::NistP384> {
+        fn sign_prehash(&self, prehash: &[u8]) -> SigResult<p384::ecdsa::Signature> {
+            PrehashSigner::<p384::ecdsa::DerSignature>::sign_prehash(self, prehash)?.try_into()
+        }
+    }
+
+    impl DigestSigner<Sha384, p384::ecdsa::Signature> for Signer<'_, p384::NistP384> {
+        fn try_sign_digest<F: Fn(&mut Sha384) -> SigResult<()>>(
+            &self,
+            f: F,
+        ) -> SigResult<p384::ecdsa::Signature> {
+            DigestSigner::<Sha384, p384::ecdsa::DerSignature>::try_sign_digest(self, f)?.try_into()
         }
     }
 }
@@ -8,6 +8,15 @@ pub(crate) const CB_BUF_MAX: usize = 3072;
 /// YubiKey max object size
 pub(crate) const CB_OBJ_MAX: usize = CB_BUF_MAX - 9;
 
+/// Max size of an extended length APDU (YubiKey 4.3 and newer)
+pub(crate) const CB_EXT_APDU_MAX: usize = 3062;
+
+/// Max command data in an extended length APDU (less header, `Lc` and `Le`)
+pub(crate) const CB_EXT_APDU_DATA_MAX: usize = CB_EXT_APDU_MAX - 9;
+
+/// Max response size (including status words) to an extended length APDU
+pub(crate) const CB_EXT_RECV_MAX: usize = 0x10000 + 2;
+
 pub(crate) const CB_OBJ_TAG_MIN: usize = 2; // 1 byte tag + 1 byte len
 pub(crate) const CB_OBJ_TAG_MAX: usize = CB_OBJ_TAG_MIN + 2; // 1 byte tag + 3 bytes len
 
@@ -0,0 +1,2114 @@
+//! Software emulation of a YubiKey's PIV application.
+//!
+//! [`VirtualYubiKey`] is a [`Transport`] which interprets APDUs in-process
+//! instead of sending them to a card, so [`YubiKey`] sessions can be opened
+//! and exercised without any hardware attached:
+//!
+//! ```
+//! use yubikey::{emulator::VirtualYubiKey, piv, MgmKey, PinPolicy, Serial, TouchPolicy, Version};
+//!
+//! let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2]))?;
+//! let mut yubikey = card.open()?;
+//!
+//! yubikey.verify_pin(b"123456")?;
+//! yubikey.authenticate(&MgmKey::get_default(&yubikey)?)?;
+//!
+//! piv::generate(
+//!     &mut yubikey,
+//!     piv::SlotId::Signature,
+//!     piv::AlgorithmId::EccP256,
+//!     PinPolicy::Default,
+//!     TouchPolicy::Default,
+//! )?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! The emulated card starts out in its factory default state: PIN `123456`,
+//! PUK `12345678`, the default management key for its firmware version, and
+//! an ECC P-256 attestation key in slot `f9` with a self-signed attestation
+//! certificate. It implements the Yubico PIV extensions used by this crate,
+//! with real key storage, PIN/PUK retry counters and management key mutual
+//! authentication. Like a YubiKey 5, it advertises support for extended
+//! length APDUs in its ATR, and also accepts command chaining.
+//!
+//! Firmware 5.7 and newer also emulate the Security Domain commands used to
+//! open [secure channels][`crate::scp`]: SCP03 with the factory default keys
+//! (key version `0xff`), and SCP11b with a key (key version `0x01`) whose
+//! certificate is issued by a CA generated for each card (see
+//! [`VirtualYubiKey::scp11b_ca`]). Like on a YubiKey, the certificates can
+//! only be read with the Security Domain application selected.
+//!
+//! The management application is emulated too, as far as reading the
+//! device configuration and changing the enabled applications and the
+//! configuration lock.
+//!
+//! Limitations:
+//!
+//! - Only firmware 5.x is emulated (the YubiKey 4 OTP applet is absent).
+//! - Writing the device configuration doesn't reboot the card.
+//! - Touch policies are only enforced after
+//!   [`VirtualYubiKey::set_touch_required`]; until then, the card behaves as
+//!   if it was touched whenever needed.
+//! - Nothing is persisted: all state is lost when the last handle is dropped.
+//!
+//! Once touch is required, touch-gated operations block until
+//! [`VirtualYubiKey::touch`] is called from another thread, the transport is
+//! interrupted (which resets the card), or the card gives up waiting after
+//! 15 seconds, like a YubiKey does.
+//!
+//! Resets by other applications, and removal of the card from its reader,
+//! can be simulated with [`VirtualYubiKey::simulate_reset`] and
+//! [`VirtualYubiKey::remove`]. Sessions then fail with the same errors
+//! PC/SC would report.
+
+use crate::{
+    apdu::{Ins, StatusWords},
+    atr,
+    consts::{
+        TAG_CONFIG_LOCK, TAG_NFC_ENABLED, TAG_NFC_SUPPORTED, TAG_SERIAL, TAG_UNLOCK,
+        TAG_USB_ENABLED, TAG_USB_SUPPORTED, TAG_VERSION,
+    },
+    error::{Error, Result},
+    mgm::{Capability, MgmAlgorithmId, MgmKey},
+    piv::{self, AlgorithmId, Origin, SlotId},
+    policy::{PinPolicy, TouchPolicy},
+    scp::{self, SessionKeys, StaticKeys},
+    serialization::Tlv,
+    transport::{Disposition, Interrupt, Transport, TransportTransaction},
+    yubikey::{Serial, Version, YubiKey},
+    Buffer, ObjectId,
+};
+use der::{asn1::OctetString, Decode, Encode};
+use elliptic_curve::Generate;
+use log::{debug, error};
+use rand_core::Rng;
+use rsa::{traits::PublicKeyParts, BoxedUint, RsaPrivateKey};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use x509_cert::{
+    builder::{profile::BuilderProfile, Builder, CertificateBuilder},
+    ext::Extension,
+    name::Name,
+    serial_number::SerialNumber,
+    spki::{ObjectIdentifier, SubjectPublicKeyInfoOwned, SubjectPublicKeyInfoRef},
+    time::Validity,
+    Certificate,
+};
+use zeroize::Zeroizing;
+
+/// Name used for sessions opened with [`VirtualYubiKey::open`].
+pub const READER_NAME: &str = "Virtual YubiKey";
+
+/// Full PIV application identifier (selection matches on any prefix of it).
+const PIV_AID: &[u8] = &[
+    0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00,
+];
+
+/// Management application identifier.
+const MGMT_AID: &[u8] = &[0xa0, 0x00, 0x00, 0x05, 0x27, 0x47, 0x11, 0x17];
+
+/// Applications of a YubiKey 5 (NFC), all of them supported and enabled on
+/// both interfaces out of the factory.
+const CAPABILITIES: Capability = Capability::OTP
+    .union(Capability::U2F)
+    .union(Capability::FIDO2)
+    .union(Capability::OATH)
+    .union(Capability::PIV)
+    .union(Capability::OPENPGP)
+    .union(Capability::HSMAUTH);
+
+/// Application property template returned when selecting the PIV applet.
+const PIV_APT: &[u8] = &[
+    0x61, 0x11, 0x4f, 0x06, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x79, 0x07, 0x4f, 0x05, 0xa0, 0x00,
+    0x00, 0x03, 0x08,
+];
+
+/// Discovery object, returned as-is (without a `0x53` wrapper).
+const DISCOVERY_OBJECT: &[u8] = &[
+    0x7e, 0x12, 0x4f, 0x0b, 0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x01, 0x00, 0x5f,
+    0x2f, 0x02, 0x40, 0x00,
+];
+
+const OBJ_DISCOVERY: ObjectId = 0x7e;
+const OBJ_PRINTED: ObjectId = 0x005f_c109;
+
+const DEFAULT_PIN: &[u8] = b"123456";
+const DEFAULT_PUK: &[u8] = b"12345678";
+const DEFAULT_TRIES: u8 = 3;
+
+const PIN_LEN_MIN: usize = 6;
+const PIN_LEN_MAX: usize = 8;
+
+const KEY_PIN: u8 = 0x80;
+const KEY_PUK: u8 = 0x81;
+const KEY_CARDMGM: u8 = 0x9b;
+const KEY_ATTESTATION: u8 = 0xf9;
+
+const TAG_DYN_AUTH: u8 = 0x7c;
+const TAG_WITNESS: u8 = 0x80;
+const TAG_CHALLENGE: u8 = 0x81;
+const TAG_RESPONSE: u8 = 0x82;
+const TAG_EXPONENTIATION: u8 = 0x85;
+
+const TAG_CERT: u8 = 0x70;
+const TAG_CERT_COMPRESS: u8 = 0x71;
+const TAG_CERT_LRC: u8 = 0xfe;
+
+/// How long the card waits for a touch, and how long a touch is cached for
+/// keys with [`TouchPolicy::Cached`]
+const TOUCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+const ATTESTATION_SUBJECT: &str = "CN=Yubico PIV Attestation";
+const SCP11B_CA_SUBJECT: &str = "CN=Virtual YubiKey SCP11b CA";
+const SCP11B_SUBJECT: &str = "CN=Virtual YubiKey SCP11b";
+
+/// Yubico attestation certificate extensions
+const OID_FIRMWARE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.3");
+const OID_SERIAL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.7");
+const OID_POLICY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.8");
+
+/// Result of processing a command: response data, or an error status.
+type Reply<T = Vec<u8>> = core::result::Result<T, StatusWords>;
+
+/// Software emulation of a YubiKey.
+///
+/// Clones share the same emulated card, which allows keeping a handle around
+/// after passing one to [`YubiKey::open_transport`] (e.g. to reopen a session
+/// later, or to inspect the card's state from a test).
+pub struct VirtualYubiKey {
+    card: Arc<Mutex<Card>>,
+
+    /// Touch sensor, shared with the card (which is locked while it waits
+    /// for a touch)
+    sensor: Arc<TouchSensor>,
+
+    /// Number of times the card had been reset when this handle last
+    /// (re)connected to it
+    connected: u64,
+}
+
+impl VirtualYubiKey {
+    /// Create a new emulated YubiKey in its factory default state.
+    ///
+    /// Returns [`Error::NotSupported`] if `version` isn't a 5.x firmware
+    /// version.
+    pub fn new(serial: Serial, version: Version) -> Result<Self> {
+        if version.major != 5 {
+            error!("unsupported emulated firmware version: {}", version);
+            return Err(Error::NotSupported);
+        }
+
+        let sensor = Arc::new(TouchSensor::default());
+
+        Ok(Self {
+            card: Arc::new(Mutex::new(Card::new(serial, version, Arc::clone(&sensor))?)),
+            sensor,
+            connected: 0,
+        })
+    }
+
+    /// Open a [`YubiKey`] session with this emulated card.
+    pub fn open(&self) -> Result<YubiKey> {
+        YubiKey::open_transport(self.clone(), READER_NAME)
+    }
+
+    /// Get the emulated device's serial number.
+    pub fn serial(&self) -> Serial {
+        self.lock().map(|card| card.serial).unwrap_or(Serial(0))
+    }
+
+    /// Get the emulated PIV application version.
+    pub fn version(&self) -> Version {
+        self.lock()
+            .map(|card| card.version)
+            .unwrap_or(Version::new([0; 3]))
+    }
+
+    /// Reset the card, as another application could: its volatile state
+    /// (e.g. PIN verification) is cleared, and sessions have to reconnect
+    /// before using it again.
+    pub fn simulate_reset(&self) -> Result<()> {
+        self.lock()?.reset_connections();
+        Ok(())
+    }
+
+    /// Remove the card from its (virtual) reader. Sessions can't use it, or
+    /// reconnect to it, until it is inserted again.
+    pub fn remove(&self) -> Result<()> {
+        let mut card = self.lock()?;
+        card.present = false;
+        card.reset_connections();
+        Ok(())
+    }
+
+    /// Get the CA certificate which issued the certificate of the card's
+    /// SCP11b key, to be trusted when opening secure channels with
+    /// [`ScpKeyParams::scp11b`][`crate::scp::ScpKeyParams::scp11b`].
+    pub fn scp11b_ca(&self) -> Result<Certificate> {
+        Ok(Certificate::from_der(&self.lock()?.scp11b_certs[0])?)
+    }
+
+    /// Require the user to touch the card for operations with keys whose
+    /// touch policy requires it (by default, touch isn't required).
+    pub fn set_touch_required(&self, required: bool) {
+        self.sensor.lock().required = required;
+    }
+
+    /// Touch the card, if it is waiting for a touch.
+    ///
+    /// Returns whether the touch completed an operation.
+    pub fn touch(&self) -> bool {
+        let mut state = self.sensor.lock();
+
+        if !state.waiting {
+            return false;
+        }
+
+        state.touched = true;
+        self.sensor.changed.notify_all();
+        true
+    }
+
+    /// Is the card waiting for a touch?
+    pub fn is_waiting_for_touch(&self) -> bool {
+        self.sensor.lock().waiting
+    }
+
+    /// Insert the card back into its reader after [`VirtualYubiKey::remove`].
+    pub fn insert(&self) -> Result<()> {
+        self.lock()?.present = true;
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Card>> {
+        self.card.lock().map_err(|_| {
+            error!("emulated card state poisoned");
+            Error::GenericError
+        })
+    }
+}
+
+impl Clone for VirtualYubiKey {
+    fn clone(&self) -> Self {
+        Self {
+            card: Arc::clone(&self.card),
+            sensor: Arc::clone(&self.sensor),
+            connected: self.lock().map(|card| card.resets).unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Debug for VirtualYubiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtualYubiKey")
+            .field("serial", &self.serial())
+            .field("version", &self.version())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Transport for VirtualYubiKey {
+    fn begin_transaction(&mut self) -> Result<Box<dyn TransportTransaction + '_>> {
+        let card = self.lock()?;
+
+        if !card.present {
+            return Err(pcsc::Error::RemovedCard.into());
+        }
+
+        if card.resets != self.connected {
+            return Err(pcsc::Error::ResetCard.into());
+        }
+
+        Ok(Box::new(VirtualTransaction {
+            card: RefCell::new(card),
+        }))
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        let mut card = self.lock()?;
+
+        if !card.present {
+            return Err(pcsc::Error::NoSmartcard.into());
+        }
+
+        card.dispose(disposition);
+        let resets = card.resets;
+        drop(card);
+
+        self.connected = resets;
+        Ok(())
+    }
+
+    fn atr(&self) -> Result<Vec<u8>> {
+        Ok(atr::YUBIKEY_5.to_vec())
+    }
+
+    fn interrupter(&self) -> Option<Arc<dyn Interrupt>> {
+        Some(Arc::clone(&self.sensor) as Arc<dyn Interrupt>)
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> core::result::Result<(), (Box<dyn Transport>, Error)> {
+        let result = self.lock().map(|mut card| card.dispose(disposition));
+        result.map_err(|e| (self as Box<dyn Transport>, e))
+    }
+}
+
+/// Transaction with a [`VirtualYubiKey`], holding exclusive access to the card.
+struct VirtualTransaction<'a> {
+    card: RefCell<MutexGuard<'a, Card>>,
+}
+
+impl TransportTransaction for VirtualTransaction<'_> {
+    fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        let mut card = self.card.borrow_mut();
+        let resets = card.resets;
+        let response = card.transmit(send_buffer, recv_len);
+
+        // The card was reset while processing the command
+        if card.resets != resets {
+            return Err(pcsc::Error::ResetCard.into());
+        }
+
+        Ok(response)
+    }
+
+    fn end(self: Box<Self>, disposition: Disposition) -> Result<()> {
+        self.card.borrow_mut().dispose(disposition);
+        Ok(())
+    }
+}
+
+/// Emulated touch sensor.
+#[derive(Default)]
+struct TouchSensor {
+    state: Mutex<TouchState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct TouchState {
+    /// Do touch-gated operations wait for a touch?
+    required: bool,
+
+    /// Is the card waiting for a touch?
+    waiting: bool,
+
+    /// Was the card touched while waiting?
+    touched: bool,
+
+    /// Was the transport interrupted while waiting?
+    interrupted: bool,
+
+    /// When the card was last touched
+    last_touch: Option<Instant>,
+}
+
+/// Outcome of waiting for a touch.
+enum TouchEvent {
+    Touched,
+    Interrupted,
+    TimedOut,
+}
+
+impl TouchSensor {
+    fn lock(&self) -> MutexGuard<'_, TouchState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait for a touch, unless touch isn't required or a touch is still
+    /// cached (when `cached` is set).
+    fn wait(&self, cached: bool) -> TouchEvent {
+        let mut state = self.lock();
+
+        let recent = state
+            .last_touch
+            .is_some_and(|touched| touched.elapsed() < TOUCH_TIMEOUT);
+
+        if !state.required || (cached && recent) {
+            return TouchEvent::Touched;
+        }
+
+        debug!("emulator: waiting for touch");
+        state.waiting = true;
+        state.touched = false;
+        state.interrupted = false;
+
+        let (mut state, _) = self
+            .changed
+            .wait_timeout_while(state, TOUCH_TIMEOUT, |state| {
+                !state.touched && !state.interrupted
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+
+        state.waiting = false;
+
+        if std::mem::take(&mut state.interrupted) {
+            TouchEvent::Interrupted
+        } else if std::mem::take(&mut state.touched) {
+            state.last_touch = Some(Instant::now());
+            TouchEvent::Touched
+        } else {
+            TouchEvent::TimedOut
+        }
+    }
+}
+
+impl Interrupt for TouchSensor {
+    /// Interrupting the emulator only has an effect while the card waits for
+    /// a touch, which then resets it.
+    fn interrupt(&self) -> Result<()> {
+        let mut state = self.lock();
+
+        if state.waiting {
+            state.interrupted = true;
+            self.changed.notify_all();
+        }
+
+        Ok(())
+    }
+}
+
+/// A parsed command APDU.
+struct Command {
+    cla: u8,
+    ins: Ins,
+    p1: u8,
+    p2: u8,
+    data: Buffer,
+    extended: bool,
+}
+
+impl Command {
+    /// Parse a serialized short or extended length APDU.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let (header, body) = bytes.split_at_checked(4)?;
+
+        let (data, extended) = match body {
+            [] | [_] => (&[][..], false),
+            // Extended length, without command data
+            [0, _, _] => (&[][..], true),
+            // Extended length, with command data
+            [0, lc1, lc2, rest @ ..] => (
+                rest.get(..usize::from(u16::from_be_bytes([*lc1, *lc2])))?,
+                true,
+            ),
+            [lc, rest @ ..] => (rest.get(..usize::from(*lc))?, false),
+        };
+
+        Some(Self {
+            cla: header[0],
+            ins: header[1].into(),
+            p1: header[2],
+            p2: header[3],
+            data: Zeroizing::new(data.to_vec()),
+            extended,
+        })
+    }
+}
+
+/// Emulated card state.
+struct Card {
+    version: Version,
+    serial: Serial,
+    pin: Reference,
+    puk: Reference,
+    mgm_key: MgmKey,
+    mgm_touch: TouchPolicy,
+    keys: BTreeMap<u8, StoredKey>,
+    objects: BTreeMap<ObjectId, Vec<u8>>,
+    session: Session,
+
+    /// Static SCP03 keys (key version `0xff`)
+    scp03_keys: StaticKeys,
+
+    /// SCP11b key, and its certificate chain (CA first)
+    scp11b_key: p256::SecretKey,
+    scp11b_certs: Vec<Vec<u8>>,
+
+    /// Applications enabled over USB and NFC (management application)
+    usb_enabled: Capability,
+    nfc_enabled: Capability,
+
+    /// Configuration lock code, if the configuration is locked
+    config_lock: Option<[u8; 16]>,
+
+    /// Is the card in its reader?
+    present: bool,
+
+    /// Number of times the card was reset by "another application"
+    resets: u64,
+
+    /// Touch sensor
+    sensor: Arc<TouchSensor>,
+}
+
+/// Volatile state, cleared when the card is reset.
+#[derive(Default)]
+struct Session {
+    /// Selected application
+    applet: Option<Applet>,
+
+    /// Has the PIN been verified?
+    pin_verified: bool,
+
+    /// Has the PIN been verified since the last `PinPolicy::Always` operation?
+    pin_fresh: bool,
+
+    /// Has the management key been authenticated?
+    mgm_authenticated: bool,
+
+    /// Outstanding management key authentication step
+    mgm_challenge: Option<MgmChallenge>,
+
+    /// Command data received so far in a command chain
+    chained: Buffer,
+
+    /// Response data not yet retrieved with GET RESPONSE
+    pending: Vec<u8>,
+
+    /// SCP03 session awaiting EXTERNAL AUTHENTICATE, and the host cryptogram
+    /// expected in it
+    scp03_pending: Option<(scp::Session, [u8; 8])>,
+
+    /// Open secure channel
+    scp: Option<scp::Session>,
+}
+
+/// Applications of the emulated card.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Applet {
+    Piv,
+    Management,
+    SecurityDomain,
+}
+
+/// Management key authentication step awaiting a response from the host.
+enum MgmChallenge {
+    /// Mutual authentication: the plaintext of the witness sent to the host
+    Witness(Buffer),
+
+    /// External authentication: the challenge sent to the host
+    Challenge(Buffer),
+}
+
+impl Card {
+    fn new(serial: Serial, version: Version, sensor: Arc<TouchSensor>) -> Result<Self> {
+        let ca_key = PrivateKey::generate(AlgorithmId::EccP256)?;
+        let ca_subject = Name::from_str(SCP11B_CA_SUBJECT)?;
+        let ca_cert = ca_key.certify(
+            CardProfile {
+                issuer: ca_subject.clone(),
+                subject: ca_subject.clone(),
+                extensions: vec![],
+            },
+            ca_key.spki()?,
+        )?;
+
+        let sd_key = PrivateKey::generate(AlgorithmId::EccP256)?;
+        let sd_cert = ca_key.certify(
+            CardProfile {
+                issuer: ca_subject,
+                subject: Name::from_str(SCP11B_SUBJECT)?,
+                extensions: vec![],
+            },
+            sd_key.spki()?,
+        )?;
+
+        let PrivateKey::EccP256(scp11b_key) = sd_key else {
+            return Err(Error::AlgorithmError);
+        };
+
+        let mut card = Self {
+            version,
+            serial,
+            pin: Reference::new(DEFAULT_PIN),
+            puk: Reference::new(DEFAULT_PUK),
+            mgm_key: MgmKey::default_for_version(version)?,
+            mgm_touch: TouchPolicy::Never,
+            keys: BTreeMap::new(),
+            objects: BTreeMap::new(),
+            session: Session::default(),
+            scp03_keys: StaticKeys::default(),
+            scp11b_key,
+            scp11b_certs: vec![ca_cert, sd_cert],
+            usb_enabled: CAPABILITIES,
+            nfc_enabled: CAPABILITIES,
+            config_lock: None,
+            present: true,
+            resets: 0,
+            sensor,
+        };
+
+        let key = PrivateKey::generate(AlgorithmId::EccP256)?;
+        let subject = Name::from_str(ATTESTATION_SUBJECT)?;
+        let cert = key.certify(
+            CardProfile {
+                issuer: subject.clone(),
+                subject,
+                extensions: vec![],
+            },
+            key.spki()?,
+        )?;
+
+        card.keys.insert(
+            KEY_ATTESTATION,
+            StoredKey {
+                key,
+                pin_policy: PinPolicy::Never,
+                touch_policy: TouchPolicy::Never,
+                origin: Origin::Generated,
+            },
+        );
+        card.objects
+            .insert(SlotId::Attestation.object_id(), certificate_object(&cert));
+
+        Ok(card)
+    }
+
+    /// Reset the card, invalidating all connections to it.
+    fn reset_connections(&mut self) {
+        self.session = Session::default();
+        self.resets += 1;
+    }
+
+    /// Wait for the user to touch the card, as required by `policy`.
+    fn require_touch(&mut self, policy: TouchPolicy) -> Reply<()> {
+        let cached = match policy {
+            TouchPolicy::Always => false,
+            TouchPolicy::Cached => true,
+            _ => return Ok(()),
+        };
+
+        match self.sensor.wait(cached) {
+            TouchEvent::Touched => Ok(()),
+            TouchEvent::Interrupted => {
+                debug!("emulator: interrupted while waiting for touch; resetting");
+                self.reset_connections();
+                Err(StatusWords::SecurityStatusError)
+            }
+            TouchEvent::TimedOut => {
+                debug!("emulator: touch timed out");
+                Err(StatusWords::SecurityStatusError)
+            }
+        }
+    }
+
+    /// Apply the disposition used when ending a transaction or connection.
+    fn dispose(&mut self, disposition: Disposition) {
+        match disposition {
+            Disposition::LeaveCard => (),
+            _ => self.session = Session::default(),
+        }
+    }
+
+    /// Process a single serialized command APDU, returning the response
+    /// (including status words).
+    fn transmit(&mut self, command: &[u8], recv_len: usize) -> Vec<u8> {
+        // Commands wrapped with the secure channel (unless it is being opened)
+        let secure =
+            self.session.scp.is_some() && command.first().is_some_and(|cla| cla & 0x04 != 0);
+
+        let unwrapped;
+        let command = if secure {
+            match self
+                .session
+                .scp
+                .as_mut()
+                .and_then(|scp| scp.unwrap_command(command))
+            {
+                Some(command) => {
+                    unwrapped = Zeroizing::new(command);
+                    &unwrapped[..]
+                }
+                None => {
+                    debug!("emulator: C-MAC mismatch, closing secure channel");
+                    self.session.scp = None;
+                    return StatusWords::SecurityStatusError
+                        .code()
+                        .to_be_bytes()
+                        .to_vec();
+                }
+            }
+        } else {
+            command
+        };
+
+        let (reply, max_len) = match Command::parse(command) {
+            Some(command) => {
+                let max_len = if command.extended { 0x10000 } else { 0x100 };
+                (self.dispatch(command, secure), max_len)
+            }
+            None => (Err(StatusWords::WrongLengthError), 0),
+        };
+
+        // Responses to wrapped commands are wrapped, except for errors
+        let reply = match (reply, self.session.scp.as_ref()) {
+            (Ok(data), Some(scp)) if secure => Ok(scp.wrap_response(&data)),
+            (reply, _) => reply,
+        };
+
+        let max_len = recv_len.saturating_sub(2).min(max_len);
+
+        let (mut response, status_words) = match reply {
+            Ok(mut data) if data.len() > max_len => {
+                self.session.pending = data.split_off(max_len);
+                let remaining = self.session.pending.len();
+                let len = if remaining > 0xff { 0 } else { remaining as u8 };
+                (data, StatusWords::BytesRemaining { len })
+            }
+            Ok(data) => (data, StatusWords::Success),
+            Err(status_words) => (vec![], status_words),
+        };
+
+        response.extend_from_slice(&status_words.code().to_be_bytes());
+        response
+    }
+
+    fn dispatch(&mut self, command: Command, secure: bool) -> Reply {
+        if command.ins == Ins::GetResponseApdu {
+            return Ok(std::mem::take(&mut self.session.pending));
+        }
+
+        self.session.pending.clear();
+
+        if command.ins == Ins::SelectApplication {
+            return self.select(&command);
+        }
+
+        let Some(applet) = self.session.applet else {
+            return Err(StatusWords::NotSupportedError);
+        };
+
+        // Security Domain commands opening a secure channel, which are
+        // accepted with any application selected
+        match command.ins {
+            Ins::InitializeUpdate => return self.initialize_update(command.p1, &command.data),
+            Ins::ExternalAuthenticate => return self.external_authenticate(&command),
+            Ins::InternalAuthenticate => {
+                return self.internal_authenticate(command.p1, command.p2, &command.data)
+            }
+            _ => (),
+        }
+
+        if self.session.scp.is_some() && !secure {
+            debug!("emulator: unwrapped command with secure channel open");
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        // Command chaining: accumulate data until the last command in the chain
+        if command.cla & 0x10 != 0 {
+            self.session.chained.extend_from_slice(&command.data);
+            return Ok(vec![]);
+        }
+
+        let data = if self.session.chained.is_empty() {
+            command.data
+        } else {
+            let mut data = std::mem::take(&mut self.session.chained);
+            data.extend_from_slice(&command.data);
+            data
+        };
+
+        let (p1, p2) = (command.p1, command.p2);
+
+        debug!(
+            "emulator: {:?} (p1: {:02x}, p2: {:02x})",
+            command.ins, p1, p2
+        );
+
+        if applet == Applet::Management {
+            return match command.ins {
+                Ins::ReadConfig => Ok(self.read_config()),
+                Ins::WriteConfig => self.write_config(&data),
+                _ => Err(StatusWords::NotSupportedError),
+            };
+        }
+
+        if applet == Applet::SecurityDomain {
+            return match command.ins {
+                Ins::GetDataSd => self.get_certificates(p1, p2, &data),
+                _ => Err(StatusWords::NotSupportedError),
+            };
+        }
+
+        match command.ins {
+            Ins::GetVersion => Ok(vec![
+                self.version.major,
+                self.version.minor,
+                self.version.patch,
+            ]),
+            Ins::GetSerial => Ok(self.serial.0.to_be_bytes().to_vec()),
+            Ins::Verify => self.verify(p1, p2, &data),
+            Ins::ChangeReference => self.change_reference(p2, &data),
+            Ins::ResetRetry => self.reset_retry(p2, &data),
+            Ins::Authenticate if p2 == KEY_CARDMGM => self.authenticate_mgm(p1, &data),
+            Ins::Authenticate => self.authenticate_key(p1, p2, &data),
+            Ins::GenerateAsymmetric => self.generate(p2, &data),
+            Ins::ImportKey => self.import(p1, p2, &data),
+            Ins::Attest => self.attest(p1),
+            Ins::GetMetadata => self.metadata(p2),
+            Ins::MoveKey => self.move_key(p1, p2),
+            Ins::GetData => self.get_data(&data),
+            Ins::PutData => self.put_data(&data),
+            Ins::SetMgmKey => self.set_mgm_key(p2, &data),
+            Ins::SetPinRetries => self.set_pin_retries(p1, p2),
+            Ins::Reset => self.reset(),
+            _ => Err(StatusWords::NotSupportedError),
+        }
+    }
+
+    /// SELECT
+    fn select(&mut self, command: &Command) -> Reply {
+        if command.p1 != 0x04 {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let (applet, response) = if command.data.len() >= 5 && PIV_AID.starts_with(&command.data) {
+            (Applet::Piv, PIV_APT.to_vec())
+        } else if command.data[..] == *MGMT_AID {
+            (Applet::Management, self.version.to_string().into_bytes())
+        } else if command.data[..] == *scp::APPLET_ID {
+            (Applet::SecurityDomain, vec![])
+        } else {
+            return Err(StatusWords::NotFoundError);
+        };
+
+        self.session = Session {
+            applet: Some(applet),
+            ..Session::default()
+        };
+
+        Ok(response)
+    }
+
+    /// READ CONFIG (management application)
+    fn read_config(&self) -> Vec<u8> {
+        let config = [
+            tlv(TAG_USB_SUPPORTED, &CAPABILITIES.bits().to_be_bytes()),
+            tlv(TAG_SERIAL, &self.serial.0.to_be_bytes()),
+            tlv(TAG_USB_ENABLED, &self.usb_enabled.bits().to_be_bytes()),
+            tlv(
+                TAG_VERSION,
+                &[self.version.major, self.version.minor, self.version.patch],
+            ),
+            tlv(TAG_NFC_SUPPORTED, &CAPABILITIES.bits().to_be_bytes()),
+            tlv(TAG_NFC_ENABLED, &self.nfc_enabled.bits().to_be_bytes()),
+            tlv(TAG_CONFIG_LOCK, &[u8::from(self.config_lock.is_some())]),
+        ]
+        .concat();
+
+        [&[config.len() as u8][..], &config].concat()
+    }
+
+    /// WRITE CONFIG (management application). Only the enabled applications
+    /// and the configuration lock are kept, and the card doesn't reboot.
+    fn write_config(&mut self, data: &[u8]) -> Reply {
+        let config = match data.split_first() {
+            Some((len, config)) if usize::from(*len) == config.len() => config,
+            _ => return Err(StatusWords::WrongLengthError),
+        };
+
+        let (mut usb_enabled, mut nfc_enabled) = (self.usb_enabled, self.nfc_enabled);
+        let (mut unlock, mut config_lock) = (None, self.config_lock);
+
+        for (tag, value) in tlv_items(config)? {
+            match tag {
+                TAG_USB_ENABLED => usb_enabled = capabilities(value)?,
+                TAG_NFC_ENABLED => nfc_enabled = capabilities(value)?,
+                TAG_UNLOCK => unlock = Some(lock_code(value)?),
+                TAG_CONFIG_LOCK => {
+                    config_lock = Some(lock_code(value)?).filter(|code| code != &[0; 16]);
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(lock) = self.config_lock {
+            if !unlock.is_some_and(|code| bool::from(code.ct_eq(&lock))) {
+                return Err(StatusWords::SecurityStatusError);
+            }
+        }
+
+        self.usb_enabled = usb_enabled;
+        self.nfc_enabled = nfc_enabled;
+        self.config_lock = config_lock;
+        Ok(vec![])
+    }
+
+    /// INITIALIZE UPDATE (SCP03)
+    fn initialize_update(&mut self, kvn: u8, data: &[u8]) -> Reply {
+        self.require_scp()?;
+
+        if kvn != 0 && kvn != scp::KVN_SCP03_DEFAULT {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        if data.len() != 8 {
+            return Err(StatusWords::WrongLengthError);
+        }
+
+        let card_challenge = random_bytes(8);
+        let context = [data, &card_challenge[..]].concat();
+        let keys = SessionKeys::scp03(&self.scp03_keys, &context);
+        let card_cryptogram = keys.card_cryptogram(&context);
+        let host_cryptogram = keys.host_cryptogram(&context);
+
+        self.session.scp = None;
+        self.session.scp03_pending = Some((scp::Session::new(keys, [0; 16]), host_cryptogram));
+
+        // Key diversification data, key information, card challenge and
+        // card cryptogram
+        let mut response = vec![0; 10];
+        response.extend_from_slice(&[scp::KVN_SCP03_DEFAULT, 0x03, 0x00]);
+        response.extend_from_slice(&card_challenge);
+        response.extend_from_slice(&card_cryptogram);
+        Ok(response)
+    }
+
+    /// EXTERNAL AUTHENTICATE (SCP03)
+    fn external_authenticate(&mut self, command: &Command) -> Reply {
+        self.require_scp()?;
+
+        let (mut session, host_cryptogram) = self
+            .session
+            .scp03_pending
+            .take()
+            .ok_or(StatusWords::ConditionsNotSatisfiedError)?;
+
+        if command.p1 != scp::SECURITY_LEVEL {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let header = [command.cla, command.ins.code(), command.p1, command.p2];
+        match session.check_mac(header, &command.data, command.extended) {
+            Some(cryptogram) if bool::from(cryptogram.ct_eq(&host_cryptogram)) => {
+                self.session.scp = Some(session);
+                Ok(vec![])
+            }
+            _ => Err(StatusWords::SecurityStatusError),
+        }
+    }
+
+    /// INTERNAL AUTHENTICATE (SCP11b)
+    fn internal_authenticate(&mut self, kvn: u8, kid: u8, data: &[u8]) -> Reply {
+        self.require_scp()?;
+
+        if kvn != scp::KVN_SCP11B_DEFAULT || kid != scp::KID_SCP11B {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        // Control reference template, followed by the host's ephemeral key
+        let (crt, epk) = match data {
+            [scp::TAG_CRT, len, rest @ ..] => rest
+                .split_at_checked(usize::from(*len))
+                .ok_or(StatusWords::WrongLengthError)?,
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        if crt.get(..4) != Some(&[0x90, 0x02, 0x11, 0x00][..]) {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let epk_oce = match epk {
+            [t1, t2, len, point @ ..]
+                if [*t1, *t2] == scp::TAG_EPK && usize::from(*len) == point.len() =>
+            {
+                p256::PublicKey::from_sec1_bytes(point)
+                    .map_err(|_| StatusWords::IncorrectParamError)?
+            }
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        let esk_sd = p256::SecretKey::generate_from_rng(&mut rand::rng());
+        let shsee =
+            elliptic_curve::ecdh::diffie_hellman(esk_sd.to_nonzero_scalar(), epk_oce.as_affine());
+        let shses = elliptic_curve::ecdh::diffie_hellman(
+            self.scp11b_key.to_nonzero_scalar(),
+            epk_oce.as_affine(),
+        );
+        let (receipt_key, keys) =
+            SessionKeys::scp11(shsee.raw_secret_bytes(), shses.raw_secret_bytes());
+
+        let mut response = scp::TAG_EPK.to_vec();
+        response.push(scp::P256_POINT_LEN as u8);
+        response.extend_from_slice(&esk_sd.public_key().to_sec1_bytes());
+
+        let receipt = scp::cmac(&receipt_key, &[data, &response].concat());
+        response.extend(tlv(scp::TAG_RECEIPT, &receipt));
+
+        self.session.scp03_pending = None;
+        self.session.scp = Some(scp::Session::new(keys, receipt));
+        Ok(response)
+    }
+
+    /// GET DATA of the Security Domain: only the certificate store is
+    /// supported, holding the SCP11b certificate chain.
+    fn get_certificates(&self, p1: u8, p2: u8, data: &[u8]) -> Reply {
+        self.require_scp()?;
+
+        if [p1, p2] != scp::CERTIFICATE_STORE {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        match data {
+            [scp::TAG_CRT, 4, 0x83, 2, scp::KID_SCP11B, scp::KVN_SCP11B_DEFAULT] => {
+                Ok(self.scp11b_certs.concat())
+            }
+            [scp::TAG_CRT, 4, 0x83, 2, _, _] => Err(StatusWords::ReferenceDataNotFoundError),
+            _ => Err(StatusWords::IncorrectParamError),
+        }
+    }
+
+    /// VERIFY
+    fn verify(&mut self, p1: u8, p2: u8, data: &[u8]) -> Reply {
+        if p2 != KEY_PIN {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        // Reset the verification status
+        if p1 == 0xff {
+            self.session.pin_verified = false;
+            self.session.pin_fresh = false;
+            return Ok(vec![]);
+        }
+
+        if data.is_empty() {
+            return match self.pin.remaining {
+                _ if self.session.pin_verified => Ok(vec![]),
+                0 => Err(StatusWords::AuthBlockedError),
+                tries => Err(StatusWords::VerifyFailError { tries }),
+            };
+        }
+
+        self.session.pin_verified = false;
+        self.session.pin_fresh = false;
+        self.pin.check(data)?;
+        self.session.pin_verified = true;
+        self.session.pin_fresh = true;
+
+        Ok(vec![])
+    }
+
+    /// CHANGE REFERENCE DATA
+    fn change_reference(&mut self, p2: u8, data: &[u8]) -> Reply {
+        let (current, new) = split_references(data)?;
+
+        let reference = match p2 {
+            KEY_PIN => &mut self.pin,
+            KEY_PUK => &mut self.puk,
+            _ => return Err(StatusWords::ReferenceDataNotFoundError),
+        };
+
+        reference.check(current)?;
+        reference.set(new)?;
+
+        if p2 == KEY_PIN {
+            self.session.pin_verified = true;
+            self.session.pin_fresh = true;
+        }
+
+        Ok(vec![])
+    }
+
+    /// RESET RETRY COUNTER
+    fn reset_retry(&mut self, p2: u8, data: &[u8]) -> Reply {
+        if p2 != KEY_PIN {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        let (puk, new_pin) = split_references(data)?;
+        self.puk.check(puk)?;
+        self.pin.set(new_pin)?;
+        Ok(vec![])
+    }
+
+    /// GENERAL AUTHENTICATE with the management key
+    fn authenticate_mgm(&mut self, p1: u8, data: &[u8]) -> Reply {
+        if p1 != u8::from(self.mgm_key.algorithm_id()) {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let items = dynamic_auth_items(data)?;
+        let item = |tag| items.iter().find(|(t, _)| *t == tag).map(|(_, v)| *v);
+        let block_size = match self.mgm_key.algorithm_id() {
+            MgmAlgorithmId::ThreeDes => 8,
+            _ => 16,
+        };
+
+        let challenge = self.session.mgm_challenge.take();
+        self.session.mgm_authenticated = false;
+
+        match (item(TAG_WITNESS), item(TAG_CHALLENGE), item(TAG_RESPONSE)) {
+            // Step 1 of mutual authentication: send an encrypted witness
+            (Some([]), None, None) => {
+                let witness = random_bytes(block_size);
+                let mut encrypted = witness.clone();
+                self.mgm_key
+                    .encrypt_block(&mut encrypted)
+                    .map_err(|_| StatusWords::CommandAbortedError)?;
+
+                self.session.mgm_challenge = Some(MgmChallenge::Witness(witness));
+                Ok(tlv(TAG_DYN_AUTH, &tlv(TAG_WITNESS, &encrypted)))
+            }
+            // Step 2 of mutual authentication: check the decrypted witness, and
+            // answer the host's challenge
+            (Some(decrypted), Some(host_challenge), None) => {
+                let witness = match challenge {
+                    Some(MgmChallenge::Witness(witness)) => witness,
+                    _ => return Err(StatusWords::ConditionsNotSatisfiedError),
+                };
+
+                if decrypted.ct_eq(&witness).unwrap_u8() != 1 {
+                    return Err(StatusWords::SecurityStatusError);
+                }
+
+                self.require_touch(self.mgm_touch)?;
+
+                let mut response = Zeroizing::new(host_challenge.to_vec());
+                self.mgm_key
+                    .encrypt_block(&mut response)
+                    .map_err(|_| StatusWords::WrongLengthError)?;
+
+                self.session.mgm_authenticated = true;
+                Ok(tlv(TAG_DYN_AUTH, &tlv(TAG_RESPONSE, &response)))
+            }
+            // Step 1 of external authentication: send a challenge
+            (None, Some([]), None) => {
+                let host_challenge = random_bytes(block_size);
+                let reply = tlv(TAG_DYN_AUTH, &tlv(TAG_CHALLENGE, &host_challenge));
+                self.session.mgm_challenge = Some(MgmChallenge::Challenge(host_challenge));
+                Ok(reply)
+            }
+            // Step 2 of external authentication: check the encrypted challenge
+            (None, None, Some(response)) => {
+                let mut expected = match challenge {
+                    Some(MgmChallenge::Challenge(challenge)) => challenge,
+                    _ => return Err(StatusWords::ConditionsNotSatisfiedError),
+                };
+
+                self.mgm_key
+                    .encrypt_block(&mut expected)
+                    .map_err(|_| StatusWords::CommandAbortedError)?;
+
+                if response.ct_eq(&expected).unwrap_u8() != 1 {
+                    return Err(StatusWords::SecurityStatusError);
+                }
+
+                self.require_touch(self.mgm_touch)?;
+
+                self.session.mgm_authenticated = true;
+                Ok(vec![])
+            }
+            _ => Err(StatusWords::IncorrectParamError),
+        }
+    }
+
+    /// GENERAL AUTHENTICATE with a private key (signing, decryption, ECDH)
+    fn authenticate_key(&mut self, p1: u8, p2: u8, data: &[u8]) -> Reply {
+        let stored = self
+            .keys
+            .get(&p2)
+            .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+        if p1 != u8::from(stored.key.algorithm()) {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let authorized = match stored.pin_policy {
+            PinPolicy::Never => true,
+            PinPolicy::Always => self.session.pin_fresh,
+            _ => self.session.pin_verified,
+        };
+
+        if !authorized {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        let items = dynamic_auth_items(data)?;
+        let item = |tag| items.iter().find(|(t, _)| *t == tag).map(|(_, v)| *v);
+
+        if item(TAG_RESPONSE) != Some(&[]) {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let touch_policy = stored.touch_policy;
+        self.require_touch(touch_policy)?;
+
+        let stored = self
+            .keys
+            .get(&p2)
+            .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+        let output = match (item(TAG_CHALLENGE), item(TAG_EXPONENTIATION)) {
+            (Some(input), None) => stored.key.sign(input)?,
+            (None, Some(input)) => stored.key.agree(input)?,
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        if stored.pin_policy == PinPolicy::Always {
+            self.session.pin_fresh = false;
+        }
+
+        Ok(tlv(TAG_DYN_AUTH, &tlv(TAG_RESPONSE, &output)))
+    }
+
+    /// GENERATE ASYMMETRIC KEY PAIR
+    fn generate(&mut self, p2: u8, data: &[u8]) -> Reply {
+        self.require_mgm()?;
+        let slot = key_slot(p2)?;
+
+        let (_, template) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+        if template.tag != 0xac {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let mut algorithm = None;
+        let mut policy = PolicyItems::default();
+
+        for (tag, value) in tlv_items(template.value)? {
+            match (tag, value) {
+                (0x80, [alg]) => {
+                    algorithm = Some(
+                        AlgorithmId::try_from(*alg)
+                            .map_err(|_| StatusWords::IncorrectParamError)?,
+                    )
+                }
+                _ => policy.parse(tag, value)?,
+            }
+        }
+
+        let algorithm = algorithm.ok_or(StatusWords::IncorrectParamError)?;
+        self.check_algorithm(algorithm)?;
+
+        let key = PrivateKey::generate(algorithm).map_err(|_| StatusWords::CommandAbortedError)?;
+        let public = key.public_key();
+
+        self.store_key(slot, key, policy, Origin::Generated);
+
+        let mut response = vec![0x7f];
+        response.extend_from_slice(&tlv(0x49, &public));
+        Ok(response)
+    }
+
+    /// IMPORT ASYMMETRIC KEY (Yubico extension)
+    fn import(&mut self, p1: u8, p2: u8, data: &[u8]) -> Reply {
+        self.require_mgm()?;
+        let slot = key_slot(p2)?;
+
+        let algorithm = AlgorithmId::try_from(p1).map_err(|_| StatusWords::IncorrectParamError)?;
+        self.check_algorithm(algorithm)?;
+
+        let mut params = BTreeMap::new();
+        let mut policy = PolicyItems::default();
+
+        for (tag, value) in tlv_items(data)? {
+            match tag {
+                0x01..=0x08 => {
+                    params.insert(tag, value);
+                }
+                _ => policy.parse(tag, value)?,
+            }
+        }
+
+        let param = |tag| {
+            params
+                .get(&tag)
+                .copied()
+                .ok_or(StatusWords::IncorrectParamError)
+        };
+
+        let key = match algorithm {
+            AlgorithmId::Rsa1024
+            | AlgorithmId::Rsa2048
+            | AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096 => {
+                let p = BoxedUint::from_be_slice_vartime(param(0x01)?);
+                let q = BoxedUint::from_be_slice_vartime(param(0x02)?);
+
+                let key = RsaPrivateKey::from_p_q(p, q, BoxedUint::from(65537u64))
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+
+                PrivateKey::Rsa(Box::new(key))
+            }
+            AlgorithmId::EccP256 => PrivateKey::EccP256(
+                p256::SecretKey::from_slice(param(0x06)?)
+                    .map_err(|_| StatusWords::IncorrectParamError)?,
+            ),
+            AlgorithmId::EccP384 => PrivateKey::EccP384(
+                p384::SecretKey::from_slice(param(0x06)?)
+                    .map_err(|_| StatusWords::IncorrectParamError)?,
+            ),
+            AlgorithmId::Ed25519 => PrivateKey::Ed25519(
+                ed25519_dalek::SigningKey::try_from(param(0x07)?)
+                    .map_err(|_| StatusWords::IncorrectParamError)?,
+            ),
+            AlgorithmId::X25519 => PrivateKey::X25519(Zeroizing::new(
+                param(0x08)?
+                    .try_into()
+                    .map_err(|_| StatusWords::IncorrectParamError)?,
+            )),
+        };
+
+        // The imported key must match the algorithm it was imported as
+        if key.algorithm() != algorithm {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        self.store_key(slot, key, policy, Origin::Imported);
+        Ok(vec![])
+    }
+
+    /// MOVE KEY (Yubico extension, firmware 5.7)
+    fn move_key(&mut self, p1: u8, p2: u8) -> Reply {
+        if self.version < Version::new([5, 7, 0]) {
+            return Err(StatusWords::NotSupportedError);
+        }
+
+        self.require_mgm()?;
+
+        // The attestation key can only be deleted
+        let from = key_slot(p2)?;
+        let to = match p1 {
+            0xff => None,
+            to => Some(key_slot(to)?),
+        };
+
+        if to == Some(SlotId::Attestation) || (to.is_some() && from == SlotId::Attestation) {
+            return Err(StatusWords::IncorrectSlotError);
+        }
+
+        if !self.keys.contains_key(&p2) {
+            return Err(StatusWords::ReferenceDataNotFoundError);
+        }
+
+        // Keys can't be moved over another key
+        if to.is_some() && self.keys.contains_key(&p1) {
+            return Err(StatusWords::ConditionsNotSatisfiedError);
+        }
+
+        let key = self.keys.remove(&p2).expect("key checked above");
+        if to.is_some() {
+            self.keys.insert(p1, key);
+        }
+
+        Ok(vec![])
+    }
+
+    /// ATTEST (Yubico extension)
+    fn attest(&self, p1: u8) -> Reply {
+        let stored = self
+            .keys
+            .get(&p1)
+            .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+        // Only keys generated on the device can be attested
+        if stored.origin != Origin::Generated {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let attestation = self
+            .keys
+            .get(&KEY_ATTESTATION)
+            .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+        let issuer = self
+            .objects
+            .get(&SlotId::Attestation.object_id())
+            .and_then(|object| {
+                let (_, cert) = Tlv::parse(object).ok()?;
+                Certificate::from_der(cert.value).ok()
+            })
+            .map(|cert| cert.tbs_certificate().subject().clone());
+
+        let profile = CardProfile {
+            issuer: match issuer {
+                Some(issuer) => issuer,
+                None => Name::from_str(ATTESTATION_SUBJECT)
+                    .map_err(|_| StatusWords::CommandAbortedError)?,
+            },
+            subject: Name::from_str(&format!("CN=YubiKey PIV Attestation {p1:02x}"))
+                .map_err(|_| StatusWords::CommandAbortedError)?,
+            extensions: self
+                .attestation_extensions(stored)
+                .map_err(|_| StatusWords::CommandAbortedError)?,
+        };
+
+        stored
+            .key
+            .spki()
+            .and_then(|spki| attestation.key.certify(profile, spki))
+            .map_err(|e| {
+                error!("emulator: failed to build attestation certificate: {}", e);
+                StatusWords::CommandAbortedError
+            })
+    }
+
+    /// GET METADATA (Yubico extension)
+    fn metadata(&self, p2: u8) -> Reply {
+        if self.version < Version::new([5, 3, 0]) {
+            return Err(StatusWords::NotSupportedError);
+        }
+
+        let mut response = vec![];
+
+        match p2 {
+            KEY_PIN | KEY_PUK => {
+                let reference = if p2 == KEY_PIN { &self.pin } else { &self.puk };
+                response.extend(tlv(0x01, &[0xff]));
+                response.extend(tlv(0x05, &[reference.is_default().into()]));
+                response.extend(tlv(0x06, &[reference.tries, reference.remaining]));
+            }
+            KEY_CARDMGM => {
+                let default = MgmKey::default_for_version(self.version).is_ok_and(|key| {
+                    key.algorithm_id() == self.mgm_key.algorithm_id()
+                        && key.as_ref() == self.mgm_key.as_ref()
+                });
+
+                response.extend(tlv(0x01, &[self.mgm_key.algorithm_id().into()]));
+                response.extend(tlv(0x02, &[0x00, self.mgm_touch.into()]));
+                response.extend(tlv(0x05, &[default.into()]));
+            }
+            _ => {
+                let stored = self
+                    .keys
+                    .get(&p2)
+                    .ok_or(StatusWords::ReferenceDataNotFoundError)?;
+
+                let origin = match stored.origin {
+                    Origin::Generated => 0x01,
+                    Origin::Imported => 0x02,
+                };
+
+                response.extend(tlv(0x01, &[stored.key.algorithm().into()]));
+                response.extend(tlv(
+                    0x02,
+                    &[stored.pin_policy.into(), stored.touch_policy.into()],
+                ));
+                response.extend(tlv(0x03, &[origin]));
+                response.extend(tlv(0x04, &stored.key.public_key()));
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// GET DATA
+    fn get_data(&self, data: &[u8]) -> Reply {
+        let object_id = object_id(data)?.0;
+
+        if object_id == OBJ_DISCOVERY {
+            return Ok(DISCOVERY_OBJECT.to_vec());
+        }
+
+        if object_id == OBJ_PRINTED && !self.session.pin_verified {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        self.objects
+            .get(&object_id)
+            .map(|value| tlv(0x53, value))
+            .ok_or(StatusWords::NotFoundError)
+    }
+
+    /// PUT DATA
+    fn put_data(&mut self, data: &[u8]) -> Reply {
+        self.require_mgm()?;
+
+        let (object_id, remaining) = object_id(data)?;
+
+        if object_id == OBJ_DISCOVERY {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        let (_, value) = Tlv::parse(remaining).map_err(|_| StatusWords::IncorrectParamError)?;
+        if value.tag != 0x53 {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        if value.value.is_empty() {
+            self.objects.remove(&object_id);
+        } else {
+            self.objects.insert(object_id, value.value.to_vec());
+        }
+
+        Ok(vec![])
+    }
+
+    /// SET MANAGEMENT KEY (Yubico extension)
+    fn set_mgm_key(&mut self, p2: u8, data: &[u8]) -> Reply {
+        self.require_mgm()?;
+
+        let touch = match p2 {
+            0xff => TouchPolicy::Never,
+            0xfe => TouchPolicy::Always,
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        let (algorithm, key) = match data {
+            [alg, KEY_CARDMGM, len, key @ ..] if usize::from(*len) == key.len() => (*alg, key),
+            _ => return Err(StatusWords::IncorrectParamError),
+        };
+
+        let algorithm =
+            MgmAlgorithmId::try_from(algorithm).map_err(|_| StatusWords::IncorrectParamError)?;
+
+        // AES management keys require firmware 5.4
+        if algorithm != MgmAlgorithmId::ThreeDes && self.version < Version::new([5, 4, 0]) {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        self.mgm_key = MgmKey::from_bytes(key, Some(algorithm))
+            .map_err(|_| StatusWords::IncorrectParamError)?;
+        self.mgm_touch = touch;
+        Ok(vec![])
+    }
+
+    /// SET PIN RETRIES (Yubico extension)
+    fn set_pin_retries(&mut self, p1: u8, p2: u8) -> Reply {
+        self.require_mgm()?;
+
+        if !self.session.pin_verified {
+            return Err(StatusWords::SecurityStatusError);
+        }
+
+        if p1 == 0 || p2 == 0 || p1 > 0x0f || p2 > 0x0f {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        // Changing the retry counters resets the PIN and PUK to their defaults
+        self.pin = Reference::with_tries(DEFAULT_PIN, p1);
+        self.puk = Reference::with_tries(DEFAULT_PUK, p2);
+        Ok(vec![])
+    }
+
+    /// RESET (Yubico extension)
+    fn reset(&mut self) -> Reply {
+        if self.pin.remaining != 0 || self.puk.remaining != 0 {
+            return Err(StatusWords::ConditionsNotSatisfiedError);
+        }
+
+        // The attestation key and certificate survive a reset
+        let attestation_key = self.keys.remove(&KEY_ATTESTATION);
+        let attestation_cert = self.objects.remove(&SlotId::Attestation.object_id());
+
+        self.pin = Reference::new(DEFAULT_PIN);
+        self.puk = Reference::new(DEFAULT_PUK);
+        self.mgm_key = MgmKey::default_for_version(self.version)
+            .map_err(|_| StatusWords::CommandAbortedError)?;
+        self.mgm_touch = TouchPolicy::Never;
+        self.keys.clear();
+        self.objects.clear();
+
+        if let Some(key) = attestation_key {
+            self.keys.insert(KEY_ATTESTATION, key);
+        }
+
+        if let Some(cert) = attestation_cert {
+            self.objects.insert(SlotId::Attestation.object_id(), cert);
+        }
+
+        self.session = Session {
+            applet: Some(Applet::Piv),
+            ..Session::default()
+        };
+
+        Ok(vec![])
+    }
+
+    fn require_scp(&self) -> Reply<()> {
+        if self.version < Version::new([5, 7, 0]) {
+            return Err(StatusWords::NotSupportedError);
+        }
+
+        Ok(())
+    }
+
+    fn require_mgm(&self) -> Reply<()> {
+        if self.session.mgm_authenticated {
+            Ok(())
+        } else {
+            Err(StatusWords::SecurityStatusError)
+        }
+    }
+
+    /// Check the given algorithm is supported by the emulated firmware.
+    fn check_algorithm(&self, algorithm: AlgorithmId) -> Reply<()> {
+        match algorithm {
+            AlgorithmId::Rsa3072
+            | AlgorithmId::Rsa4096
+            | AlgorithmId::Ed25519
+            | AlgorithmId::X25519
+                if self.version < Version::new([5, 7, 0]) =>
+            {
+                Err(StatusWords::IncorrectParamError)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn store_key(&mut self, slot: SlotId, key: PrivateKey, policy: PolicyItems, origin: Origin) {
+        let pin_policy = match policy.pin {
+            PinPolicy::Default => match slot {
+                SlotId::Signature => PinPolicy::Always,
+                SlotId::CardAuthentication | SlotId::Attestation => PinPolicy::Never,
+                _ => PinPolicy::Once,
+            },
+            pin_policy => pin_policy,
+        };
+
+        let touch_policy = match policy.touch {
+            TouchPolicy::Default => TouchPolicy::Never,
+            touch_policy => touch_policy,
+        };
+
+        self.keys.insert(
+            slot.into(),
+            StoredKey {
+                key,
+                pin_policy,
+                touch_policy,
+                origin,
+            },
+        );
+    }
+
+    fn attestation_extensions(&self, stored: &StoredKey) -> Result<Vec<Extension>> {
+        let extension = |extn_id, value: Vec<u8>| -> Result<Extension> {
+            Ok(Extension {
+                extn_id,
+                critical: false,
+                extn_value: OctetString::new(value)?,
+            })
+        };
+
+        Ok(vec![
+            extension(
+                OID_FIRMWARE,
+                vec![self.version.major, self.version.minor, self.version.patch],
+            )?,
+            extension(OID_SERIAL, self.serial.0.to_der()?)?,
+            extension(
+                OID_POLICY,
+                vec![stored.pin_policy.into(), stored.touch_policy.into()],
+            )?,
+        ])
+    }
+}
+
+/// PIN or PUK with its retry counter.
+struct Reference {
+    value: Buffer,
+    default: &'static [u8],
+    tries: u8,
+    remaining: u8,
+}
+
+impl Reference {
+    fn new(default: &'static [u8]) -> Self {
+        Self::with_tries(default, DEFAULT_TRIES)
+    }
+
+    fn with_tries(default: &'static [u8], tries: u8) -> Self {
+        Self {
+            value: Zeroizing::new(default.to_vec()),
+            default,
+            tries,
+            remaining: tries,
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self.value.as_slice() == self.default
+    }
+
+    /// Check the given (0xff-padded) value, updating the retry counter.
+    fn check(&mut self, padded: &[u8]) -> Reply<()> {
+        if self.remaining == 0 {
+            return Err(StatusWords::AuthBlockedError);
+        }
+
+        let mut expected = Zeroizing::new([0xff; PIN_LEN_MAX]);
+        expected[..self.value.len()].copy_from_slice(&self.value);
+
+        if expected.ct_eq(padded).unwrap_u8() == 1 {
+            self.remaining = self.tries;
+            Ok(())
+        } else {
+            self.remaining -= 1;
+            Err(StatusWords::VerifyFailError {
+                tries: self.remaining,
+            })
+        }
+    }
+
+    /// Set a new (0xff-padded) value, resetting the retry counter.
+    fn set(&mut self, padded: &[u8]) -> Reply<()> {
+        let len = padded
+            .iter()
+            .position(|&b| b == 0xff)
+            .unwrap_or(padded.len());
+
+        if len < PIN_LEN_MIN || padded[len..].iter().any(|&b| b != 0xff) {
+            return Err(StatusWords::IncorrectParamError);
+        }
+
+        self.value = Zeroizing::new(padded[..len].to_vec());
+        self.remaining = self.tries;
+        Ok(())
+    }
+}
+
+/// Key stored in a slot.
+struct StoredKey {
+    key: PrivateKey,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+    origin: Origin,
+}
+
+/// PIN and touch policies parsed from a generate or import command.
+struct PolicyItems {
+    pin: PinPolicy,
+    touch: TouchPolicy,
+}
+
+impl Default for PolicyItems {
+    fn default() -> Self {
+        Self {
+            pin: PinPolicy::Default,
+            touch: TouchPolicy::Default,
+        }
+    }
+}
+
+impl PolicyItems {
+    fn parse(&mut self, tag: u8, value: &[u8]) -> Reply<()> {
+        match (tag, value) {
+            (0xaa, [pin]) => {
+                self.pin =
+                    PinPolicy::try_from(*pin).map_err(|_| StatusWords::IncorrectParamError)?
+            }
+            (0xab, [touch]) => {
+                self.touch =
+                    TouchPolicy::try_from(*touch).map_err(|_| StatusWords::IncorrectParamError)?
+            }
+            _ => return Err(StatusWords::IncorrectParamError),
+        }
+
+        Ok(())
+    }
+}
+
+/// Private key material held by the emulated card.
+enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    EccP256(p256::SecretKey),
+    EccP384(p384::SecretKey),
+    Ed25519(ed25519_dalek::SigningKey),
+    X25519(Zeroizing<[u8; 32]>),
+}
+
+impl PrivateKey {
+    /// Generate a new random key.
+    fn generate(algorithm: AlgorithmId) -> Result<Self> {
+        let mut rng = rand::rng();
+
+        let bits = match algorithm {
+            AlgorithmId::Rsa1024 => 1024,
+            AlgorithmId::Rsa2048 => 2048,
+            AlgorithmId::Rsa3072 => 3072,
+            AlgorithmId::Rsa4096 => 4096,
+            AlgorithmId::EccP256 => {
+                return Ok(Self::EccP256(p256::SecretKey::generate_from_rng(&mut rng)))
+            }
+            AlgorithmId::EccP384 => {
+                return Ok(Self::EccP384(p384::SecretKey::generate_from_rng(&mut rng)))
+            }
+            AlgorithmId::Ed25519 => {
+                let secret = Zeroizing::new(<[u8; 32]>::generate_from_rng(&mut rng));
+                return Ok(Self::Ed25519(ed25519_dalek::SigningKey::from_bytes(
+                    &secret,
+                )));
+            }
+            AlgorithmId::X25519 => {
+                return Ok(Self::X25519(Zeroizing::new(<[u8; 32]>::generate_from_rng(
+                    &mut rng,
+                ))))
+            }
+        };
+
+        RsaPrivateKey::new(&mut rng, bits)
+            .map(|key| Self::Rsa(Box::new(key)))
+            .map_err(|e| {
+                error!("emulator: RSA key generation failed: {}", e);
+                Error::KeyError
+            })
+    }
+
+    fn algorithm(&self) -> AlgorithmId {
+        match self {
+            Self::Rsa(key) => match key.n().as_ref().bits() {
+                ..=1024 => AlgorithmId::Rsa1024,
+                1025..=2048 => AlgorithmId::Rsa2048,
+                2049..=3072 => AlgorithmId::Rsa3072,
+                _ => AlgorithmId::Rsa4096,
+            },
+            Self::EccP256(_) => AlgorithmId::EccP256,
+            Self::EccP384(_) => AlgorithmId::EccP384,
+            Self::Ed25519(_) => AlgorithmId::Ed25519,
+            Self::X25519(_) => AlgorithmId::X25519,
+        }
+    }
+
+    /// Encode the public key the way the card returns it (without the outer
+    /// `0x7f49` tag).
+    fn public_key(&self) -> Vec<u8> {
+        match self {
+            Self::Rsa(key) => {
+                let mut public = tlv(0x81, &key.n_bytes());
+                public.extend(tlv(0x82, &key.e_bytes()));
+                public
+            }
+            Self::EccP256(key) => tlv(0x86, &key.public_key().to_sec1_bytes()),
+            Self::EccP384(key) => tlv(0x86, &key.public_key().to_sec1_bytes()),
+            Self::Ed25519(key) => tlv(0x86, key.verifying_key().as_bytes()),
+            Self::X25519(key) => tlv(
+                0x86,
+                &x25519_dalek::x25519(**key, x25519_dalek::X25519_BASEPOINT_BYTES),
+            ),
+        }
+    }
+
+    fn spki(&self) -> Result<SubjectPublicKeyInfoOwned> {
+        piv::read_public_key(self.algorithm(), &self.public_key(), false)
+    }
+
+    /// Perform a private key operation on a challenge (tag `0x81`).
+    fn sign(&self, input: &[u8]) -> Reply {
+        use signature::hazmat::PrehashSigner;
+
+        match self {
+            Self::Rsa(key) => {
+                let len = key.size();
+                if input.len() != len {
+                    return Err(StatusWords::WrongLengthError);
+                }
+
+                let c = BoxedUint::from_be_slice(input, key.n().as_ref().bits_precision())
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                let m =
+                    rsa::hazmat::rsa_decrypt_and_check(key.as_ref(), Some(&mut rand::rng()), &c)
+                        .map_err(|_| StatusWords::IncorrectParamError)?;
+
+                let bytes = Zeroizing::new(m.to_be_bytes());
+                let mut output = vec![0u8; len];
+                let offset = len.saturating_sub(bytes.len());
+                output[offset..].copy_from_slice(&bytes[bytes.len() - (len - offset)..]);
+                Ok(output)
+            }
+            Self::EccP256(key) => {
+                let signature: p256::ecdsa::DerSignature =
+                    p256::ecdsa::SigningKey::from(key.clone())
+                        .sign_prehash(input)
+                        .map_err(|_| StatusWords::IncorrectParamError)?;
+                Ok(signature.as_bytes().to_vec())
+            }
+            Self::EccP384(key) => {
+                let signature: p384::ecdsa::DerSignature =
+                    p384::ecdsa::SigningKey::from(key.clone())
+                        .sign_prehash(input)
+                        .map_err(|_| StatusWords::IncorrectParamError)?;
+                Ok(signature.as_bytes().to_vec())
+            }
+            Self::Ed25519(key) => Ok(signature::Signer::sign(key, input).to_bytes().to_vec()),
+            Self::X25519(_) => Err(StatusWords::IncorrectParamError),
+        }
+    }
+
+    /// Perform key agreement with a peer public key (tag `0x85`).
+    fn agree(&self, input: &[u8]) -> Reply {
+        match self {
+            Self::EccP256(key) => {
+                let peer = p256::PublicKey::from_sec1_bytes(input)
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                let shared =
+                    elliptic_curve::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer.as_affine());
+                Ok(shared.raw_secret_bytes().to_vec())
+            }
+            Self::EccP384(key) => {
+                let peer = p384::PublicKey::from_sec1_bytes(input)
+                    .map_err(|_| StatusWords::IncorrectParamError)?;
+                let shared =
+                    elliptic_curve::ecdh::diffie_hellman(key.to_nonzero_scalar(), peer.as_affine());
+                Ok(shared.raw_secret_bytes().to_vec())
+            }
+            Self::X25519(key) => {
+                let peer: [u8; 32] = input
+                    .try_into()
+                    .map_err(|_| StatusWords::WrongLengthError)?;
+                Ok(x25519_dalek::x25519(**key, peer).to_vec())
+            }
+            _ => Err(StatusWords::IncorrectParamError),
+        }
+    }
+
+    /// Issue a certificate for the given public key, signed by this key.
+    fn certify(&self, profile: CardProfile, spki: SubjectPublicKeyInfoOwned) -> Result<Vec<u8>> {
+        let serial = SerialNumber::generate(&mut rand::rng());
+        let validity = Validity::from_now(Duration::from_secs(20 * 365 * 24 * 60 * 60))?;
+        let builder = CertificateBuilder::new(profile, serial, validity, spki)?;
+
+        let cert = match self {
+            Self::Rsa(key) => {
+                builder.build::<_, rsa::pkcs1v15::Signature>(&rsa::pkcs1v15::SigningKey::<
+                    sha2::Sha256,
+                >::new(
+                    key.as_ref().clone()
+                ))?
+            }
+            Self::EccP256(key) => builder.build::<_, p256::ecdsa::DerSignature>(
+                &p256::ecdsa::SigningKey::from(key.clone()),
+            )?,
+            Self::EccP384(key) => builder.build::<_, p384::ecdsa::DerSignature>(
+                &p384::ecdsa::SigningKey::from(key.clone()),
+            )?,
+            _ => return Err(Error::AlgorithmError),
+        };
+
+        Ok(cert.to_der()?)
+    }
+}
+
+/// Certificate profile used for the attestation certificates issued by the
+/// emulated card.
+struct CardProfile {
+    issuer: Name,
+    subject: Name,
+    extensions: Vec<Extension>,
+}
+
+impl BuilderProfile for CardProfile {
+    fn get_issuer(&self, _subject: &Name) -> Name {
+        self.issuer.clone()
+    }
+
+    fn get_subject(&self) -> Name {
+        self.subject.clone()
+    }
+
+    fn build_extensions(
+        &self,
+        _spk: SubjectPublicKeyInfoRef<'_>,
+        _issuer_spk: SubjectPublicKeyInfoRef<'_>,
+        _tbs: &x509_cert::TbsCertificate,
+    ) -> x509_cert::builder::Result<Vec<Extension>> {
+        Ok(self.extensions.clone())
+    }
+}
+
+/// Serialize a TLV.
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; value.len() + 4];
+    let len = Tlv::write(&mut buf, tag, value).expect("large enough");
+    buf.truncate(len);
+    buf
+}
+
+/// Parse a set of applications (management application).
+fn capabilities(value: &[u8]) -> Reply<Capability> {
+    let bits = value
+        .try_into()
+        .map_err(|_| StatusWords::IncorrectParamError)?;
+    Ok(Capability::from_bits_retain(u16::from_be_bytes(bits)))
+}
+
+/// Parse a configuration lock code (management application).
+fn lock_code(value: &[u8]) -> Reply<[u8; 16]> {
+    value
+        .try_into()
+        .map_err(|_| StatusWords::IncorrectParamError)
+}
+
+/// Parse a sequence of TLVs.
+fn tlv_items(mut data: &[u8]) -> Reply<Vec<(u8, &[u8])>> {
+    let mut items = vec![];
+
+    while !data.is_empty() {
+        let (remaining, item) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+        items.push((item.tag, item.value));
+        data = remaining;
+    }
+
+    Ok(items)
+}
+
+/// Parse the items of a dynamic authentication template (tag `0x7c`).
+fn dynamic_auth_items(data: &[u8]) -> Reply<Vec<(u8, &[u8])>> {
+    let (_, template) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+
+    if template.tag != TAG_DYN_AUTH {
+        return Err(StatusWords::IncorrectParamError);
+    }
+
+    tlv_items(template.value)
+}
+
+/// Parse the object ID tag list (tag `0x5c`) at the start of a GET/PUT DATA
+/// command, returning the remaining data.
+fn object_id(data: &[u8]) -> Reply<(ObjectId, &[u8])> {
+    let (remaining, tag_list) = Tlv::parse(data).map_err(|_| StatusWords::IncorrectParamError)?;
+
+    if tag_list.tag != 0x5c || tag_list.value.is_empty() || tag_list.value.len() > 3 {
+        return Err(StatusWords::IncorrectParamError);
+    }
+
+    let object_id = tag_list
+        .value
+        .iter()
+        .fold(0, |id, &b| (id << 8) | ObjectId::from(b));
+
+    Ok((object_id, remaining))
+}
+
+/// Check the given slot can hold an asymmetric key.
+fn key_slot(p2: u8) -> Reply<SlotId> {
+    match SlotId::try_from(p2) {
+        Ok(SlotId::Management(_)) | Err(_) => Err(StatusWords::IncorrectSlotError),
+        Ok(slot) => Ok(slot),
+    }
+}
+
+/// Split the data of a CHANGE REFERENCE/RESET RETRY command into the current
+/// and new (0xff-padded) values.
+fn split_references(data: &[u8]) -> Reply<(&[u8], &[u8])> {
+    if data.len() != PIN_LEN_MAX * 2 {
+        return Err(StatusWords::WrongLengthError);
+    }
+
+    Ok(data.split_at(PIN_LEN_MAX))
+}
+
+/// Serialize a certificate the way [`crate::certificate::Certificate::write`]
+/// stores it.
+fn certificate_object(cert: &[u8]) -> Vec<u8> {
+    let mut object = tlv(TAG_CERT, cert);
+    object.extend(tlv(TAG_CERT_COMPRESS, &[0x00]));
+    object.extend(tlv(TAG_CERT_LRC, &[]));
+    object
+}
+
+fn random_bytes(len: usize) -> Buffer {
+    let mut bytes = Zeroizing::new(vec![0u8; len]);
+    rand::rng().fill_bytes(&mut bytes);
+    bytes
+}
@@ -57,6 +57,9 @@ pub enum Error {
     /// Authentication error
     AuthenticationError,
 
+    /// The decrypted message was malformed (e.g. it had invalid padding)
+    DecryptionError,
+
     /// Error while building a certificate
     CertificateBuilder,
 
@@ -93,9 +96,24 @@ pub enum Error {
     /// Range error
     RangeError,
 
+    /// The secure channel of the session is closed (e.g. after a response
+    /// failed to authenticate), so commands can't be sent
+    SecureChannelClosed,
+
+    /// The card was reset or removed, and the session couldn't be restored
+    SessionLost,
+
     /// Size error
     SizeError,
 
+    /// The slot already holds a key or certificate, which would be
+    /// overwritten
+    SlotOccupied,
+
+    /// The user didn't touch the YubiKey before the operation was cancelled
+    /// or timed out
+    TouchNotReceived,
+
     /// Wrong PIN
     WrongPin {
         /// Number of tries remaining
@@ -140,6 +158,7 @@ impl Error {
             Error::ArgumentError => f.write_str("argument error"),
             Error::AuthenticationError => f.write_str("authentication error"),
             Error::CertificateBuilder => f.write_str("certificate builder error"),
+            Error::DecryptionError => f.write_str("decryption error"),
             Error::GenericError => f.write_str("generic error"),
             Error::InvalidObject => f.write_str("invalid object"),
             Error::KeyError => f.write_str("key error"),
@@ -156,10 +175,24 @@ impl Error {
 
             Error::PinLocked => f.write_str("PIN locked"),
             Error::RangeError => f.write_str("range error"),
+            Error::SecureChannelClosed => f.write_str("secure channel closed"),
+            Error::SessionLost => f.write_str("session lost"),
             Error::SizeError => f.write_str("size error"),
+            Error::SlotOccupied => f.write_str("slot occupied"),
+            Error::TouchNotReceived => f.write_str("touch not received"),
             Error::WrongPin { .. } => f.write_str("wrong pin"),
         }
     }
+
+    /// Did the transport report that the card was reset or removed?
+    pub(crate) fn is_session_lost(self) -> bool {
+        matches!(
+            self,
+            Error::PcscError {
+                inner: Some(pcsc::Error::ResetCard | pcsc::Error::RemovedCard)
+            }
+        )
+    }
 }
 
 impl Display for Error {
@@ -0,0 +1,501 @@
+//! Hybrid Public Key Encryption ([RFC 9180]) with a key stored in a PIV slot.
+//!
+//! Messages sealed with HPKE to the public key of an X25519, P-256 or P-384
+//! key stored on the YubiKey (e.g. in the key management slot, `9d`) can be
+//! opened with a [`Receiver`]. The key agreement of the KEM is performed by
+//! the YubiKey with [`piv::ecdh`], so the private key never leaves it; the
+//! rest of the key schedule and the AEAD run on the host.
+//!
+//! The Base and Auth modes are supported, with the DHKEMs of the supported
+//! curves, HKDF with SHA-2, and AES-GCM or ChaCha20Poly1305:
+//!
+//! ```no_run
+//! use yubikey::{
+//!     hpke::{Aead, Kdf, Kem, Receiver, Suite},
+//!     piv::SlotId,
+//!     YubiKey,
+//! };
+//!
+//! # let (enc, ciphertext) = (vec![], vec![]);
+//! let mut yubikey = YubiKey::open()?;
+//! yubikey.verify_pin(b"123456")?;
+//!
+//! let suite = Suite::new(Kem::X25519HkdfSha256, Kdf::HkdfSha256, Aead::ChaCha20Poly1305);
+//! let mut receiver = Receiver::setup_base(
+//!     &mut yubikey,
+//!     SlotId::KeyManagement,
+//!     suite,
+//!     &enc,
+//!     b"example info",
+//! )?;
+//!
+//! let plaintext = receiver.open(b"", &ciphertext)?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! [RFC 9180]: https://www.rfc-editor.org/rfc/rfc9180
+
+use crate::{
+    error::{Error, Result},
+    piv::{self, AlgorithmId, SlotId},
+    Buffer, YubiKey,
+};
+use aes_gcm::{
+    aead::{self, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::{hmac::Hmac, GenericHkdf, GenericHkdfExtract, HmacImpl};
+use log::error;
+use sha2::{Sha256, Sha384, Sha512};
+use zeroize::Zeroizing;
+
+/// Version label prepended to all labeled KDF inputs
+const VERSION_LABEL: &[u8] = b"HPKE-v1";
+
+/// Length of the AEAD nonces (`Nn`)
+const NONCE_LEN: usize = 12;
+
+/// HPKE mode identifier for the Base mode
+const MODE_BASE: u8 = 0x00;
+
+/// HPKE mode identifier for the Auth mode
+const MODE_AUTH: u8 = 0x02;
+
+/// Key encapsulation mechanism (KEM) of a [`Suite`].
+///
+/// The KEM must match the algorithm of the key in the receiver's slot.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Kem {
+    /// DHKEM(P-256, HKDF-SHA256), for [`AlgorithmId::EccP256`][`piv::AlgorithmId::EccP256`] keys
+    P256HkdfSha256,
+
+    /// DHKEM(P-384, HKDF-SHA384), for [`AlgorithmId::EccP384`][`piv::AlgorithmId::EccP384`] keys
+    P384HkdfSha384,
+
+    /// DHKEM(X25519, HKDF-SHA256), for [`AlgorithmId::X25519`][`piv::AlgorithmId::X25519`] keys
+    X25519HkdfSha256,
+}
+
+impl Kem {
+    /// IANA identifier of the KEM.
+    pub fn id(self) -> u16 {
+        match self {
+            Kem::P256HkdfSha256 => 0x0010,
+            Kem::P384HkdfSha384 => 0x0011,
+            Kem::X25519HkdfSha256 => 0x0020,
+        }
+    }
+
+    /// KDF used by the KEM to derive the shared secret.
+    fn kdf(self) -> Kdf {
+        match self {
+            Kem::P256HkdfSha256 | Kem::X25519HkdfSha256 => Kdf::HkdfSha256,
+            Kem::P384HkdfSha384 => Kdf::HkdfSha384,
+        }
+    }
+
+    /// Length of serialized public keys (`Npk`), and of the encapsulated
+    /// key.
+    fn public_key_len(self) -> usize {
+        match self {
+            Kem::P256HkdfSha256 => 65,
+            Kem::P384HkdfSha384 => 97,
+            Kem::X25519HkdfSha256 => 32,
+        }
+    }
+
+    /// Algorithm of the PIV keys used with the KEM.
+    fn algorithm(self) -> AlgorithmId {
+        match self {
+            Kem::P256HkdfSha256 => AlgorithmId::EccP256,
+            Kem::P384HkdfSha384 => AlgorithmId::EccP384,
+            Kem::X25519HkdfSha256 => AlgorithmId::X25519,
+        }
+    }
+
+    /// Length of the shared secret (`Nsecret`).
+    fn secret_len(self) -> usize {
+        match self {
+            Kem::P256HkdfSha256 | Kem::X25519HkdfSha256 => 32,
+            Kem::P384HkdfSha384 => 48,
+        }
+    }
+
+    /// Compute the Diffie-Hellman shared secret between the key in `slot`
+    /// and a serialized public key.
+    fn dh(self, yubikey: &mut YubiKey, slot: SlotId, public_key: &[u8]) -> Result<Buffer> {
+        if public_key.len() != self.public_key_len() {
+            error!(
+                "invalid public key length for {:?}: {}",
+                self,
+                public_key.len()
+            );
+            return Err(Error::KeyError);
+        }
+
+        match self {
+            Kem::P256HkdfSha256 => {
+                let key =
+                    p256::PublicKey::from_sec1_bytes(public_key).map_err(|_| Error::KeyError)?;
+                piv::ecdh(yubikey, slot, &key)
+            }
+            Kem::P384HkdfSha384 => {
+                let key =
+                    p384::PublicKey::from_sec1_bytes(public_key).map_err(|_| Error::KeyError)?;
+                piv::ecdh(yubikey, slot, &key)
+            }
+            Kem::X25519HkdfSha256 => {
+                let bytes = <[u8; 32]>::try_from(public_key).map_err(|_| Error::KeyError)?;
+                piv::ecdh(yubikey, slot, &x25519_dalek::PublicKey::from(bytes))
+            }
+        }
+    }
+
+    /// Decapsulate the shared secret from `enc`, with the key in `slot` and
+    /// (in Auth mode) the sender's public key.
+    ///
+    /// <https://www.rfc-editor.org/rfc/rfc9180#section-4.1>
+    fn decap(
+        self,
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        enc: &[u8],
+        sender: Option<&[u8]>,
+    ) -> Result<Buffer> {
+        let mut dh = self.dh(yubikey, slot, enc)?;
+        let receiver = piv::slot_public_key(yubikey, slot, self.algorithm())?;
+        let receiver = receiver
+            .subject_public_key
+            .as_bytes()
+            .ok_or(Error::InvalidObject)?;
+
+        if receiver.len() != self.public_key_len() {
+            error!("key in slot {:?} doesn't match {:?}", slot, self);
+            return Err(Error::AlgorithmError);
+        }
+
+        let mut kem_context = [enc, receiver].concat();
+
+        if let Some(sender) = sender {
+            dh.extend_from_slice(&self.dh(yubikey, slot, sender)?);
+            kem_context.extend_from_slice(sender);
+        }
+
+        let suite_id = [b"KEM".as_slice(), &self.id().to_be_bytes()].concat();
+        let kdf = self.kdf();
+        let eae_prk = kdf.labeled_extract(&suite_id, b"", b"eae_prk", &dh);
+        kdf.labeled_expand(
+            &suite_id,
+            &eae_prk,
+            b"shared_secret",
+            &kem_context,
+            self.secret_len(),
+        )
+    }
+}
+
+/// Key derivation function (KDF) of a [`Suite`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Kdf {
+    /// HKDF-SHA256
+    HkdfSha256,
+
+    /// HKDF-SHA384
+    HkdfSha384,
+
+    /// HKDF-SHA512
+    HkdfSha512,
+}
+
+impl Kdf {
+    /// IANA identifier of the KDF.
+    pub fn id(self) -> u16 {
+        match self {
+            Kdf::HkdfSha256 => 0x0001,
+            Kdf::HkdfSha384 => 0x0002,
+            Kdf::HkdfSha512 => 0x0003,
+        }
+    }
+
+    /// Output length of the hash function (`Nh`).
+    fn hash_len(self) -> usize {
+        match self {
+            Kdf::HkdfSha256 => 32,
+            Kdf::HkdfSha384 => 48,
+            Kdf::HkdfSha512 => 64,
+        }
+    }
+
+    /// `LabeledExtract(salt, label, ikm)`
+    ///
+    /// <https://www.rfc-editor.org/rfc/rfc9180#section-4>
+    fn labeled_extract(self, suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> Buffer {
+        let input = [VERSION_LABEL, suite_id, label, ikm];
+
+        match self {
+            Kdf::HkdfSha256 => extract::<Hmac<Sha256>>(salt, &input),
+            Kdf::HkdfSha384 => extract::<Hmac<Sha384>>(salt, &input),
+            Kdf::HkdfSha512 => extract::<Hmac<Sha512>>(salt, &input),
+        }
+    }
+
+    /// `LabeledExpand(prk, label, info, len)`
+    ///
+    /// <https://www.rfc-editor.org/rfc/rfc9180#section-4>
+    fn labeled_expand(
+        self,
+        suite_id: &[u8],
+        prk: &[u8],
+        label: &[u8],
+        info: &[u8],
+        len: usize,
+    ) -> Result<Buffer> {
+        let len_bytes = u16::try_from(len)
+            .map_err(|_| Error::SizeError)?
+            .to_be_bytes();
+        let info = [&len_bytes, VERSION_LABEL, suite_id, label, info];
+
+        match self {
+            Kdf::HkdfSha256 => expand::<Hmac<Sha256>>(prk, &info, len),
+            Kdf::HkdfSha384 => expand::<Hmac<Sha384>>(prk, &info, len),
+            Kdf::HkdfSha512 => expand::<Hmac<Sha512>>(prk, &info, len),
+        }
+    }
+}
+
+/// HKDF-Extract over the concatenation of `ikm`.
+fn extract<H: HmacImpl>(salt: &[u8], ikm: &[&[u8]]) -> Buffer {
+    let mut hkdf = GenericHkdfExtract::<H>::new(Some(salt));
+
+    for part in ikm {
+        hkdf.input_ikm(part);
+    }
+
+    Zeroizing::new(hkdf.finalize().0.to_vec())
+}
+
+/// HKDF-Expand of `len` bytes over the concatenation of `info`.
+fn expand<H: HmacImpl>(prk: &[u8], info: &[&[u8]], len: usize) -> Result<Buffer> {
+    let hkdf = GenericHkdf::<H>::from_prk(prk).map_err(|_| Error::SizeError)?;
+    let mut okm = Zeroizing::new(vec![0u8; len]);
+    hkdf.expand_multi_info(info, &mut okm)
+        .map_err(|_| Error::SizeError)?;
+    Ok(okm)
+}
+
+/// Authenticated encryption with associated data (AEAD) algorithm of a
+/// [`Suite`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Aead {
+    /// AES-128-GCM
+    Aes128Gcm,
+
+    /// AES-256-GCM
+    Aes256Gcm,
+
+    /// ChaCha20Poly1305
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    /// IANA identifier of the AEAD algorithm.
+    pub fn id(self) -> u16 {
+        match self {
+            Aead::Aes128Gcm => 0x0001,
+            Aead::Aes256Gcm => 0x0002,
+            Aead::ChaCha20Poly1305 => 0x0003,
+        }
+    }
+
+    /// Length of the keys (`Nk`).
+    fn key_len(self) -> usize {
+        match self {
+            Aead::Aes128Gcm => 16,
+            Aead::Aes256Gcm | Aead::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    fn open(self, key: &[u8], nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Result<Buffer> {
+        match self {
+            Aead::Aes128Gcm => open::<Aes128Gcm>(key, nonce, aad, ciphertext),
+            Aead::Aes256Gcm => open::<Aes256Gcm>(key, nonce, aad, ciphertext),
+            Aead::ChaCha20Poly1305 => open::<ChaCha20Poly1305>(key, nonce, aad, ciphertext),
+        }
+    }
+}
+
+/// Decrypt and authenticate `ciphertext` with the AEAD algorithm `A`.
+fn open<A: aead::Aead + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Buffer> {
+    let cipher = A::new_from_slice(key).map_err(|_| Error::SizeError)?;
+    let nonce = aead::Nonce::<A>::try_from(nonce).map_err(|_| Error::SizeError)?;
+
+    cipher
+        .decrypt(
+            &nonce,
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| Error::DecryptionError)
+}
+
+/// HPKE cipher suite: the combination of a KEM, a KDF and an AEAD algorithm.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Suite {
+    /// Key encapsulation mechanism
+    pub kem: Kem,
+
+    /// Key derivation function
+    pub kdf: Kdf,
+
+    /// AEAD algorithm
+    pub aead: Aead,
+}
+
+impl Suite {
+    /// Create a new cipher suite.
+    pub fn new(kem: Kem, kdf: Kdf, aead: Aead) -> Self {
+        Self { kem, kdf, aead }
+    }
+
+    /// Suite identifier used in the key schedule.
+    fn id(&self) -> Vec<u8> {
+        [
+            b"HPKE".as_slice(),
+            &self.kem.id().to_be_bytes(),
+            &self.kdf.id().to_be_bytes(),
+            &self.aead.id().to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// HPKE receiver context, for opening messages sealed to the key in a PIV
+/// slot.
+///
+/// The context holds the symmetric keys derived from the encapsulated key;
+/// the YubiKey is only needed to set it up. Messages must be opened in the
+/// order they were sealed.
+pub struct Receiver {
+    suite: Suite,
+    key: Buffer,
+    base_nonce: [u8; NONCE_LEN],
+    exporter_secret: Buffer,
+    seq: u64,
+}
+
+impl Receiver {
+    /// Set up a receiver context in Base mode, from the encapsulated key
+    /// `enc` and the application-supplied `info`.
+    ///
+    /// This performs a key agreement with the key in `slot`, which may
+    /// require the PIN to have been verified, and the user to touch the
+    /// YubiKey (see [`crate::touch`]).
+    pub fn setup_base(
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        suite: Suite,
+        enc: &[u8],
+        info: &[u8],
+    ) -> Result<Self> {
+        let shared_secret = suite.kem.decap(yubikey, slot, enc, None)?;
+        Self::key_schedule(suite, MODE_BASE, &shared_secret, info)
+    }
+
+    /// Set up a receiver context in Auth mode, authenticating the sender
+    /// with their serialized public key `sender`.
+    ///
+    /// This performs two key agreements with the key in `slot` (see
+    /// [`Receiver::setup_base`]).
+    pub fn setup_auth(
+        yubikey: &mut YubiKey,
+        slot: SlotId,
+        suite: Suite,
+        enc: &[u8],
+        info: &[u8],
+        sender: &[u8],
+    ) -> Result<Self> {
+        let shared_secret = suite.kem.decap(yubikey, slot, enc, Some(sender))?;
+        Self::key_schedule(suite, MODE_AUTH, &shared_secret, info)
+    }
+
+    /// Derive the context from the shared secret (without a PSK).
+    ///
+    /// <https://www.rfc-editor.org/rfc/rfc9180#section-5.1>
+    fn key_schedule(suite: Suite, mode: u8, shared_secret: &[u8], info: &[u8]) -> Result<Self> {
+        let suite_id = suite.id();
+        let kdf = suite.kdf;
+
+        let psk_id_hash = kdf.labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+        let info_hash = kdf.labeled_extract(&suite_id, b"", b"info_hash", info);
+        let context = [&[mode], psk_id_hash.as_slice(), &info_hash].concat();
+
+        let secret = kdf.labeled_extract(&suite_id, shared_secret, b"secret", b"");
+        let key = kdf.labeled_expand(&suite_id, &secret, b"key", &context, suite.aead.key_len())?;
+        let nonce = kdf.labeled_expand(&suite_id, &secret, b"base_nonce", &context, NONCE_LEN)?;
+        let exporter_secret =
+            kdf.labeled_expand(&suite_id, &secret, b"exp", &context, kdf.hash_len())?;
+
+        let mut base_nonce = [0u8; NONCE_LEN];
+        base_nonce.copy_from_slice(&nonce);
+
+        Ok(Self {
+            suite,
+            key,
+            base_nonce,
+            exporter_secret,
+            seq: 0,
+        })
+    }
+
+    /// Cipher suite of the context.
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    /// Open the next message, sealed with the associated data `aad`.
+    ///
+    /// Messages which fail to authenticate are rejected with
+    /// [`Error::DecryptionError`], and don't advance the context.
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Buffer> {
+        let next = self.seq.checked_add(1).ok_or(Error::RangeError)?;
+
+        let mut nonce = self.base_nonce;
+        for (byte, seq) in nonce.iter_mut().rev().zip(self.seq.to_le_bytes()) {
+            *byte ^= seq;
+        }
+
+        let plaintext = self.suite.aead.open(&self.key, &nonce, aad, ciphertext)?;
+        self.seq = next;
+        Ok(plaintext)
+    }
+
+    /// Export a secret of `len` bytes from the context, bound to
+    /// `exporter_context`.
+    ///
+    /// <https://www.rfc-editor.org/rfc/rfc9180#section-5.3>
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Result<Buffer> {
+        if len > 255 * self.suite.kdf.hash_len() {
+            return Err(Error::SizeError);
+        }
+
+        self.suite.kdf.labeled_expand(
+            &self.suite.id(),
+            &self.exporter_secret,
+            b"sec",
+            exporter_context,
+            len,
+        )
+    }
+}
@@ -44,25 +44,41 @@
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
 
 mod apdu;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod atr;
 mod cccid;
 pub mod certificate;
 mod chuid;
 mod config;
 mod consts;
+#[cfg(feature = "emulator")]
+pub mod emulator;
 mod error;
+#[cfg(feature = "hpke")]
+pub mod hpke;
 mod metadata;
 pub mod mgm;
 #[cfg(feature = "untested")]
 mod mscmap;
 #[cfg(feature = "untested")]
 mod msroots;
+mod open_options;
 mod otp;
+mod padding;
 pub mod piv;
+mod pkcs12;
 mod policy;
+mod private_key;
 pub mod reader;
+pub mod scp;
 mod serialization;
 mod setting;
+pub mod shared;
+pub mod touch;
 mod transaction;
+pub mod transcript;
+pub mod transport;
 mod yubikey;
 
 pub use crate::{
@@ -72,11 +88,13 @@ pub use crate::{
     config::Config,
     error::{Error, Result},
     mgm::{MgmAlgorithmId, MgmKey, MgmType},
+    open_options::OpenOptions,
     piv::Key,
     policy::{PinPolicy, TouchPolicy},
     reader::Context,
     setting::{Setting, SettingSource},
-    yubikey::{CachedPin, Serial, Version, YubiKey},
+    transport::Transport,
+    yubikey::{CachedPin, Recovery, Serial, Version, YubiKey},
 };
 
 #[cfg(feature = "untested")]
@@ -249,7 +249,12 @@ impl MgmKey {
     ///
     /// Returns an error if the Yubikey's default algorithm is unsupported.
     pub fn get_default(yubikey: &YubiKey) -> Result<Self> {
-        match MgmAlgorithmId::default_for_version(yubikey.version()) {
+        Self::default_for_version(yubikey.version())
+    }
+
+    /// Gets the default management key for the given firmware version.
+    pub(crate) fn default_for_version(version: Version) -> Result<Self> {
+        match MgmAlgorithmId::default_for_version(version) {
             MgmAlgorithmId::ThreeDes => Ok(Self(MgmKeyKind::Tdes(DEFAULT_MGM_KEY.into()))),
             MgmAlgorithmId::Aes192 => Ok(Self(MgmKeyKind::Aes192(DEFAULT_MGM_KEY.into()))),
             _ => Err(Error::NotSupported),
@@ -331,7 +336,15 @@ impl MgmKey {
     /// management operations.
     ///
     /// This will wipe any metadata related to derived and PIN-protected management keys.
+    ///
+    /// The YubiKey may wait for the user to touch it, if the current management key
+    /// requires touch (see [`crate::touch`]).
     pub fn set_manual(&self, yubikey: &mut YubiKey, require_touch: bool) -> Result<()> {
+        yubikey.touch_gated(None, |yubikey| self.write_manual(yubikey, require_touch))
+    }
+
+    /// Set the management key and clear its metadata, for [`MgmKey::set_manual`].
+    fn write_manual(&self, yubikey: &mut YubiKey, require_touch: bool) -> Result<()> {
         let txn = yubikey.begin_transaction()?;
 
         txn.set_mgm_key(self, require_touch)
@@ -498,7 +511,7 @@ impl MgmKey {
     /// Encrypts a block with this key.
     ///
     /// Returns an error if the block is the wrong size.
-    fn encrypt_block(&self, block: &mut [u8]) -> Result<()> {
+    pub(crate) fn encrypt_block(&self, block: &mut [u8]) -> Result<()> {
         match &self.0 {
             MgmKeyKind::Tdes(k) => {
                 des::TdesEde3::new(k).encrypt_block(block.try_into().map_err(|_| Error::SizeError)?)
@@ -580,8 +593,11 @@ pub struct Manager {
 #[cfg(feature = "untested")]
 impl Manager {
     /// Open the manager applet on the YubiKey
+    ///
+    /// If a secure channel is open, it is used for the management applet
+    /// too.
     pub fn new(mut client: YubiKey) -> Result<Self> {
-        Transaction::new(&mut client.card)?.select_application(
+        client.begin_transaction()?.select_application(
             APPLET_ID,
             APPLET_NAME,
             "failed selecting YkHSM auth application",
@@ -592,40 +608,34 @@ impl Manager {
 
     /// Enable YubiHSM applet
     pub fn enable_yubihsm(&mut self) -> Result<()> {
-        let mut config = Transaction::new(&mut self.client.card)?.read_config()?;
+        let mut config = self.client.begin_transaction()?.read_config()?;
         config.config.usb_enabled_apps |= Capability::HSMAUTH;
-        Transaction::new(&mut self.client.card)?.write_config(
-            self.client.version,
-            config.config,
-            None,
-            None,
-        )?;
+        let version = self.client.version;
+        self.client
+            .begin_transaction()?
+            .write_config(version, config.config, None, None)?;
         Ok(())
     }
 
     /// Enable PIV applet
     pub fn enable_piv(&mut self) -> Result<()> {
-        let mut config = Transaction::new(&mut self.client.card)?.read_config()?;
+        let mut config = self.client.begin_transaction()?.read_config()?;
         config.config.usb_enabled_apps |= Capability::PIV;
-        Transaction::new(&mut self.client.card)?.write_config(
-            self.client.version,
-            config.config,
-            None,
-            None,
-        )?;
+        let version = self.client.version;
+        self.client
+            .begin_transaction()?
+            .write_config(version, config.config, None, None)?;
         Ok(())
     }
 
     /// Disable NFC interface on the device
     pub fn disable_nfc(&mut self) -> Result<()> {
-        let mut config = Transaction::new(&mut self.client.card)?.read_config()?;
+        let mut config = self.client.begin_transaction()?.read_config()?;
         config.config.nfc_enabled_apps = Some(Capability::empty());
-        Transaction::new(&mut self.client.card)?.write_config(
-            self.client.version,
-            config.config,
-            None,
-            None,
-        )?;
+        let version = self.client.version;
+        self.client
+            .begin_transaction()?
+            .write_config(version, config.config, None, None)?;
         Ok(())
     }
 
@@ -635,9 +645,10 @@ impl Manager {
         current_lock: Option<Lock>,
         new_lock: Option<Lock>,
     ) -> Result<()> {
-        let config = Transaction::new(&mut self.client.card)?.read_config()?;
-        Transaction::new(&mut self.client.card)?.write_config(
-            self.client.version,
+        let config = self.client.begin_transaction()?.read_config()?;
+        let version = self.client.version;
+        self.client.begin_transaction()?.write_config(
+            version,
             config.config,
             current_lock,
             new_lock,
@@ -647,12 +658,12 @@ impl Manager {
 
     /// Read configuration from yubikey
     pub fn read_config(&mut self) -> Result<DeviceInfo> {
-        Transaction::new(&mut self.client.card)?.read_config()
+        self.client.begin_transaction()?.read_config()
     }
 
     /// Return the inner [`YubiKey`]
     pub fn into_inner(mut self) -> Result<YubiKey> {
-        Transaction::new(&mut self.client.card)?.select_piv_application()?;
+        self.client.begin_transaction()?.select_piv_application()?;
         Ok(self.client)
     }
 }
@@ -0,0 +1,196 @@
+//! Options for opening a YubiKey.
+
+use crate::{
+    reader::{Context, Reader},
+    scp::ScpKeyParams,
+    transport::{Disposition, PcscTransport, Protocols, Scope, ShareMode, Transport},
+    Error, Recovery, Result, Serial, YubiKey,
+};
+use log::{error, info};
+
+/// Options for opening a [`YubiKey`] over PC/SC.
+///
+/// [`YubiKey::open`] and [`YubiKey::open_by_serial`] use the defaults: a
+/// system-wide PC/SC context, shared access using the T=1 protocol, and
+/// resetting the card when reconnecting.
+///
+/// ```no_run
+/// use yubikey::{transport::ShareMode, OpenOptions, Serial};
+///
+/// // Lock out other applications for as long as the session is open
+/// let yubikey = OpenOptions::new()
+///     .share_mode(ShareMode::Exclusive)
+///     .open_by_serial(Serial(12345678))?;
+/// # Ok::<(), yubikey::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    scope: Scope,
+    share_mode: ShareMode,
+    protocols: Protocols,
+    reconnect_disposition: Disposition,
+    recovery: Recovery,
+    secure_channel: Option<ScpKeyParams>,
+}
+
+impl OpenOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self {
+            scope: Scope::System,
+            share_mode: ShareMode::Shared,
+            protocols: Protocols::T1,
+            reconnect_disposition: Disposition::ResetCard,
+            recovery: Recovery::default(),
+            secure_channel: None,
+        }
+    }
+
+    /// Set the scope of the PC/SC context used to find readers.
+    pub fn scope(&mut self, scope: Scope) -> &mut Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Set whether other applications may use the card while the session is
+    /// open ([`ShareMode::Shared`]) or not ([`ShareMode::Exclusive`]).
+    pub fn share_mode(&mut self, share_mode: ShareMode) -> &mut Self {
+        self.share_mode = share_mode;
+        self
+    }
+
+    /// Set the protocols which are acceptable to connect with.
+    pub fn protocols(&mut self, protocols: Protocols) -> &mut Self {
+        self.protocols = protocols;
+        self
+    }
+
+    /// Set how the card is left by [`YubiKey::reconnect`].
+    pub fn reconnect_disposition(&mut self, disposition: Disposition) -> &mut Self {
+        self.reconnect_disposition = disposition;
+        self
+    }
+
+    /// Set how the session is recovered after the card has been reset or
+    /// removed.
+    pub fn recovery(&mut self, recovery: Recovery) -> &mut Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Open a secure channel with the YubiKey as soon as it is connected to
+    /// (see [`YubiKey::open_secure_channel`]).
+    pub fn secure_channel(&mut self, params: ScpKeyParams) -> &mut Self {
+        self.secure_channel = Some(params);
+        self
+    }
+
+    /// Open a connection to a YubiKey.
+    ///
+    /// See [`YubiKey::open`].
+    pub fn open(&self) -> Result<YubiKey> {
+        let mut yubikey: Option<YubiKey> = None;
+
+        let mut readers = Context::open_with_scope(self.scope)?;
+        for reader in readers.iter()? {
+            if let Ok(yk_found) = self.open_reader(&reader) {
+                if let Some(yk_stored) = yubikey {
+                    // We found two YubiKeys, so we won't use either.
+                    // Don't reset them.
+                    let _ = yk_stored.disconnect(Disposition::LeaveCard);
+                    let _ = yk_found.disconnect(Disposition::LeaveCard);
+
+                    error!("multiple YubiKeys detected!");
+                    return Err(Error::PcscError { inner: None });
+                } else {
+                    yubikey = Some(yk_found);
+                }
+            }
+        }
+
+        if let Some(yubikey) = yubikey {
+            // We found exactly one YubiKey that we could open, so we return it.
+            Ok(yubikey)
+        } else {
+            error!("no YubiKey detected!");
+            Err(Error::NotFound)
+        }
+    }
+
+    /// Open a YubiKey with a specific serial number.
+    ///
+    /// See [`YubiKey::open_by_serial`]. The devices are probed with the
+    /// configured share mode and protocols.
+    pub fn open_by_serial(&self, serial: Serial) -> Result<YubiKey> {
+        let mut readers = Context::open_with_scope(self.scope)?;
+
+        let mut open_error = None;
+
+        for reader in readers.iter()? {
+            match reader.probe_with(self.share_mode, self.protocols) {
+                Ok(summary) if summary.serial == Some(serial) => return self.open_reader(&reader),
+                Ok(_) => continue,
+                Err(e) => {
+                    // Save the first error we see that indicates we might have been able
+                    // to find a matching YubiKey.
+                    if open_error.is_none() {
+                        if let Error::PcscError {
+                            inner: Some(pcsc::Error::SharingViolation),
+                        } = e
+                        {
+                            open_error = Some(e);
+                        }
+                    }
+                }
+            }
+        }
+
+        Err(if let Some(e) = open_error {
+            e
+        } else {
+            error!("no YubiKey detected with serial: {}", serial);
+            Error::NotFound
+        })
+    }
+
+    /// Open the YubiKey in the given reader.
+    pub fn open_reader(&self, reader: &Reader<'_>) -> Result<YubiKey> {
+        let card = reader
+            .connect_with(self.share_mode, self.protocols)
+            .inspect_err(|e| error!("error connecting to reader '{}': {}", reader.name(), e))?;
+
+        info!("connected to reader: {}", reader.name());
+
+        let transport = PcscTransport::new(card)
+            .share_mode(self.share_mode)
+            .protocols(self.protocols);
+
+        self.open_transport(transport, &reader.name())
+    }
+
+    /// Open a YubiKey using the given [`Transport`].
+    ///
+    /// The PC/SC specific options (scope, share mode and protocols) don't
+    /// apply in this case.
+    pub fn open_transport(
+        &self,
+        transport: impl Transport + 'static,
+        name: &str,
+    ) -> Result<YubiKey> {
+        let mut yubikey = YubiKey::connect(Box::new(transport), String::from(name))?;
+        yubikey.reconnect_disposition = self.reconnect_disposition;
+        yubikey.set_recovery(self.recovery.clone());
+
+        if let Some(params) = &self.secure_channel {
+            yubikey.open_secure_channel(params.clone())?;
+        }
+
+        Ok(yubikey)
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
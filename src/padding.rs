@@ -0,0 +1,399 @@
+//! Host-side RSA padding schemes, applied around the raw RSA operation
+//! performed by the YubiKey.
+//!
+//! See [RFC 8017](https://www.rfc-editor.org/rfc/rfc8017).
+
+use crate::{
+    error::{Error, Result},
+    Buffer,
+};
+use cipher::common::getrandom::SysRng;
+use der::{
+    asn1::{Any, OctetStringRef},
+    oid::AssociatedOid,
+    Encode, Sequence,
+};
+use rand_core::TryRng;
+use sha2::Digest;
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq, ConstantTimeGreater};
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use zeroize::Zeroizing;
+
+/// Encode a message digest with EMSA-PSS, using MGF1 with the same hash
+/// function and a random salt of `salt_len` bytes.
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-9.1.1>
+pub(crate) fn emsa_pss_encode<D: Digest>(
+    m_hash: &[u8],
+    em_bits: usize,
+    salt_len: usize,
+) -> Result<Vec<u8>> {
+    let mut salt = Zeroizing::new(vec![0u8; salt_len]);
+    SysRng
+        .try_fill_bytes(&mut salt)
+        .map_err(|_| Error::GenericError)?;
+
+    emsa_pss_encode_with_salt::<D>(m_hash, em_bits, &salt)
+}
+
+/// Encode a message digest with EMSA-PSS, using the given salt.
+fn emsa_pss_encode_with_salt<D: Digest>(
+    m_hash: &[u8],
+    em_bits: usize,
+    salt: &[u8],
+) -> Result<Vec<u8>> {
+    let h_len = <D as Digest>::output_size();
+    let em_len = em_bits.div_ceil(8);
+
+    if m_hash.len() != h_len || em_len < h_len + salt.len() + 2 {
+        return Err(Error::SizeError);
+    }
+
+    // H = Hash(00 00 00 00 00 00 00 00 || mHash || salt)
+    let h = D::new()
+        .chain_update([0u8; 8])
+        .chain_update(m_hash)
+        .chain_update(salt)
+        .finalize();
+
+    // EM = maskedDB || H || 0xbc, where DB = PS || 0x01 || salt
+    let mut em = vec![0u8; em_len];
+    let (db, rest) = em.split_at_mut(em_len - h_len - 1);
+
+    let ps_len = db.len() - salt.len() - 1;
+    db[ps_len] = 0x01;
+    db[ps_len + 1..].copy_from_slice(salt);
+    mgf1_xor::<D>(db, &h);
+
+    // Clear the leftmost 8 * emLen - emBits bits
+    db[0] &= 0xff >> (8 * em_len - em_bits);
+
+    rest[..h_len].copy_from_slice(&h);
+    rest[h_len] = 0xbc;
+
+    Ok(em)
+}
+
+/// Encode a message digest with EMSA-PKCS1-v1_5, in `em_len` bytes (the
+/// modulus length).
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-9.2>
+pub(crate) fn emsa_pkcs1v15_encode<D: Digest + AssociatedOid>(
+    digest: &[u8],
+    em_len: usize,
+) -> Result<Vec<u8>> {
+    if digest.len() != <D as Digest>::output_size() {
+        return Err(Error::SizeError);
+    }
+
+    /// <https://www.rfc-editor.org/rfc/rfc8017#appendix-A.2.4>
+    #[derive(Sequence)]
+    struct DigestInfo<'a> {
+        digest_algorithm: AlgorithmIdentifierOwned,
+        digest: &'a OctetStringRef,
+    }
+
+    let t = DigestInfo {
+        digest_algorithm: AlgorithmIdentifierOwned {
+            oid: D::OID,
+            parameters: Some(Any::null()),
+        },
+        digest: OctetStringRef::new(digest).map_err(|_| Error::SizeError)?,
+    }
+    .to_der()
+    .map_err(|_| Error::SizeError)?;
+
+    // EM = 0x00 || 0x01 || PS || 0x00 || T, with at least 8 bytes of PS
+    if em_len < t.len() + 11 {
+        return Err(Error::SizeError);
+    }
+
+    let mut em = vec![0xff; em_len];
+    em[0] = 0x00;
+    em[1] = 0x01;
+    em[em_len - t.len() - 1] = 0x00;
+    em[em_len - t.len()..].copy_from_slice(&t);
+    Ok(em)
+}
+
+/// Decode an EME-PKCS1-v1_5 encoded message of `k` bytes (the modulus
+/// length).
+///
+/// The padding is checked in constant time, and all malformed encodings are
+/// rejected with the same error.
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-7.2.2>
+pub(crate) fn eme_pkcs1v15_decode(em: &[u8], k: usize) -> Result<Buffer> {
+    let em = left_pad(em, k)?;
+
+    // EM = 0x00 || 0x02 || PS || 0x00 || M, with at least 8 bytes of PS
+    let mut valid = em[0].ct_eq(&0x00) & em[1].ct_eq(&0x02);
+    let mut looking = Choice::from(1);
+    let mut index = 0u32;
+
+    for (i, byte) in (0u32..).zip(&em[2..]) {
+        let zero = byte.ct_eq(&0x00);
+        index.conditional_assign(&(i + 2), looking & zero);
+        looking &= !zero;
+    }
+
+    valid &= !looking & index.ct_gt(&9);
+    unpadded(em, valid, index)
+}
+
+/// Decode an EME-OAEP encoded message of `k` bytes (the modulus length),
+/// using the hash function `D` (for the label and MGF1).
+///
+/// The padding is checked in constant time, and all malformed encodings
+/// (including label mismatches) are rejected with the same error.
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-7.1.2>
+pub(crate) fn eme_oaep_decode<D: Digest>(em: &[u8], k: usize, label: &[u8]) -> Result<Buffer> {
+    let h_len = <D as Digest>::output_size();
+
+    if k < 2 * h_len + 2 {
+        return Err(Error::DecryptionError);
+    }
+
+    let mut em = left_pad(em, k)?;
+
+    // EM = Y || maskedSeed || maskedDB
+    let (y, rest) = em.split_at_mut(1);
+    let (seed, db) = rest.split_at_mut(h_len);
+    mgf1_xor::<D>(seed, db);
+    mgf1_xor::<D>(db, seed);
+
+    // DB = lHash || PS || 0x01 || M
+    let l_hash = D::digest(label);
+    let mut valid = y[0].ct_eq(&0x00) & db[..h_len].ct_eq(&l_hash);
+    let mut looking = Choice::from(1);
+    let mut index = 0u32;
+
+    for (i, byte) in (0u32..).zip(&db[h_len..]) {
+        let zero = byte.ct_eq(&0x00);
+        let one = byte.ct_eq(&0x01);
+        index.conditional_assign(&i, looking & one);
+        valid &= !looking | zero | one;
+        looking &= !one;
+    }
+
+    valid &= !looking;
+
+    // Offset of the 0x01 separator in EM
+    let offset = u32::try_from(1 + 2 * h_len).map_err(|_| Error::DecryptionError)?;
+    unpadded(em, valid, index + offset)
+}
+
+/// Left-pad the output of the raw RSA operation to the modulus length.
+fn left_pad(em: &[u8], k: usize) -> Result<Buffer> {
+    if em.len() > k || k < 11 {
+        return Err(Error::DecryptionError);
+    }
+
+    let mut padded = Zeroizing::new(vec![0u8; k]);
+    padded[k - em.len()..].copy_from_slice(em);
+    Ok(padded)
+}
+
+/// Extract the message following the separator at `index`, if the encoding
+/// is valid.
+fn unpadded(em: Buffer, valid: Choice, index: u32) -> Result<Buffer> {
+    if !bool::from(valid) {
+        return Err(Error::DecryptionError);
+    }
+
+    let start = usize::try_from(index).map_err(|_| Error::DecryptionError)? + 1;
+    Ok(Zeroizing::new(em[start..].to_vec()))
+}
+
+/// XOR `out` with the MGF1 mask generated from `seed`.
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#appendix-B.2.1>
+pub(crate) fn mgf1_xor<D: Digest>(out: &mut [u8], seed: &[u8]) {
+    for (counter, chunk) in (0u32..).zip(out.chunks_mut(<D as Digest>::output_size())) {
+        let mask = D::new()
+            .chain_update(seed)
+            .chain_update(counter.to_be_bytes())
+            .finalize();
+
+        for (byte, mask) in chunk.iter_mut().zip(mask.iter()) {
+            *byte ^= mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::{
+        pss::{Signature, VerifyingKey},
+        traits::PublicKeyParts,
+        RsaPrivateKey,
+    };
+    use sha2::Sha256;
+    use signature::hazmat::PrehashVerifier;
+
+    #[test]
+    fn pss_verifies() {
+        let key = RsaPrivateKey::new(&mut rand::rng(), 1024).expect("key");
+        let public = key.to_public_key();
+        let digest = Sha256::digest(b"yubikey");
+
+        for salt_len in [0, 20, 32] {
+            let em = emsa_pss_encode::<Sha256>(&digest, 1023, salt_len).expect("encode");
+            let c = rsa::BoxedUint::from_be_slice(&em, public.n_bits_precision()).expect("em");
+            let s =
+                rsa::hazmat::rsa_decrypt_and_check(&key, Some(&mut rand::rng()), &c).expect("sign");
+
+            let signature = Signature::try_from(&*s.to_be_bytes()).expect("signature");
+            VerifyingKey::<Sha256>::new_with_salt_len(public.clone(), salt_len)
+                .verify_prehash(&digest, &signature)
+                .expect("valid signature");
+        }
+    }
+
+    #[test]
+    fn pss_size_errors() {
+        let digest = Sha256::digest(b"yubikey");
+
+        assert_eq!(
+            emsa_pss_encode::<Sha256>(&digest[1..], 1023, 32),
+            Err(Error::SizeError)
+        );
+        assert_eq!(
+            emsa_pss_encode::<Sha256>(&digest, 8 * 65, 32),
+            Err(Error::SizeError)
+        );
+    }
+
+    #[test]
+    fn pkcs1v15_encode() {
+        let digest = Sha256::digest(b"yubikey");
+        let em = emsa_pkcs1v15_encode::<Sha256>(&digest, 128).expect("encode");
+
+        // DigestInfo prefix for SHA-256 (RFC 8017, section 9.2, note 1)
+        let prefix = hex::lower::decode_vec("3031300d060960864801650304020105000420").expect("hex");
+        let t = [prefix.as_slice(), &digest].concat();
+
+        assert_eq!(&em[..2], &[0x00, 0x01]);
+        assert!(em[2..128 - t.len() - 1].iter().all(|&b| b == 0xff));
+        assert_eq!(em[128 - t.len() - 1], 0x00);
+        assert_eq!(&em[128 - t.len()..], t.as_slice());
+
+        // At least 8 bytes of padding
+        assert_eq!(
+            emsa_pkcs1v15_encode::<Sha256>(&digest, t.len() + 10),
+            Err(Error::SizeError)
+        );
+    }
+
+    /// EME-PKCS1-v1_5 encoding of `msg` with `ps_len` bytes of padding.
+    fn pkcs1v15_encoded(msg: &[u8], ps_len: usize) -> Vec<u8> {
+        let mut em = vec![0x00, 0x02];
+        em.extend(std::iter::repeat_n(0xa5, ps_len));
+        em.push(0x00);
+        em.extend_from_slice(msg);
+        em
+    }
+
+    /// EME-OAEP encoding of `msg` in `k` bytes.
+    fn oaep_encoded(msg: &[u8], k: usize, label: &[u8]) -> Vec<u8> {
+        let mut em = vec![0u8; k];
+        let (seed, db) = em[1..].split_at_mut(32);
+
+        seed.copy_from_slice(&[0x5e; 32]);
+        db[..32].copy_from_slice(&Sha256::digest(label));
+        let start = db.len() - msg.len();
+        db[start - 1] = 0x01;
+        db[start..].copy_from_slice(msg);
+
+        mgf1_xor::<Sha256>(db, seed);
+        mgf1_xor::<Sha256>(seed, db);
+        em
+    }
+
+    #[test]
+    fn pkcs1v15_decode() {
+        let em = pkcs1v15_encoded(b"yubikey", 54);
+        assert_eq!(
+            eme_pkcs1v15_decode(&em, 64).expect("valid").as_slice(),
+            b"yubikey"
+        );
+
+        // Leading zeros stripped by the RSA operation
+        assert_eq!(
+            eme_pkcs1v15_decode(&em[1..], 64).expect("valid").as_slice(),
+            b"yubikey"
+        );
+
+        // Empty message
+        let em = pkcs1v15_encoded(b"", 61);
+        assert!(eme_pkcs1v15_decode(&em, 64).expect("valid").is_empty());
+    }
+
+    #[test]
+    fn pkcs1v15_malformed() {
+        let mut wrong_type = pkcs1v15_encoded(b"yubikey", 54);
+        wrong_type[1] = 0x01;
+
+        let mut no_separator = pkcs1v15_encoded(b"yubikey", 54);
+        no_separator[56] = 0xff;
+
+        let mut wrong_first = pkcs1v15_encoded(b"yubikey", 54);
+        wrong_first[0] = 0x01;
+
+        // Padding shorter than 8 bytes
+        let mut short_padding = pkcs1v15_encoded(b"yubikey", 54);
+        short_padding[9] = 0x00;
+
+        for em in [wrong_type, no_separator, wrong_first, short_padding] {
+            assert_eq!(eme_pkcs1v15_decode(&em, 64), Err(Error::DecryptionError));
+        }
+
+        assert_eq!(
+            eme_pkcs1v15_decode(&[0u8; 65], 64),
+            Err(Error::DecryptionError)
+        );
+    }
+
+    #[test]
+    fn oaep_decode() {
+        let em = oaep_encoded(b"yubikey", 128, b"label");
+        assert_eq!(
+            eme_oaep_decode::<Sha256>(&em, 128, b"label")
+                .expect("valid")
+                .as_slice(),
+            b"yubikey"
+        );
+
+        let em = oaep_encoded(b"", 128, b"");
+        assert!(eme_oaep_decode::<Sha256>(&em, 128, b"")
+            .expect("valid")
+            .is_empty());
+    }
+
+    #[test]
+    fn oaep_malformed() {
+        let em = oaep_encoded(b"yubikey", 128, b"label");
+        assert_eq!(
+            eme_oaep_decode::<Sha256>(&em, 128, b"other"),
+            Err(Error::DecryptionError)
+        );
+
+        // Any modification is detected
+        for i in [0, 1, 40, 127] {
+            let mut corrupted = em.clone();
+            corrupted[i] ^= 0x01;
+            assert_eq!(
+                eme_oaep_decode::<Sha256>(&corrupted, 128, b"label"),
+                Err(Error::DecryptionError)
+            );
+        }
+
+        // Too short for the hash function
+        assert_eq!(
+            eme_oaep_decode::<Sha256>(&em[..64], 64, b"label"),
+            Err(Error::DecryptionError)
+        );
+    }
+}
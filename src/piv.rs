@@ -44,26 +44,33 @@
 
 use crate::{
     apdu::{Ins, StatusWords},
-    certificate::{self, Certificate},
+    certificate::{self, CertInfo, Certificate},
     error::{Error, Result},
     mgm::MgmAlgorithmId,
+    padding,
+    pkcs12::Pkcs12,
     policy::{PinPolicy, TouchPolicy},
+    private_key::PrivateKey,
     serialization::*,
     setting,
-    yubikey::YubiKey,
+    touch::Request,
+    transaction::Transaction,
+    yubikey::{Version, YubiKey},
     Buffer, ObjectId,
 };
 use elliptic_curve::{sec1::Sec1Point as EcPublicKey, PublicKey};
 use log::{debug, error, warn};
 use p256::NistP256;
 use p384::NistP384;
-use rsa::{pkcs8::EncodePublicKey, BoxedUint, RsaPublicKey};
+use rsa::{pkcs8::EncodePublicKey, traits::PublicKeyParts, BoxedUint, RsaPublicKey};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use std::{
     fmt::{Display, Formatter},
     str::FromStr,
 };
+use subtle::ConstantTimeEq;
 use x509_cert::{
-    der::{asn1::BitString, Decode},
+    der::{asn1::BitString, oid::AssociatedOid, referenced::OwnedToRef, Decode, Encode},
     spki::{AlgorithmIdentifier, ObjectIdentifier, SubjectPublicKeyInfoOwned},
 };
 
@@ -478,6 +485,35 @@ pub const SLOTS: [SlotId; 28] = [
     SlotId::Management(ManagementSlotId::Management),
 ];
 
+/// Slots which can hold an asymmetric key
+pub const KEY_SLOTS: [SlotId; 25] = [
+    SlotId::Authentication,
+    SlotId::Signature,
+    SlotId::KeyManagement,
+    SlotId::CardAuthentication,
+    SlotId::Retired(RetiredSlotId::R1),
+    SlotId::Retired(RetiredSlotId::R2),
+    SlotId::Retired(RetiredSlotId::R3),
+    SlotId::Retired(RetiredSlotId::R4),
+    SlotId::Retired(RetiredSlotId::R5),
+    SlotId::Retired(RetiredSlotId::R6),
+    SlotId::Retired(RetiredSlotId::R7),
+    SlotId::Retired(RetiredSlotId::R8),
+    SlotId::Retired(RetiredSlotId::R9),
+    SlotId::Retired(RetiredSlotId::R10),
+    SlotId::Retired(RetiredSlotId::R11),
+    SlotId::Retired(RetiredSlotId::R12),
+    SlotId::Retired(RetiredSlotId::R13),
+    SlotId::Retired(RetiredSlotId::R14),
+    SlotId::Retired(RetiredSlotId::R15),
+    SlotId::Retired(RetiredSlotId::R16),
+    SlotId::Retired(RetiredSlotId::R17),
+    SlotId::Retired(RetiredSlotId::R18),
+    SlotId::Retired(RetiredSlotId::R19),
+    SlotId::Retired(RetiredSlotId::R20),
+    SlotId::Attestation,
+];
+
 /// Algorithm identifiers
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AlgorithmId {
@@ -545,7 +581,17 @@ impl AlgorithmId {
         Tlv::write(buf, 0x80, &[self.into()])
     }
 
-    #[cfg(feature = "untested")]
+    /// Modulus length in bits, for RSA algorithms.
+    pub(crate) fn rsa_bits(self) -> Option<usize> {
+        match self {
+            AlgorithmId::Rsa1024 => Some(1024),
+            AlgorithmId::Rsa2048 => Some(2048),
+            AlgorithmId::Rsa3072 => Some(3072),
+            AlgorithmId::Rsa4096 => Some(4096),
+            _ => None,
+        }
+    }
+
     fn get_elem_len(self) -> usize {
         match self {
             AlgorithmId::Rsa1024 => 64,
@@ -559,7 +605,6 @@ impl AlgorithmId {
         }
     }
 
-    #[cfg(feature = "untested")]
     fn get_param_tag(self) -> u8 {
         match self {
             AlgorithmId::Rsa1024
@@ -585,6 +630,9 @@ pub struct Key {
 
 impl Key {
     /// List Personal Identity Verification (PIV) keys stored in a YubiKey
+    ///
+    /// Only keys with a certificate are listed; see [`SlotStatus::list`] for
+    /// listing all keys.
     pub fn list(yubikey: &mut YubiKey) -> Result<Vec<Self>> {
         let mut keys = vec![];
         let txn = yubikey.begin_transaction()?;
@@ -618,6 +666,343 @@ impl Key {
     }
 }
 
+/// Handle to the key stored in a slot, carrying its algorithm, policies and
+/// public key.
+///
+/// Operations on the key always use the key's algorithm, so unlike with the
+/// functions of this module (e.g. [`sign_data`]) it can't be mismatched.
+#[derive(Clone, Debug)]
+pub struct SlotKey {
+    slot: SlotId,
+    algorithm: AlgorithmId,
+    origin: Option<Origin>,
+    policy: Option<(PinPolicy, TouchPolicy)>,
+    public: SubjectPublicKeyInfoOwned,
+}
+
+impl SlotKey {
+    /// Read the key stored in the given slot.
+    ///
+    /// The key is described by the slot's [`metadata`]. With firmware older
+    /// than 5.3, only its public key and algorithm are known, which are read
+    /// from the certificate stored in the slot.
+    pub fn read(yubikey: &mut YubiKey, slot: SlotId) -> Result<Self> {
+        let metadata = match metadata(yubikey, slot) {
+            Ok(metadata) => metadata,
+            Err(Error::NotSupported) => {
+                let cert = Certificate::read(yubikey, slot)?;
+                let public = cert.cert.tbs_certificate().subject_public_key_info();
+
+                return Ok(Self {
+                    slot,
+                    algorithm: spki_algorithm(public)?,
+                    origin: None,
+                    policy: None,
+                    public: public.clone(),
+                });
+            }
+            Err(e) => return Err(e),
+        };
+
+        Self::from_metadata(slot, metadata)
+    }
+
+    /// Describe the key in `slot` from its metadata.
+    fn from_metadata(slot: SlotId, metadata: SlotMetadata) -> Result<Self> {
+        let algorithm = match metadata.algorithm {
+            SlotAlgorithmId::Asymmetric(algorithm) => algorithm,
+            other => {
+                error!(
+                    "slot {:?} doesn't hold an asymmetric key: {:?}",
+                    slot, other
+                );
+                return Err(Error::AlgorithmError);
+            }
+        };
+
+        Ok(Self {
+            slot,
+            algorithm,
+            origin: metadata.origin,
+            policy: metadata.policy,
+            public: metadata.public.ok_or(Error::InvalidObject)?,
+        })
+    }
+
+    /// Get the slot ID of this key
+    pub fn slot(&self) -> SlotId {
+        self.slot
+    }
+
+    /// Get the algorithm of this key
+    pub fn algorithm(&self) -> AlgorithmId {
+        self.algorithm
+    }
+
+    /// Get whether this key was generated or imported (with firmware 5.3 and
+    /// newer)
+    pub fn origin(&self) -> Option<Origin> {
+        self.origin
+    }
+
+    /// Get the PIN policy of this key (with firmware 5.3 and newer)
+    pub fn pin_policy(&self) -> Option<PinPolicy> {
+        self.policy.map(|(pin_policy, _)| pin_policy)
+    }
+
+    /// Get the touch policy of this key (with firmware 5.3 and newer)
+    pub fn touch_policy(&self) -> Option<TouchPolicy> {
+        self.policy.map(|(_, touch_policy)| touch_policy)
+    }
+
+    /// Get the public key of this key
+    pub fn public_key(&self) -> &SubjectPublicKeyInfoOwned {
+        &self.public
+    }
+
+    /// Sign a message, hashing and encoding it as required by the signature
+    /// scheme (see [`sign_message`]).
+    pub fn sign(
+        &self,
+        yubikey: &mut YubiKey,
+        message: &[u8],
+        hash: HashAlgorithm,
+        scheme: SignatureScheme,
+    ) -> Result<Buffer> {
+        sign_hashed(yubikey, self.slot, message, self.algorithm, hash, scheme)
+    }
+
+    /// Sign data already prepared for the key's algorithm (see
+    /// [`sign_data`]).
+    pub fn sign_data(&self, yubikey: &mut YubiKey, raw_in: &[u8]) -> Result<Buffer> {
+        sign_data(yubikey, raw_in, self.algorithm, self.slot)
+    }
+
+    /// Decrypt a message encrypted with RSAES-PKCS1-v1_5 (see
+    /// [`decrypt_pkcs1v15`]).
+    pub fn decrypt_pkcs1v15(&self, yubikey: &mut YubiKey, ciphertext: &[u8]) -> Result<Buffer> {
+        decrypt_pkcs1v15(yubikey, ciphertext, self.algorithm, self.slot)
+    }
+
+    /// Decrypt a message encrypted with RSAES-OAEP (see [`decrypt_oaep`]).
+    pub fn decrypt_oaep<D: Digest>(
+        &self,
+        yubikey: &mut YubiKey,
+        ciphertext: &[u8],
+        label: &[u8],
+    ) -> Result<Buffer> {
+        decrypt_oaep::<D>(yubikey, ciphertext, label, self.algorithm, self.slot)
+    }
+
+    /// Agree on a shared secret with a peer's public key (see [`ecdh`]).
+    pub fn ecdh<K: EcdhPublicKey>(&self, yubikey: &mut YubiKey, peer: &K) -> Result<Buffer> {
+        if self.algorithm != K::ALGORITHM {
+            error!(
+                "slot {:?} holds a {:?} key, not {:?}",
+                self.slot,
+                self.algorithm,
+                K::ALGORITHM
+            );
+            return Err(Error::AlgorithmError);
+        }
+
+        agree(yubikey, self.slot, peer)
+    }
+
+    /// Generate an attestation certificate for this key (see [`attest`]).
+    #[cfg(feature = "untested")]
+    pub fn attest(&self, yubikey: &mut YubiKey) -> Result<Certificate> {
+        Certificate::from_bytes(attest(yubikey, self.slot)?)
+    }
+
+    /// Read the certificate stored for this key.
+    pub fn certificate(&self, yubikey: &mut YubiKey) -> Result<Certificate> {
+        Certificate::read(yubikey, self.slot)
+    }
+}
+
+/// Status of a key slot: the key and certificate it holds.
+#[derive(Clone, Debug)]
+pub struct SlotStatus {
+    slot: SlotId,
+
+    /// Key in the slot, or `None` if the slot is empty
+    key: Option<SlotKey>,
+
+    /// Error describing the key in the slot from its metadata
+    key_error: Option<Error>,
+
+    /// Whether the slot's [`metadata`] could be read
+    metadata: bool,
+
+    certificate: Option<Certificate>,
+
+    /// Error parsing the certificate stored for the slot
+    certificate_error: Option<Error>,
+}
+
+impl SlotStatus {
+    /// Report the status of every slot which can hold a key (see
+    /// [`KEY_SLOTS`]).
+    ///
+    /// Keys are described by the slots' [`metadata`], which requires firmware
+    /// 5.3 or newer: with older firmware, only the certificates are known.
+    ///
+    /// Keys and certificates which can't be parsed don't stop the listing:
+    /// the errors are reported for their slots by [`SlotStatus::key_error`]
+    /// and [`SlotStatus::certificate_error`].
+    pub fn list(yubikey: &mut YubiKey) -> Result<Vec<Self>> {
+        yubikey.retrying(|yubikey| {
+            let txn = yubikey.begin_transaction()?;
+            let mut slots = Vec::with_capacity(KEY_SLOTS.len());
+
+            for slot in KEY_SLOTS {
+                let (key, key_error, metadata) = match txn.get_metadata(slot) {
+                    Ok(metadata) => match SlotKey::from_metadata(slot, metadata) {
+                        Ok(key) => (Some(key), None, true),
+                        Err(e) => {
+                            warn!("error describing key in slot {:?}: {}", slot, e);
+                            (None, Some(e), true)
+                        }
+                    },
+                    Err(Error::NotFound) => (None, None, true),
+                    Err(Error::NotSupported) => (None, None, false),
+                    Err(e) => {
+                        error!("error reading metadata of slot {:?}: {}", slot, e);
+                        return Err(e);
+                    }
+                };
+
+                let (certificate, certificate_error) =
+                    match certificate::find_certificate(&txn, slot).inspect_err(|e| {
+                        error!("error reading certificate in slot {:?}: {}", slot, e);
+                    })? {
+                        Some(Ok(certificate)) => (Some(certificate), None),
+                        Some(Err(e)) => {
+                            warn!("error parsing certificate in slot {:?}: {}", slot, e);
+                            (None, Some(e))
+                        }
+                        None => (None, None),
+                    };
+
+                slots.push(Self {
+                    slot,
+                    key,
+                    key_error,
+                    metadata,
+                    certificate,
+                    certificate_error,
+                });
+            }
+
+            Ok(slots)
+        })
+    }
+
+    /// Get the slot ID
+    pub fn slot(&self) -> SlotId {
+        self.slot
+    }
+
+    /// Is there a key in the slot?
+    ///
+    /// Returns `None` if this can't be determined (with firmware older than
+    /// 5.3).
+    pub fn is_present(&self) -> Option<bool> {
+        self.metadata
+            .then_some(self.key.is_some() || self.key_error.is_some())
+    }
+
+    /// Get the key in the slot, with its algorithm, origin, policies and
+    /// public key (with firmware 5.3 and newer)
+    ///
+    /// Returns `None` if there is no key, or its metadata can't be parsed
+    /// (see [`SlotStatus::key_error`]).
+    pub fn key(&self) -> Option<&SlotKey> {
+        self.key.as_ref()
+    }
+
+    /// Get the error describing the key in the slot from its metadata, e.g.
+    /// [`Error::InvalidObject`] if the metadata lacks the public key
+    pub fn key_error(&self) -> Option<Error> {
+        self.key_error
+    }
+
+    /// Are the key's PIN and touch policies the defaults of the slot?
+    ///
+    /// Returns `None` if there is no key or its policies are unknown.
+    pub fn has_default_policies(&self) -> Option<bool> {
+        let key = self.key.as_ref()?;
+        let pin_policy = key.pin_policy()?;
+        let touch_policy = key.touch_policy()?;
+
+        let default_pin_policy = match self.slot {
+            SlotId::Signature => PinPolicy::Always,
+            SlotId::CardAuthentication | SlotId::Attestation => PinPolicy::Never,
+            _ => PinPolicy::Once,
+        };
+
+        Some(pin_policy == default_pin_policy && touch_policy == TouchPolicy::Never)
+    }
+
+    /// Get the certificate stored for the slot
+    ///
+    /// Returns `None` if there is no certificate, or it can't be parsed (see
+    /// [`SlotStatus::certificate_error`]).
+    pub fn certificate(&self) -> Option<&Certificate> {
+        self.certificate.as_ref()
+    }
+
+    /// Get the error parsing the certificate stored for the slot, e.g.
+    /// [`Error::InvalidObject`] for a compressed certificate
+    pub fn certificate_error(&self) -> Option<Error> {
+        self.certificate_error
+    }
+
+    /// Does the certificate's public key match the key in the slot?
+    ///
+    /// Returns `None` if there is no certificate, or the key is unknown (with
+    /// firmware older than 5.3, or if its metadata can't be parsed).
+    pub fn certificate_matches(&self) -> Option<bool> {
+        let certificate = self.certificate.as_ref()?;
+
+        (self.metadata && self.key_error.is_none()).then(|| {
+            self.key
+                .as_ref()
+                .is_some_and(|key| certificate.subject_pki() == key.public_key().owned_to_ref())
+        })
+    }
+}
+
+/// Determine the algorithm of a PIV key from its public key.
+fn spki_algorithm(public: &SubjectPublicKeyInfoOwned) -> Result<AlgorithmId> {
+    let spki = public.owned_to_ref();
+
+    if public.algorithm.oid == OID_X25519 {
+        Ok(AlgorithmId::X25519)
+    } else if let Ok(key) = RsaPublicKey::try_from(spki.clone()) {
+        match key.size() * 8 {
+            1024 => Ok(AlgorithmId::Rsa1024),
+            2048 => Ok(AlgorithmId::Rsa2048),
+            3072 => Ok(AlgorithmId::Rsa3072),
+            4096 => Ok(AlgorithmId::Rsa4096),
+            bits => {
+                error!("unsupported RSA key size: {}", bits);
+                Err(Error::AlgorithmError)
+            }
+        }
+    } else if p256::PublicKey::try_from(spki.clone()).is_ok() {
+        Ok(AlgorithmId::EccP256)
+    } else if p384::PublicKey::try_from(spki.clone()).is_ok() {
+        Ok(AlgorithmId::EccP384)
+    } else if ed25519_dalek::VerifyingKey::try_from(spki).is_ok() {
+        Ok(AlgorithmId::Ed25519)
+    } else {
+        error!("unsupported public key algorithm: {}", public.algorithm.oid);
+        Err(Error::AlgorithmError)
+    }
+}
+
 /// Generate new key.
 pub fn generate(
     yubikey: &mut YubiKey,
@@ -639,6 +1024,7 @@ pub fn generate(
 
     let setting_roca: setting::Setting;
 
+    #[allow(clippy::collapsible_match)]
     match algorithm {
         AlgorithmId::Rsa1024
         | AlgorithmId::Rsa2048
@@ -737,7 +1123,289 @@ pub fn generate(
     read_public_key(algorithm, value, true)
 }
 
-#[cfg(feature = "untested")]
+/// Move the key in slot `from` to slot `to` (firmware 5.7 and newer).
+///
+/// Slot `to` must not hold a key: the YubiKey refuses to overwrite it, and
+/// [`Error::SlotOccupied`] is returned (see [`delete_key`] for deleting it
+/// first).
+///
+/// Requires authentication with the management key. The certificate stored
+/// for `from` is left in place: see [`move_key_and_certificate`] for moving
+/// it too.
+///
+/// The attestation key (slot `f9`) can't be moved, only deleted.
+pub fn move_key(yubikey: &mut YubiKey, from: SlotId, to: SlotId) -> Result<()> {
+    check_move_key(yubikey)?;
+    transfer_key(&yubikey.begin_transaction()?, to.into(), from)
+}
+
+/// Move the key in slot `from` to slot `to`, along with its certificate
+/// (firmware 5.7 and newer).
+///
+/// See [`move_key`]: slot `to` must not hold a key, or
+/// [`Error::SlotOccupied`] is returned and nothing is changed. Any
+/// certificate stored for `to` is replaced, or deleted if there is no
+/// certificate stored for `from`.
+pub fn move_key_and_certificate(yubikey: &mut YubiKey, from: SlotId, to: SlotId) -> Result<()> {
+    check_move_key(yubikey)?;
+    let txn = yubikey.begin_transaction()?;
+
+    let certificate = match txn.fetch_object(from.object_id()) {
+        Ok(certificate) => Some(certificate),
+        Err(Error::NotFound) => None,
+        Err(e) => return Err(e),
+    };
+
+    transfer_key(&txn, to.into(), from)?;
+
+    txn.save_object(
+        to.object_id(),
+        certificate.as_deref().map_or(&[], Vec::as_slice),
+    )?;
+    if certificate.is_some() {
+        txn.save_object(from.object_id(), &[])?;
+    }
+
+    Ok(())
+}
+
+/// Delete the key in the given slot (firmware 5.7 and newer).
+///
+/// Requires authentication with the management key. The certificate stored
+/// for the slot is left in place.
+pub fn delete_key(yubikey: &mut YubiKey, slot: SlotId) -> Result<()> {
+    check_move_key(yubikey)?;
+    transfer_key(&yubikey.begin_transaction()?, 0xff, slot)
+}
+
+/// Check the YubiKey supports MOVE KEY.
+fn check_move_key(yubikey: &YubiKey) -> Result<()> {
+    if yubikey.version < Version::new([5, 7, 0]) {
+        error!(
+            "moving and deleting keys require firmware 5.7 (is {})",
+            yubikey.version
+        );
+        return Err(Error::NotSupported);
+    }
+
+    Ok(())
+}
+
+/// Move the key in slot `from` to the slot with the given key reference, or
+/// delete it with `0xff`.
+fn transfer_key(txn: &Transaction<'_>, to: u8, from: SlotId) -> Result<()> {
+    let templ = [0, Ins::MoveKey.code(), to, from.into()];
+    let response = txn.transfer_data(&templ, &[], 256)?;
+
+    if !response.is_success() {
+        let err_msg = "failed to move key";
+
+        match response.status_words() {
+            StatusWords::ReferenceDataNotFoundError => {
+                error!("{} (no key in slot {:?})", err_msg, from);
+                return Err(Error::NotFound);
+            }
+            StatusWords::IncorrectSlotError | StatusWords::IncorrectParamError => {
+                error!("{} (incorrect slot)", err_msg);
+                return Err(Error::KeyError);
+            }
+            StatusWords::ConditionsNotSatisfiedError => {
+                error!("{} (slot {:#04x} already holds a key)", err_msg, to);
+                return Err(Error::SlotOccupied);
+            }
+            StatusWords::SecurityStatusError => {
+                error!("{} (not authenticated)", err_msg);
+                return Err(Error::AuthenticationError);
+            }
+            other => {
+                error!("{} (error {:?})", err_msg, other);
+                return Err(Error::GenericError);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Import a private key into the given slot, returning its public key.
+///
+/// The key can be encoded as PKCS#8 (optionally encrypted with `passphrase`,
+/// using PBES2 with PBKDF2 and AES-CBC) or as a SEC1 EC private key, either
+/// DER or PEM encoded. Its algorithm is determined from the encoding, and
+/// must be supported by the YubiKey: RSA keys must use the public exponent
+/// 65537, and RSA 3072/4096, Ed25519 and X25519 keys require firmware 5.7 or
+/// newer.
+///
+/// Requires authentication with the management key. With firmware 5.3 and
+/// newer, the public key is checked against the slot's [`metadata`] once the
+/// key is imported.
+pub fn import_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    key: &[u8],
+    passphrase: Option<&[u8]>,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<SubjectPublicKeyInfoOwned> {
+    let key = PrivateKey::decode(key, passphrase)?;
+    let algorithm = check_import(yubikey, slot, &key)?;
+    import_private_key(yubikey, slot, &key, algorithm, pin_policy, touch_policy)
+}
+
+/// Import the private key and certificate of a PKCS#12 bundle into the given
+/// slot, returning the certificate.
+///
+/// The bundle must be DER encoded, and hold a single private key (of one of
+/// the algorithms supported by [`import_key`]) and its certificate. Its
+/// integrity is checked and its contents are decrypted with `password`,
+/// which supports bundles written by OpenSSL 3 and most other tools, but not
+/// those encrypted with RC2 (e.g. by `openssl pkcs12 -legacy`).
+///
+/// The intermediate certificates of the chain, starting with the issuer of
+/// the certificate, are written to the certificate objects of the `chain`
+/// slots, which must have room for all of them and be empty: a slot which
+/// would receive an intermediate certificate but already holds a key or a
+/// certificate is rejected with [`Error::SlotOccupied`]. With no `chain`
+/// slots, intermediates aren't stored.
+///
+/// Everything is validated before writing to the YubiKey, so that nothing
+/// is written if e.g. the key doesn't match the certificate, or a
+/// certificate is too large for its object. Requires authentication with
+/// the management key.
+pub fn import_pkcs12(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    pkcs12: &[u8],
+    password: &str,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+    chain: &[RetiredSlotId],
+) -> Result<Certificate> {
+    let bundle = Pkcs12::decode(pkcs12, password)?;
+    let algorithm = check_import(yubikey, slot, &bundle.key)?;
+
+    let intermediates = match chain {
+        [] => &[][..],
+        _ => bundle.intermediates.as_slice(),
+    };
+
+    if intermediates.len() > chain.len() {
+        error!(
+            "no room for {} intermediate certificates in {} slots",
+            intermediates.len(),
+            chain.len()
+        );
+        return Err(Error::SizeError);
+    }
+
+    for (i, retired) in chain.iter().enumerate() {
+        if SlotId::Retired(*retired) == slot || chain[..i].contains(retired) {
+            error!("slot {:?} can't hold more than one certificate", retired);
+            return Err(Error::ArgumentError);
+        }
+    }
+
+    for cert in std::iter::once(&bundle.leaf).chain(intermediates) {
+        let data = cert.cert.to_der().map_err(|_| Error::InvalidObject)?;
+        certificate::certificate_object(&data, CertInfo::Uncompressed)?;
+    }
+
+    // Slots receiving intermediates mustn't hold anything they'd overwrite
+    {
+        let txn = yubikey.begin_transaction()?;
+
+        for retired in &chain[..intermediates.len()] {
+            let retired = SlotId::Retired(*retired);
+
+            match txn.get_metadata(retired) {
+                Ok(_) => {
+                    error!("slot {:?} already holds a key", retired);
+                    return Err(Error::SlotOccupied);
+                }
+                Err(Error::NotFound) | Err(Error::NotSupported) => (),
+                Err(e) => return Err(e),
+            }
+
+            if certificate::find_certificate(&txn, retired)?.is_some() {
+                error!("slot {:?} already holds a certificate", retired);
+                return Err(Error::SlotOccupied);
+            }
+        }
+    }
+
+    import_private_key(
+        yubikey,
+        slot,
+        &bundle.key,
+        algorithm,
+        pin_policy,
+        touch_policy,
+    )?;
+
+    bundle.leaf.write(yubikey, slot, CertInfo::Uncompressed)?;
+    for (cert, retired) in intermediates.iter().zip(chain) {
+        cert.write(yubikey, SlotId::Retired(*retired), CertInfo::Uncompressed)?;
+    }
+
+    Ok(bundle.leaf)
+}
+
+/// Check that `key` can be imported into the given slot, returning its
+/// algorithm.
+fn check_import(yubikey: &YubiKey, slot: SlotId, key: &PrivateKey) -> Result<AlgorithmId> {
+    if let SlotId::Management(_) = slot {
+        error!("can't import a private key into slot {:?}", slot);
+        return Err(Error::KeyError);
+    }
+
+    let algorithm = key.algorithm()?;
+
+    if matches!(
+        algorithm,
+        AlgorithmId::Rsa3072 | AlgorithmId::Rsa4096 | AlgorithmId::Ed25519 | AlgorithmId::X25519
+    ) && yubikey.version < Version::new([5, 7, 0])
+    {
+        error!(
+            "{:?} keys require firmware 5.7 (is {})",
+            algorithm, yubikey.version
+        );
+        return Err(Error::NotSupported);
+    }
+
+    Ok(algorithm)
+}
+
+/// Write `key` into the given slot, and check it against the slot's
+/// metadata (when supported), returning its public key.
+fn import_private_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    key: &PrivateKey,
+    algorithm: AlgorithmId,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<SubjectPublicKeyInfoOwned> {
+    let public = key.public_key()?;
+    let params = key.params()?;
+    write_key(
+        yubikey,
+        slot,
+        params.iter().map(|param| param.as_slice()).collect(),
+        pin_policy,
+        touch_policy,
+        algorithm,
+    )?;
+
+    match metadata(yubikey, slot) {
+        Ok(metadata) if metadata.public.as_ref() != Some(&public) => {
+            error!("key in slot {:?} doesn't match the imported key", slot);
+            Err(Error::KeyError)
+        }
+        Ok(_) | Err(Error::NotSupported) => Ok(public),
+        Err(e) => Err(e),
+    }
+}
+
 fn write_key(
     yubikey: &mut YubiKey,
     slot: SlotId,
@@ -746,13 +1414,23 @@ fn write_key(
     touch_policy: TouchPolicy,
     algorithm: AlgorithmId,
 ) -> Result<()> {
-    let mut key_data = Buffer::new(vec![0u8; KEYDATA_LEN]);
-    let templ = [0, Ins::ImportKey.code(), algorithm.into(), slot.into()];
-    let mut offset = 0;
-
     let elem_len = algorithm.get_elem_len();
     let param_tag = algorithm.get_param_tag();
 
+    // e.g. RSA primes of unequal lengths
+    if params.iter().any(|param| param.len() > elem_len) {
+        error!(
+            "key parameters of {:?} keys can't exceed {} bytes",
+            algorithm, elem_len
+        );
+        return Err(Error::AlgorithmError);
+    }
+
+    // Each parameter with a tag and length of up to 4 bytes, and the policies
+    let mut key_data = Buffer::new(vec![0u8; params.len() * (elem_len + 4) + 6]);
+    let templ = [0, Ins::ImportKey.code(), algorithm.into(), slot.into()];
+    let mut offset = 0;
+
     for (i, param) in params.into_iter().enumerate() {
         offset += Tlv::write_as(
             &mut key_data[offset..],
@@ -971,19 +1649,193 @@ pub fn attest(yubikey: &mut YubiKey, key: SlotId) -> Result<Buffer> {
 }
 
 /// Sign data using a PIV key.
+///
+/// If the key's touch policy requires it, this waits for the user to touch
+/// the YubiKey (see [`crate::touch`]).
 pub fn sign_data(
     yubikey: &mut YubiKey,
     raw_in: &[u8],
     algorithm: AlgorithmId,
     key: SlotId,
 ) -> Result<Buffer> {
-    let txn = yubikey.begin_transaction()?;
+    yubikey.touch_gated(Some(Request::Sign(key)), |yubikey| {
+        yubikey.retrying(|yubikey| {
+            let txn = yubikey.begin_transaction()?;
+
+            // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
+            txn.authenticated_command(raw_in, algorithm, key, false)
+        })
+    })
+}
+
+/// Sign a message digest using an RSA PIV key with RSASSA-PSS.
+///
+/// `digest` is the hash of the message with `D`, which is also used by the
+/// EMSA-PSS encoding and its mask generation function (MGF1). The encoding
+/// is done on the host, with a random salt of `salt_len` bytes (usually the
+/// length of the digest), and the YubiKey performs the raw RSA operation.
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-8.1>
+pub fn sign_pss<D: Digest>(
+    yubikey: &mut YubiKey,
+    digest: &[u8],
+    salt_len: usize,
+    algorithm: AlgorithmId,
+    key: SlotId,
+) -> Result<Buffer> {
+    let bits = algorithm.rsa_bits().ok_or(Error::AlgorithmError)?;
+    let encoded = padding::emsa_pss_encode::<D>(digest, bits - 1, salt_len)?;
+    sign_data(yubikey, &encoded, algorithm, key)
+}
+
+/// Hash functions for [`sign_message`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HashAlgorithm {
+    /// SHA-256
+    Sha256,
+
+    /// SHA-384
+    Sha384,
+
+    /// SHA-512
+    Sha512,
+}
+
+/// Signature schemes for [`sign_message`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SignatureScheme {
+    /// ECDSA with a P-256 or P-384 key, returning an ASN.1 DER encoded
+    /// signature
+    EcdsaDer,
+
+    /// ECDSA with a P-256 or P-384 key, returning a fixed-size signature
+    /// (the concatenation of `r` and `s`)
+    EcdsaFixed,
+
+    /// RSASSA-PKCS1-v1_5
+    RsaPkcs1v15,
+
+    /// RSASSA-PSS, using MGF1 with the same hash function and a salt as long
+    /// as the digest
+    RsaPss,
+
+    /// Ed25519 (PureEdDSA), over the message itself
+    Ed25519,
+}
+
+/// Sign a message using a PIV key, hashing and encoding it on the host as
+/// required by the signature scheme.
+///
+/// The algorithm of the key is read from the slot's [`metadata`], which
+/// requires firmware 5.3 or newer, and must match the signature scheme
+/// (otherwise [`Error::AlgorithmError`] is returned). ECDSA digests longer
+/// than the curve's order are truncated. `hash` is ignored for Ed25519,
+/// which signs the message itself (with the message size limited by what
+/// fits in a single command).
+///
+/// If the key's touch policy requires it, this waits for the user to touch
+/// the YubiKey (see [`crate::touch`]).
+pub fn sign_message(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    message: &[u8],
+    hash: HashAlgorithm,
+    scheme: SignatureScheme,
+) -> Result<Buffer> {
+    let algorithm = match metadata(yubikey, slot)?.algorithm {
+        SlotAlgorithmId::Asymmetric(algorithm) => algorithm,
+        other => {
+            error!(
+                "slot {:?} doesn't hold an asymmetric key: {:?}",
+                slot, other
+            );
+            return Err(Error::AlgorithmError);
+        }
+    };
+
+    sign_hashed(yubikey, slot, message, algorithm, hash, scheme)
+}
+
+/// Sign a message with the `algorithm` key in `slot`, hashing it with
+/// `hash`.
+fn sign_hashed(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    message: &[u8],
+    algorithm: AlgorithmId,
+    hash: HashAlgorithm,
+    scheme: SignatureScheme,
+) -> Result<Buffer> {
+    match hash {
+        HashAlgorithm::Sha256 => sign_with::<Sha256>(yubikey, slot, message, algorithm, scheme),
+        HashAlgorithm::Sha384 => sign_with::<Sha384>(yubikey, slot, message, algorithm, scheme),
+        HashAlgorithm::Sha512 => sign_with::<Sha512>(yubikey, slot, message, algorithm, scheme),
+    }
+}
+
+/// Sign a message with the given signature scheme, using the hash function
+/// `D`.
+fn sign_with<D: Digest + AssociatedOid>(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    message: &[u8],
+    algorithm: AlgorithmId,
+    scheme: SignatureScheme,
+) -> Result<Buffer> {
+    match (scheme, algorithm) {
+        (SignatureScheme::EcdsaDer, AlgorithmId::EccP256 | AlgorithmId::EccP384) => {
+            let field_len = if algorithm == AlgorithmId::EccP256 {
+                32
+            } else {
+                48
+            };
+
+            let digest = D::digest(message);
+            let len = digest.len().min(field_len);
+            sign_data(yubikey, &digest[..len], algorithm, slot)
+        }
+        (SignatureScheme::EcdsaFixed, AlgorithmId::EccP256 | AlgorithmId::EccP384) => {
+            let der = sign_with::<D>(yubikey, slot, message, algorithm, SignatureScheme::EcdsaDer)?;
 
-    // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
-    txn.authenticated_command(raw_in, algorithm, key, false)
+            let fixed = if algorithm == AlgorithmId::EccP256 {
+                p256::ecdsa::Signature::from_der(&der).map(|sig| sig.to_vec())
+            } else {
+                p384::ecdsa::Signature::from_der(&der).map(|sig| sig.to_vec())
+            };
+
+            fixed.map(Buffer::new).map_err(|_| Error::ParseError)
+        }
+        (SignatureScheme::RsaPkcs1v15, _) if algorithm.rsa_bits().is_some() => {
+            let bits = algorithm.rsa_bits().ok_or(Error::AlgorithmError)?;
+            let encoded = padding::emsa_pkcs1v15_encode::<D>(&D::digest(message), bits / 8)?;
+            sign_data(yubikey, &encoded, algorithm, slot)
+        }
+        (SignatureScheme::RsaPss, _) if algorithm.rsa_bits().is_some() => sign_pss::<D>(
+            yubikey,
+            &D::digest(message),
+            <D as Digest>::output_size(),
+            algorithm,
+            slot,
+        ),
+        (SignatureScheme::Ed25519, AlgorithmId::Ed25519) => {
+            sign_data(yubikey, message, algorithm, slot)
+        }
+        _ => {
+            error!(
+                "slot {:?} holds a {:?} key, which can't be used with {:?}",
+                slot, algorithm, scheme
+            );
+            Err(Error::AlgorithmError)
+        }
+    }
 }
 
 /// Decrypt data using a PIV key.
+///
+/// If the key's touch policy requires it, this waits for the user to touch
+/// the YubiKey (see [`crate::touch`]).
 #[cfg(feature = "untested")]
 pub fn decrypt_data(
     yubikey: &mut YubiKey,
@@ -991,17 +1843,208 @@ pub fn decrypt_data(
     algorithm: AlgorithmId,
     key: SlotId,
 ) -> Result<Buffer> {
-    let txn = yubikey.begin_transaction()?;
+    decipher(yubikey, input, algorithm, key)
+}
 
-    // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
-    txn.authenticated_command(input, algorithm, key, true)
+/// Decrypt an RSAES-PKCS1-v1_5 ciphertext using an RSA PIV key.
+///
+/// The YubiKey performs the raw RSA operation, and the padding is removed on
+/// the host in constant time. Malformed padding is rejected with
+/// [`Error::DecryptionError`].
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-7.2>
+pub fn decrypt_pkcs1v15(
+    yubikey: &mut YubiKey,
+    ciphertext: &[u8],
+    algorithm: AlgorithmId,
+    key: SlotId,
+) -> Result<Buffer> {
+    let bits = algorithm.rsa_bits().ok_or(Error::AlgorithmError)?;
+    let em = decipher(yubikey, ciphertext, algorithm, key)?;
+    padding::eme_pkcs1v15_decode(&em, bits / 8)
+}
+
+/// Decrypt an RSAES-OAEP ciphertext using an RSA PIV key.
+///
+/// `D` is the hash function used for the label and the mask generation
+/// function (MGF1), e.g. [`sha1::Sha1`] or [`sha2::Sha256`], and `label` is
+/// the label the message was encrypted with (usually empty). The YubiKey
+/// performs the raw RSA operation, and the encoding is checked on the host in
+/// constant time. Malformed encodings (including label mismatches) are
+/// rejected with [`Error::DecryptionError`].
+///
+/// <https://www.rfc-editor.org/rfc/rfc8017#section-7.1>
+pub fn decrypt_oaep<D: Digest>(
+    yubikey: &mut YubiKey,
+    ciphertext: &[u8],
+    label: &[u8],
+    algorithm: AlgorithmId,
+    key: SlotId,
+) -> Result<Buffer> {
+    let bits = algorithm.rsa_bits().ok_or(Error::AlgorithmError)?;
+    let em = decipher(yubikey, ciphertext, algorithm, key)?;
+    padding::eme_oaep_decode::<D>(&em, bits / 8, label)
+}
+
+/// Public key of a peer, for key agreement with [`ecdh`].
+///
+/// This is implemented for [`p256::PublicKey`], [`p384::PublicKey`] and
+/// [`x25519_dalek::PublicKey`].
+pub trait EcdhPublicKey: private::Sealed {
+    /// Algorithm of the PIV keys this key can agree on a secret with
+    const ALGORITHM: AlgorithmId;
+}
+
+impl EcdhPublicKey for p256::PublicKey {
+    const ALGORITHM: AlgorithmId = AlgorithmId::EccP256;
+}
+
+impl EcdhPublicKey for p384::PublicKey {
+    const ALGORITHM: AlgorithmId = AlgorithmId::EccP384;
+}
+
+impl EcdhPublicKey for x25519_dalek::PublicKey {
+    const ALGORITHM: AlgorithmId = AlgorithmId::X25519;
+}
+
+mod private {
+    pub trait Sealed {
+        /// Encoding of the key sent to the YubiKey, and the length of the
+        /// resulting shared secret
+        fn encode(&self) -> (Vec<u8>, usize);
+    }
+
+    impl Sealed for p256::PublicKey {
+        fn encode(&self) -> (Vec<u8>, usize) {
+            // Uncompressed SEC1 point
+            (self.to_sec1_bytes().into(), 32)
+        }
+    }
+
+    impl Sealed for p384::PublicKey {
+        fn encode(&self) -> (Vec<u8>, usize) {
+            (self.to_sec1_bytes().into(), 48)
+        }
+    }
+
+    impl Sealed for x25519_dalek::PublicKey {
+        fn encode(&self) -> (Vec<u8>, usize) {
+            (self.as_bytes().to_vec(), 32)
+        }
+    }
+}
+
+/// Agree on a shared secret between the key in `slot` and a peer's public
+/// key, with ECDH (P-256 or P-384) or X25519.
+///
+/// The slot's algorithm is checked against the peer's key using the slot's
+/// [`metadata`] (with firmware 5.3 and newer). For the NIST curves the
+/// returned secret is the x-coordinate of the shared point; X25519 secrets
+/// from low-order peer keys (which are all zero) are rejected with
+/// [`Error::KeyError`].
+///
+/// If the key's touch policy requires it, this waits for the user to touch
+/// the YubiKey (see [`crate::touch`]).
+pub fn ecdh<K: EcdhPublicKey>(yubikey: &mut YubiKey, slot: SlotId, peer: &K) -> Result<Buffer> {
+    match metadata(yubikey, slot) {
+        Ok(metadata) if metadata.algorithm != SlotAlgorithmId::Asymmetric(K::ALGORITHM) => {
+            error!(
+                "slot {:?} holds a {:?} key, not {:?}",
+                slot,
+                metadata.algorithm,
+                K::ALGORITHM
+            );
+            return Err(Error::AlgorithmError);
+        }
+        // Older firmware can't tell; the card rejects mismatched keys anyway
+        Ok(_) | Err(Error::NotSupported) => (),
+        Err(e) => return Err(e),
+    }
+
+    agree(yubikey, slot, peer)
+}
+
+/// Agree on a shared secret with the key in `slot`, without checking its
+/// algorithm.
+fn agree<K: EcdhPublicKey>(yubikey: &mut YubiKey, slot: SlotId, peer: &K) -> Result<Buffer> {
+    let (encoded, secret_len) = peer.encode();
+    let secret = decipher(yubikey, &encoded, K::ALGORITHM, slot)?;
+
+    if secret.len() != secret_len {
+        error!("unexpected shared secret length: {}", secret.len());
+        return Err(Error::InvalidObject);
+    }
+
+    if bool::from(secret.ct_eq(&vec![0u8; secret_len])) {
+        error!("peer public key has a low order");
+        return Err(Error::KeyError);
+    }
+
+    Ok(secret)
+}
+
+/// Perform a raw private key operation for decryption or key agreement.
+fn decipher(
+    yubikey: &mut YubiKey,
+    input: &[u8],
+    algorithm: AlgorithmId,
+    key: SlotId,
+) -> Result<Buffer> {
+    let request = match algorithm {
+        AlgorithmId::Rsa1024
+        | AlgorithmId::Rsa2048
+        | AlgorithmId::Rsa3072
+        | AlgorithmId::Rsa4096 => Request::Decrypt(key),
+        _ => Request::KeyAgreement(key),
+    };
+
+    yubikey.touch_gated(Some(request), |yubikey| {
+        yubikey.retrying(|yubikey| {
+            let txn = yubikey.begin_transaction()?;
+
+            // don't attempt to reselect in crypt operations to avoid problems with PIN_ALWAYS
+            txn.authenticated_command(input, algorithm, key, true)
+        })
+    })
+}
+
+/// Read the public key of the key in `slot`, which must be an `algorithm`
+/// key.
+///
+/// The public key is read from the slot's metadata, which also checks the
+/// algorithm. With firmware older than 5.3 it is read from the certificate
+/// stored for the slot instead.
+pub(crate) fn slot_public_key(
+    yubikey: &mut YubiKey,
+    slot: SlotId,
+    algorithm: AlgorithmId,
+) -> Result<SubjectPublicKeyInfoOwned> {
+    let public = match metadata(yubikey, slot) {
+        Ok(metadata) if metadata.algorithm != SlotAlgorithmId::Asymmetric(algorithm) => {
+            error!(
+                "slot {:?} holds a {:?} key, not {:?}",
+                slot, metadata.algorithm, algorithm
+            );
+            return Err(Error::AlgorithmError);
+        }
+        Ok(metadata) => metadata.public,
+        Err(Error::NotSupported) => None,
+        Err(e) => return Err(e),
+    };
+
+    match public {
+        Some(public) => Ok(public),
+        None => Ok(Certificate::read(yubikey, slot)?
+            .cert
+            .tbs_certificate()
+            .subject_public_key_info()
+            .clone()),
+    }
 }
 
 /// Read metadata
 pub fn metadata(yubikey: &mut YubiKey, slot: SlotId) -> Result<SlotMetadata> {
-    let txn = yubikey.begin_transaction()?;
-
-    txn.get_metadata(slot)
+    yubikey.retrying(|yubikey| yubikey.begin_transaction()?.get_metadata(slot))
 }
 
 /// Metadata from a slot
@@ -1167,7 +2210,7 @@ impl TryFrom<u8> for Origin {
     }
 }
 
-fn read_public_key(
+pub(crate) fn read_public_key(
     algorithm: AlgorithmId,
     input: &[u8],
     skip_asn1_tag: bool,
@@ -0,0 +1,442 @@
+//! Decoding of PKCS#12 bundles ([RFC 7292]), for importing them into a
+//! YubiKey (see [`piv::import_pkcs12`][`crate::piv::import_pkcs12`]).
+//!
+//! Bundles must hold a single private key and the certificate matching it,
+//! and may hold further certificates of its chain. Keys and certificates can
+//! be encrypted with PBES2 (as written by OpenSSL 3) or with
+//! `pbeWithSHAAnd3-KeyTripleDES-CBC`; the RC2 and RC4 based schemes of
+//! legacy bundles aren't supported.
+//!
+//! [RFC 7292]: https://www.rfc-editor.org/rfc/rfc7292
+
+use crate::{
+    certificate::Certificate,
+    error::{Error, Result},
+    private_key::{self, EncryptedPrivateKeyInfo, PrivateKey},
+    Buffer,
+};
+use der::{
+    asn1::{AnyRef, ObjectIdentifier, OctetStringRef},
+    oid::db::rfc5912,
+    Decode, Encode, Sequence,
+};
+use hmac::{KeyInit, Mac, SimpleHmac};
+use log::error;
+use sha1::Sha1;
+use sha2::{digest::block_api::BlockSizeUser, Digest, Sha224, Sha256, Sha384, Sha512};
+use x509_cert::spki::AlgorithmIdentifierRef;
+use zeroize::Zeroizing;
+
+/// Version of the `PFX` structure
+const PFX_VERSION: u8 = 3;
+
+/// PKCS#7 content types
+const OID_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+const OID_ENCRYPTED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.6");
+
+/// PKCS#12 bag types
+const OID_KEY_BAG: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.10.1.1");
+const OID_SHROUDED_KEY_BAG: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.10.1.2");
+const OID_CERT_BAG: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.10.1.3");
+
+/// Certificate type of X.509 certificates in certificate bags
+const OID_X509_CERTIFICATE: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.22.1");
+
+/// PKCS#12 password-based encryption with SHA-1 and three-key 3DES-CBC
+const OID_PBE_SHA1_3DES: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.12.1.3");
+
+/// PBMAC1 message authentication scheme (RFC 9579)
+const OID_PBMAC1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.5.14");
+
+/// Purposes of the key material derived with the PKCS#12 key derivation
+/// function
+const KDF_KEY: u8 = 1;
+const KDF_IV: u8 = 2;
+const KDF_MAC: u8 = 3;
+
+/// Contents of a PKCS#12 bundle: a private key, the certificate matching it,
+/// and the intermediate certificates of its chain.
+pub(crate) struct Pkcs12 {
+    pub(crate) key: PrivateKey,
+    pub(crate) leaf: Certificate,
+    pub(crate) intermediates: Vec<Certificate>,
+}
+
+impl Pkcs12 {
+    /// Decode a DER encoded PKCS#12 bundle, checking its integrity and
+    /// decrypting it with `password`.
+    ///
+    /// Fails unless the bundle holds exactly one private key and a
+    /// certificate for it.
+    pub(crate) fn decode(der: &[u8], password: &str) -> Result<Self> {
+        let pfx = Pfx::from_der(der).map_err(|_| Error::ParseError)?;
+
+        if pfx.version != PFX_VERSION || pfx.auth_safe.content_type != OID_DATA {
+            error!("unsupported PKCS#12 bundle (not password integrity mode)");
+            return Err(Error::ParseError);
+        }
+
+        let auth_safe = pfx.auth_safe.data()?;
+        let password = Password::new(password);
+
+        if let Some(mac_data) = &pfx.mac_data {
+            mac_data.verify(&password, auth_safe)?;
+        }
+
+        let mut keys = vec![];
+        let mut certificates = vec![];
+
+        for content in Vec::<ContentInfo<'_>>::from_der(auth_safe).map_err(|_| Error::ParseError)? {
+            let safe_contents = match content.content_type {
+                OID_DATA => Zeroizing::new(content.data()?.to_vec()),
+                OID_ENCRYPTED_DATA => content.decrypt(&password)?,
+                oid => {
+                    error!("unsupported PKCS#12 content type: {}", oid);
+                    return Err(Error::AlgorithmError);
+                }
+            };
+
+            let bags =
+                Vec::<SafeBag<'_>>::from_der(&safe_contents).map_err(|_| Error::ParseError)?;
+
+            for bag in bags {
+                match bag.bag_id {
+                    OID_KEY_BAG => {
+                        let der = Zeroizing::new(bag.bag_value.to_der()?);
+                        keys.push(PrivateKey::from_pkcs8(&der)?);
+                    }
+                    OID_SHROUDED_KEY_BAG => {
+                        let info: EncryptedPrivateKeyInfo<'_> =
+                            bag.bag_value.decode_as().map_err(|_| Error::ParseError)?;
+                        let der = decrypt(
+                            &info.encryption_algorithm,
+                            &password,
+                            info.encrypted_data.as_bytes(),
+                        )?;
+
+                        keys.push(PrivateKey::from_pkcs8(&der).map_err(|e| match e {
+                            Error::ParseError => Error::DecryptionError,
+                            e => e,
+                        })?);
+                    }
+                    OID_CERT_BAG => {
+                        let bag: CertBag<'_> =
+                            bag.bag_value.decode_as().map_err(|_| Error::ParseError)?;
+
+                        if bag.cert_id == OID_X509_CERTIFICATE {
+                            certificates
+                                .push(Certificate::from_bytes(bag.cert_value.as_bytes().to_vec())?);
+                        }
+                    }
+                    // CRL, secret and nested bags are of no use to a YubiKey
+                    _ => (),
+                }
+            }
+        }
+
+        let key = match <[PrivateKey; 1]>::try_from(keys) {
+            Ok([key]) => key,
+            Err(keys) => {
+                error!(
+                    "PKCS#12 bundle holds {} private keys (expected 1)",
+                    keys.len()
+                );
+                return Err(Error::KeyError);
+            }
+        };
+
+        let public = key.public_key()?;
+        let leaf = certificates
+            .iter()
+            .position(|cert| {
+                let spki = cert.subject_pki();
+                spki.algorithm.oid == public.algorithm.oid
+                    && spki.subject_public_key.raw_bytes() == public.subject_public_key.raw_bytes()
+            })
+            .map(|index| certificates.swap_remove(index))
+            .ok_or_else(|| {
+                error!("PKCS#12 bundle holds no certificate for its private key");
+                Error::KeyError
+            })?;
+
+        // Follow the chain from the leaf, up to (but excluding) the root
+        let mut intermediates: Vec<Certificate> = vec![];
+        loop {
+            let issuer = intermediates
+                .last()
+                .unwrap_or(&leaf)
+                .cert
+                .tbs_certificate()
+                .issuer();
+            let Some(index) = certificates.iter().position(|cert| {
+                let tbs = cert.cert.tbs_certificate();
+                tbs.subject() == issuer && tbs.issuer() != tbs.subject()
+            }) else {
+                break;
+            };
+
+            intermediates.push(certificates.swap_remove(index));
+        }
+
+        Ok(Self {
+            key,
+            leaf,
+            intermediates,
+        })
+    }
+}
+
+/// `PFX` (RFC 7292)
+#[derive(Sequence)]
+struct Pfx<'a> {
+    version: u8,
+    auth_safe: ContentInfo<'a>,
+    #[asn1(optional = "true")]
+    mac_data: Option<MacData<'a>>,
+}
+
+/// `ContentInfo` (RFC 5652), of the `data` or `encryptedData` types
+#[derive(Sequence)]
+struct ContentInfo<'a> {
+    content_type: ObjectIdentifier,
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT", optional = "true")]
+    content: Option<AnyRef<'a>>,
+}
+
+impl<'a> ContentInfo<'a> {
+    /// Content of `data`.
+    fn data(&self) -> Result<&'a [u8]> {
+        self.content
+            .ok_or(Error::ParseError)?
+            .decode_as::<&OctetStringRef>()
+            .map(OctetStringRef::as_bytes)
+            .map_err(|_| Error::ParseError)
+    }
+
+    /// Decrypted content of `encryptedData`.
+    fn decrypt(&self, password: &Password) -> Result<Buffer> {
+        let encrypted: EncryptedData<'_> = self
+            .content
+            .ok_or(Error::ParseError)?
+            .decode_as()
+            .map_err(|_| Error::ParseError)?;
+
+        let info = encrypted.encrypted_content_info;
+        let ciphertext = info.encrypted_content.ok_or(Error::ParseError)?;
+        decrypt(
+            &info.content_encryption_algorithm,
+            password,
+            ciphertext.as_bytes(),
+        )
+    }
+}
+
+/// `EncryptedData` (RFC 5652)
+#[derive(Sequence)]
+struct EncryptedData<'a> {
+    version: u8,
+    encrypted_content_info: EncryptedContentInfo<'a>,
+}
+
+/// `EncryptedContentInfo` (RFC 5652)
+#[derive(Sequence)]
+struct EncryptedContentInfo<'a> {
+    content_type: ObjectIdentifier,
+    content_encryption_algorithm: AlgorithmIdentifierRef<'a>,
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT", optional = "true")]
+    encrypted_content: Option<&'a OctetStringRef>,
+}
+
+/// `SafeBag` (RFC 7292)
+#[derive(Sequence)]
+struct SafeBag<'a> {
+    bag_id: ObjectIdentifier,
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT")]
+    bag_value: AnyRef<'a>,
+    #[asn1(optional = "true")]
+    bag_attributes: Option<AnyRef<'a>>,
+}
+
+/// `CertBag` (RFC 7292)
+#[derive(Sequence)]
+struct CertBag<'a> {
+    cert_id: ObjectIdentifier,
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT")]
+    cert_value: &'a OctetStringRef,
+}
+
+/// `MacData` (RFC 7292)
+#[derive(Sequence)]
+struct MacData<'a> {
+    mac: DigestInfo<'a>,
+    mac_salt: &'a OctetStringRef,
+    #[asn1(default = "default_iterations")]
+    iterations: u32,
+}
+
+impl MacData<'_> {
+    /// Check the MAC of the authenticated safe.
+    fn verify(&self, password: &Password, content: &[u8]) -> Result<()> {
+        let (salt, rounds) = (self.mac_salt.as_bytes(), self.iterations);
+        let expected = self.mac.digest.as_bytes();
+
+        match self.mac.digest_algorithm.oid {
+            rfc5912::ID_SHA_1 => verify_mac::<Sha1>(password, salt, rounds, content, expected),
+            rfc5912::ID_SHA_224 => verify_mac::<Sha224>(password, salt, rounds, content, expected),
+            rfc5912::ID_SHA_256 => verify_mac::<Sha256>(password, salt, rounds, content, expected),
+            rfc5912::ID_SHA_384 => verify_mac::<Sha384>(password, salt, rounds, content, expected),
+            rfc5912::ID_SHA_512 => verify_mac::<Sha512>(password, salt, rounds, content, expected),
+            OID_PBMAC1 => {
+                error!("unsupported PKCS#12 MAC: PBMAC1");
+                Err(Error::AlgorithmError)
+            }
+            oid => {
+                error!("unsupported PKCS#12 MAC digest: {}", oid);
+                Err(Error::AlgorithmError)
+            }
+        }
+    }
+}
+
+fn default_iterations() -> u32 {
+    1
+}
+
+/// `DigestInfo` (RFC 8017)
+#[derive(Sequence)]
+struct DigestInfo<'a> {
+    digest_algorithm: AlgorithmIdentifierRef<'a>,
+    digest: &'a OctetStringRef,
+}
+
+/// `pkcs-12PbeParams` (RFC 7292)
+#[derive(Sequence)]
+struct PbeParams<'a> {
+    salt: &'a OctetStringRef,
+    iterations: u32,
+}
+
+/// Password of a PKCS#12 bundle, in both of its encodings: UTF-8 for PBES2,
+/// and a null-terminated `BMPString` for the PKCS#12 key derivation function.
+struct Password {
+    utf8: Buffer,
+    bmp: Buffer,
+}
+
+impl Password {
+    fn new(password: &str) -> Self {
+        Self {
+            utf8: Zeroizing::new(password.as_bytes().to_vec()),
+            bmp: Zeroizing::new(
+                password
+                    .encode_utf16()
+                    .chain([0])
+                    .flat_map(u16::to_be_bytes)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Decrypt a private key or safe contents encrypted with PBES2 or with
+/// `pbeWithSHAAnd3-KeyTripleDES-CBC`.
+fn decrypt(
+    algorithm: &AlgorithmIdentifierRef<'_>,
+    password: &Password,
+    ciphertext: &[u8],
+) -> Result<Buffer> {
+    if algorithm.oid != OID_PBE_SHA1_3DES {
+        return private_key::pbes2_decrypt(algorithm, &password.utf8, ciphertext);
+    }
+
+    let params: PbeParams<'_> = algorithm
+        .parameters
+        .ok_or(Error::ParseError)?
+        .decode_as()
+        .map_err(|_| Error::ParseError)?;
+
+    let (salt, rounds) = (params.salt.as_bytes(), params.iterations);
+    let key = kdf::<Sha1>(&password.bmp, salt, KDF_KEY, rounds, 24)?;
+    let iv = kdf::<Sha1>(&password.bmp, salt, KDF_IV, rounds, 8)?;
+
+    private_key::cbc_decrypt::<des::TdesEde3>(&key, &iv, ciphertext)
+}
+
+/// Check an HMAC keyed with the PKCS#12 key derivation function.
+fn verify_mac<D: Digest + BlockSizeUser + Clone>(
+    password: &Password,
+    salt: &[u8],
+    rounds: u32,
+    content: &[u8],
+    expected: &[u8],
+) -> Result<()> {
+    let key = kdf::<D>(
+        &password.bmp,
+        salt,
+        KDF_MAC,
+        rounds,
+        <D as Digest>::output_size(),
+    )?;
+    let mut mac = SimpleHmac::<D>::new_from_slice(&key).map_err(|_| Error::SizeError)?;
+    mac.update(content);
+
+    mac.verify_slice(expected).map_err(|_| {
+        error!("PKCS#12 MAC verification failed (wrong password?)");
+        Error::DecryptionError
+    })
+}
+
+/// Derive key material with the PKCS#12 key derivation function.
+///
+/// <https://www.rfc-editor.org/rfc/rfc7292#appendix-B.2>
+fn kdf<D: Digest + BlockSizeUser>(
+    password: &[u8],
+    salt: &[u8],
+    id: u8,
+    rounds: u32,
+    len: usize,
+) -> Result<Buffer> {
+    if rounds == 0 {
+        return Err(Error::ParseError);
+    }
+
+    let v = D::block_size();
+
+    // Concatenate copies of the salt and password, each to a multiple of v
+    let mut input: Buffer = Zeroizing::new(
+        (salt.iter().cycle().take(salt.len().div_ceil(v) * v))
+            .chain(password.iter().cycle().take(password.len().div_ceil(v) * v))
+            .copied()
+            .collect(),
+    );
+    let mut output = Zeroizing::new(vec![]);
+
+    loop {
+        let mut hash = D::new()
+            .chain_update(vec![id; v])
+            .chain_update(&input)
+            .finalize();
+        for _ in 1..rounds {
+            hash = D::digest(&hash);
+        }
+
+        output.extend_from_slice(&hash);
+        if output.len() >= len {
+            output.truncate(len);
+            return Ok(output);
+        }
+
+        // Add the hash (repeated to v bytes) plus one to each block of input
+        let hash: Vec<u8> = hash.iter().copied().cycle().take(v).collect();
+        for block in input.chunks_exact_mut(v) {
+            let mut carry = 1;
+            for (byte, add) in block.iter_mut().zip(&hash).rev() {
+                let sum = u16::from(*byte) + u16::from(*add) + carry;
+                *byte = sum as u8;
+                carry = sum >> 8;
+            }
+        }
+    }
+}
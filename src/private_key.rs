@@ -0,0 +1,452 @@
+//! Decoding of private keys from standard encodings, for importing them into
+//! a YubiKey (see [`piv::import_key`][`crate::piv::import_key`]).
+//!
+//! Keys are accepted as PKCS#8 ([RFC 5208]), optionally encrypted with PBES2
+//! ([RFC 8018]) using PBKDF2 and AES-CBC, or as SEC1 EC private keys
+//! ([RFC 5915]), either DER or PEM encoded.
+//!
+//! [RFC 5208]: https://www.rfc-editor.org/rfc/rfc5208
+//! [RFC 8018]: https://www.rfc-editor.org/rfc/rfc8018
+//! [RFC 5915]: https://www.rfc-editor.org/rfc/rfc5915
+
+use crate::{
+    error::{Error, Result},
+    piv::{AlgorithmId, OID_X25519},
+    Buffer,
+};
+use cipher::{BlockCipherDecrypt, KeyInit};
+use der::{
+    asn1::{BitString, ObjectIdentifier, OctetStringRef},
+    oid::db::{rfc5912, rfc8410},
+    pem, Decode, Sequence,
+};
+use log::error;
+use pbkdf2::pbkdf2_hmac;
+use pkcs8::{DecodePrivateKey, EncodePublicKey, PrivateKeyInfoRef};
+use rsa::{
+    traits::{PrivateKeyParts, PublicKeyParts},
+    BoxedUint, RsaPrivateKey, RsaPublicKey,
+};
+use sha1::Sha1;
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+use x509_cert::spki::{AlgorithmIdentifier, AlgorithmIdentifierRef, SubjectPublicKeyInfoOwned};
+use zeroize::Zeroizing;
+
+/// Public exponent of the RSA keys supported by YubiKeys
+const RSA_EXPONENT: u64 = 65537;
+
+/// PBES2 encryption scheme
+const OID_PBES2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.5.13");
+
+/// PBKDF2 key derivation function
+const OID_PBKDF2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.5.12");
+
+/// PBKDF2 pseudorandom functions
+const OID_HMAC_SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.2.7");
+const OID_HMAC_SHA224: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.2.8");
+const OID_HMAC_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.2.9");
+const OID_HMAC_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.2.10");
+const OID_HMAC_SHA512: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.2.11");
+
+/// PBES2 encryption schemes
+const OID_AES128_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.2");
+const OID_AES192_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.22");
+const OID_AES256_CBC: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.42");
+
+/// PEM label of PKCS#8 private keys
+const LABEL_PKCS8: &str = "PRIVATE KEY";
+
+/// PEM label of encrypted PKCS#8 private keys
+const LABEL_ENCRYPTED_PKCS8: &str = "ENCRYPTED PRIVATE KEY";
+
+/// PEM label of SEC1 EC private keys
+const LABEL_SEC1: &str = "EC PRIVATE KEY";
+
+/// Private key of one of the algorithms supported by YubiKeys.
+pub(crate) enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    EccP256(p256::SecretKey),
+    EccP384(p384::SecretKey),
+    Ed25519(ed25519_dalek::SigningKey),
+    X25519(Zeroizing<[u8; 32]>),
+}
+
+impl PrivateKey {
+    /// Decode a DER or PEM encoded private key, decrypting it with
+    /// `passphrase` if it is encrypted.
+    pub(crate) fn decode(input: &[u8], passphrase: Option<&[u8]>) -> Result<Self> {
+        let (label, der) = match pem_block(input)? {
+            Some((label, der)) => (Some(label), der),
+            None => (None, Zeroizing::new(input.to_vec())),
+        };
+
+        match label {
+            Some(LABEL_PKCS8) => Self::from_pkcs8(&der),
+            Some(LABEL_ENCRYPTED_PKCS8) => Self::from_encrypted_pkcs8(&der, passphrase),
+            Some(LABEL_SEC1) => Self::from_sec1(&der),
+            Some(label) => {
+                error!("unsupported PEM label: {}", label);
+                Err(Error::ParseError)
+            }
+            None => Self::from_pkcs8(&der)
+                .or_else(|_| Self::from_sec1(&der))
+                .or_else(|_| Self::from_encrypted_pkcs8(&der, passphrase)),
+        }
+    }
+
+    /// Decode a PKCS#8 `PrivateKeyInfo`.
+    pub(crate) fn from_pkcs8(der: &[u8]) -> Result<Self> {
+        let info = PrivateKeyInfoRef::from_der(der).map_err(|_| Error::ParseError)?;
+        let oid = info.algorithm.oid;
+
+        if oid == rfc5912::RSA_ENCRYPTION {
+            let key = RsaPrivateKey::from_pkcs8_der(der).map_err(|_| Error::ParseError)?;
+            Ok(Self::Rsa(Box::new(key)))
+        } else if oid == rfc5912::ID_EC_PUBLIC_KEY {
+            match info.algorithm.parameters_oid() {
+                Ok(rfc5912::SECP_256_R_1) => p256::SecretKey::from_pkcs8_der(der)
+                    .map(Self::EccP256)
+                    .map_err(|_| Error::ParseError),
+                Ok(rfc5912::SECP_384_R_1) => p384::SecretKey::from_pkcs8_der(der)
+                    .map(Self::EccP384)
+                    .map_err(|_| Error::ParseError),
+                _ => {
+                    error!("unsupported EC private key curve");
+                    Err(Error::AlgorithmError)
+                }
+            }
+        } else if oid == rfc8410::ID_ED_25519 {
+            ed25519_dalek::SigningKey::from_pkcs8_der(der)
+                .map(Self::Ed25519)
+                .map_err(|_| Error::ParseError)
+        } else if oid == OID_X25519 {
+            // CurvePrivateKey ::= OCTET STRING
+            let key = <&OctetStringRef>::from_der(info.private_key.as_bytes())
+                .map_err(|_| Error::ParseError)?;
+
+            Ok(Self::X25519(Zeroizing::new(
+                key.as_bytes().try_into().map_err(|_| Error::ParseError)?,
+            )))
+        } else {
+            error!("unsupported private key algorithm: {}", oid);
+            Err(Error::AlgorithmError)
+        }
+    }
+
+    /// Decode a PKCS#8 `EncryptedPrivateKeyInfo`.
+    fn from_encrypted_pkcs8(der: &[u8], passphrase: Option<&[u8]>) -> Result<Self> {
+        let info = EncryptedPrivateKeyInfo::from_der(der).map_err(|_| Error::ParseError)?;
+
+        let Some(passphrase) = passphrase else {
+            error!("private key is encrypted, but no passphrase was given");
+            return Err(Error::ArgumentError);
+        };
+
+        let der = pbes2_decrypt(
+            &info.encryption_algorithm,
+            passphrase,
+            info.encrypted_data.as_bytes(),
+        )?;
+
+        // With a wrong passphrase, decryption may succeed with garbage
+        Self::from_pkcs8(&der).map_err(|e| match e {
+            Error::ParseError => Error::DecryptionError,
+            e => e,
+        })
+    }
+
+    /// Decode a SEC1 `ECPrivateKey`.
+    fn from_sec1(der: &[u8]) -> Result<Self> {
+        let key = sec1::EcPrivateKey::from_der(der).map_err(|_| Error::ParseError)?;
+
+        match key.parameters.and_then(|params| params.named_curve()) {
+            Some(rfc5912::SECP_256_R_1) => p256::SecretKey::from_sec1_der(der)
+                .map(Self::EccP256)
+                .map_err(|_| Error::ParseError),
+            Some(rfc5912::SECP_384_R_1) => p384::SecretKey::from_sec1_der(der)
+                .map(Self::EccP384)
+                .map_err(|_| Error::ParseError),
+            _ => {
+                error!("unsupported or missing EC private key curve");
+                Err(Error::AlgorithmError)
+            }
+        }
+    }
+
+    /// Algorithm of the key.
+    pub(crate) fn algorithm(&self) -> Result<AlgorithmId> {
+        match self {
+            Self::Rsa(key) => {
+                if key.e() != &BoxedUint::from(RSA_EXPONENT) {
+                    error!("unsupported RSA public exponent (must be {})", RSA_EXPONENT);
+                    return Err(Error::AlgorithmError);
+                }
+
+                match key.size() * 8 {
+                    1024 => Ok(AlgorithmId::Rsa1024),
+                    2048 => Ok(AlgorithmId::Rsa2048),
+                    3072 => Ok(AlgorithmId::Rsa3072),
+                    4096 => Ok(AlgorithmId::Rsa4096),
+                    bits => {
+                        error!("unsupported RSA key size: {}", bits);
+                        Err(Error::AlgorithmError)
+                    }
+                }
+            }
+            Self::EccP256(_) => Ok(AlgorithmId::EccP256),
+            Self::EccP384(_) => Ok(AlgorithmId::EccP384),
+            Self::Ed25519(_) => Ok(AlgorithmId::Ed25519),
+            Self::X25519(_) => Ok(AlgorithmId::X25519),
+        }
+    }
+
+    /// Public key of the key.
+    pub(crate) fn public_key(&self) -> Result<SubjectPublicKeyInfoOwned> {
+        let der = match self {
+            Self::Rsa(key) => RsaPublicKey::from(key.as_ref()).to_public_key_der(),
+            Self::EccP256(key) => key.public_key().to_public_key_der(),
+            Self::EccP384(key) => key.public_key().to_public_key_der(),
+            Self::Ed25519(key) => key.verifying_key().to_public_key_der(),
+            Self::X25519(key) => {
+                let public = x25519_dalek::x25519(**key, x25519_dalek::X25519_BASEPOINT_BYTES);
+
+                return Ok(SubjectPublicKeyInfoOwned {
+                    algorithm: AlgorithmIdentifier {
+                        oid: OID_X25519,
+                        parameters: None,
+                    },
+                    subject_public_key: BitString::from_bytes(&public)?,
+                });
+            }
+        }
+        .map_err(|_| Error::InvalidObject)?;
+
+        Ok(SubjectPublicKeyInfoOwned::from_der(der.as_bytes())?)
+    }
+
+    /// Parameters of the key, as sent to the YubiKey by IMPORT ASYMMETRIC
+    /// KEY: the primes and CRT coefficients (`p`, `q`, `dp`, `dq`, `qinv`) of
+    /// RSA keys, and the private scalar of other keys.
+    pub(crate) fn params(&self) -> Result<Vec<Buffer>> {
+        let param = |bytes: &[u8]| Zeroizing::new(bytes.to_vec());
+
+        match self {
+            Self::Rsa(key) => {
+                let mut key = key.clone();
+                key.precompute().map_err(|_| Error::AlgorithmError)?;
+
+                let [p, q] = key.primes() else {
+                    error!("unsupported multi-prime RSA key");
+                    return Err(Error::AlgorithmError);
+                };
+
+                let (Some(dp), Some(dq), Some(qinv)) = (key.dp(), key.dq(), key.qinv()) else {
+                    return Err(Error::AlgorithmError);
+                };
+
+                Ok(vec![
+                    param(&p.to_be_bytes()),
+                    param(&q.to_be_bytes()),
+                    param(&dp.to_be_bytes()),
+                    param(&dq.to_be_bytes()),
+                    param(&qinv.retrieve().to_be_bytes()),
+                ])
+            }
+            Self::EccP256(key) => Ok(vec![param(&key.to_bytes())]),
+            Self::EccP384(key) => Ok(vec![param(&key.to_bytes())]),
+            Self::Ed25519(key) => Ok(vec![param(key.as_bytes())]),
+            Self::X25519(key) => Ok(vec![param(key.as_slice())]),
+        }
+    }
+}
+
+/// `EncryptedPrivateKeyInfo` (RFC 5208)
+#[derive(Sequence)]
+pub(crate) struct EncryptedPrivateKeyInfo<'a> {
+    pub(crate) encryption_algorithm: AlgorithmIdentifierRef<'a>,
+    pub(crate) encrypted_data: &'a OctetStringRef,
+}
+
+/// `PBES2-params` (RFC 8018)
+#[derive(Sequence)]
+struct Pbes2Params<'a> {
+    key_derivation_func: AlgorithmIdentifierRef<'a>,
+    encryption_scheme: AlgorithmIdentifierRef<'a>,
+}
+
+/// `PBKDF2-params` (RFC 8018), with a specified salt
+#[derive(Sequence)]
+struct Pbkdf2Params<'a> {
+    salt: &'a OctetStringRef,
+    iteration_count: u32,
+    #[asn1(optional = "true")]
+    key_length: Option<u32>,
+    #[asn1(optional = "true")]
+    prf: Option<AlgorithmIdentifierRef<'a>>,
+}
+
+/// Decrypt data encrypted with PBES2, using PBKDF2 and AES-CBC.
+///
+/// <https://www.rfc-editor.org/rfc/rfc8018#section-6.2.2>
+pub(crate) fn pbes2_decrypt(
+    algorithm: &AlgorithmIdentifierRef<'_>,
+    passphrase: &[u8],
+    ciphertext: &[u8],
+) -> Result<Buffer> {
+    if algorithm.oid != OID_PBES2 {
+        error!("unsupported private key encryption: {}", algorithm.oid);
+        return Err(Error::AlgorithmError);
+    }
+
+    let params: Pbes2Params<'_> = algorithm
+        .parameters
+        .ok_or(Error::ParseError)?
+        .decode_as()
+        .map_err(|_| Error::ParseError)?;
+
+    let kdf = params.key_derivation_func;
+    if kdf.oid != OID_PBKDF2 {
+        error!("unsupported PBES2 key derivation function: {}", kdf.oid);
+        return Err(Error::AlgorithmError);
+    }
+
+    let kdf: Pbkdf2Params<'_> = kdf
+        .parameters
+        .ok_or(Error::ParseError)?
+        .decode_as()
+        .map_err(|_| Error::ParseError)?;
+
+    let scheme = params.encryption_scheme;
+    let key_len = match scheme.oid {
+        OID_AES128_CBC => 16,
+        OID_AES192_CBC => 24,
+        OID_AES256_CBC => 32,
+        oid => {
+            error!("unsupported PBES2 encryption scheme: {}", oid);
+            return Err(Error::AlgorithmError);
+        }
+    };
+
+    if kdf.key_length.is_some_and(|len| len as usize != key_len) {
+        return Err(Error::ParseError);
+    }
+
+    let iv = scheme
+        .parameters
+        .ok_or(Error::ParseError)?
+        .decode_as::<&OctetStringRef>()
+        .map_err(|_| Error::ParseError)?;
+
+    let mut key = Zeroizing::new(vec![0u8; key_len]);
+    let (salt, rounds) = (kdf.salt.as_bytes(), kdf.iteration_count);
+
+    match kdf.prf.map_or(OID_HMAC_SHA1, |prf| prf.oid) {
+        OID_HMAC_SHA1 => pbkdf2_hmac::<Sha1>(passphrase, salt, rounds, &mut key),
+        OID_HMAC_SHA224 => pbkdf2_hmac::<Sha224>(passphrase, salt, rounds, &mut key),
+        OID_HMAC_SHA256 => pbkdf2_hmac::<Sha256>(passphrase, salt, rounds, &mut key),
+        OID_HMAC_SHA384 => pbkdf2_hmac::<Sha384>(passphrase, salt, rounds, &mut key),
+        OID_HMAC_SHA512 => pbkdf2_hmac::<Sha512>(passphrase, salt, rounds, &mut key),
+        oid => {
+            error!("unsupported PBKDF2 pseudorandom function: {}", oid);
+            return Err(Error::AlgorithmError);
+        }
+    }
+
+    match key_len {
+        16 => cbc_decrypt::<aes::Aes128>(&key, iv.as_bytes(), ciphertext),
+        24 => cbc_decrypt::<aes::Aes192>(&key, iv.as_bytes(), ciphertext),
+        _ => cbc_decrypt::<aes::Aes256>(&key, iv.as_bytes(), ciphertext),
+    }
+}
+
+/// Decrypt data encrypted with a block cipher in CBC mode, and remove its
+/// PKCS#7 padding.
+pub(crate) fn cbc_decrypt<C: BlockCipherDecrypt + KeyInit>(
+    key: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+) -> Result<Buffer> {
+    let block_len = C::block_size();
+
+    if iv.len() != block_len || ciphertext.is_empty() || ciphertext.len() % block_len != 0 {
+        return Err(Error::ParseError);
+    }
+
+    let cipher = C::new_from_slice(key).map_err(|_| Error::SizeError)?;
+    let mut plaintext = Zeroizing::new(ciphertext.to_vec());
+    let mut previous = iv;
+
+    for (block, encrypted) in plaintext
+        .chunks_exact_mut(block_len)
+        .zip(ciphertext.chunks_exact(block_len))
+    {
+        cipher.decrypt_block(block.try_into().map_err(|_| Error::SizeError)?);
+
+        for (byte, chained) in block.iter_mut().zip(previous) {
+            *byte ^= chained;
+        }
+
+        previous = encrypted;
+    }
+
+    let pad = usize::from(plaintext[plaintext.len() - 1]);
+    let unpadded_len = plaintext
+        .len()
+        .checked_sub(pad)
+        .ok_or(Error::DecryptionError)?;
+
+    if pad == 0
+        || pad > block_len
+        || plaintext[unpadded_len..]
+            .iter()
+            .any(|&b| usize::from(b) != pad)
+    {
+        return Err(Error::DecryptionError);
+    }
+
+    plaintext.truncate(unpadded_len);
+    Ok(plaintext)
+}
+
+/// Decode the first supported PEM block of `input`, if it is PEM encoded.
+///
+/// Other blocks are skipped, e.g. the `EC PARAMETERS` block OpenSSL writes
+/// before SEC1 keys.
+fn pem_block(input: &[u8]) -> Result<Option<(&'static str, Buffer)>> {
+    const BEGIN: &str = "-----BEGIN ";
+
+    let Ok(text) = std::str::from_utf8(input) else {
+        return Ok(None);
+    };
+
+    if !text.trim_start().starts_with(BEGIN) {
+        return Ok(None);
+    }
+
+    for (start, _) in text.match_indices(BEGIN) {
+        let block = &text[start..];
+        let label = match block[BEGIN.len()..].split_once("-----") {
+            Some((label, _)) => label,
+            None => break,
+        };
+
+        let label = match label {
+            LABEL_PKCS8 => LABEL_PKCS8,
+            LABEL_ENCRYPTED_PKCS8 => LABEL_ENCRYPTED_PKCS8,
+            LABEL_SEC1 => LABEL_SEC1,
+            _ => continue,
+        };
+
+        let end = format!("-----END {label}-----");
+        let block = block
+            .find(&end)
+            .map(|end_start| &block[..end_start + end.len()])
+            .ok_or(Error::ParseError)?;
+
+        let (_, der) = pem::decode_vec(block.as_bytes()).map_err(|_| Error::ParseError)?;
+        return Ok(Some((label, Zeroizing::new(der))));
+    }
+
+    error!("no supported private key found in PEM input");
+    Err(Error::ParseError)
+}
@@ -1,16 +1,88 @@
-//! Support for enumerating available PC/SC card readers.
+//! Support for enumerating available PC/SC card readers, and watching for
+//! readers and cards being attached or removed.
 
-use crate::{Error, Result, YubiKey};
+use crate::{
+    atr,
+    transaction::Transaction,
+    transport::{PcscTransport, Protocols, Scope, ShareMode, Transport},
+    Error, Result, Serial, Version, YubiKey,
+};
+use log::{debug, error};
 use std::{
     borrow::Cow,
-    ffi::CStr,
+    collections::{BTreeSet, VecDeque},
+    ffi::{CStr, CString},
     fmt,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 /// Iterator over connected readers
 pub type Iter<'ctx> = std::vec::IntoIter<Reader<'ctx>>;
 
+/// Iterator over the devices in connected readers, returned by
+/// [`Context::probe`].
+pub struct Probe<'ctx> {
+    readers: Iter<'ctx>,
+}
+
+impl Iterator for Probe<'_> {
+    type Item = Result<DeviceSummary>;
+
+    fn next(&mut self) -> Option<Result<DeviceSummary>> {
+        for reader in &mut self.readers {
+            match reader.probe() {
+                Err(Error::PcscError {
+                    inner: Some(pcsc::Error::NoSmartcard | pcsc::Error::RemovedCard),
+                }) => debug!("no card in reader: {}", reader.name()),
+                result => return Some(result),
+            }
+        }
+
+        None
+    }
+}
+
+impl fmt::Debug for Probe<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Probe").finish_non_exhaustive()
+    }
+}
+
+/// Interface a device is connected through.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Interface {
+    /// USB (CCID)
+    Usb,
+
+    /// NFC, using a contactless reader
+    Nfc,
+
+    /// Couldn't be determined
+    Unknown,
+}
+
+/// Summary of the device in a reader, found without opening a session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct DeviceSummary {
+    /// Name of the reader
+    pub reader: String,
+
+    /// Serial number, if the PIV application answered
+    pub serial: Option<Serial>,
+
+    /// PIV application version, if the PIV application answered
+    pub version: Option<Version>,
+
+    /// Did the PIV application answer when selected?
+    pub piv: bool,
+
+    /// Interface the device is (most likely) connected through
+    pub interface: Interface,
+}
+
 /// PC/SC reader context: used to enumerate available PC/SC [`Reader`]s.
 pub struct Context {
     /// PC/SC context
@@ -30,7 +102,12 @@ impl Context {
     /// Open a PC/SC context, which can be used to enumerate available PC/SC
     /// readers (which can be used to connect to YubiKeys).
     pub fn open() -> Result<Self> {
-        let ctx = pcsc::Context::establish(pcsc::Scope::System)?;
+        Self::open_with_scope(Scope::System)
+    }
+
+    /// Open a PC/SC context with the given scope.
+    pub fn open_with_scope(scope: Scope) -> Result<Self> {
+        let ctx = pcsc::Context::establish(scope)?;
         let reader_names = vec![0u8; ctx.list_readers_len()?];
         Ok(Self {
             ctx: Arc::new(Mutex::new(ctx)),
@@ -60,6 +137,19 @@ impl Context {
 
         Ok(readers.into_iter())
     }
+
+    /// Probe the devices in the available readers, skipping empty readers.
+    ///
+    /// Each device is briefly connected to, and disconnected from with
+    /// [`pcsc::Disposition::LeaveCard`], so the card isn't reset. The PIV
+    /// application is still selected to read its version and serial number,
+    /// so the previously selected application and PIV's verification state
+    /// (e.g. a verified PIN) are lost.
+    pub fn probe(&mut self) -> Result<Probe<'_>> {
+        Ok(Probe {
+            readers: self.iter()?,
+        })
+    }
 }
 
 /// An individual connected PC/SC card reader.
@@ -74,7 +164,6 @@ pub struct Reader<'ctx> {
 impl<'ctx> Reader<'ctx> {
     /// Create a new reader from its name and context.
     fn new(name: &'ctx CStr, ctx: Arc<Mutex<pcsc::Context>>) -> Self {
-        // TODO(tarcieri): open devices, determine they're YubiKeys, get serial?
         Self { name, ctx }
     }
 
@@ -89,10 +178,418 @@ impl<'ctx> Reader<'ctx> {
         self.try_into()
     }
 
+    /// Connect to this reader, returning a [`PcscTransport`].
+    ///
+    /// The transport can be wrapped (e.g. by a
+    /// [`Recorder`][`crate::transcript::Recorder`]) before opening a session
+    /// with [`YubiKey::open_transport`].
+    pub fn transport(&self) -> Result<PcscTransport> {
+        self.connect().map(PcscTransport::new)
+    }
+
+    /// Probe the device in this reader, without opening a session with it.
+    ///
+    /// See [`Context::probe`].
+    pub fn probe(&self) -> Result<DeviceSummary> {
+        self.probe_with(ShareMode::Shared, Protocols::T1)
+    }
+
+    /// Probe the device in this reader, connecting to it with the given share
+    /// mode and protocols.
+    pub(crate) fn probe_with(
+        &self,
+        share_mode: ShareMode,
+        protocols: Protocols,
+    ) -> Result<DeviceSummary> {
+        summarize(
+            self.name().into_owned(),
+            self.connect_with(share_mode, protocols)?,
+        )
+    }
+
     /// Connect to this reader, returning its `pcsc::Card`.
     pub(crate) fn connect(&self) -> Result<pcsc::Card> {
+        self.connect_with(ShareMode::Shared, Protocols::T1)
+    }
+
+    /// Connect to this reader with the given share mode and protocols.
+    pub(crate) fn connect_with(
+        &self,
+        share_mode: ShareMode,
+        protocols: Protocols,
+    ) -> Result<pcsc::Card> {
         // TODO(tarcieri): better error?
         let ctx = self.ctx.lock().map_err(|_| Error::GenericError)?;
-        Ok(ctx.connect(self.name, pcsc::ShareMode::Shared, pcsc::Protocols::T1)?)
+        Ok(ctx.connect(self.name, share_mode, protocols)?)
+    }
+}
+
+/// Interval at which the list of readers is polled when the PC/SC
+/// implementation doesn't support reader hotplug notifications.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reader or card event reported by a [`Watcher`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A reader was attached.
+    ReaderAttached {
+        /// Name of the reader
+        reader: String,
+    },
+
+    /// A reader was detached.
+    ReaderDetached {
+        /// Name of the reader
+        reader: String,
+    },
+
+    /// A card was inserted into a reader.
+    CardInserted {
+        /// Name of the reader
+        reader: String,
+
+        /// Serial number, if the card is a YubiKey
+        serial: Option<Serial>,
+
+        /// PIV application version, if the card is a YubiKey
+        version: Option<Version>,
+    },
+
+    /// A card was removed from a reader.
+    CardRemoved {
+        /// Name of the reader
+        reader: String,
+    },
+}
+
+impl Event {
+    /// Get the name of the reader this event is about.
+    pub fn reader(&self) -> &str {
+        match self {
+            Event::ReaderAttached { reader }
+            | Event::ReaderDetached { reader }
+            | Event::CardInserted { reader, .. }
+            | Event::CardRemoved { reader } => reader,
+        }
+    }
+}
+
+/// Watches for readers being attached or detached, and cards being inserted
+/// or removed, using PC/SC status change notifications.
+///
+/// The readers and cards which are present when the watcher is created are
+/// reported as attached and inserted by the first events. When a card is
+/// inserted, it is briefly connected to (leaving its state untouched) to
+/// find out whether it's a YubiKey.
+///
+/// ```no_run
+/// use yubikey::reader::{Event, Watcher};
+///
+/// for event in Watcher::new()? {
+///     if let Event::CardInserted { serial: Some(serial), .. } = event? {
+///         println!("YubiKey {} inserted", serial);
+///     }
+/// }
+/// # Ok::<(), yubikey::Error>(())
+/// ```
+pub struct Watcher {
+    /// PC/SC context, separate from any [`Context`] so that waiting for
+    /// events doesn't block it
+    ctx: pcsc::Context,
+
+    /// States of the watched readers, and of the hotplug notification
+    /// pseudo-reader (if supported) as the first entry
+    states: Vec<pcsc::ReaderState>,
+
+    /// Readers with a card present
+    cards: BTreeSet<CString>,
+
+    /// Does the PC/SC implementation support hotplug notifications?
+    hotplug: bool,
+
+    /// Events not yet returned
+    pending: VecDeque<Event>,
+}
+
+impl Watcher {
+    /// Start watching the readers on this system.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            ctx: pcsc::Context::establish(Scope::System)?,
+            states: vec![pcsc::ReaderState::new(
+                pcsc::PNP_NOTIFICATION(),
+                pcsc::State::UNAWARE,
+            )],
+            cards: BTreeSet::new(),
+            hotplug: true,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Returns `None` if no event occurred before `timeout` elapsed, or
+    /// waits indefinitely if `timeout` is `None`.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+
+            self.update_readers()?;
+
+            if !self.pending.is_empty() {
+                continue;
+            }
+
+            let mut wait =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            if !self.hotplug {
+                wait = Some(wait.map_or(POLL_INTERVAL, |wait| wait.min(POLL_INTERVAL)));
+            }
+
+            for state in &mut self.states {
+                state.sync_current_state();
+            }
+
+            match self.ctx.get_status_change(wait, &mut self.states) {
+                Ok(()) => self.update_cards(),
+                Err(pcsc::Error::Timeout) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Ok(None);
+                    }
+                }
+                Err(e) => {
+                    error!("error waiting for reader status change: {}", e);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Update the watched readers from the current list of readers.
+    fn update_readers(&mut self) -> Result<()> {
+        let readers = match self.ctx.list_readers_owned() {
+            Ok(readers) => readers,
+            Err(pcsc::Error::NoReadersAvailable) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        let hotplug = self.hotplug;
+        let mut detached = vec![];
+
+        self.states.retain(|state| {
+            let name = state.name();
+
+            if hotplug && name == pcsc::PNP_NOTIFICATION() {
+                return true;
+            }
+
+            let present = readers.iter().any(|reader| reader.as_c_str() == name);
+
+            if !present {
+                detached.push(name.to_owned());
+            }
+
+            present
+        });
+
+        for reader in detached {
+            if self.cards.remove(&reader) {
+                self.pending.push_back(Event::CardRemoved {
+                    reader: reader.to_string_lossy().into_owned(),
+                });
+            }
+
+            self.pending.push_back(Event::ReaderDetached {
+                reader: reader.to_string_lossy().into_owned(),
+            });
+        }
+
+        for reader in readers {
+            if !self
+                .states
+                .iter()
+                .any(|state| state.name() == reader.as_c_str())
+            {
+                self.pending.push_back(Event::ReaderAttached {
+                    reader: reader.to_string_lossy().into_owned(),
+                });
+                self.states
+                    .push(pcsc::ReaderState::new(reader, pcsc::State::UNAWARE));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Report cards inserted or removed since the last status change.
+    fn update_cards(&mut self) {
+        let mut inserted = vec![];
+
+        for state in &self.states {
+            let name = state.name();
+            let event_state = state.event_state();
+
+            if name == pcsc::PNP_NOTIFICATION() {
+                if event_state.contains(pcsc::State::UNKNOWN) {
+                    debug!("reader hotplug notifications not supported; polling");
+                    self.hotplug = false;
+                }
+
+                continue;
+            }
+
+            let present = card_present(event_state);
+
+            if present && !self.cards.contains(name) {
+                inserted.push(name.to_owned());
+            } else if !present && self.cards.remove(name) {
+                self.pending.push_back(Event::CardRemoved {
+                    reader: name.to_string_lossy().into_owned(),
+                });
+            }
+        }
+
+        if !self.hotplug {
+            self.states
+                .retain(|state| state.name() != pcsc::PNP_NOTIFICATION());
+        }
+
+        for reader in inserted {
+            let (serial, version) = self.identify(&reader).unzip();
+
+            self.pending.push_back(Event::CardInserted {
+                reader: reader.to_string_lossy().into_owned(),
+                serial,
+                version,
+            });
+            self.cards.insert(reader);
+        }
+    }
+
+    /// Get the serial number and version of the YubiKey in the given reader,
+    /// if it holds one.
+    fn identify(&self, reader: &CStr) -> Option<(Serial, Version)> {
+        let card = self
+            .ctx
+            .connect(reader, ShareMode::Shared, Protocols::T1)
+            .inspect_err(|e| debug!("could not connect to {:?}: {}", reader, e))
+            .ok()?;
+
+        let summary = summarize(reader.to_string_lossy().into_owned(), card).ok()?;
+        summary.serial.zip(summary.version)
+    }
+}
+
+impl fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watcher").finish_non_exhaustive()
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<Event>;
+
+    /// Wait indefinitely for the next event.
+    fn next(&mut self) -> Option<Result<Event>> {
+        self.wait(None).transpose()
+    }
+}
+
+/// Summarize the device in the given (connected) card, disconnecting without
+/// resetting it.
+fn summarize(reader: String, card: pcsc::Card) -> Result<DeviceSummary> {
+    let mut transport = PcscTransport::new(card);
+    let atr = transport.atr().unwrap_or_default();
+
+    let (serial, version, piv) = {
+        let txn = Transaction::new(&mut transport)?;
+
+        if txn.select_piv_application().is_ok() {
+            let version = txn.get_version().ok();
+            let serial = version.and_then(|version| txn.get_serial(version).ok());
+            (serial, version, true)
+        } else {
+            (None, None, false)
+        }
+    };
+
+    if let Err((_, e)) = Box::new(transport).disconnect(pcsc::Disposition::LeaveCard) {
+        error!("failed to disconnect gracefully from card: {}", e);
+    }
+
+    Ok(DeviceSummary {
+        interface: guess_interface(&reader, &atr),
+        reader,
+        serial,
+        version,
+        piv,
+    })
+}
+
+/// Guess the interface of a device from its reader's name and ATR.
+fn guess_interface(reader: &str, atr: &[u8]) -> Interface {
+    // The CCID interface of a YubiKey is a reader of its own
+    if reader.to_lowercase().contains("yubikey") {
+        Interface::Usb
+    } else if atr::is_contactless(atr) {
+        Interface::Nfc
+    } else {
+        Interface::Unknown
+    }
+}
+
+/// Is there a (usable) card in a reader with the given state?
+fn card_present(state: pcsc::State) -> bool {
+    state.contains(pcsc::State::PRESENT)
+        && !state.intersects(pcsc::State::UNKNOWN | pcsc::State::IGNORE | pcsc::State::UNAVAILABLE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{card_present, guess_interface, Event, Interface};
+    use pcsc::State;
+
+    #[test]
+    fn card_presence() {
+        assert!(card_present(State::PRESENT | State::CHANGED));
+        assert!(card_present(State::PRESENT | State::INUSE | State::MUTE));
+        assert!(!card_present(State::EMPTY | State::CHANGED));
+        assert!(!card_present(State::PRESENT | State::UNAVAILABLE));
+        assert!(!card_present(State::UNKNOWN | State::IGNORE));
+    }
+
+    #[test]
+    fn interface() {
+        let usb = [0x3b, 0xfd, 0x13, 0x00, 0x00, 0x81, 0x31, 0xfe, 0x15];
+        let nfc = [0x3b, 0x8d, 0x80, 0x01, 0x80, 0x73, 0xc0, 0x21, 0xc0];
+
+        assert_eq!(
+            guess_interface("Yubico YubiKey OTP+FIDO+CCID 00 00", &usb),
+            Interface::Usb
+        );
+        assert_eq!(
+            guess_interface("ACS ACR1252 Dual Reader PICC 00 00", &nfc),
+            Interface::Nfc
+        );
+        assert_eq!(
+            guess_interface("Generic Smart Card Reader 00 00", &usb),
+            Interface::Unknown
+        );
+    }
+
+    #[test]
+    fn event_reader() {
+        let event = Event::CardInserted {
+            reader: "Yubico YubiKey OTP+FIDO+CCID".into(),
+            serial: None,
+            version: None,
+        };
+        assert_eq!(event.reader(), "Yubico YubiKey OTP+FIDO+CCID");
     }
 }
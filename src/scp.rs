@@ -0,0 +1,1019 @@
+//! Global Platform secure channels (SCP03 and SCP11b).
+//!
+//! YubiKeys with firmware 5.7 and newer can protect the traffic of a PIV
+//! session with a secure channel established with the card's Security
+//! Domain. Once it is open, every command is encrypted (C-ENC) and
+//! authenticated (C-MAC), and every response is authenticated (R-MAC) and,
+//! if it carries data, encrypted (R-ENC). PINs, management keys and imported
+//! private keys are then protected from anything on the path to the card,
+//! e.g. an NFC reader or other applications using a shared reader.
+//!
+//! Two ways of establishing a secure channel are supported:
+//!
+//! - **SCP03**: the host and the card authenticate each other with static
+//!   AES-128 keys they share. YubiKeys ship with well-known default keys
+//!   (see [`ScpKeyParams::scp03_default`]), which should be replaced to get
+//!   any protection from them.
+//! - **SCP11b**: the card authenticates with an ECDH key pair, whose
+//!   certificate must chain to a certificate trusted by the host (e.g. the
+//!   CA which issued it). No shared secret is needed, but only the card is
+//!   authenticated. The chain is read from the card's Security Domain, and
+//!   verified, once when the secure channel is first opened.
+//!
+//! ```no_run
+//! use yubikey::{scp::ScpKeyParams, YubiKey};
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! yubikey.open_secure_channel(ScpKeyParams::scp03_default())?;
+//!
+//! // The PIN is only ever sent encrypted
+//! yubikey.verify_pin(b"123456")?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! The card closes the secure channel when it is reset, or when an
+//! application is selected. It is opened again right after selecting an
+//! application, and whenever the session is restored (see
+//! [`YubiKey::reconnect`][`crate::YubiKey::reconnect`]).
+//!
+//! Once a secure channel is open, commands are never sent in clear. If a
+//! response fails to authenticate, the card closes the secure channel and the
+//! operation fails with [`Error::SecureChannelClosed`], along with the rest
+//! of its transaction; the channel is opened again for the next operation.
+
+use crate::{
+    apdu::{Apdu, Ins, Response, StatusWords, HEADER_LEN},
+    consts::CB_BUF_MAX,
+    transaction::Transaction,
+    Error, Result,
+};
+use aes::Aes128;
+use cipher::{
+    common::getrandom::SysRng, common::Generate, BlockCipherDecrypt, BlockCipherEncrypt, KeyInit,
+};
+use der::{Decode, Encode, Reader, SliceReader};
+use log::error;
+use rand_core::TryRng;
+use sha2::{Digest, Sha256, Sha384};
+use signature::hazmat::PrehashVerifier;
+use std::{cell::RefCell, fmt};
+use subtle::ConstantTimeEq;
+use x509_cert::{spki::ObjectIdentifier, Certificate};
+use zeroize::Zeroizing;
+
+/// Length of AES blocks and of the (AES-128) secure channel keys.
+const BLOCK_LEN: usize = 16;
+
+/// Length of the (truncated) MACs appended to commands and responses.
+const MAC_LEN: usize = 8;
+
+/// Maximum number of bytes wrapping adds to a command or response.
+pub(crate) const OVERHEAD: usize = BLOCK_LEN + MAC_LEN;
+
+/// Security Domain application name.
+const APPLET_NAME: &str = "Security Domain";
+
+/// Security Domain application identifier.
+pub(crate) const APPLET_ID: &[u8] = &[0xa0, 0x00, 0x00, 0x01, 0x51, 0x00, 0x00, 0x00];
+
+/// Key version of the factory default SCP03 keys.
+pub(crate) const KVN_SCP03_DEFAULT: u8 = 0xff;
+
+/// Key version of the factory SCP11b key.
+#[cfg(feature = "emulator")]
+pub(crate) const KVN_SCP11B_DEFAULT: u8 = 0x01;
+
+/// Key identifier of SCP11b keys.
+pub(crate) const KID_SCP11B: u8 = 0x13;
+
+/// Factory default SCP03 keys.
+const DEFAULT_KEY: [u8; BLOCK_LEN] = [
+    0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x4b, 0x4c, 0x4d, 0x4e, 0x4f,
+];
+
+/// Security level of the channel: C-MAC, C-DECRYPTION, R-MAC and R-ENCRYPTION.
+pub(crate) const SECURITY_LEVEL: u8 = 0x33;
+
+/// SCP identifier and parameter (`i`) of SCP11b.
+const SCP11B_PARAMS: [u8; 2] = [0x11, 0x00];
+
+/// Usage of the SCP11 session keys: C-MAC, C-ENC, R-MAC and R-ENC.
+const KEY_USAGE: u8 = 0x3c;
+
+/// Type of the SCP11 session keys: AES.
+const KEY_TYPE_AES: u8 = 0x88;
+
+/// Control reference template of SCP11 key agreement.
+pub(crate) const TAG_CRT: u8 = 0xa6;
+
+/// Ephemeral public key (two byte tag).
+pub(crate) const TAG_EPK: [u8; 2] = [0x5f, 0x49];
+
+/// Receipt of SCP11 key agreement.
+pub(crate) const TAG_RECEIPT: u8 = 0x86;
+
+/// Key reference.
+const TAG_KEY_REF: u8 = 0x83;
+
+/// Certificate store of the Security Domain (P1/P2 of GET DATA).
+pub(crate) const CERTIFICATE_STORE: [u8; 2] = [0xbf, 0x21];
+
+/// Length of an uncompressed P-256 point.
+pub(crate) const P256_POINT_LEN: usize = 65;
+
+// Derivation constants of the SCP03 key derivation function
+const DERIVE_CARD_CRYPTOGRAM: u8 = 0x00;
+const DERIVE_HOST_CRYPTOGRAM: u8 = 0x01;
+const DERIVE_S_ENC: u8 = 0x04;
+const DERIVE_S_MAC: u8 = 0x06;
+const DERIVE_S_RMAC: u8 = 0x07;
+
+const OID_ECDSA_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const OID_ECDSA_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+/// AES-128 key.
+type Key = Zeroizing<[u8; BLOCK_LEN]>;
+
+/// Static AES-128 keys shared with the card, used to open SCP03 secure
+/// channels.
+#[derive(Clone)]
+pub struct StaticKeys {
+    enc: Key,
+    mac: Key,
+}
+
+impl StaticKeys {
+    /// Create static keys from the card's encryption (`K-ENC`) and MAC
+    /// (`K-MAC`) keys.
+    ///
+    /// The data encryption key (`K-DEK`) isn't needed to open a secure
+    /// channel.
+    pub fn new(enc: [u8; BLOCK_LEN], mac: [u8; BLOCK_LEN]) -> Self {
+        Self {
+            enc: Zeroizing::new(enc),
+            mac: Zeroizing::new(mac),
+        }
+    }
+}
+
+impl Default for StaticKeys {
+    /// The factory default keys (`404142...4f`).
+    fn default() -> Self {
+        Self::new(DEFAULT_KEY, DEFAULT_KEY)
+    }
+}
+
+impl fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticKeys").finish_non_exhaustive()
+    }
+}
+
+/// Parameters for opening a secure channel with the card.
+#[derive(Clone)]
+pub struct ScpKeyParams {
+    /// Version of the card's key(s) to use
+    kvn: u8,
+
+    kind: Kind,
+}
+
+#[derive(Clone)]
+enum Kind {
+    Scp03(StaticKeys),
+    Scp11b(Box<Certificate>),
+}
+
+impl ScpKeyParams {
+    /// SCP03, using the static keys with the given key version.
+    pub fn scp03(kvn: u8, keys: StaticKeys) -> Self {
+        Self {
+            kvn,
+            kind: Kind::Scp03(keys),
+        }
+    }
+
+    /// SCP03, using the factory default static keys (key version `0xff`).
+    ///
+    /// As these keys are public, they only protect against passive
+    /// eavesdropping.
+    pub fn scp03_default() -> Self {
+        Self::scp03(KVN_SCP03_DEFAULT, StaticKeys::default())
+    }
+
+    /// SCP11b, using the card's key with the given key version (`0x01` for
+    /// the key the YubiKey is shipped with).
+    ///
+    /// The card's certificate chain for the key must include `ca`, or be
+    /// issued by it.
+    pub fn scp11b(kvn: u8, ca: Certificate) -> Self {
+        Self {
+            kvn,
+            kind: Kind::Scp11b(Box::new(ca)),
+        }
+    }
+}
+
+impl fmt::Debug for ScpKeyParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.kind {
+            Kind::Scp03(_) => "SCP03",
+            Kind::Scp11b(_) => "SCP11b",
+        };
+
+        f.debug_struct("ScpKeyParams")
+            .field("protocol", &protocol)
+            .field("kvn", &self.kvn)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Secure channel of a [`YubiKey`][`crate::YubiKey`] session: the parameters
+/// to open it with, and its state while it is open.
+pub(crate) struct SecureChannel {
+    pub(crate) params: ScpKeyParams,
+
+    /// The card's (verified) SCP11b public key
+    card_key: Option<p256::PublicKey>,
+
+    pub(crate) session: RefCell<Option<Session>>,
+}
+
+impl SecureChannel {
+    /// Prepare a secure channel with the card, over a transaction with the
+    /// PIV application selected.
+    ///
+    /// For SCP11b, the certificate chain of the card's key is read from the
+    /// Security Domain (which is selected for that, before selecting the PIV
+    /// application again) and verified once, so that the channel can be
+    /// opened again with any application selected.
+    pub(crate) fn new(txn: &Transaction<'_>, params: ScpKeyParams) -> Result<Self> {
+        let card_key = match &params.kind {
+            Kind::Scp03(_) => None,
+            Kind::Scp11b(ca) => {
+                txn.select_application(APPLET_ID, APPLET_NAME, "failed selecting Security Domain")?;
+                let certificates = read_certificates(txn, params.kvn);
+                txn.select_piv_application()?;
+
+                Some(verify_chain(ca, &certificates?)?)
+            }
+        };
+
+        Ok(Self {
+            params,
+            card_key,
+            session: RefCell::new(None),
+        })
+    }
+}
+
+/// Session keys of a secure channel.
+pub(crate) struct SessionKeys {
+    enc: Key,
+    mac: Key,
+    rmac: Key,
+}
+
+impl SessionKeys {
+    /// Derive SCP03 session keys from the static keys, for the context
+    /// (host challenge followed by card challenge) of INITIALIZE UPDATE.
+    pub(crate) fn scp03(keys: &StaticKeys, context: &[u8]) -> Self {
+        Self {
+            enc: derive(&keys.enc, DERIVE_S_ENC, context),
+            mac: derive(&keys.mac, DERIVE_S_MAC, context),
+            rmac: derive(&keys.mac, DERIVE_S_RMAC, context),
+        }
+    }
+
+    /// Derive SCP11 session keys from the shared secrets of ephemeral/ephemeral
+    /// and ephemeral/static key agreement, returning them along with the key
+    /// used to compute the receipt.
+    pub(crate) fn scp11(shsee: &[u8], shses: &[u8]) -> (Key, Self) {
+        let mut z = Zeroizing::new(shsee.to_vec());
+        z.extend_from_slice(shses);
+
+        let shared_info = [KEY_USAGE, KEY_TYPE_AES, BLOCK_LEN as u8];
+        let keys = x963_kdf(&z, &shared_info, 4 * BLOCK_LEN);
+        let key = |i: usize| {
+            let mut key = Key::default();
+            key.copy_from_slice(&keys[i * BLOCK_LEN..(i + 1) * BLOCK_LEN]);
+            key
+        };
+
+        // The fifth key (the data encryption key) isn't used
+        let receipt_key = key(0);
+        let keys = Self {
+            enc: key(1),
+            mac: key(2),
+            rmac: key(3),
+        };
+
+        (receipt_key, keys)
+    }
+
+    /// SCP03 card cryptogram.
+    pub(crate) fn card_cryptogram(&self, context: &[u8]) -> [u8; MAC_LEN] {
+        truncate(&*derive(&self.mac, DERIVE_CARD_CRYPTOGRAM, context))
+    }
+
+    /// SCP03 host cryptogram.
+    pub(crate) fn host_cryptogram(&self, context: &[u8]) -> [u8; MAC_LEN] {
+        truncate(&*derive(&self.mac, DERIVE_HOST_CRYPTOGRAM, context))
+    }
+}
+
+/// State of an open secure channel.
+///
+/// Both ends of the channel keep the same state: this is used by
+/// [`Transaction`] on the host side, and by the
+/// [emulator][`crate::emulator`] on the card side.
+pub(crate) struct Session {
+    keys: SessionKeys,
+
+    /// MAC chaining value: the full C-MAC of the last command
+    mac_chain: [u8; BLOCK_LEN],
+
+    /// Encryption counter of the next command
+    counter: u32,
+}
+
+impl Session {
+    /// Start a session with the given keys and initial MAC chaining value.
+    pub(crate) fn new(keys: SessionKeys, mac_chain: [u8; BLOCK_LEN]) -> Self {
+        Self {
+            keys,
+            mac_chain,
+            counter: 1,
+        }
+    }
+
+    /// Open a secure channel over a transaction (which must not be using
+    /// one already).
+    pub(crate) fn open(txn: &Transaction<'_>, channel: &SecureChannel) -> Result<Self> {
+        let kvn = channel.params.kvn;
+
+        match (&channel.params.kind, &channel.card_key) {
+            (Kind::Scp03(keys), _) => scp03(txn, kvn, keys),
+            (Kind::Scp11b(_), Some(pk_sd)) => scp11b(txn, kvn, pk_sd),
+            (Kind::Scp11b(_), None) => Err(Error::GenericError),
+        }
+    }
+
+    /// Wrap a serialized command: encrypt its data, and append a C-MAC.
+    pub(crate) fn wrap(&mut self, command: &[u8]) -> Result<Vec<u8>> {
+        let (header, data, extended) = split_command(command).ok_or(Error::SizeError)?;
+
+        let data = self.encrypt(data, self.counter, false);
+        self.counter = self.counter.wrapping_add(1);
+
+        Ok(self.mac_command(header, &data, extended))
+    }
+
+    /// Append a C-MAC to a command, updating the MAC chaining value.
+    pub(crate) fn mac_command(
+        &mut self,
+        mut header: [u8; 4],
+        data: &[u8],
+        extended: bool,
+    ) -> Vec<u8> {
+        header[0] |= 0x04;
+
+        let mut command = serialize_command(header, data, MAC_LEN, extended);
+        self.mac_chain = cmac(&self.keys.mac, &[&self.mac_chain[..], &command].concat());
+        command.extend_from_slice(&self.mac_chain[..MAC_LEN]);
+
+        if extended || data.len() + MAC_LEN > 0xff {
+            command.extend_from_slice(&[0, 0]);
+        }
+
+        command
+    }
+
+    /// Verify the R-MAC of a response (including status words) and decrypt
+    /// its data.
+    pub(crate) fn unwrap(&self, response: &[u8]) -> Result<Vec<u8>> {
+        let (body, sw) = response
+            .split_at_checked(response.len().wrapping_sub(2))
+            .ok_or(Error::SizeError)?;
+
+        // Error responses aren't authenticated
+        if body.is_empty() && sw != [0x90, 0x00] {
+            return Ok(sw.to_vec());
+        }
+
+        let (data, mac) = body
+            .split_at_checked(body.len().wrapping_sub(MAC_LEN))
+            .ok_or_else(|| {
+                error!("secure channel response is missing its R-MAC");
+                Error::AuthenticationError
+            })?;
+
+        if !bool::from(self.response_mac(data, sw)[..].ct_eq(mac)) {
+            error!("secure channel response R-MAC mismatch");
+            return Err(Error::AuthenticationError);
+        }
+
+        let mut response = self
+            .decrypt(data, self.counter.wrapping_sub(1), true)
+            .ok_or_else(|| {
+                error!("malformed secure channel response data");
+                Error::AuthenticationError
+            })?;
+
+        response.extend_from_slice(sw);
+        Ok(std::mem::take(&mut response))
+    }
+
+    /// Verify the C-MAC of a command, updating the MAC chaining value, and
+    /// return its data without the MAC (card side).
+    #[cfg(feature = "emulator")]
+    pub(crate) fn check_mac<'a>(
+        &mut self,
+        header: [u8; 4],
+        data: &'a [u8],
+        extended: bool,
+    ) -> Option<&'a [u8]> {
+        let (data, mac) = data.split_at_checked(data.len().checked_sub(MAC_LEN)?)?;
+
+        let command = serialize_command(header, data, MAC_LEN, extended);
+        let mac_chain = cmac(&self.keys.mac, &[&self.mac_chain[..], &command].concat());
+
+        if !bool::from(mac_chain[..MAC_LEN].ct_eq(mac)) {
+            return None;
+        }
+
+        self.mac_chain = mac_chain;
+        Some(data)
+    }
+
+    /// Verify and decrypt a wrapped command (card side), returning it
+    /// serialized in the same (short or extended length) format.
+    #[cfg(feature = "emulator")]
+    pub(crate) fn unwrap_command(&mut self, command: &[u8]) -> Option<Vec<u8>> {
+        let (mut header, data, extended) = split_command(command)?;
+        let data = self.check_mac(header, data, extended)?;
+
+        let counter = self.counter;
+        self.counter = self.counter.wrapping_add(1);
+        let data = self.decrypt(data, counter, false)?;
+
+        header[0] &= !0x04;
+        Some(serialize_command(header, &data, 0, extended))
+    }
+
+    /// Encrypt the data of a successful response, and append an R-MAC (card
+    /// side).
+    #[cfg(feature = "emulator")]
+    pub(crate) fn wrap_response(&self, data: &[u8]) -> Vec<u8> {
+        let mut response = self
+            .encrypt(data, self.counter.wrapping_sub(1), true)
+            .to_vec();
+        let mac = self.response_mac(&response, &StatusWords::Success.code().to_be_bytes());
+        response.extend_from_slice(&mac);
+        response
+    }
+
+    /// Compute the R-MAC of response data.
+    fn response_mac(&self, data: &[u8], sw: &[u8]) -> [u8; MAC_LEN] {
+        truncate(&cmac(
+            &self.keys.rmac,
+            &[&self.mac_chain[..], data, sw].concat(),
+        ))
+    }
+
+    /// Initial chaining value of the command or response with the given
+    /// encryption counter.
+    fn icv(&self, counter: u32, response: bool) -> aes::Block {
+        let mut block = aes::Block::default();
+        block[BLOCK_LEN - 4..].copy_from_slice(&counter.to_be_bytes());
+
+        if response {
+            block[0] = 0x80;
+        }
+
+        Aes128::new(&(*self.keys.enc).into()).encrypt_block(&mut block);
+        block
+    }
+
+    /// Pad and encrypt data (AES-CBC). Empty data isn't encrypted.
+    fn encrypt(&self, data: &[u8], counter: u32, response: bool) -> Zeroizing<Vec<u8>> {
+        if data.is_empty() {
+            return Zeroizing::new(vec![]);
+        }
+
+        let mut buffer = Zeroizing::new(data.to_vec());
+        buffer.push(0x80);
+        let len = buffer.len().next_multiple_of(BLOCK_LEN);
+        buffer.resize(len, 0);
+
+        let cipher = Aes128::new(&(*self.keys.enc).into());
+        let mut chain = self.icv(counter, response);
+
+        for block in buffer.chunks_exact_mut(BLOCK_LEN) {
+            for (b, c) in block.iter_mut().zip(chain.iter()) {
+                *b ^= c;
+            }
+
+            let mut encrypted = aes::Block::try_from(&*block).expect("block size");
+            cipher.encrypt_block(&mut encrypted);
+            block.copy_from_slice(&encrypted);
+            chain = encrypted;
+        }
+
+        buffer
+    }
+
+    /// Decrypt and unpad data (AES-CBC).
+    fn decrypt(&self, data: &[u8], counter: u32, response: bool) -> Option<Zeroizing<Vec<u8>>> {
+        if data.is_empty() {
+            return Some(Zeroizing::new(vec![]));
+        }
+
+        if data.len() % BLOCK_LEN != 0 {
+            return None;
+        }
+
+        let mut buffer = Zeroizing::new(data.to_vec());
+        let cipher = Aes128::new(&(*self.keys.enc).into());
+        let mut chain = self.icv(counter, response);
+
+        for block in buffer.chunks_exact_mut(BLOCK_LEN) {
+            let ciphertext = aes::Block::try_from(&*block).ok()?;
+            let mut decrypted = ciphertext;
+            cipher.decrypt_block(&mut decrypted);
+            block.copy_from_slice(&decrypted);
+
+            for (b, c) in block.iter_mut().zip(chain.iter()) {
+                *b ^= c;
+            }
+
+            chain = ciphertext;
+        }
+
+        let padding = buffer.iter().rposition(|&b| b != 0)?;
+        if buffer[padding] != 0x80 {
+            return None;
+        }
+
+        buffer.truncate(padding);
+        Some(buffer)
+    }
+}
+
+/// Get the largest amount of command data that still fits in `max` bytes
+/// once wrapped.
+pub(crate) fn max_data(max: usize) -> usize {
+    (max - MAC_LEN) / BLOCK_LEN * BLOCK_LEN - 1
+}
+
+/// Open an SCP03 secure channel.
+fn scp03(txn: &Transaction<'_>, kvn: u8, keys: &StaticKeys) -> Result<Session> {
+    let mut host_challenge = [0u8; MAC_LEN];
+    SysRng
+        .try_fill_bytes(&mut host_challenge)
+        .map_err(|_| Error::GenericError)?;
+
+    let response = Apdu::new(Ins::InitializeUpdate)
+        .cla(0x80)
+        .p1(kvn)
+        .data(host_challenge)
+        .transmit(txn, 0xff)?;
+
+    if !response.is_success() {
+        error!("INITIALIZE UPDATE failed: {:04x}", response.code());
+        return Err(key_error(response.status_words()));
+    }
+
+    // Key diversification data (10 bytes), key information (3 bytes), card
+    // challenge and card cryptogram (8 bytes each), and an optional sequence
+    // counter
+    let (card_challenge, card_cryptogram) = match response.data() {
+        data if data.len() >= 29 && data[11] == 0x03 => (&data[13..21], truncate(&data[21..29])),
+        _ => {
+            error!("unexpected INITIALIZE UPDATE response");
+            return Err(Error::ParseError);
+        }
+    };
+
+    let context = [&host_challenge[..], card_challenge].concat();
+    let keys = SessionKeys::scp03(keys, &context);
+
+    if !bool::from(keys.card_cryptogram(&context).ct_eq(&card_cryptogram)) {
+        error!("SCP03 card cryptogram mismatch (wrong keys?)");
+        return Err(Error::AuthenticationError);
+    }
+
+    let host_cryptogram = keys.host_cryptogram(&context);
+    let mut session = Session::new(keys, [0; BLOCK_LEN]);
+
+    let command = session.mac_command(
+        [0x80, Ins::ExternalAuthenticate.code(), SECURITY_LEVEL, 0],
+        &host_cryptogram,
+        false,
+    );
+    let response = Response::from(txn.transmit(&command, 0xff)?);
+
+    if !response.is_success() {
+        error!("EXTERNAL AUTHENTICATE failed: {:04x}", response.code());
+        return Err(Error::AuthenticationError);
+    }
+
+    Ok(session)
+}
+
+/// Open an SCP11b secure channel with the card's key `pk_sd`.
+fn scp11b(txn: &Transaction<'_>, kvn: u8, pk_sd: &p256::PublicKey) -> Result<Session> {
+    let esk_oce =
+        p256::SecretKey::try_generate_from_rng(&mut SysRng).map_err(|_| Error::GenericError)?;
+    let epk_oce = esk_oce.public_key().to_sec1_bytes();
+
+    let mut data = vec![
+        TAG_CRT,
+        13,
+        0x90,
+        2,
+        SCP11B_PARAMS[0],
+        SCP11B_PARAMS[1],
+        0x95,
+        1,
+        KEY_USAGE,
+        0x80,
+        1,
+        KEY_TYPE_AES,
+        0x81,
+        1,
+        BLOCK_LEN as u8,
+    ];
+    data.extend_from_slice(&TAG_EPK);
+    data.push(P256_POINT_LEN as u8);
+    data.extend_from_slice(&epk_oce);
+
+    let response = Apdu::new(Ins::InternalAuthenticate)
+        .cla(0x80)
+        .params(kvn, KID_SCP11B)
+        .data(&data)
+        .transmit(txn, 0xff)?;
+
+    if !response.is_success() {
+        error!("INTERNAL AUTHENTICATE failed: {:04x}", response.code());
+        return Err(key_error(response.status_words()));
+    }
+
+    // The card's ephemeral public key, followed by the receipt
+    let (epk_sd_tlv, receipt) = response
+        .data()
+        .split_at_checked(3 + P256_POINT_LEN)
+        .filter(|(epk, receipt)| {
+            epk.starts_with(&TAG_EPK)
+                && epk[2] == P256_POINT_LEN as u8
+                && receipt.len() == 2 + BLOCK_LEN
+                && receipt[..2] == [TAG_RECEIPT, BLOCK_LEN as u8]
+        })
+        .ok_or_else(|| {
+            error!("unexpected INTERNAL AUTHENTICATE response");
+            Error::ParseError
+        })?;
+
+    let epk_sd = p256::PublicKey::from_sec1_bytes(&epk_sd_tlv[3..]).map_err(|_| {
+        error!("invalid ephemeral key from card");
+        Error::ParseError
+    })?;
+
+    let shsee =
+        elliptic_curve::ecdh::diffie_hellman(esk_oce.to_nonzero_scalar(), epk_sd.as_affine());
+    let shses =
+        elliptic_curve::ecdh::diffie_hellman(esk_oce.to_nonzero_scalar(), pk_sd.as_affine());
+    let (receipt_key, keys) =
+        SessionKeys::scp11(shsee.raw_secret_bytes(), shses.raw_secret_bytes());
+
+    let expected = cmac(&receipt_key, &[&data[..], epk_sd_tlv].concat());
+    if !bool::from(expected[..].ct_eq(&receipt[2..])) {
+        error!("SCP11b receipt mismatch");
+        return Err(Error::AuthenticationError);
+    }
+
+    Ok(Session::new(keys, expected))
+}
+
+/// Read the certificate chain of the card's SCP11b key (with the Security
+/// Domain selected).
+fn read_certificates(txn: &Transaction<'_>, kvn: u8) -> Result<Vec<Certificate>> {
+    let templ = [
+        0x80,
+        Ins::GetDataSd.code(),
+        CERTIFICATE_STORE[0],
+        CERTIFICATE_STORE[1],
+    ];
+    let key_ref = [TAG_CRT, 4, TAG_KEY_REF, 2, KID_SCP11B, kvn];
+    let response = txn.transfer_data(&templ, &key_ref, CB_BUF_MAX)?;
+
+    if !response.is_success() {
+        error!(
+            "failed reading SCP11b certificates: {:04x}",
+            response.code()
+        );
+        return Err(key_error(response.status_words()));
+    }
+
+    let mut reader = SliceReader::new(response.data())?;
+    let mut certificates = vec![];
+
+    while !reader.is_finished() {
+        certificates.push(Certificate::decode(&mut reader)?);
+    }
+
+    if certificates.is_empty() {
+        error!("no SCP11b certificates for key version {:02x}", kvn);
+        return Err(Error::NotFound);
+    }
+
+    Ok(certificates)
+}
+
+/// Check a certificate chain (ordered from the issuer to the card's key)
+/// against the trusted `ca`, returning the card's public key.
+fn verify_chain(ca: &Certificate, chain: &[Certificate]) -> Result<p256::PublicKey> {
+    let start = chain
+        .iter()
+        .position(|certificate| certificate == ca)
+        .map_or(0, |i| i + 1);
+
+    let mut issuer = ca;
+    for certificate in &chain[start..] {
+        verify_signature(issuer, certificate)?;
+        issuer = certificate;
+    }
+
+    let spki = issuer.tbs_certificate().subject_public_key_info();
+    p256::PublicKey::from_sec1_bytes(spki.subject_public_key.raw_bytes()).map_err(|_| {
+        error!("card's SCP11b key isn't a P-256 key");
+        Error::AlgorithmError
+    })
+}
+
+/// Verify that `certificate` was signed by the key of `issuer`.
+fn verify_signature(issuer: &Certificate, certificate: &Certificate) -> Result<()> {
+    let tbs = certificate.tbs_certificate().to_der()?;
+    let prehash = match certificate.signature_algorithm().oid {
+        OID_ECDSA_SHA256 => Sha256::digest(&tbs).to_vec(),
+        OID_ECDSA_SHA384 => Sha384::digest(&tbs).to_vec(),
+        oid => {
+            error!("unsupported certificate signature algorithm: {}", oid);
+            return Err(Error::AlgorithmError);
+        }
+    };
+
+    let key = issuer
+        .tbs_certificate()
+        .subject_public_key_info()
+        .subject_public_key
+        .raw_bytes();
+    let signature = certificate.signature().raw_bytes();
+
+    let verified = if let Ok(key) = p256::ecdsa::VerifyingKey::from_sec1_bytes(key) {
+        p256::ecdsa::Signature::from_der(signature)
+            .and_then(|signature| key.verify_prehash(&prehash, &signature))
+            .is_ok()
+    } else if let Ok(key) = p384::ecdsa::VerifyingKey::from_sec1_bytes(key) {
+        p384::ecdsa::Signature::from_der(signature)
+            .and_then(|signature| key.verify_prehash(&prehash, &signature))
+            .is_ok()
+    } else {
+        error!("unsupported certificate issuer key");
+        return Err(Error::AlgorithmError);
+    };
+
+    if !verified {
+        error!(
+            "SCP11b certificate for '{}' isn't trusted",
+            certificate.tbs_certificate().subject()
+        );
+        return Err(Error::AuthenticationError);
+    }
+
+    Ok(())
+}
+
+/// Map the status words of a failed key operation to an error.
+fn key_error(status_words: StatusWords) -> Error {
+    match status_words {
+        StatusWords::ReferenceDataNotFoundError | StatusWords::NotFoundError => Error::NotFound,
+        StatusWords::NotSupportedError => Error::NotSupported,
+        _ => Error::GenericError,
+    }
+}
+
+/// Split a serialized (short or extended length) command into its header,
+/// data and whether it is extended length.
+pub(crate) fn split_command(command: &[u8]) -> Option<([u8; 4], &[u8], bool)> {
+    let (header, body) = command.split_at_checked(HEADER_LEN)?;
+    let header = header.try_into().ok()?;
+
+    match body {
+        [] | [_] => Some((header, &[], false)),
+        [0, _, _] => Some((header, &[], true)),
+        [0, lc1, lc2, data @ ..] => Some((
+            header,
+            data.get(..usize::from(u16::from_be_bytes([*lc1, *lc2])))?,
+            true,
+        )),
+        [lc, data @ ..] => Some((header, data.get(..usize::from(*lc))?, false)),
+    }
+}
+
+/// Serialize the header and data of a command, with room for `extra` bytes
+/// of data to be appended (and no `Le`).
+fn serialize_command(header: [u8; 4], data: &[u8], extra: usize, extended: bool) -> Vec<u8> {
+    let lc = data.len() + extra;
+    let mut command = header.to_vec();
+
+    if extended || lc > 0xff {
+        command.push(0);
+        command.extend_from_slice(&(lc as u16).to_be_bytes());
+    } else {
+        command.push(lc as u8);
+    }
+
+    command.extend_from_slice(data);
+    command
+}
+
+/// SCP03 key derivation function (NIST SP 800-108 in counter mode, with
+/// AES-CMAC), deriving a 128-bit value.
+fn derive(key: &[u8; BLOCK_LEN], constant: u8, context: &[u8]) -> Key {
+    let bits = if constant == DERIVE_CARD_CRYPTOGRAM || constant == DERIVE_HOST_CRYPTOGRAM {
+        MAC_LEN * 8
+    } else {
+        BLOCK_LEN * 8
+    };
+
+    let mut input = Zeroizing::new(vec![0u8; 11]);
+    input.extend_from_slice(&[constant, 0x00]);
+    input.extend_from_slice(&(bits as u16).to_be_bytes());
+    input.push(0x01);
+    input.extend_from_slice(context);
+
+    Zeroizing::new(cmac(key, &input))
+}
+
+/// ANSI X9.63 key derivation function, with SHA-256.
+fn x963_kdf(z: &[u8], shared_info: &[u8], len: usize) -> Zeroizing<Vec<u8>> {
+    let mut output = Zeroizing::new(Vec::with_capacity(len));
+
+    for counter in 1u32.. {
+        if output.len() >= len {
+            break;
+        }
+
+        let digest = Sha256::new()
+            .chain_update(z)
+            .chain_update(counter.to_be_bytes())
+            .chain_update(shared_info)
+            .finalize();
+        output.extend_from_slice(&digest);
+    }
+
+    output.truncate(len);
+    output
+}
+
+/// AES-CMAC (RFC 4493).
+pub(crate) fn cmac(key: &[u8; BLOCK_LEN], data: &[u8]) -> [u8; BLOCK_LEN] {
+    let cipher = Aes128::new(key.into());
+
+    let mut l = aes::Block::default();
+    cipher.encrypt_block(&mut l);
+    let k1 = double(l.into());
+    let k2 = double(k1);
+
+    let blocks = data.len().div_ceil(BLOCK_LEN).max(1);
+    let (full, last) = data.split_at((blocks - 1) * BLOCK_LEN);
+
+    let mut last_block = [0u8; BLOCK_LEN];
+    last_block[..last.len()].copy_from_slice(last);
+
+    let subkey = if last.len() == BLOCK_LEN {
+        k1
+    } else {
+        last_block[last.len()] = 0x80;
+        k2
+    };
+
+    let mut mac = aes::Block::default();
+    for block in full.chunks_exact(BLOCK_LEN).chain([&last_block[..]]) {
+        for (m, b) in mac.iter_mut().zip(block) {
+            *m ^= b;
+        }
+
+        if block.as_ptr() == last_block.as_ptr() {
+            for (m, k) in mac.iter_mut().zip(subkey) {
+                *m ^= k;
+            }
+        }
+
+        cipher.encrypt_block(&mut mac);
+    }
+
+    mac.into()
+}
+
+/// Multiply by `x` in GF(2^128), for CMAC subkey generation.
+fn double(block: [u8; BLOCK_LEN]) -> [u8; BLOCK_LEN] {
+    let value = u128::from_be_bytes(block);
+    let doubled = (value << 1) ^ if value >> 127 == 1 { 0x87 } else { 0 };
+    doubled.to_be_bytes()
+}
+
+fn truncate(mac: &[u8]) -> [u8; MAC_LEN] {
+    let mut truncated = [0u8; MAC_LEN];
+    truncated.copy_from_slice(&mac[..MAC_LEN]);
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cmac, max_data, Session, SessionKeys, StaticKeys, OVERHEAD};
+
+    #[test]
+    fn cmac_rfc4493() {
+        let key = [
+            0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf,
+            0x4f, 0x3c,
+        ];
+        let message = [
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+            0x45, 0xaf, 0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11,
+        ];
+
+        assert_eq!(
+            cmac(&key, &[]),
+            [
+                0xbb, 0x1d, 0x69, 0x29, 0xe9, 0x59, 0x37, 0x28, 0x7f, 0xa3, 0x7d, 0x12, 0x9b, 0x75,
+                0x67, 0x46
+            ]
+        );
+        assert_eq!(
+            cmac(&key, &message[..16]),
+            [
+                0x07, 0x0a, 0x16, 0xb4, 0x6b, 0x4d, 0x41, 0x44, 0xf7, 0x9b, 0xdd, 0x9d, 0xd0, 0x4a,
+                0x28, 0x7c
+            ]
+        );
+        assert_eq!(
+            cmac(&key, &message),
+            [
+                0xdf, 0xa6, 0x67, 0x47, 0xde, 0x9a, 0xe6, 0x30, 0x30, 0xca, 0x32, 0x61, 0x14, 0x97,
+                0xc8, 0x27
+            ]
+        );
+    }
+
+    #[test]
+    fn wrap_round_trip() {
+        let session = || {
+            let context = [0x11; 16];
+            Session::new(
+                SessionKeys::scp03(&StaticKeys::default(), &context),
+                [0; 16],
+            )
+        };
+        let (mut host, mut card) = (session(), session());
+
+        for len in [0, 1, 15, 16, max_data(0xff), 0x100, 0x1000] {
+            let mut command = vec![0x00, 0xdb, 0x3f, 0xff];
+            if len > 0xff {
+                command.push(0);
+                command.extend_from_slice(&(len as u16).to_be_bytes());
+            } else {
+                command.push(len as u8);
+            }
+            command.extend(std::iter::repeat_n(0xab, len));
+
+            let wrapped = host.wrap(&command).expect("wrap failed");
+            assert_eq!(wrapped[0], 0x04);
+            assert!(wrapped.len() <= command.len() + OVERHEAD + 2);
+            assert!(len > max_data(0xff) || wrapped.len() <= 0xff + 5);
+
+            let unwrapped = card.unwrap_command(&wrapped).expect("invalid C-MAC");
+            assert_eq!(unwrapped, command);
+
+            let mut response = card.wrap_response(&[0xcd; 20]);
+            response.extend_from_slice(&[0x90, 0x00]);
+            let mut expected = vec![0xcd; 20];
+            expected.extend_from_slice(&[0x90, 0x00]);
+            assert_eq!(host.unwrap(&response).expect("invalid R-MAC"), expected);
+
+            // Tampering is detected
+            response[0] ^= 1;
+            assert!(host.unwrap(&response).is_err());
+        }
+
+        // Error responses aren't authenticated
+        assert_eq!(
+            host.unwrap(&[0x6a, 0x82]).expect("error response"),
+            [0x6a, 0x82]
+        );
+        assert!(host.unwrap(&[0x90, 0x00]).is_err());
+    }
+}
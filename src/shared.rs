@@ -0,0 +1,285 @@
+//! Thread-safe handle for sharing a YubiKey between threads.
+//!
+//! [`YubiKey`] requires `&mut self` for all card operations. [`SharedYubiKey`]
+//! wraps a single session in a cloneable, `Send + Sync` handle: operations
+//! requested from any number of threads are queued and performed one at a
+//! time, in the order they were requested.
+//!
+//! All clones share the same session, so the cached PIN (which is used to
+//! re-verify after a reconnect) and the PIN/management key authentication
+//! state of the card are kept across operations until the session is
+//! closed or deauthenticated.
+//!
+//! ```no_run
+//! use std::thread;
+//! use yubikey::{piv::{AlgorithmId, SlotId}, shared::SharedYubiKey};
+//!
+//! let yubikey = SharedYubiKey::open()?;
+//! yubikey.verify_pin(b"123456")?;
+//!
+//! let workers: Vec<_> = (0..4u8)
+//!     .map(|i| {
+//!         let yubikey = yubikey.clone();
+//!         thread::spawn(move || {
+//!             yubikey.sign_data(&[i; 32], AlgorithmId::EccP256, SlotId::Signature)
+//!         })
+//!     })
+//!     .collect();
+//!
+//! for worker in workers {
+//!     let signature = worker.join().unwrap()?;
+//! }
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+
+#[cfg(feature = "untested")]
+use crate::ObjectId;
+use crate::{
+    certificate::{CertInfo, Certificate},
+    piv::{self, AlgorithmId, SlotId, SlotMetadata},
+    policy::{PinPolicy, TouchPolicy},
+    touch::CancelHandle,
+    Buffer, MgmKey, Result, Serial, Version, YubiKey,
+};
+use std::{
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+};
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+
+/// Cloneable handle to a [`YubiKey`] session which can be shared between
+/// threads.
+#[derive(Clone)]
+pub struct SharedYubiKey {
+    inner: Arc<Inner>,
+}
+
+/// State shared by all clones of a [`SharedYubiKey`].
+struct Inner {
+    /// The session, locked only by the operation whose turn it is.
+    yubikey: Mutex<YubiKey>,
+
+    /// Queue of pending operations.
+    queue: Mutex<Queue>,
+
+    /// Signalled whenever an operation completes.
+    turn: Condvar,
+
+    name: String,
+    version: Version,
+    serial: Serial,
+    cancel: CancelHandle,
+}
+
+/// FIFO queue of operations, as a ticket lock.
+#[derive(Default)]
+struct Queue {
+    /// Ticket handed out to the next operation to be queued.
+    next: u64,
+
+    /// Ticket of the operation allowed to use the YubiKey.
+    serving: u64,
+}
+
+impl SharedYubiKey {
+    /// Share an open [`YubiKey`] session.
+    pub fn new(yubikey: YubiKey) -> Self {
+        let inner = Inner {
+            name: yubikey.name().to_owned(),
+            version: yubikey.version(),
+            serial: yubikey.serial(),
+            cancel: yubikey.cancel_handle(),
+            yubikey: Mutex::new(yubikey),
+            queue: Mutex::new(Queue::default()),
+            turn: Condvar::new(),
+        };
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Open a connection to a YubiKey (see [`YubiKey::open`]).
+    pub fn open() -> Result<Self> {
+        YubiKey::open().map(Self::new)
+    }
+
+    /// Open a YubiKey with a specific serial number (see
+    /// [`YubiKey::open_by_serial`]).
+    pub fn open_by_serial(serial: Serial) -> Result<Self> {
+        YubiKey::open_by_serial(serial).map(Self::new)
+    }
+
+    /// Get the name of the associated PC/SC card reader.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Get the YubiKey's PIV application version.
+    pub fn version(&self) -> Version {
+        self.inner.version
+    }
+
+    /// Get YubiKey device serial number.
+    pub fn serial(&self) -> Serial {
+        self.inner.serial
+    }
+
+    /// Get a handle for cancelling the touch-gated operation in progress
+    /// (see [`crate::touch`]).
+    ///
+    /// Unlike operations performed with [`SharedYubiKey::with`], this doesn't
+    /// wait for the operation to complete.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.inner.cancel.clone()
+    }
+
+    /// Perform an operation with exclusive access to the [`YubiKey`].
+    ///
+    /// Blocks until all operations queued before it have completed. This
+    /// allows using any part of the API which doesn't have a counterpart
+    /// here, or performing several steps without other threads' operations
+    /// being interleaved with them.
+    pub fn with<T>(&self, f: impl FnOnce(&mut YubiKey) -> T) -> T {
+        let _turn = self.inner.wait_turn();
+        let mut yubikey = lock(&self.inner.yubikey);
+        f(&mut yubikey)
+    }
+
+    /// Reconnect to the card (see [`YubiKey::reconnect`]).
+    pub fn reconnect(&self) -> Result<()> {
+        self.with(YubiKey::reconnect)
+    }
+
+    /// Verify device PIN (see [`YubiKey::verify_pin`]).
+    pub fn verify_pin(&self, pin: &[u8]) -> Result<()> {
+        self.with(|yubikey| yubikey.verify_pin(pin))
+    }
+
+    /// Get the number of PIN retries (see [`YubiKey::get_pin_retries`]).
+    pub fn get_pin_retries(&self) -> Result<u8> {
+        self.with(YubiKey::get_pin_retries)
+    }
+
+    /// Authenticate to the card using the provided management key (see
+    /// [`YubiKey::authenticate`]).
+    pub fn authenticate(&self, mgm_key: &MgmKey) -> Result<()> {
+        self.with(|yubikey| yubikey.authenticate(mgm_key))
+    }
+
+    /// Deauthenticate (see [`YubiKey::deauthenticate`]).
+    #[cfg(feature = "untested")]
+    pub fn deauthenticate(&self) -> Result<()> {
+        self.with(YubiKey::deauthenticate)
+    }
+
+    /// Generate a new key (see [`piv::generate`]).
+    pub fn generate(
+        &self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> Result<SubjectPublicKeyInfoOwned> {
+        self.with(|yubikey| piv::generate(yubikey, slot, algorithm, pin_policy, touch_policy))
+    }
+
+    /// Sign data using a PIV key (see [`piv::sign_data`]).
+    pub fn sign_data(&self, raw_in: &[u8], algorithm: AlgorithmId, slot: SlotId) -> Result<Buffer> {
+        self.with(|yubikey| piv::sign_data(yubikey, raw_in, algorithm, slot))
+    }
+
+    /// Decrypt data using a PIV key (see [`piv::decrypt_data`]).
+    #[cfg(feature = "untested")]
+    pub fn decrypt_data(
+        &self,
+        input: &[u8],
+        algorithm: AlgorithmId,
+        slot: SlotId,
+    ) -> Result<Buffer> {
+        self.with(|yubikey| piv::decrypt_data(yubikey, input, algorithm, slot))
+    }
+
+    /// Read metadata of a slot (see [`piv::metadata`]).
+    pub fn metadata(&self, slot: SlotId) -> Result<SlotMetadata> {
+        self.with(|yubikey| piv::metadata(yubikey, slot))
+    }
+
+    /// Read the certificate in the given slot (see [`Certificate::read`]).
+    pub fn read_certificate(&self, slot: SlotId) -> Result<Certificate> {
+        self.with(|yubikey| Certificate::read(yubikey, slot))
+    }
+
+    /// Write a certificate to the given slot (see [`Certificate::write`]).
+    pub fn write_certificate(
+        &self,
+        certificate: &Certificate,
+        slot: SlotId,
+        certinfo: CertInfo,
+    ) -> Result<()> {
+        self.with(|yubikey| certificate.write(yubikey, slot, certinfo))
+    }
+
+    /// Fetch an object (see [`YubiKey::fetch_object`]).
+    #[cfg(feature = "untested")]
+    pub fn fetch_object(&self, object_id: ObjectId) -> Result<Buffer> {
+        self.with(|yubikey| yubikey.fetch_object(object_id))
+    }
+
+    /// Save an object (see [`YubiKey::save_object`]).
+    #[cfg(feature = "untested")]
+    pub fn save_object(&self, object_id: ObjectId, indata: &mut [u8]) -> Result<()> {
+        self.with(|yubikey| yubikey.save_object(object_id, indata))
+    }
+}
+
+impl From<YubiKey> for SharedYubiKey {
+    fn from(yubikey: YubiKey) -> Self {
+        Self::new(yubikey)
+    }
+}
+
+impl fmt::Debug for SharedYubiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedYubiKey")
+            .field("name", &self.inner.name)
+            .field("version", &self.inner.version)
+            .field("serial", &self.inner.serial)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Inner {
+    /// Queue up, and wait until it is the caller's turn to use the YubiKey.
+    fn wait_turn(&self) -> Turn<'_> {
+        let mut queue = lock(&self.queue);
+        let ticket = queue.next;
+        queue.next += 1;
+
+        while queue.serving != ticket {
+            queue = self
+                .turn
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+
+        Turn { inner: self }
+    }
+}
+
+/// Exclusive turn to use the YubiKey, passed on to the next queued
+/// operation when dropped (even if the operation panicked).
+struct Turn<'a> {
+    inner: &'a Inner,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        lock(&self.inner.queue).serving += 1;
+        self.inner.turn.notify_all();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
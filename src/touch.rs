@@ -0,0 +1,344 @@
+//! Timeouts and cancellation for operations which wait for the user to touch
+//! the YubiKey.
+//!
+//! Keys generated or imported with [`TouchPolicy::Always`] or
+//! [`TouchPolicy::Cached`] (and management keys set with `require_touch`)
+//! make the card wait for a touch before answering, which blocks the calling
+//! thread inside the transport. These operations can be bounded with
+//! [`YubiKey::set_touch_timeout`], and aborted from another thread with a
+//! [`CancelHandle`]:
+//!
+//! ```no_run
+//! use std::{thread, time::Duration};
+//! use yubikey::{piv::{self, AlgorithmId, SlotId}, Error, YubiKey};
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! yubikey.set_touch_timeout(Some(Duration::from_secs(30)));
+//!
+//! // e.g. the user clicked "Cancel" in a prompt
+//! let cancel = yubikey.cancel_handle();
+//! thread::spawn(move || cancel.cancel());
+//!
+//! match piv::sign_data(&mut yubikey, &[0; 32], AlgorithmId::EccP256, SlotId::Signature) {
+//!     Err(Error::TouchNotReceived) => println!("no touch; giving up"),
+//!     result => println!("{:?}", result),
+//! }
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! Aborting an operation interrupts the transport (see
+//! [`Transport::interrupter`][`crate::transport::Transport::interrupter`]),
+//! which for PC/SC resets the card. The session is then reconnected and
+//! restored (as described in [`Recovery`][`crate::Recovery`], whether or not
+//! recovery is enabled), and the operation fails with
+//! [`Error::TouchNotReceived`][`crate::Error::TouchNotReceived`]. If the
+//! transport can't be interrupted, the operation only fails once the card
+//! answers (YubiKeys stop waiting for a touch after about 15 seconds).
+//!
+//! Operations are only watched while a timeout is set or a [`CancelHandle`]
+//! exists, and not at all for keys known (from the metadata read for the
+//! touch callback, see below) not to require touch.
+//!
+//! # Prompting for touch
+//!
+//! YubiKeys only signal that they are waiting for a touch by blinking, which
+//! is easy to miss. A callback registered with
+//! [`YubiKey::set_touch_callback`] is called with the pending [`Request`]
+//! right before the card is expected to wait, so that the user can be
+//! prompted:
+//!
+//! ```no_run
+//! use yubikey::YubiKey;
+//!
+//! let mut yubikey = YubiKey::open()?;
+//! yubikey.set_touch_callback(|request| eprintln!("Touch your YubiKey ({:?})", request));
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! Whether a touch will be needed is determined from the touch policy in the
+//! key's [metadata][`crate::piv::metadata`], so the callback is never called
+//! with firmware older than 5.3. Keys with [`TouchPolicy::Cached`] only
+//! prompt if no touch-gated operation of the session completed within the
+//! last 15 seconds.
+//!
+//! [`TouchPolicy::Always`]: crate::TouchPolicy::Always
+//! [`TouchPolicy::Cached`]: crate::TouchPolicy::Cached
+//! [`YubiKey::set_touch_timeout`]: crate::YubiKey::set_touch_timeout
+//! [`YubiKey::set_touch_callback`]: crate::YubiKey::set_touch_callback
+
+use crate::{
+    piv::{ManagementSlotId, SlotId},
+    policy::TouchPolicy,
+    transport::Interrupt,
+};
+use log::{error, info};
+use std::{
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long YubiKeys cache a touch for keys with [`TouchPolicy::Cached`]
+const TOUCH_CACHE: Duration = Duration::from_secs(15);
+
+/// Callback registered with [`YubiKey::set_touch_callback`][`crate::YubiKey::set_touch_callback`].
+pub(crate) type Callback = Box<dyn Fn(Request) + Send>;
+
+/// Operation about to wait for the user to touch the YubiKey.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Request {
+    /// Signing with the key in the given slot
+    Sign(SlotId),
+
+    /// RSA decryption with the key in the given slot
+    Decrypt(SlotId),
+
+    /// Key agreement (ECDH or X25519) with the key in the given slot
+    KeyAgreement(SlotId),
+
+    /// Authentication with the management key
+    Authenticate,
+}
+
+impl Request {
+    /// Slot of the key which requires touch.
+    pub fn slot(self) -> SlotId {
+        match self {
+            Request::Sign(slot) | Request::Decrypt(slot) | Request::KeyAgreement(slot) => slot,
+            Request::Authenticate => SlotId::Management(ManagementSlotId::Management),
+        }
+    }
+}
+
+/// Handle for aborting the touch-gated operation of a session in progress,
+/// from another thread.
+///
+/// Get one with [`YubiKey::cancel_handle`][`crate::YubiKey::cancel_handle`].
+#[derive(Clone)]
+pub struct CancelHandle {
+    shared: Arc<Shared>,
+}
+
+impl CancelHandle {
+    /// Abort the touch-gated operation in progress, if any.
+    ///
+    /// Operations started afterwards aren't affected.
+    pub fn cancel(&self) {
+        self.shared.abort("cancelled");
+    }
+
+    /// Is a touch-gated operation in progress?
+    pub fn is_pending(&self) -> bool {
+        self.shared.lock().active
+    }
+}
+
+impl fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("pending", &self.is_pending())
+            .finish()
+    }
+}
+
+/// Touch timeout, cancellation and prompting state of a session.
+pub(crate) struct Touch {
+    timeout: Option<Duration>,
+    shared: Arc<Shared>,
+    callback: Option<Callback>,
+
+    /// When an operation which required touch last completed
+    last_touch: Option<Instant>,
+}
+
+impl Touch {
+    pub(crate) fn new() -> Self {
+        Self {
+            timeout: None,
+            shared: Arc::new(Shared::default()),
+            callback: None,
+            last_touch: None,
+        }
+    }
+
+    pub(crate) fn set_callback(&mut self, callback: Option<Callback>) {
+        self.callback = callback;
+    }
+
+    pub(crate) fn has_callback(&self) -> bool {
+        self.callback.is_some()
+    }
+
+    /// Call the callback if a key with the given touch policy will wait for
+    /// a touch.
+    pub(crate) fn prompt(&self, request: Request, policy: TouchPolicy) {
+        let cached = self
+            .last_touch
+            .is_some_and(|touched| touched.elapsed() < TOUCH_CACHE);
+
+        let needed = match policy {
+            TouchPolicy::Always => true,
+            TouchPolicy::Cached => !cached,
+            _ => false,
+        };
+
+        if let Some(callback) = self.callback.as_ref().filter(|_| needed) {
+            info!("waiting for touch: {:?}", request);
+            callback(request);
+        }
+    }
+
+    /// Record that an operation with a key with the given touch policy
+    /// completed.
+    pub(crate) fn completed(&mut self, policy: TouchPolicy) {
+        if matches!(policy, TouchPolicy::Always | TouchPolicy::Cached) {
+            self.last_touch = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub(crate) fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Can operations be aborted, i.e. is there a timeout or a cancel handle?
+    pub(crate) fn is_abortable(&self) -> bool {
+        self.timeout.is_some() || Arc::strong_count(&self.shared) > 1
+    }
+
+    /// Has the operation in progress been aborted?
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.shared.lock().aborted
+    }
+
+    /// Start watching an operation, which is aborted by interrupting the
+    /// transport if it is cancelled or times out.
+    pub(crate) fn watch(&self, interrupter: Option<Arc<dyn Interrupt>>) -> Watchdog {
+        {
+            let mut state = self.shared.lock();
+            state.active = true;
+            state.aborted = false;
+            state.interrupter = interrupter;
+        }
+
+        let timer = self.timeout.and_then(|timeout| {
+            let shared = Arc::clone(&self.shared);
+
+            thread::Builder::new()
+                .name("yubikey-touch-timeout".into())
+                .spawn(move || {
+                    let state = shared.lock();
+                    let (state, result) = shared
+                        .changed
+                        .wait_timeout_while(state, timeout, |state| state.active && !state.aborted)
+                        .unwrap_or_else(PoisonError::into_inner);
+
+                    drop(state);
+                    if result.timed_out() {
+                        shared.abort("timed out");
+                    }
+                })
+                .inspect_err(|e| error!("error spawning touch timeout thread: {}", e))
+                .ok()
+        });
+
+        Watchdog {
+            shared: Arc::clone(&self.shared),
+            timer,
+        }
+    }
+}
+
+/// Watch over a touch-gated operation, started with [`Touch::watch`].
+pub(crate) struct Watchdog {
+    shared: Arc<Shared>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Stop watching the operation, returning whether it was aborted.
+    pub(crate) fn finish(mut self) -> bool {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> bool {
+        let aborted = {
+            let mut state = self.shared.lock();
+            state.active = false;
+            state.interrupter = None;
+            self.shared.changed.notify_all();
+            state.aborted
+        };
+
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+
+        aborted
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Is an operation in progress?
+    active: bool,
+
+    /// Was the operation in progress aborted?
+    aborted: bool,
+
+    /// Interrupter of the session's transport, while an operation is in
+    /// progress
+    interrupter: Option<Arc<dyn Interrupt>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Abort the operation in progress, if any.
+    fn abort(&self, reason: &str) {
+        let interrupter = {
+            let mut state = self.lock();
+
+            if !state.active || state.aborted {
+                return;
+            }
+
+            state.aborted = true;
+            self.changed.notify_all();
+            state.interrupter.clone()
+        };
+
+        info!("touch-gated operation {}; interrupting transport", reason);
+
+        if let Some(interrupter) = interrupter {
+            if let Err(e) = interrupter.interrupt() {
+                error!("error interrupting transport: {}", e);
+            }
+        }
+    }
+}
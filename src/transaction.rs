@@ -2,17 +2,20 @@
 
 use crate::{
     apdu::Response,
-    apdu::{Apdu, Ins, StatusWords},
-    consts::{CB_BUF_MAX, CB_OBJ_MAX},
+    apdu::{Apdu, Ins, StatusWords, Trace},
+    consts::{CB_BUF_MAX, CB_EXT_APDU_DATA_MAX, CB_EXT_RECV_MAX, CB_OBJ_MAX},
     error::{Error, Result},
     mgm::MgmKey,
     otp,
     piv::{self, AlgorithmId, SlotId},
+    scp,
     serialization::*,
+    transport::{Transport, TransportTransaction},
     yubikey::*,
     Buffer, ObjectId,
 };
-use log::{error, trace};
+use log::{error, info, trace};
+use std::{cell::Cell, time::Instant};
 use zeroize::Zeroizing;
 
 #[cfg(feature = "untested")]
@@ -27,38 +30,169 @@ pub(crate) enum ChangeRefAction {
     UnblockPin,
 }
 
-/// Exclusive transaction with the YubiKey's PC/SC card.
+/// Exclusive transaction with the YubiKey's card.
 pub(crate) struct Transaction<'tx> {
-    inner: pcsc::Transaction<'tx>,
+    inner: Box<dyn TransportTransaction + 'tx>,
+
+    /// Use extended length APDUs in [`Transaction::transfer_data`]
+    extended_apdus: bool,
+
+    /// Flag set when the transport reports that the card was reset or removed
+    lost: Option<&'tx Cell<bool>>,
+
+    /// Redacting trace of the exchanged APDUs
+    trace: Trace,
+
+    /// Secure channel wrapping the exchanged APDUs, if the session uses one
+    secure_channel: Option<&'tx scp::SecureChannel>,
+
+    /// Set while the secure channel is being opened, the only time commands
+    /// are sent in clear once it is configured
+    opening: Cell<bool>,
+
+    /// Set when a response couldn't be unwrapped, after which no more
+    /// commands are sent
+    aborted: Cell<bool>,
 }
 
 impl<'tx> Transaction<'tx> {
-    /// Create a new transaction with the given card.
-    pub fn new(card: &'tx mut pcsc::Card) -> Result<Self> {
+    /// Create a new transaction with the given transport.
+    pub fn new(transport: &'tx mut dyn Transport) -> Result<Self> {
         Ok(Transaction {
-            inner: card.transaction()?,
+            inner: transport.begin_transaction()?,
+            extended_apdus: false,
+            lost: None,
+            trace: Trace::default(),
+            secure_channel: None,
+            opening: Cell::new(false),
+            aborted: Cell::new(false),
         })
     }
 
+    /// Use extended length APDUs (instead of command chaining and GET
+    /// RESPONSE) to transfer large amounts of data.
+    pub fn extended_apdus(mut self, enabled: bool) -> Self {
+        self.extended_apdus = enabled;
+        self
+    }
+
+    /// Set the given flag if the transport reports that the session was lost
+    /// (i.e. the card was reset or removed) during this transaction.
+    pub fn track_session(mut self, lost: &'tx Cell<bool>) -> Self {
+        self.lost = Some(lost);
+        self
+    }
+
+    /// Wrap commands and unwrap responses with the given secure channel.
+    ///
+    /// Once it is set, commands are never sent in clear: they fail with
+    /// [`Error::SecureChannelClosed`] while the channel isn't open.
+    pub fn secure_channel(mut self, channel: &'tx scp::SecureChannel) -> Self {
+        self.secure_channel = Some(channel);
+        self
+    }
+
+    /// Open the secure channel set with [`Transaction::secure_channel`] (see
+    /// [`scp`]), which wraps all subsequent APDUs of the session.
+    ///
+    /// The session state is kept in the [`scp::SecureChannel`], so that it
+    /// outlives this transaction.
+    pub fn open_secure_channel(&self) -> Result<()> {
+        let channel = self.secure_channel.ok_or(Error::GenericError)?;
+        channel.session.replace(None);
+
+        self.opening.set(true);
+        let session = scp::Session::open(self, channel);
+        self.opening.set(false);
+
+        channel.session.replace(Some(session?));
+        Ok(())
+    }
+
+    /// Is a secure channel open in this transaction?
+    fn is_secure(&self) -> bool {
+        self.secure_channel
+            .is_some_and(|channel| channel.session.borrow().is_some())
+    }
+
     /// Transmit a single serialized APDU to the card this transaction is open
     /// with and receive a response.
     ///
-    /// This is a wrapper for the raw `SCardTransmit` function and operates on
-    /// single APDU messages at a time. For larger messages that need to be
-    /// split into multiple APDUs, use the [`Transaction::transfer_data`]
-    /// method instead.
+    /// This is a wrapper for the transport's raw transmit function (e.g.
+    /// `SCardTransmit`) and operates on single APDU messages at a time. For
+    /// larger messages that need to be split into multiple APDUs, use the
+    /// [`Transaction::transfer_data`] method instead.
+    ///
+    /// Both the command and the response are traced, with any secrets
+    /// redacted (see [`Trace`]). If a secure channel is used, they are traced
+    /// before being wrapped and after being unwrapped respectively.
     pub fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>> {
-        trace!(">>> {:?}", send_buffer);
+        self.trace.command(send_buffer);
 
-        let mut recv_buffer = vec![0u8; recv_len];
+        let start = Instant::now();
+        let response = if self.aborted.get() {
+            error!("transaction aborted after a secure channel failure");
+            Err(Error::SecureChannelClosed)
+        } else {
+            match self.secure_channel.filter(|_| !self.opening.get()) {
+                Some(channel) => self.transmit_secure(channel, send_buffer, recv_len),
+                None => self.inner.transmit(send_buffer, recv_len),
+            }
+        };
+        self.trace.response(&response, start.elapsed());
 
-        let len = self
-            .inner
-            .transmit(send_buffer, recv_buffer.as_mut())?
-            .len();
+        response.inspect_err(|e| {
+            if let Some(lost) = self.lost.filter(|_| e.is_session_lost()) {
+                lost.set(true);
+            }
+        })
+    }
+
+    /// Wrap a command with the secure channel, transmit it, and unwrap the
+    /// response (collecting all of it with GET RESPONSE first).
+    ///
+    /// Commands aren't sent if the secure channel is closed. If the response
+    /// can't be unwrapped, the secure channel is closed (as the card closes
+    /// its end of it in that case) and the transaction is aborted.
+    fn transmit_secure(
+        &self,
+        channel: &scp::SecureChannel,
+        send_buffer: &[u8],
+        recv_len: usize,
+    ) -> Result<Vec<u8>> {
+        let command = match channel.session.borrow_mut().as_mut() {
+            Some(session) => session.wrap(send_buffer)?,
+            None => {
+                error!("secure channel is closed; not sending command in clear");
+                return Err(Error::SecureChannelClosed);
+            }
+        };
+
+        let recv_len = recv_len + scp::OVERHEAD;
+        let mut response = self.inner.transmit(&command, recv_len)?;
+
+        while let [.., 0x61, _] = *response {
+            response.truncate(response.len() - 2);
+
+            let get_response = Apdu::new(Ins::GetResponseApdu)
+                .extended(self.extended_apdus)
+                .to_bytes();
+            let more = self.inner.transmit(&get_response, recv_len)?;
+            response.extend_from_slice(&more);
+        }
+
+        let unwrapped = match channel.session.borrow().as_ref() {
+            Some(session) => session.unwrap(&response),
+            None => Err(Error::SecureChannelClosed),
+        };
+
+        if unwrapped.is_err() {
+            error!("closing secure channel and aborting transaction");
+            channel.session.replace(None);
+            self.aborted.set(true);
+        }
 
-        recv_buffer.truncate(len);
-        Ok(recv_buffer)
+        unwrapped
     }
 
     /// Select PIV application.
@@ -77,11 +211,23 @@ impl<'tx> Transaction<'tx> {
         applet_name: &'static str,
         error: &'static str,
     ) -> Result<()> {
+        // Selecting an application closes the secure channel, so SELECT is
+        // sent in clear and the channel is opened again right after it
+        if let Some(channel) = self.secure_channel {
+            if channel.session.replace(None).is_some() {
+                info!("closing secure channel to select {}", applet_name);
+            }
+        }
+
+        self.opening.set(true);
         let response = Apdu::new(Ins::SelectApplication)
             .p1(0x04)
             .data(applet)
-            .transmit(self, 0xFF)
-            .inspect_err(|e| error!("failed communicating with card: '{}'", e))?;
+            .transmit(self, 0xFF);
+        self.opening.set(false);
+
+        let response =
+            response.inspect_err(|e| error!("failed communicating with card: '{}'", e))?;
 
         if !response.is_success() {
             error!("{}: {:04x}", error, response.status_words().code());
@@ -91,6 +237,11 @@ impl<'tx> Transaction<'tx> {
             });
         }
 
+        if self.secure_channel.is_some() {
+            info!("re-opening secure channel");
+            self.open_secure_channel()?;
+        }
+
         Ok(())
     }
 
@@ -160,9 +311,9 @@ impl<'tx> Transaction<'tx> {
 
     /// Read metadata
     pub(crate) fn get_metadata(&self, slot: SlotId) -> Result<piv::SlotMetadata> {
-        let response = Apdu::new(Ins::GetMetadata)
-            .p2(slot.into())
-            .transmit(self, CB_OBJ_MAX)?;
+        // RSA public keys don't fit in a single short response
+        let templ = [0, Ins::GetMetadata.code(), 0, slot.into()];
+        let response = self.transfer_data(&templ, &[], CB_OBJ_MAX)?;
 
         match response.status_words() {
             StatusWords::Success => {
@@ -398,15 +549,30 @@ impl<'tx> Transaction<'tx> {
     /// messages into smaller APDU-sized messages (using the provided APDU
     /// template to construct them), and then sending those via
     /// [`Transaction::transmit`].
+    ///
+    /// When extended length APDUs are enabled, messages are only split if
+    /// they exceed the maximum extended APDU size, and responses are received
+    /// in one go (GET RESPONSE is still used if the card asks for it).
     pub fn transfer_data(&self, templ: &[u8], in_data: &[u8], max_out: usize) -> Result<Response> {
+        let (mut max_size, recv_len) = if self.extended_apdus {
+            (CB_EXT_APDU_DATA_MAX, CB_EXT_RECV_MAX)
+        } else {
+            (0xff, 261)
+        };
+
+        // Leave room for the padding and MAC of the secure channel
+        if self.is_secure() {
+            max_size = scp::max_data(max_size);
+        }
+
         let mut in_offset = 0;
         let mut out_data = vec![];
         let mut sw;
 
         loop {
-            let mut this_size = 0xff;
+            let mut this_size = max_size;
 
-            let cla = if in_offset + 0xff < in_data.len() {
+            let cla = if in_offset + max_size < in_data.len() {
                 0x10
             } else {
                 this_size = in_data.len() - in_offset;
@@ -418,8 +584,9 @@ impl<'tx> Transaction<'tx> {
             let response = Apdu::new(templ[1])
                 .cla(cla)
                 .params(templ[2], templ[3])
+                .extended(self.extended_apdus)
                 .data(&in_data[in_offset..(in_offset + this_size)])
-                .transmit(self, 261)?;
+                .transmit(self, recv_len)?;
 
             sw = response.status_words();
 
@@ -450,7 +617,9 @@ impl<'tx> Transaction<'tx> {
         while let StatusWords::BytesRemaining { len } = sw {
             trace!("The card indicates there is {} bytes more data for us", len);
 
-            let response = Apdu::new(Ins::GetResponseApdu).transmit(self, 261)?;
+            let response = Apdu::new(Ins::GetResponseApdu)
+                .extended(self.extended_apdus)
+                .transmit(self, recv_len)?;
             sw = response.status_words();
 
             match sw {
@@ -0,0 +1,593 @@
+//! APDU transcripts: recording exchanges with a card and replaying them.
+//!
+//! A [`Recorder`] wraps another [`Transport`] and logs every command/response
+//! pair it carries, and [`Replay`] is a [`Transport`] which serves a recorded
+//! [`Transcript`] back. Together they allow capturing a session with a real
+//! device once and turning it into a hardware-free regression test:
+//!
+//! ```no_run
+//! use yubikey::{reader::Context, transcript::Recorder, YubiKey};
+//!
+//! let mut readers = Context::open()?;
+//! let reader = readers.iter()?.next().expect("no reader");
+//! let recorder = Recorder::create(reader.transport()?, "session.apdu")?;
+//! let mut yubikey = YubiKey::open_transport(recorder, &reader.name())?;
+//! # Ok::<(), yubikey::Error>(())
+//! ```
+//!
+//! Transcripts are stored as text, one line per APDU: commands are prefixed
+//! with `>` and responses with `<`, both hex encoded. The card's ATR (which
+//! determines e.g. whether extended length APDUs are used) is recorded on a
+//! line prefixed with `@`. Lines starting with `#` are comments.
+//!
+//! Secret-bearing data is never written to a transcript (the same data is
+//! redacted from the APDU trace):
+//!
+//! - The data of commands which carry PINs, PUKs, management keys or private
+//!   keys (VERIFY, CHANGE REFERENCE, RESET RETRY, SET MGM KEY, IMPORT KEY,
+//!   management key AUTHENTICATE, and PUT DATA of the PIN-protected data
+//!   object) is dropped, along with the data of the commands chained to them,
+//!   and only the command header is kept. Replay matches these commands on
+//!   their header alone.
+//! - The data of the responses to reading the PIN-protected data object
+//!   (which may hold a PIN-protected management key) and to GENERAL
+//!   AUTHENTICATE with a key slot (which may be decrypted data or a shared
+//!   secret) is dropped, along with the following GET RESPONSE results, and
+//!   only their status words are kept. Replaying these operations therefore
+//!   fails.
+//!
+//! Management key authentication involves a random host challenge, so its
+//! recorded response can't be replayed verbatim. [`Replay::mgm_key`] can be
+//! used to give the replay the management key the session was recorded with,
+//! which it then uses to answer the host challenge.
+
+use crate::{
+    apdu::{
+        command_data, is_mgm_auth, is_secret_command, is_secret_response, Secrets, StatusWords,
+        HEADER_LEN,
+    },
+    mgm::MgmKey,
+    serialization::Tlv,
+    transport::{Disposition, Interrupt, Transport, TransportTransaction},
+    Error, Result,
+};
+use log::error;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Comment written at the start of transcript files.
+const HEADER: &str = "# yubikey.rs APDU transcript";
+
+/// Prefix of the line holding the card's ATR.
+const ATR_PREFIX: &str = "@";
+
+/// Marker appended to redacted commands and responses.
+const REDACTED: &str = "[redacted]";
+
+/// A single command APDU and the card's response to it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Exchange {
+    /// Serialized command (only the header if `command_redacted` is set)
+    command: Vec<u8>,
+
+    /// Serialized response, including status words (only the status words
+    /// if `response_redacted` is set)
+    response: Vec<u8>,
+
+    /// Was the command data removed?
+    command_redacted: bool,
+
+    /// Was the response data removed?
+    response_redacted: bool,
+}
+
+impl Exchange {
+    /// Record an exchange, redacting any secret-bearing data.
+    ///
+    /// Only the command itself is used to tell whether the exchange carries
+    /// secrets: [`Recorder`] also redacts the commands chained to a secret
+    /// command, and the GET RESPONSE results following a secret response.
+    pub fn new(command: &[u8], response: &[u8]) -> Self {
+        Self::redacted(
+            command,
+            response,
+            is_secret_command(command),
+            is_secret_response(command),
+        )
+    }
+
+    /// Record an exchange, dropping the command and response data as
+    /// requested.
+    fn redacted(
+        command: &[u8],
+        response: &[u8],
+        command_redacted: bool,
+        response_redacted: bool,
+    ) -> Self {
+        Self {
+            command: if command_redacted {
+                command[..command.len().min(HEADER_LEN)].to_vec()
+            } else {
+                command.to_vec()
+            },
+            response: if response_redacted {
+                response[response.len().saturating_sub(2)..].to_vec()
+            } else {
+                response.to_vec()
+            },
+            command_redacted,
+            response_redacted,
+        }
+    }
+
+    /// Serialized command APDU.
+    ///
+    /// For redacted commands this is only the command header.
+    pub fn command(&self) -> &[u8] {
+        &self.command
+    }
+
+    /// Serialized response APDU, including status words.
+    ///
+    /// For redacted responses these are only the status words.
+    pub fn response(&self) -> &[u8] {
+        &self.response
+    }
+
+    /// Was secret-bearing data removed from this exchange?
+    pub fn is_redacted(&self) -> bool {
+        self.command_redacted || self.response_redacted
+    }
+
+    /// Does the given command match the recorded one?
+    fn matches(&self, command: &[u8]) -> bool {
+        if self.command_redacted {
+            command.get(..self.command.len()) == Some(&self.command)
+        } else {
+            command == self.command
+        }
+    }
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = |redacted| if redacted { " " } else { "" };
+        let redacted = |redacted| if redacted { REDACTED } else { "" };
+
+        writeln!(
+            f,
+            "> {}{}{}",
+            hex::lower::encode_string(&self.command),
+            marker(self.command_redacted),
+            redacted(self.command_redacted)
+        )?;
+        writeln!(
+            f,
+            "< {}{}{}",
+            hex::lower::encode_string(&self.response),
+            marker(self.response_redacted),
+            redacted(self.response_redacted)
+        )
+    }
+}
+
+/// Sequence of recorded [`Exchange`]s.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Transcript {
+    atr: Option<Vec<u8>>,
+    exchanges: Vec<Exchange>,
+}
+
+impl Transcript {
+    /// Create a new empty transcript.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ATR of the card the transcript was recorded with, if known.
+    pub fn atr(&self) -> Option<&[u8]> {
+        self.atr.as_deref()
+    }
+
+    /// Set the ATR of the card the transcript was recorded with.
+    pub fn set_atr(&mut self, atr: impl Into<Vec<u8>>) {
+        self.atr = Some(atr.into());
+    }
+
+    /// Read a transcript from a file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        std::fs::read_to_string(path.as_ref())
+            .map_err(|e| {
+                error!(
+                    "error reading transcript {}: {}",
+                    path.as_ref().display(),
+                    e
+                );
+                Error::GenericError
+            })?
+            .parse()
+    }
+
+    /// Append an exchange to this transcript.
+    pub fn push(&mut self, exchange: Exchange) {
+        self.exchanges.push(exchange);
+    }
+
+    /// Iterate over the exchanges in this transcript.
+    pub fn iter(&self) -> impl Iterator<Item = &Exchange> {
+        self.exchanges.iter()
+    }
+
+    /// Number of exchanges in this transcript.
+    pub fn len(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    /// Is this transcript empty?
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{HEADER}")?;
+
+        if let Some(atr) = &self.atr {
+            writeln!(f, "{ATR_PREFIX} {}", hex::lower::encode_string(atr))?;
+        }
+
+        for exchange in &self.exchanges {
+            write!(f, "{exchange}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Transcript {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut transcript = Self::new();
+        let mut command = None;
+
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parse_error = || {
+                error!("invalid transcript line {}: {:?}", n + 1, line);
+                Error::ParseError
+            };
+
+            let (direction, value) = line.split_at_checked(1).ok_or_else(parse_error)?;
+            let value = value.trim();
+            let (value, redacted) = match value.strip_suffix(REDACTED) {
+                Some(value) => (value.trim_end(), true),
+                None => (value, false),
+            };
+            let bytes = hex::mixed::decode_vec(value).map_err(|_| parse_error())?;
+
+            match (direction, command.take()) {
+                (ATR_PREFIX, None) if !redacted => transcript.atr = Some(bytes),
+                (">", None) => command = Some((bytes, redacted)),
+                ("<", Some((command, command_redacted))) => transcript.push(Exchange {
+                    command,
+                    response: bytes,
+                    command_redacted,
+                    response_redacted: redacted,
+                }),
+                _ => return Err(parse_error()),
+            }
+        }
+
+        if command.is_some() {
+            error!("transcript ends with a command without a response");
+            return Err(Error::ParseError);
+        }
+
+        Ok(transcript)
+    }
+}
+
+/// [`Transport`] which records every exchange with the card it wraps.
+///
+/// Exchanges are written out as they happen, so the transcript is usable
+/// even if the session ends abnormally. Failing to write the transcript is
+/// logged, but doesn't interrupt the session.
+pub struct Recorder<T: Transport> {
+    /// Transport being recorded
+    inner: T,
+
+    /// Destination of the transcript
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl<T: Transport> Recorder<T> {
+    /// Record exchanges over `inner`, writing the transcript to `output`.
+    pub fn new(inner: T, output: impl Write + Send + 'static) -> Self {
+        let mut output: Box<dyn Write + Send> = Box::new(output);
+
+        let mut header = format!("{HEADER}\n");
+        if let Ok(atr) = inner.atr() {
+            header += &format!("{ATR_PREFIX} {}\n", hex::lower::encode_string(&atr));
+        }
+
+        if let Err(e) = output.write_all(header.as_bytes()) {
+            error!("error writing transcript: {}", e);
+        }
+
+        Self {
+            inner,
+            output: Mutex::new(output),
+        }
+    }
+
+    /// Record exchanges over `inner`, writing the transcript to the file at
+    /// `path` (which is created, or truncated if it exists).
+    pub fn create(inner: T, path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::create(path.as_ref()).map_err(|e| {
+            error!(
+                "error creating transcript {}: {}",
+                path.as_ref().display(),
+                e
+            );
+            Error::GenericError
+        })?;
+
+        Ok(Self::new(inner, BufWriter::new(file)))
+    }
+}
+
+impl<T: Transport + fmt::Debug> fmt::Debug for Recorder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn begin_transaction(&mut self) -> Result<Box<dyn TransportTransaction + '_>> {
+        Ok(Box::new(RecorderTransaction {
+            inner: self.inner.begin_transaction()?,
+            output: &self.output,
+            secrets: Secrets::default(),
+        }))
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        self.inner.reconnect(disposition)
+    }
+
+    fn atr(&self) -> Result<Vec<u8>> {
+        self.inner.atr()
+    }
+
+    fn interrupter(&self) -> Option<Arc<dyn Interrupt>> {
+        self.inner.interrupter()
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> core::result::Result<(), (Box<dyn Transport>, Error)> {
+        let Self { inner, output } = *self;
+
+        if let Ok(mut output) = output.lock() {
+            if let Err(e) = output.flush() {
+                error!("error writing transcript: {}", e);
+            }
+        }
+
+        Box::new(inner).disconnect(disposition)
+    }
+}
+
+/// Transaction opened with a [`Recorder`].
+struct RecorderTransaction<'a> {
+    inner: Box<dyn TransportTransaction + 'a>,
+    output: &'a Mutex<Box<dyn Write + Send>>,
+    secrets: Secrets,
+}
+
+impl TransportTransaction for RecorderTransaction<'_> {
+    fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        let secret_command = self.secrets.command(send_buffer);
+        let response = self
+            .inner
+            .transmit(send_buffer, recv_len)
+            .inspect_err(|_| {
+                self.secrets.response(None);
+            })?;
+
+        let secret_response = self.secrets.response(Some(&response));
+        let exchange = Exchange::redacted(send_buffer, &response, secret_command, secret_response);
+
+        let written = match self.output.lock() {
+            Ok(mut output) => write!(output, "{exchange}").and_then(|()| output.flush()),
+            Err(_) => Err(io::Error::other("transcript output poisoned")),
+        };
+
+        if let Err(e) = written {
+            error!("error writing transcript: {}", e);
+        }
+
+        Ok(response)
+    }
+
+    fn end(self: Box<Self>, disposition: Disposition) -> Result<()> {
+        self.inner.end(disposition)
+    }
+}
+
+/// [`Transport`] which replays a recorded [`Transcript`].
+///
+/// Each command sent must match the next exchange in the transcript (redacted
+/// commands are matched on their header only), otherwise the transmission
+/// fails with [`Error::GenericError`].
+///
+/// Clones share the same position in the transcript, which allows keeping a
+/// handle around to check [`Replay::is_finished`] once a session is done.
+#[derive(Clone)]
+pub struct Replay {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+struct ReplayState {
+    transcript: Transcript,
+    position: usize,
+    mgm_key: Option<MgmKey>,
+}
+
+impl Replay {
+    /// Replay the given transcript.
+    pub fn new(transcript: Transcript) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                transcript,
+                position: 0,
+                mgm_key: None,
+            })),
+        }
+    }
+
+    /// Use the given management key to answer management key authentication
+    /// challenges, instead of replaying the card's recorded answer.
+    pub fn mgm_key(self, mgm_key: MgmKey) -> Self {
+        if let Ok(mut state) = self.state.lock() {
+            state.mgm_key = Some(mgm_key);
+        }
+
+        self
+    }
+
+    /// Have all exchanges in the transcript been replayed?
+    pub fn is_finished(&self) -> bool {
+        self.state
+            .lock()
+            .map(|state| state.position == state.transcript.len())
+            .unwrap_or(false)
+    }
+}
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Replay").finish_non_exhaustive()
+    }
+}
+
+impl Transport for Replay {
+    fn begin_transaction(&mut self) -> Result<Box<dyn TransportTransaction + '_>> {
+        Ok(Box::new(ReplayTransaction { state: &self.state }))
+    }
+
+    fn reconnect(&mut self, _disposition: Disposition) -> Result<()> {
+        Ok(())
+    }
+
+    fn atr(&self) -> Result<Vec<u8>> {
+        let state = self.state.lock().map_err(|_| Error::GenericError)?;
+        state
+            .transcript
+            .atr()
+            .map(<[u8]>::to_vec)
+            .ok_or(Error::NotSupported)
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        _disposition: Disposition,
+    ) -> core::result::Result<(), (Box<dyn Transport>, Error)> {
+        Ok(())
+    }
+}
+
+/// Transaction opened with a [`Replay`].
+struct ReplayTransaction<'a> {
+    state: &'a Mutex<ReplayState>,
+}
+
+impl TransportTransaction for ReplayTransaction<'_> {
+    fn transmit(&self, send_buffer: &[u8], _recv_len: usize) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().map_err(|_| Error::GenericError)?;
+        let position = state.position;
+
+        let exchange = state.transcript.exchanges.get(position).ok_or_else(|| {
+            error!("transcript exhausted after {} exchanges", position);
+            Error::GenericError
+        })?;
+
+        if !exchange.matches(send_buffer) {
+            error!(
+                "command #{} doesn't match transcript: expected {}, got {}",
+                position + 1,
+                hex::lower::encode_string(&exchange.command),
+                hex::lower::encode_string(send_buffer)
+            );
+            return Err(Error::GenericError);
+        }
+
+        let response = match &state.mgm_key {
+            Some(mgm_key) if is_mgm_auth(send_buffer) && is_success(&exchange.response) => {
+                answer_mgm_challenge(mgm_key, send_buffer)?
+                    .unwrap_or_else(|| exchange.response.clone())
+            }
+            _ => exchange.response.clone(),
+        };
+
+        state.position += 1;
+        Ok(response)
+    }
+
+    fn end(self: Box<Self>, _disposition: Disposition) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn is_success(response: &[u8]) -> bool {
+    response
+        .get(response.len().saturating_sub(2)..)
+        .and_then(|sw| <[u8; 2]>::try_from(sw).ok())
+        .map(|sw| StatusWords::from(u16::from_be_bytes(sw)).is_success())
+        .unwrap_or(false)
+}
+
+/// Answer the host challenge (tag `0x81`) of a mutual management key
+/// authentication, if the given command carries one.
+fn answer_mgm_challenge(mgm_key: &MgmKey, command: &[u8]) -> Result<Option<Vec<u8>>> {
+    let (_, template) = match command_data(command).map(Tlv::parse) {
+        Some(Ok(template)) => template,
+        _ => return Ok(None),
+    };
+
+    let mut items = template.value;
+
+    while !items.is_empty() {
+        let (remaining, item) = Tlv::parse(items)?;
+
+        if item.tag == 0x81 && !item.value.is_empty() {
+            let mut block = item.value.to_vec();
+            mgm_key.encrypt_block(&mut block)?;
+
+            let mut response = vec![0x7c, block.len() as u8 + 2, 0x82, block.len() as u8];
+            response.extend_from_slice(&block);
+            response.extend_from_slice(&StatusWords::Success.code().to_be_bytes());
+            return Ok(Some(response));
+        }
+
+        items = remaining;
+    }
+
+    Ok(None)
+}
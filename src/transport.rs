@@ -0,0 +1,197 @@
+//! Card transports: the channel used to exchange APDUs with a YubiKey.
+//!
+//! All communication with a YubiKey happens through a [`Transport`], which
+//! provides exclusive transactions over which serialized APDUs are sent.
+//! [`PcscTransport`] is the default implementation, and is what
+//! [`Reader::open`][`crate::reader::Reader::open`] uses. Other transports
+//! (e.g. stand-ins used for testing) can be used to open a session with
+//! [`YubiKey::open_transport`][`crate::YubiKey::open_transport`].
+
+use crate::{Error, Result};
+use std::{ffi::CString, fmt, sync::Arc};
+
+pub use pcsc::{Disposition, Protocols, Scope, ShareMode};
+
+/// Transport used to communicate with a YubiKey's smart card interface.
+pub trait Transport: Send {
+    /// Begin an exclusive transaction with the card.
+    ///
+    /// The transaction ends when the returned [`TransportTransaction`] is
+    /// dropped, or when [`TransportTransaction::end`] is called.
+    fn begin_transaction(&mut self) -> Result<Box<dyn TransportTransaction + '_>>;
+
+    /// Reconnect to the card, using the given disposition for the current
+    /// connection.
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()>;
+
+    /// Get the card's Answer To Reset (ATR).
+    ///
+    /// This is used to discover the card's capabilities (e.g. support for
+    /// extended length APDUs). Transports which can't provide it return
+    /// [`Error::NotSupported`], in which case only baseline capabilities are
+    /// assumed.
+    fn atr(&self) -> Result<Vec<u8>> {
+        Err(Error::NotSupported)
+    }
+
+    /// Get a handle which interrupts an exchange with the card in progress
+    /// (e.g. one waiting for the user to touch the YubiKey) from another
+    /// thread, if the transport supports it.
+    ///
+    /// Interrupting may reset the card. It is used to abort touch-gated
+    /// operations (see [`crate::touch`]).
+    fn interrupter(&self) -> Option<Arc<dyn Interrupt>> {
+        None
+    }
+
+    /// Disconnect from the card.
+    ///
+    /// In case of error, ownership of the transport is returned to the caller.
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> core::result::Result<(), (Box<dyn Transport>, Error)>;
+}
+
+/// Handle returned by [`Transport::interrupter`].
+pub trait Interrupt: Send + Sync {
+    /// Interrupt the exchange with the card in progress, if any.
+    fn interrupt(&self) -> Result<()>;
+}
+
+/// Exclusive transaction opened with [`Transport::begin_transaction`].
+pub trait TransportTransaction {
+    /// Transmit a single serialized APDU to the card and receive a response
+    /// of at most `recv_len` bytes (including status words).
+    fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>>;
+
+    /// End the transaction using the given disposition.
+    fn end(self: Box<Self>, disposition: Disposition) -> Result<()>;
+}
+
+/// [`Transport`] backed by a PC/SC card connection.
+pub struct PcscTransport {
+    /// PC/SC card
+    card: pcsc::Card,
+
+    /// Share mode used when reconnecting
+    share_mode: ShareMode,
+
+    /// Protocols used when reconnecting
+    protocols: Protocols,
+}
+
+impl PcscTransport {
+    /// Create a new transport from a connected PC/SC card.
+    pub fn new(card: pcsc::Card) -> Self {
+        Self {
+            card,
+            share_mode: ShareMode::Shared,
+            protocols: Protocols::T1,
+        }
+    }
+
+    /// Set the share mode used when reconnecting to the card (by default,
+    /// [`ShareMode::Shared`]).
+    ///
+    /// This should match the share mode the card was connected with.
+    pub fn share_mode(mut self, share_mode: ShareMode) -> Self {
+        self.share_mode = share_mode;
+        self
+    }
+
+    /// Set the protocols accepted when reconnecting to the card (by default,
+    /// [`Protocols::T1`]).
+    pub fn protocols(mut self, protocols: Protocols) -> Self {
+        self.protocols = protocols;
+        self
+    }
+}
+
+impl fmt::Debug for PcscTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcscTransport").finish_non_exhaustive()
+    }
+}
+
+impl From<pcsc::Card> for PcscTransport {
+    fn from(card: pcsc::Card) -> Self {
+        Self::new(card)
+    }
+}
+
+impl Transport for PcscTransport {
+    fn begin_transaction(&mut self) -> Result<Box<dyn TransportTransaction + '_>> {
+        Ok(Box::new(self.card.transaction()?))
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> Result<()> {
+        Ok(self
+            .card
+            .reconnect(self.share_mode, self.protocols, disposition)?)
+    }
+
+    fn atr(&self) -> Result<Vec<u8>> {
+        Ok(self.card.get_attribute_owned(pcsc::Attribute::AtrString)?)
+    }
+
+    /// PC/SC can't abort a pending `SCardTransmit`, so this resets the card
+    /// through a separate connection to its reader instead. This is best
+    /// effort: the PC/SC service may refuse to reset the card while the
+    /// transaction is in progress.
+    fn interrupter(&self) -> Option<Arc<dyn Interrupt>> {
+        let status = self.card.status2_owned().ok()?;
+        let reader = status.reader_names().first()?.clone();
+        Some(Arc::new(PcscInterrupt { reader }))
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> core::result::Result<(), (Box<dyn Transport>, Error)> {
+        let Self {
+            card,
+            share_mode,
+            protocols,
+        } = *self;
+
+        card.disconnect(disposition).map_err(|(card, e)| {
+            let transport = Self {
+                card,
+                share_mode,
+                protocols,
+            };
+
+            (Box::new(transport) as Box<dyn Transport>, e.into())
+        })
+    }
+}
+
+/// [`Interrupt`] for [`PcscTransport`]: resets the card in the reader.
+struct PcscInterrupt {
+    reader: CString,
+}
+
+impl Interrupt for PcscInterrupt {
+    fn interrupt(&self) -> Result<()> {
+        let context = pcsc::Context::establish(Scope::User)?;
+        let card = context.connect(&self.reader, ShareMode::Shared, Protocols::ANY)?;
+        card.disconnect(Disposition::ResetCard)
+            .map_err(|(_, e)| e.into())
+    }
+}
+
+impl TransportTransaction for pcsc::Transaction<'_> {
+    fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> Result<Vec<u8>> {
+        let mut recv_buffer = vec![0u8; recv_len];
+
+        let len = pcsc::Card::transmit(self, send_buffer, recv_buffer.as_mut())?.len();
+
+        recv_buffer.truncate(len);
+        Ok(recv_buffer)
+    }
+
+    fn end(self: Box<Self>, disposition: Disposition) -> Result<()> {
+        (*self).end(disposition).map_err(|(_, e)| e.into())
+    }
+}
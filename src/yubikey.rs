@@ -32,24 +32,32 @@
 
 use crate::{
     apdu::{Apdu, Ins},
+    atr,
     cccid::CccId,
     chuid::ChuId,
     config::Config,
     error::{Error, Result},
     mgm::MgmKey,
     piv,
-    reader::{Context, Reader},
+    policy::TouchPolicy,
+    reader::Reader,
+    scp::{self, ScpKeyParams},
+    touch::{self, CancelHandle, Touch},
     transaction::Transaction,
+    transport::Transport,
+    OpenOptions,
 };
 use cipher::common::getrandom::SysRng;
-use log::{error, info};
-use pcsc::Card;
+use log::{debug, error, info};
 use rand_core::TryRng;
 use std::{
+    cell::Cell,
     cmp::{Ord, Ordering},
     fmt::{self, Display},
     str::FromStr,
+    time::Duration,
 };
+use zeroize::Zeroizing;
 
 #[cfg(feature = "untested")]
 use {
@@ -203,6 +211,62 @@ impl PartialOrd for Version {
     }
 }
 
+/// Automatic recovery of a [`YubiKey`] session after the card has been reset
+/// (e.g. by another application) or removed and reinserted.
+///
+/// When enabled, a lost session is restored before the next operation by
+/// reconnecting, reselecting the PIV application, checking that the card is
+/// the same device, re-verifying the cached PIN, and optionally
+/// re-authenticating with a management key. Idempotent operations (e.g.
+/// reading objects and certificates, or signing) which fail because the
+/// session was lost are retried once; other operations return the
+/// transport's error, and the session is restored before the next one.
+///
+/// If the session can't be restored, operations fail with
+/// [`Error::SessionLost`].
+#[derive(Clone, Default)]
+pub struct Recovery {
+    enabled: bool,
+    mgm_key: Option<Box<MgmKey>>,
+}
+
+impl Recovery {
+    /// Don't recover lost sessions: operations fail with the transport's
+    /// error (the default).
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Reconnect and restore lost sessions, re-verifying the cached PIN.
+    pub fn reconnect() -> Self {
+        Self {
+            enabled: true,
+            mgm_key: None,
+        }
+    }
+
+    /// Also re-authenticate with the given management key when restoring a
+    /// session.
+    pub fn mgm_key(mut self, mgm_key: MgmKey) -> Self {
+        self.mgm_key = Some(Box::new(mgm_key));
+        self
+    }
+
+    /// Is recovery enabled?
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+impl fmt::Debug for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recovery")
+            .field("enabled", &self.enabled)
+            .field("mgm_key", &self.mgm_key.as_ref().map(|_| "[redacted]"))
+            .finish()
+    }
+}
+
 /// YubiKey device: primary API for opening a session and performing various operations.
 ///
 /// Almost all functionality in this library will require an open session
@@ -210,11 +274,23 @@ impl PartialOrd for Version {
 // TODO(tarcieri): reduce coupling to internal fields via `pub(crate)`
 #[cfg_attr(not(feature = "untested"), allow(dead_code))]
 pub struct YubiKey {
-    pub(crate) card: Card,
+    pub(crate) transport: Box<dyn Transport>,
     pub(crate) name: String,
     pub(crate) pin: Option<CachedPin>,
     pub(crate) version: Version,
     pub(crate) serial: Serial,
+    pub(crate) extended_apdus: bool,
+    pub(crate) recovery: Recovery,
+    pub(crate) reconnect_disposition: pcsc::Disposition,
+
+    /// Set when the transport reports that the card was reset or removed
+    pub(crate) lost: Cell<bool>,
+
+    /// Secure channel used by the session, if any
+    pub(crate) scp: Option<Box<scp::SecureChannel>>,
+
+    /// Timeout and cancellation of touch-gated operations
+    pub(crate) touch: Touch,
 }
 
 impl fmt::Debug for YubiKey {
@@ -239,100 +315,255 @@ impl YubiKey {
     ///
     /// If you need to operate in environments with more than one YubiKey
     /// attached to the same system, use [`YubiKey::open_by_serial`] or
-    /// [`yubikey::reader::Context`][`Context`] to select from the available
-    /// PC/SC readers.
+    /// [`yubikey::reader::Context`][`crate::reader::Context`] to select from
+    /// the available PC/SC readers.
+    ///
+    /// To change how the device is connected to, use [`OpenOptions`].
     pub fn open() -> Result<Self> {
-        let mut yubikey: Option<Self> = None;
-
-        let mut readers = Context::open()?;
-        for reader in readers.iter()? {
-            if let Ok(yk_found) = reader.open() {
-                if let Some(yk_stored) = yubikey {
-                    // We found two YubiKeys, so we won't use either.
-                    // Don't reset them.
-                    let _ = yk_stored.disconnect(pcsc::Disposition::LeaveCard);
-                    let _ = yk_found.disconnect(pcsc::Disposition::LeaveCard);
-
-                    error!("multiple YubiKeys detected!");
-                    return Err(Error::PcscError { inner: None });
-                } else {
-                    yubikey = Some(yk_found);
-                }
-            }
-        }
-
-        if let Some(yubikey) = yubikey {
-            // We found exactly one YubiKey that we could open, so we return it.
-            Ok(yubikey)
-        } else {
-            error!("no YubiKey detected!");
-            Err(Error::NotFound)
-        }
+        OpenOptions::new().open()
     }
 
     /// Open a YubiKey with a specific serial number.
+    ///
+    /// The devices in the available readers are probed (see
+    /// [`Context::probe`][`crate::reader::Context::probe`]), and only the
+    /// matching one is opened.
     pub fn open_by_serial(serial: Serial) -> Result<Self> {
-        let mut readers = Context::open()?;
+        OpenOptions::new().open_by_serial(serial)
+    }
 
-        let mut open_error = None;
+    /// Open a YubiKey using the given [`Transport`].
+    ///
+    /// This allows sessions to be opened with transports other than PC/SC,
+    /// e.g. stand-ins used for testing. `name` is used in place of the PC/SC
+    /// reader name.
+    pub fn open_transport(transport: impl Transport + 'static, name: &str) -> Result<Self> {
+        Self::connect(Box::new(transport), String::from(name))
+    }
 
-        for reader in readers.iter()? {
-            let yubikey = match reader.open() {
-                Ok(yk) => yk,
-                Err(e) => {
-                    // Save the first error we see that indicates we might have been able
-                    // to find a matching YubiKey.
-                    if open_error.is_none() {
-                        if let Error::PcscError {
-                            inner: Some(pcsc::Error::SharingViolation),
-                        } = e
-                        {
-                            open_error = Some(e);
-                        }
-                    }
-                    continue;
+    /// Connect to a YubiKey over the given transport, selecting the PIV
+    /// application and querying the device's version and serial number.
+    pub(crate) fn connect(mut transport: Box<dyn Transport>, name: String) -> Result<Self> {
+        let mut app_version_serial = || -> Result<(Version, Serial)> {
+            let txn = Transaction::new(transport.as_mut())?;
+            txn.select_piv_application()?;
+
+            let v = txn.get_version()?;
+            let s = txn.get_serial(v)?;
+            Ok((v, s))
+        };
+
+        match app_version_serial() {
+            Err(e) => {
+                error!("Could not use reader: {}", e);
+
+                // We were unable to use the card, so we've effectively only connected as
+                // a side-effect of determining this. Avoid disrupting its internal state
+                // any further (e.g. preserve the PIN cache of whatever applet is selected
+                // currently).
+                if let Err((_, e)) = transport.disconnect(pcsc::Disposition::LeaveCard) {
+                    error!("Failed to disconnect gracefully from card: {}", e);
                 }
-            };
 
-            if serial == yubikey.serial() {
-                return Ok(yubikey);
-            } else {
-                // We didn't want this YubiKey; don't reset it.
-                let _ = yubikey.disconnect(pcsc::Disposition::LeaveCard);
+                Err(e)
             }
-        }
+            Ok((version, serial)) => {
+                let extended_apdus = supports_extended_apdus(transport.as_ref(), version);
 
-        Err(if let Some(e) = open_error {
-            e
-        } else {
-            error!("no YubiKey detected with serial: {}", serial);
-            Error::NotFound
-        })
+                Ok(YubiKey {
+                    transport,
+                    name,
+                    pin: None,
+                    version,
+                    serial,
+                    extended_apdus,
+                    recovery: Recovery::default(),
+                    reconnect_disposition: pcsc::Disposition::ResetCard,
+                    lost: Cell::new(false),
+                    scp: None,
+                    touch: Touch::new(),
+                })
+            }
+        }
     }
 
-    /// Reconnect to a YubiKey.
-    #[cfg(feature = "untested")]
+    /// Reconnect to a YubiKey, resetting the card (unless another disposition
+    /// was chosen with [`OpenOptions::reconnect_disposition`]).
+    ///
+    /// The session is then restored as described in [`Recovery`]: the
+    /// secure channel (if any) is opened again, the cached PIN is
+    /// re-verified, and the management key configured with
+    /// [`YubiKey::set_recovery`] (if any) is re-authenticated.
     pub fn reconnect(&mut self) -> Result<()> {
         info!("trying to reconnect to current reader");
 
-        self.card.reconnect(
-            pcsc::ShareMode::Shared,
-            pcsc::Protocols::T1,
-            pcsc::Disposition::ResetCard,
-        )?;
+        self.lost.set(false);
+        self.restore(self.reconnect_disposition)
+    }
+
+    /// Configure automatic recovery of this session after the card has been
+    /// reset or removed (see [`Recovery`]).
+    pub fn set_recovery(&mut self, recovery: Recovery) {
+        self.recovery = recovery;
+    }
+
+    /// Get the automatic recovery configuration of this session.
+    pub fn recovery(&self) -> &Recovery {
+        &self.recovery
+    }
+
+    /// Restore a lost session, if recovery is enabled.
+    fn recover(&mut self) -> Result<()> {
+        info!("card was reset or removed; restoring session");
+
+        self.lost.set(false);
+        self.restore(pcsc::Disposition::LeaveCard).map_err(|e| {
+            error!("could not restore session: {}", e);
+            self.lost.set(true);
+            Error::SessionLost
+        })
+    }
+
+    /// Reconnect to the card and restore the session's state.
+    fn restore(&mut self, disposition: pcsc::Disposition) -> Result<()> {
+        self.transport.reconnect(disposition)?;
 
-        let pin = self.pin.as_ref().map(|p| Buffer::new(p.clone()));
+        let (version, serial) = (self.version, self.serial);
+        let pin = self.pin.clone().map(Zeroizing::new);
 
-        let txn = Transaction::new(&mut self.card)?;
-        txn.select_piv_application()?;
+        let verified = {
+            let txn = self.transaction()?;
+            txn.select_piv_application()?;
+
+            // Make sure the card wasn't swapped for another one
+            if txn.get_serial(version)? != serial {
+                error!("card in reader is no longer YubiKey {}", serial);
+                return Err(Error::NotFound);
+            }
 
-        if let Some(p) = &pin {
-            txn.verify_pin(p)?;
+            pin.as_ref().map(|pin| txn.verify_pin(pin)).transpose()
+        };
+
+        if let Err(e @ (Error::WrongPin { .. } | Error::PinLocked)) = verified {
+            // Don't use up the remaining tries with later attempts
+            self.pin = None;
+            return Err(e);
+        }
+
+        verified?;
+
+        if let Some(mgm_key) = self.recovery.mgm_key.clone() {
+            self.authenticate_mgm_key(&mgm_key)?;
         }
 
         Ok(())
     }
 
+    /// Perform an idempotent operation, retrying it once if it failed because
+    /// the session was lost and recovery is enabled.
+    ///
+    /// Operations aborted with a [`CancelHandle`] or by the touch timeout
+    /// aren't retried.
+    pub(crate) fn retrying<T>(&mut self, mut op: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        match op(self) {
+            Err(e)
+                if e.is_session_lost()
+                    && self.recovery.is_enabled()
+                    && !self.touch.is_aborted() =>
+            {
+                info!("session lost during operation ({}); retrying", e);
+                op(self)
+            }
+            result => result,
+        }
+    }
+
+    /// Set the maximum time to wait for the user to touch the YubiKey during
+    /// touch-gated operations (by default, there is no limit).
+    ///
+    /// Operations which time out fail with [`Error::TouchNotReceived`] (see
+    /// [`crate::touch`]).
+    pub fn set_touch_timeout(&mut self, timeout: Option<Duration>) {
+        self.touch.set_timeout(timeout);
+    }
+
+    /// Get the maximum time to wait for the user to touch the YubiKey.
+    pub fn touch_timeout(&self) -> Option<Duration> {
+        self.touch.timeout()
+    }
+
+    /// Get a handle for cancelling this session's touch-gated operation in
+    /// progress from another thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.touch.cancel_handle()
+    }
+
+    /// Set a callback which is called right before an operation waits for
+    /// the user to touch the YubiKey, e.g. to prompt them to do so (see
+    /// [`crate::touch`]).
+    pub fn set_touch_callback(&mut self, callback: impl Fn(touch::Request) + Send + 'static) {
+        self.touch.set_callback(Some(Box::new(callback)));
+    }
+
+    /// Remove the callback set with [`YubiKey::set_touch_callback`].
+    pub fn clear_touch_callback(&mut self) {
+        self.touch.set_callback(None);
+    }
+
+    /// Perform an operation which may wait for the user to touch the YubiKey,
+    /// aborting it if it is cancelled or times out.
+    ///
+    /// If the key used by `request` requires touch, the touch callback is
+    /// called first. The session is restored after an operation was aborted,
+    /// which then fails with [`Error::TouchNotReceived`].
+    pub(crate) fn touch_gated<T>(
+        &mut self,
+        request: Option<touch::Request>,
+        op: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        // Only query the touch policy if there's someone to tell
+        let policy = match request.filter(|_| self.touch.has_callback()) {
+            Some(request) => match piv::metadata(self, request.slot()) {
+                Ok(metadata) => metadata.policy.map(|(_, touch_policy)| touch_policy),
+                Err(e) => {
+                    debug!(
+                        "couldn't get touch policy of slot {:?}: {}",
+                        request.slot(),
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        if let (Some(request), Some(policy)) = (request, policy) {
+            self.touch.prompt(request, policy);
+        }
+
+        // Only watch operations which may wait for a touch, and be aborted
+        let watchdog = (policy != Some(TouchPolicy::Never) && self.touch.is_abortable())
+            .then(|| self.touch.watch(self.transport.interrupter()));
+        let result = op(self);
+
+        if !watchdog.is_some_and(touch::Watchdog::finish) {
+            if let (Ok(_), Some(policy)) = (&result, policy) {
+                self.touch.completed(policy);
+            }
+
+            return result;
+        }
+
+        // The card was reset, or is in an unknown state if it couldn't be
+        self.lost.set(false);
+        if let Err(e) = self.restore(pcsc::Disposition::ResetCard) {
+            error!("could not restore session after aborting operation: {}", e);
+            self.lost.set(true);
+        }
+
+        result.map_err(|_| Error::TouchNotReceived)
+    }
+
     /// Disconnect from the YubiKey.
     ///
     /// In case of error, ownership of the YubiKey is returned to the caller.
@@ -342,36 +573,114 @@ impl YubiKey {
     /// `YubiKey` implements `Drop` which automatically disconnects the card using
     /// `Disposition::ResetCard`; you only need to call this function if you want to
     /// handle errors or use a different disposition method.
+    // The session is handed back as is, so that it can still be used
+    #[allow(clippy::result_large_err)]
     pub fn disconnect(
         self,
         disposition: pcsc::Disposition,
     ) -> core::result::Result<(), (Self, Error)> {
         let Self {
-            card,
+            transport,
             name,
             pin,
             version,
             serial,
+            extended_apdus,
+            recovery,
+            reconnect_disposition,
+            lost,
+            scp,
+            touch,
         } = self;
 
-        card.disconnect(disposition).map_err(|(card, e)| {
+        transport.disconnect(disposition).map_err(|(transport, e)| {
             (
                 Self {
-                    card,
+                    transport,
                     name,
                     pin,
                     version,
                     serial,
+                    extended_apdus,
+                    recovery,
+                    reconnect_disposition,
+                    lost,
+                    scp,
+                    touch,
                 },
-                e.into(),
+                e,
             )
         })
     }
 
+    /// Open a secure channel with the YubiKey (firmware 5.7 and newer).
+    ///
+    /// All subsequent commands of this session are wrapped with it, and it is
+    /// opened again whenever the card closes it, e.g. when the session is
+    /// restored (see [`Recovery`]). See [`scp`] for details.
+    pub fn open_secure_channel(&mut self, params: ScpKeyParams) -> Result<()> {
+        if self.version < Version::new([5, 7, 0]) {
+            error!("secure channels require firmware 5.7 (is {})", self.version);
+            return Err(Error::NotSupported);
+        }
+
+        self.scp = None;
+
+        let txn = self.begin_transaction()?;
+        let channel = Box::new(scp::SecureChannel::new(&txn, params)?);
+        txn.secure_channel(&channel).open_secure_channel()?;
+        self.scp = Some(channel);
+
+        info!("opened secure channel with YubiKey {}", self.serial);
+        Ok(())
+    }
+
     /// Begin a transaction.
+    ///
+    /// If the session was lost and [`Recovery`] is enabled, it is restored
+    /// first. If the secure channel of the session was closed, it is opened
+    /// again.
     pub(crate) fn begin_transaction(&mut self) -> Result<Transaction<'_>> {
-        // TODO(tarcieri): reconnect support
-        Transaction::new(&mut self.card)
+        if self.lost.get() && self.recovery.is_enabled() {
+            self.recover()?;
+        }
+
+        let reopen = self
+            .scp
+            .as_ref()
+            .is_some_and(|scp| scp.session.borrow().is_none());
+
+        let txn = self.transaction()?;
+
+        if reopen {
+            info!("re-opening secure channel");
+            txn.open_secure_channel()?;
+        }
+
+        Ok(txn)
+    }
+
+    /// Begin a transaction, without restoring the session first.
+    fn transaction(&mut self) -> Result<Transaction<'_>> {
+        match Transaction::new(self.transport.as_mut()) {
+            Ok(txn) => {
+                let txn = txn
+                    .extended_apdus(self.extended_apdus)
+                    .track_session(&self.lost);
+
+                Ok(match &self.scp {
+                    Some(scp) => txn.secure_channel(scp),
+                    None => txn,
+                })
+            }
+            Err(e) => {
+                if e.is_session_lost() {
+                    self.lost.set(true);
+                }
+
+                Err(e)
+            }
+        }
     }
 
     /// Get the name of the associated PC/SC card reader.
@@ -395,21 +704,31 @@ impl YubiKey {
 
     /// Get device configuration.
     pub fn config(&mut self) -> Result<Config> {
-        Config::get(self)
+        self.retrying(Config::get)
     }
 
     /// Get Cardholder Unique Identifier (CHUID).
     pub fn chuid(&mut self) -> Result<ChuId> {
-        ChuId::get(self)
+        self.retrying(ChuId::get)
     }
 
     /// Get Cardholder Capability Container (CCC) Identifier.
     pub fn cccid(&mut self) -> Result<CccId> {
-        CccId::get(self)
+        self.retrying(CccId::get)
     }
 
     /// Authenticate to the card using the provided management key (MGM).
+    ///
+    /// If the management key requires touch, this waits for the user to touch
+    /// the YubiKey (see [`crate::touch`]).
     pub fn authenticate(&mut self, mgm_key: &MgmKey) -> Result<()> {
+        self.touch_gated(Some(touch::Request::Authenticate), |yubikey| {
+            yubikey.authenticate_mgm_key(mgm_key)
+        })
+    }
+
+    /// Authenticate with the management key, without a touch timeout.
+    fn authenticate_mgm_key(&mut self, mgm_key: &MgmKey) -> Result<()> {
         let txn = self.begin_transaction()?;
 
         // get a challenge from the card
@@ -462,7 +781,7 @@ impl YubiKey {
 
     /// Get the PIV keys contained in this YubiKey.
     pub fn piv_keys(&mut self) -> Result<Vec<piv::Key>> {
-        piv::Key::list(self)
+        self.retrying(piv::Key::list)
     }
 
     /// Deauthenticate.
@@ -508,19 +827,21 @@ impl YubiKey {
 
     /// Get the number of PIN retries.
     pub fn get_pin_retries(&mut self) -> Result<u8> {
-        let txn = self.begin_transaction()?;
+        self.retrying(|yubikey| {
+            let txn = yubikey.begin_transaction()?;
 
-        // Force a re-select to unverify, because once verified the spec dictates that
-        // subsequent verify calls will return a "verification not needed" instead of
-        // the number of tries left...
-        txn.select_piv_application()?;
+            // Force a re-select to unverify, because once verified the spec dictates that
+            // subsequent verify calls will return a "verification not needed" instead of
+            // the number of tries left...
+            txn.select_piv_application()?;
 
-        // WRONG_PIN is expected on successful query.
-        match txn.verify_pin(&[]) {
-            Ok(()) => Ok(0), // TODO(tarcieri): verify this matches `yubico-piv-tool`
-            Err(Error::WrongPin { tries }) => Ok(tries),
-            Err(e) => Err(e),
-        }
+            // WRONG_PIN is expected on successful query.
+            match txn.verify_pin(&[]) {
+                Ok(()) => Ok(0), // TODO(tarcieri): verify this matches `yubico-piv-tool`
+                Err(Error::WrongPin { tries }) => Ok(tries),
+                Err(e) => Err(e),
+            }
+        })
     }
 
     /// Set the number of PIN retries.
@@ -670,8 +991,7 @@ impl YubiKey {
     /// Fetch an object from the YubiKey.
     #[cfg(feature = "untested")]
     pub fn fetch_object(&mut self, object_id: ObjectId) -> Result<Buffer> {
-        let txn = self.begin_transaction()?;
-        txn.fetch_object(object_id)
+        self.retrying(|yubikey| yubikey.begin_transaction()?.fetch_object(object_id))
     }
 
     /// Save an object.
@@ -747,50 +1067,21 @@ impl YubiKey {
     }
 }
 
+/// Can extended length APDUs be used with the given card?
+///
+/// This requires the card to advertise support in its ATR, and a YubiKey 4.3
+/// or newer (earlier firmware accepts smaller extended APDUs than we send).
+fn supports_extended_apdus(transport: &dyn Transport, version: Version) -> bool {
+    version >= Version::new([4, 3, 0])
+        && transport
+            .atr()
+            .is_ok_and(|atr| atr::supports_extended_length(&atr))
+}
+
 impl<'a> TryFrom<&'a Reader<'_>> for YubiKey {
     type Error = Error;
 
     fn try_from(reader: &'a Reader<'_>) -> Result<Self> {
-        let mut card = reader
-            .connect()
-            .inspect_err(|e| error!("error connecting to reader '{}': {}", reader.name(), e))?;
-
-        info!("connected to reader: {}", reader.name());
-
-        let mut app_version_serial = || -> Result<(Version, Serial)> {
-            let txn = Transaction::new(&mut card)?;
-            txn.select_piv_application()?;
-
-            let v = txn.get_version()?;
-            let s = txn.get_serial(v)?;
-            Ok((v, s))
-        };
-
-        match app_version_serial() {
-            Err(e) => {
-                error!("Could not use reader: {}", e);
-
-                // We were unable to use the card, so we've effectively only connected as
-                // a side-effect of determining this. Avoid disrupting its internal state
-                // any further (e.g. preserve the PIN cache of whatever applet is selected
-                // currently).
-                if let Err((_, e)) = card.disconnect(pcsc::Disposition::LeaveCard) {
-                    error!("Failed to disconnect gracefully from card: {}", e);
-                }
-
-                Err(e)
-            }
-            Ok((version, serial)) => {
-                let yubikey = YubiKey {
-                    card,
-                    name: String::from(reader.name()),
-                    pin: None,
-                    version,
-                    serial,
-                };
-
-                Ok(yubikey)
-            }
-        }
+        OpenOptions::new().open_reader(reader)
     }
 }
@@ -0,0 +1,112 @@
+//! Tests for the async facade, using the software PIV emulator
+
+#![cfg(feature = "async")]
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use std::{
+    future::Future,
+    pin::pin,
+    sync::{mpsc, Arc},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+use yubikey::{
+    asynchronous::AsyncYubiKey,
+    emulator::VirtualYubiKey,
+    piv::{AlgorithmId, SlotId},
+    Error, MgmKey, PinPolicy, Serial, TouchPolicy, Version,
+};
+
+/// Waker which unparks the thread blocked on a future.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Minimal executor: poll the future on the current thread until it's ready.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn open() -> AsyncYubiKey {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    AsyncYubiKey::new(card.open().unwrap()).unwrap()
+}
+
+#[test]
+fn test_generate_and_sign() {
+    let yubikey = open();
+    assert_eq!(yubikey.serial(), Serial(12345678));
+    assert_eq!(yubikey.version(), Version::new([5, 7, 2]));
+
+    block_on(async {
+        let mgm_key = yubikey.run(|yubikey| MgmKey::get_default(yubikey)).await?;
+        yubikey.verify_pin(b"123456").await?;
+        yubikey.authenticate(mgm_key).await?;
+
+        yubikey
+            .generate(
+                SlotId::Authentication,
+                AlgorithmId::EccP256,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            )
+            .await?;
+
+        let signature = yubikey
+            .sign_data(&[0x42; 32], AlgorithmId::EccP256, SlotId::Authentication)
+            .await?;
+        assert!(!signature.is_empty());
+
+        let attestation = yubikey.read_certificate(SlotId::Attestation).await?;
+        assert_eq!(attestation.subject(), "CN=Yubico PIV Attestation");
+        Ok::<(), Error>(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_cancellation() {
+    let yubikey = open();
+    let (unblock, blocked) = mpsc::channel::<()>();
+
+    // Keep the worker busy until the operation below has been dropped
+    let busy = yubikey.run(move |_| {
+        blocked.recv().ok();
+        Ok(())
+    });
+
+    drop(yubikey.verify_pin(b"000000"));
+    unblock.send(()).unwrap();
+    block_on(busy).unwrap();
+
+    // The wrong PIN was never sent to the card
+    assert_eq!(block_on(yubikey.get_pin_retries()), Ok(3));
+}
+
+#[test]
+fn test_errors() {
+    let yubikey = open();
+
+    assert_eq!(
+        block_on(yubikey.verify_pin(b"000000")),
+        Err(Error::WrongPin { tries: 2 })
+    );
+    assert_eq!(
+        block_on(yubikey.run(|_| Err::<(), _>(Error::NotSupported))),
+        Err(Error::NotSupported)
+    );
+}
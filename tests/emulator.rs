@@ -0,0 +1,1336 @@
+//! Tests against the software PIV emulator (no hardware required)
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use der::{oid::db::rfc5912, referenced::OwnedToRef, Encode};
+use p256::ecdsa::{
+    signature::{
+        hazmat::{PrehashSigner, PrehashVerifier},
+        DigestSigner, Keypair, Signer as _, Verifier,
+    },
+    DerSignature, VerifyingKey,
+};
+use p256::elliptic_curve::Generate;
+use rsa::{pss, Oaep, Pkcs1v15Encrypt, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384};
+use std::{str::FromStr, time::Duration};
+use x509_cert::{name::Name, serial_number::SerialNumber, time::Validity};
+use yubikey::{
+    certificate::{
+        yubikey_signer::{Rsa1024, Signer, YubiRsa, YubiRsaPss},
+        CertInfo, Certificate,
+    },
+    emulator::VirtualYubiKey,
+    piv::{
+        self, AlgorithmId, HashAlgorithm, ManagementSlotId, Origin, RetiredSlotId, SignatureScheme,
+        SlotAlgorithmId, SlotId,
+    },
+    transport::{Disposition, Transport, TransportTransaction},
+    Error, MgmKey, PinPolicy, Serial, TouchPolicy, Version, YubiKey,
+};
+
+/// Transport which drops the public key from the metadata of the key with
+/// the given reference, like a card returning malformed metadata.
+struct NoPublicKey(VirtualYubiKey, u8);
+
+impl Transport for NoPublicKey {
+    fn begin_transaction(&mut self) -> yubikey::Result<Box<dyn TransportTransaction + '_>> {
+        Ok(Box::new(NoPublicKeyTransaction(
+            self.0.begin_transaction()?,
+            self.1,
+        )))
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> yubikey::Result<()> {
+        self.0.reconnect(disposition)
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> Result<(), (Box<dyn Transport>, Error)> {
+        Box::new(self.0).disconnect(disposition)
+    }
+}
+
+struct NoPublicKeyTransaction<'a>(Box<dyn TransportTransaction + 'a>, u8);
+
+impl TransportTransaction for NoPublicKeyTransaction<'_> {
+    fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> yubikey::Result<Vec<u8>> {
+        let mut response = self.0.transmit(send_buffer, recv_len)?;
+
+        // Algorithm, policy and origin come first, and are 10 bytes long
+        if send_buffer[1] == 0xf7 && send_buffer[3] == self.1 && response.ends_with(&[0x90, 0x00]) {
+            response.drain(10..response.len() - 2);
+        }
+
+        Ok(response)
+    }
+
+    fn end(self: Box<Self>, disposition: Disposition) -> yubikey::Result<()> {
+        self.0.end(disposition)
+    }
+}
+
+fn emulator() -> VirtualYubiKey {
+    VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap()
+}
+
+/// Open a session which has verified the PIN and authenticated with the
+/// default management key.
+fn authenticated(card: &VirtualYubiKey) -> YubiKey {
+    let mut yubikey = card.open().unwrap();
+    let default_key = MgmKey::get_default(&yubikey).unwrap();
+
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    assert!(yubikey.authenticate(&default_key).is_ok());
+    yubikey
+}
+
+#[test]
+fn test_open() {
+    let card = emulator();
+    let yubikey = card.open().unwrap();
+
+    assert_eq!(yubikey.name(), "Virtual YubiKey");
+    assert_eq!(yubikey.serial(), Serial(12345678));
+    assert_eq!(yubikey.version(), Version::new([5, 7, 2]));
+}
+
+#[test]
+fn test_unsupported_version() {
+    assert_eq!(
+        VirtualYubiKey::new(Serial(1), Version::new([4, 3, 7])).unwrap_err(),
+        Error::NotSupported
+    );
+}
+
+#[test]
+fn test_verify_pin() {
+    let card = emulator();
+    let mut yubikey = card.open().unwrap();
+
+    assert_eq!(yubikey.get_pin_retries(), Ok(3));
+    assert_eq!(
+        yubikey.verify_pin(b"000000"),
+        Err(Error::WrongPin { tries: 2 })
+    );
+    assert_eq!(yubikey.get_pin_retries(), Ok(2));
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    assert_eq!(yubikey.get_pin_retries(), Ok(3));
+}
+
+#[test]
+fn test_pin_blocked() {
+    let card = emulator();
+    let mut yubikey = card.open().unwrap();
+
+    for tries in (0..3).rev() {
+        assert_eq!(
+            yubikey.verify_pin(b"000000"),
+            Err(Error::WrongPin { tries })
+        );
+    }
+
+    assert_eq!(
+        yubikey.verify_pin(b"123456"),
+        Err(Error::WrongPin { tries: 0 })
+    );
+}
+
+#[test]
+fn test_authenticate() {
+    let card = emulator();
+    let mut yubikey = card.open().unwrap();
+
+    let default_key = MgmKey::get_default(&yubikey).unwrap();
+    assert!(yubikey.authenticate(&default_key).is_ok());
+
+    let wrong_key = MgmKey::generate_for(&yubikey, &mut rand::rng()).unwrap();
+    assert!(yubikey.authenticate(&wrong_key).is_err());
+}
+
+#[test]
+fn test_generate_requires_authentication() {
+    let card = emulator();
+    let mut yubikey = card.open().unwrap();
+
+    assert!(piv::generate(
+        &mut yubikey,
+        SlotId::Authentication,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .is_err());
+}
+
+#[test]
+fn test_generate_and_sign() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    let metadata = piv::metadata(&mut yubikey, slot).unwrap();
+    assert_eq!(
+        metadata.algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::EccP256)
+    );
+    assert_eq!(metadata.policy, Some((PinPolicy::Once, TouchPolicy::Never)));
+    assert_eq!(metadata.origin, Some(Origin::Generated));
+    assert_eq!(metadata.public.as_ref(), Some(&generated));
+
+    let digest = Sha256::digest(b"emulated signature");
+    let signature = piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, slot).unwrap();
+
+    let verifying_key = VerifyingKey::try_from(generated.owned_to_ref()).unwrap();
+    let signature = DerSignature::try_from(signature.as_slice()).unwrap();
+    assert!(verifying_key.verify_prehash(&digest, &signature).is_ok());
+}
+
+#[test]
+fn test_sign_requires_pin() {
+    let card = emulator();
+
+    {
+        let mut yubikey = authenticated(&card);
+        assert!(piv::generate(
+            &mut yubikey,
+            SlotId::Authentication,
+            AlgorithmId::EccP384,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .is_ok());
+    }
+
+    let mut yubikey = card.open().unwrap();
+    let digest = [0u8; 48];
+    assert!(piv::sign_data(
+        &mut yubikey,
+        &digest,
+        AlgorithmId::EccP384,
+        SlotId::Authentication
+    )
+    .is_err());
+
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    assert!(piv::sign_data(
+        &mut yubikey,
+        &digest,
+        AlgorithmId::EccP384,
+        SlotId::Authentication
+    )
+    .is_ok());
+}
+
+#[test]
+fn test_metadata_defaults() {
+    let card = emulator();
+    let mut yubikey = card.open().unwrap();
+
+    let pin = piv::metadata(&mut yubikey, SlotId::Management(ManagementSlotId::Pin)).unwrap();
+    assert_eq!(pin.default, Some(true));
+
+    let mgm = piv::metadata(
+        &mut yubikey,
+        SlotId::Management(ManagementSlotId::Management),
+    )
+    .unwrap();
+    assert_eq!(mgm.default, Some(true));
+
+    assert_eq!(
+        piv::metadata(&mut yubikey, SlotId::Retired(RetiredSlotId::R20)).unwrap_err(),
+        Error::NotFound
+    );
+}
+
+#[test]
+fn test_self_signed_certificate() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::KeyManagement;
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+
+    let cert = Certificate::generate_self_signed::<_, p256::NistP256>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=emulated").unwrap(),
+        generated,
+        |_builder| Ok(()),
+    )
+    .unwrap();
+
+    assert_eq!(
+        Certificate::read(&mut yubikey, slot).unwrap().cert,
+        cert.cert
+    );
+    assert_eq!(cert.subject(), "CN=emulated");
+}
+
+#[test]
+fn test_rsa_pss() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R6);
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::Rsa1024,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let public = RsaPublicKey::try_from(generated.owned_to_ref()).unwrap();
+
+    // Host-side EMSA-PSS encoding, with a configurable hash and salt length
+    let digest = Sha384::digest(b"message");
+    for salt_len in [0, 48] {
+        let signature =
+            piv::sign_pss::<Sha384>(&mut yubikey, &digest, salt_len, AlgorithmId::Rsa1024, slot)
+                .unwrap();
+
+        pss::VerifyingKey::<Sha384>::new_with_salt_len(public.clone(), salt_len)
+            .verify_prehash(&digest, &pss::Signature::try_from(&signature[..]).unwrap())
+            .unwrap();
+    }
+
+    assert_eq!(
+        piv::sign_pss::<Sha256>(&mut yubikey, &digest, 32, AlgorithmId::Rsa1024, slot),
+        Err(Error::SizeError)
+    );
+    assert_eq!(
+        piv::sign_pss::<Sha256>(&mut yubikey, &digest[..32], 32, AlgorithmId::EccP256, slot),
+        Err(Error::AlgorithmError)
+    );
+
+    // Certificates signed with RSASSA-PSS
+    let cert = Certificate::generate_self_signed::<_, YubiRsaPss<Rsa1024>>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=pss").unwrap(),
+        generated,
+        |_builder| Ok(()),
+    )
+    .unwrap();
+
+    let cert = &cert.cert;
+    assert_eq!(cert.signature_algorithm().oid, rfc5912::ID_RSASSA_PSS);
+
+    let signature = pss::Signature::try_from(cert.signature().raw_bytes()).unwrap();
+    pss::VerifyingKey::<Sha256>::new(public)
+        .verify(&cert.tbs_certificate().to_der().unwrap(), &signature)
+        .unwrap();
+}
+
+#[test]
+fn test_sign_message() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let message = b"message";
+
+    let mut generate = |slot, algorithm| {
+        piv::generate(
+            &mut yubikey,
+            slot,
+            algorithm,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .unwrap()
+    };
+
+    let p256_slot = SlotId::Retired(RetiredSlotId::R1);
+    let p256 =
+        VerifyingKey::try_from(generate(p256_slot, AlgorithmId::EccP256).owned_to_ref()).unwrap();
+    let p384_slot = SlotId::Retired(RetiredSlotId::R2);
+    let p384 = p384::ecdsa::VerifyingKey::try_from(
+        generate(p384_slot, AlgorithmId::EccP384).owned_to_ref(),
+    )
+    .unwrap();
+    let rsa_slot = SlotId::Retired(RetiredSlotId::R3);
+    let rsa =
+        RsaPublicKey::try_from(generate(rsa_slot, AlgorithmId::Rsa1024).owned_to_ref()).unwrap();
+    let ed25519_slot = SlotId::Retired(RetiredSlotId::R4);
+    let ed25519 = ed25519_dalek::VerifyingKey::try_from(
+        generate(ed25519_slot, AlgorithmId::Ed25519).owned_to_ref(),
+    )
+    .unwrap();
+
+    let mut sign =
+        |slot, hash, scheme| piv::sign_message(&mut yubikey, slot, message, hash, scheme);
+
+    // ECDSA, with digests truncated to the curve's order
+    let signature = sign(p256_slot, HashAlgorithm::Sha256, SignatureScheme::EcdsaDer).unwrap();
+    p256.verify(message, &DerSignature::from_bytes(&signature).unwrap())
+        .unwrap();
+
+    let signature = sign(
+        p256_slot,
+        HashAlgorithm::Sha512,
+        SignatureScheme::EcdsaFixed,
+    )
+    .unwrap();
+    p256.verify_prehash(
+        &sha2::Sha512::digest(message),
+        &p256::ecdsa::Signature::from_slice(&signature).unwrap(),
+    )
+    .unwrap();
+
+    let signature = sign(
+        p384_slot,
+        HashAlgorithm::Sha384,
+        SignatureScheme::EcdsaFixed,
+    )
+    .unwrap();
+    assert_eq!(signature.len(), 96);
+    p384.verify(
+        message,
+        &p384::ecdsa::Signature::from_slice(&signature).unwrap(),
+    )
+    .unwrap();
+
+    // RSA
+    let signature = sign(
+        rsa_slot,
+        HashAlgorithm::Sha512,
+        SignatureScheme::RsaPkcs1v15,
+    )
+    .unwrap();
+    rsa::pkcs1v15::VerifyingKey::<sha2::Sha512>::new(rsa.clone())
+        .verify(
+            message,
+            &rsa::pkcs1v15::Signature::try_from(&signature[..]).unwrap(),
+        )
+        .unwrap();
+
+    let signature = sign(rsa_slot, HashAlgorithm::Sha384, SignatureScheme::RsaPss).unwrap();
+    pss::VerifyingKey::<Sha384>::new(rsa)
+        .verify(message, &pss::Signature::try_from(&signature[..]).unwrap())
+        .unwrap();
+
+    // Ed25519, over the message itself
+    let signature = sign(
+        ed25519_slot,
+        HashAlgorithm::Sha256,
+        SignatureScheme::Ed25519,
+    )
+    .unwrap();
+    ed25519
+        .verify(
+            message,
+            &ed25519_dalek::Signature::from_slice(&signature).unwrap(),
+        )
+        .unwrap();
+
+    // Schemes not matching the key in the slot
+    assert_eq!(
+        sign(rsa_slot, HashAlgorithm::Sha256, SignatureScheme::EcdsaDer),
+        Err(Error::AlgorithmError)
+    );
+    assert_eq!(
+        sign(p256_slot, HashAlgorithm::Sha256, SignatureScheme::RsaPss),
+        Err(Error::AlgorithmError)
+    );
+    assert_eq!(
+        sign(
+            SlotId::KeyManagement,
+            HashAlgorithm::Sha256,
+            SignatureScheme::EcdsaDer
+        ),
+        Err(Error::NotFound)
+    );
+}
+
+#[test]
+fn test_signer() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let message = b"message";
+
+    let mut generate = |slot, algorithm| {
+        piv::generate(
+            &mut yubikey,
+            slot,
+            algorithm,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .unwrap();
+    };
+
+    let p384_slot = SlotId::Retired(RetiredSlotId::R1);
+    generate(p384_slot, AlgorithmId::EccP384);
+    let rsa_slot = SlotId::Retired(RetiredSlotId::R2);
+    generate(rsa_slot, AlgorithmId::Rsa1024);
+    let ed25519_slot = SlotId::Retired(RetiredSlotId::R3);
+    generate(ed25519_slot, AlgorithmId::Ed25519);
+
+    // ECDSA with SHA-384, with DER and fixed-size signatures
+    let signer = Signer::<p384::NistP384>::from_slot(&mut yubikey, p384_slot).unwrap();
+    let public = signer.verifying_key();
+
+    let signature: p384::ecdsa::DerSignature = signer.sign(message);
+    public.verify(message, &signature).unwrap();
+
+    let signature: p384::ecdsa::Signature = signer.sign(message);
+    public.verify(message, &signature).unwrap();
+
+    let signature: p384::ecdsa::Signature =
+        signer.sign_digest(|digest: &mut Sha384| digest.update(message));
+    public.verify(message, &signature).unwrap();
+
+    let prehash = Sha384::digest(message);
+    let signature: p384::ecdsa::DerSignature = signer.sign_prehash(&prehash).unwrap();
+    public.verify_prehash(&prehash, &signature).unwrap();
+
+    // RSASSA-PKCS1-v1_5 with a choice of hash
+    let signer =
+        Signer::<YubiRsa<Rsa1024, sha2::Sha512>>::from_slot(&mut yubikey, rsa_slot).unwrap();
+    signer
+        .verifying_key()
+        .verify(message, &signer.sign(message))
+        .unwrap();
+
+    // Ed25519, over the message itself
+    let signer =
+        Signer::<ed25519_dalek::SigningKey>::from_slot(&mut yubikey, ed25519_slot).unwrap();
+    signer
+        .verifying_key()
+        .verify(message, &signer.sign(message))
+        .unwrap();
+
+    // Key not matching the key type
+    assert!(matches!(
+        Signer::<p256::NistP256>::from_slot(&mut yubikey, p384_slot),
+        Err(Error::AlgorithmError)
+    ));
+}
+
+#[test]
+fn test_signer_from_certificate() {
+    // No metadata before firmware 5.3
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 2, 7])).unwrap();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        Signer::<p256::NistP256>::from_slot(&mut yubikey, slot),
+        Err(Error::InvalidObject)
+    ));
+
+    Certificate::generate_self_signed::<_, p256::NistP256>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=signer").unwrap(),
+        generated,
+        |_builder| Ok(()),
+    )
+    .unwrap();
+
+    let signer = Signer::<p256::NistP256>::from_slot(&mut yubikey, slot).unwrap();
+    let signature: p256::ecdsa::Signature = signer.sign(b"message");
+    signer
+        .verifying_key()
+        .verify(b"message", &signature)
+        .unwrap();
+}
+
+#[test]
+fn test_rsa_decrypt() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::KeyManagement;
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::Rsa1024,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let public = RsaPublicKey::try_from(generated.owned_to_ref()).unwrap();
+    let mut rng = rand::rng();
+
+    let ciphertext = public
+        .encrypt(&mut rng, Pkcs1v15Encrypt, b"pkcs1v15")
+        .unwrap();
+    let decrypted =
+        piv::decrypt_pkcs1v15(&mut yubikey, &ciphertext, AlgorithmId::Rsa1024, slot).unwrap();
+    assert_eq!(decrypted.as_slice(), b"pkcs1v15");
+
+    let ciphertext = public
+        .encrypt(&mut rng, Oaep::<Sha256>::new_with_label(*b"label"), b"oaep")
+        .unwrap();
+    let decrypted = piv::decrypt_oaep::<Sha256>(
+        &mut yubikey,
+        &ciphertext,
+        b"label",
+        AlgorithmId::Rsa1024,
+        slot,
+    )
+    .unwrap();
+    assert_eq!(decrypted.as_slice(), b"oaep");
+
+    // Wrong label, or wrong padding scheme
+    assert_eq!(
+        piv::decrypt_oaep::<Sha256>(&mut yubikey, &ciphertext, b"", AlgorithmId::Rsa1024, slot),
+        Err(Error::DecryptionError)
+    );
+    assert_eq!(
+        piv::decrypt_pkcs1v15(&mut yubikey, &ciphertext, AlgorithmId::Rsa1024, slot),
+        Err(Error::DecryptionError)
+    );
+
+    let ciphertext = public
+        .encrypt(&mut rng, Oaep::<Sha1>::new(), b"oaep-sha1")
+        .unwrap();
+    let decrypted =
+        piv::decrypt_oaep::<Sha1>(&mut yubikey, &ciphertext, b"", AlgorithmId::Rsa1024, slot)
+            .unwrap();
+    assert_eq!(decrypted.as_slice(), b"oaep-sha1");
+}
+
+#[test]
+fn test_ecdh() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let mut rng = rand::rng();
+
+    // P-256
+    let slot = SlotId::Retired(RetiredSlotId::R7);
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let public = p256::PublicKey::try_from(generated.owned_to_ref()).unwrap();
+    let ephemeral = p256::ecdh::EphemeralSecret::generate_from_rng(&mut rng);
+
+    let secret = piv::ecdh(&mut yubikey, slot, &ephemeral.public_key()).unwrap();
+    assert_eq!(
+        secret.as_slice(),
+        ephemeral
+            .diffie_hellman(&public)
+            .raw_secret_bytes()
+            .as_slice()
+    );
+
+    // Peer key for another algorithm
+    let other = p384::ecdh::EphemeralSecret::generate_from_rng(&mut rng);
+    assert_eq!(
+        piv::ecdh(&mut yubikey, slot, &other.public_key()),
+        Err(Error::AlgorithmError)
+    );
+
+    // P-384
+    let slot = SlotId::Retired(RetiredSlotId::R8);
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP384,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let public = p384::PublicKey::try_from(generated.owned_to_ref()).unwrap();
+
+    let secret = piv::ecdh(&mut yubikey, slot, &other.public_key()).unwrap();
+    assert_eq!(
+        secret.as_slice(),
+        other.diffie_hellman(&public).raw_secret_bytes().as_slice()
+    );
+
+    // X25519
+    let slot = SlotId::Retired(RetiredSlotId::R9);
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::X25519,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let public: [u8; 32] = generated.subject_public_key.raw_bytes().try_into().unwrap();
+    let ephemeral = x25519_dalek::EphemeralSecret::random_from_rng(&mut rng);
+    let peer = x25519_dalek::PublicKey::from(&ephemeral);
+
+    let secret = piv::ecdh(&mut yubikey, slot, &peer).unwrap();
+    assert_eq!(
+        secret.as_slice(),
+        ephemeral
+            .diffie_hellman(&x25519_dalek::PublicKey::from(public))
+            .as_bytes()
+    );
+
+    // Low order point
+    assert_eq!(
+        piv::ecdh(&mut yubikey, slot, &x25519_dalek::PublicKey::from([0; 32])),
+        Err(Error::KeyError)
+    );
+
+    // Empty slot
+    assert_eq!(
+        piv::ecdh(&mut yubikey, SlotId::Retired(RetiredSlotId::R10), &peer),
+        Err(Error::NotFound)
+    );
+}
+
+#[test]
+fn test_slot_key() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R2);
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    let key = piv::SlotKey::read(&mut yubikey, slot).unwrap();
+    assert_eq!(key.slot(), slot);
+    assert_eq!(key.algorithm(), AlgorithmId::EccP256);
+    assert_eq!(key.origin(), Some(Origin::Generated));
+    assert_eq!(key.pin_policy(), Some(PinPolicy::Once));
+    assert_eq!(key.touch_policy(), Some(TouchPolicy::Never));
+    assert_eq!(key.public_key(), &generated);
+
+    let verifying_key = VerifyingKey::try_from(generated.owned_to_ref()).unwrap();
+    let signature = key
+        .sign(
+            &mut yubikey,
+            b"message",
+            HashAlgorithm::Sha256,
+            SignatureScheme::EcdsaDer,
+        )
+        .unwrap();
+    verifying_key
+        .verify(
+            b"message",
+            &DerSignature::try_from(signature.as_slice()).unwrap(),
+        )
+        .unwrap();
+
+    let digest = Sha256::digest(b"message");
+    let signature = key.sign_data(&mut yubikey, &digest).unwrap();
+    verifying_key
+        .verify_prehash(
+            &digest,
+            &DerSignature::try_from(signature.as_slice()).unwrap(),
+        )
+        .unwrap();
+
+    // Operations not matching the key's algorithm
+    let mut rng = rand::rng();
+    let ephemeral = p256::ecdh::EphemeralSecret::generate_from_rng(&mut rng);
+    let public = p256::PublicKey::try_from(generated.owned_to_ref()).unwrap();
+    let secret = key.ecdh(&mut yubikey, &ephemeral.public_key()).unwrap();
+    assert_eq!(
+        secret.as_slice(),
+        ephemeral
+            .diffie_hellman(&public)
+            .raw_secret_bytes()
+            .as_slice()
+    );
+
+    let other = p384::ecdh::EphemeralSecret::generate_from_rng(&mut rng);
+    assert_eq!(
+        key.ecdh(&mut yubikey, &other.public_key()),
+        Err(Error::AlgorithmError)
+    );
+    assert_eq!(
+        key.sign(
+            &mut yubikey,
+            b"message",
+            HashAlgorithm::Sha256,
+            SignatureScheme::RsaPss,
+        ),
+        Err(Error::AlgorithmError)
+    );
+
+    // No certificate yet
+    assert!(matches!(
+        key.certificate(&mut yubikey),
+        Err(Error::InvalidObject)
+    ));
+
+    // Empty slot
+    assert!(matches!(
+        piv::SlotKey::read(&mut yubikey, SlotId::Retired(RetiredSlotId::R3)),
+        Err(Error::NotFound)
+    ));
+
+    // Management key
+    assert!(matches!(
+        piv::SlotKey::read(
+            &mut yubikey,
+            SlotId::Management(ManagementSlotId::Management)
+        ),
+        Err(Error::AlgorithmError)
+    ));
+}
+
+#[test]
+fn test_slot_key_from_certificate() {
+    // No metadata before firmware 5.3
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 2, 7])).unwrap();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R2);
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::Rsa1024,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        piv::SlotKey::read(&mut yubikey, slot),
+        Err(Error::InvalidObject)
+    ));
+
+    Certificate::generate_self_signed::<_, YubiRsa<Rsa1024>>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=slot key").unwrap(),
+        generated.clone(),
+        |_builder| Ok(()),
+    )
+    .unwrap();
+
+    let key = piv::SlotKey::read(&mut yubikey, slot).unwrap();
+    assert_eq!(key.algorithm(), AlgorithmId::Rsa1024);
+    assert_eq!(key.origin(), None);
+    assert_eq!(key.pin_policy(), None);
+    assert_eq!(key.touch_policy(), None);
+    assert_eq!(key.public_key(), &generated);
+    assert_eq!(
+        key.certificate(&mut yubikey).unwrap().subject_pki(),
+        generated.owned_to_ref()
+    );
+
+    let public = RsaPublicKey::try_from(generated.owned_to_ref()).unwrap();
+    let ciphertext = public
+        .encrypt(&mut rand::rng(), Pkcs1v15Encrypt, b"pkcs1v15")
+        .unwrap();
+    let decrypted = key.decrypt_pkcs1v15(&mut yubikey, &ciphertext).unwrap();
+    assert_eq!(decrypted.as_slice(), b"pkcs1v15");
+}
+
+#[test]
+fn test_slot_status() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+
+    // Key without certificate
+    piv::generate(
+        &mut yubikey,
+        SlotId::Retired(RetiredSlotId::R1),
+        AlgorithmId::EccP384,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    // Key with its certificate
+    let slot = SlotId::Authentication;
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    Certificate::generate_self_signed::<_, p256::NistP256>(
+        &mut yubikey,
+        slot,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=status").unwrap(),
+        generated,
+        |_builder| Ok(()),
+    )
+    .unwrap();
+
+    // Certificate without key, and certificate for another key
+    let bob = Certificate::from_bytes(std::fs::read("tests/assets/Bob.der").unwrap()).unwrap();
+    bob.write(
+        &mut yubikey,
+        SlotId::Retired(RetiredSlotId::R2),
+        CertInfo::Uncompressed,
+    )
+    .unwrap();
+    piv::generate(
+        &mut yubikey,
+        SlotId::Retired(RetiredSlotId::R3),
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    bob.write(
+        &mut yubikey,
+        SlotId::Retired(RetiredSlotId::R3),
+        CertInfo::Uncompressed,
+    )
+    .unwrap();
+
+    let slots = piv::SlotStatus::list(&mut yubikey).unwrap();
+    assert_eq!(
+        slots.iter().map(|status| status.slot()).collect::<Vec<_>>(),
+        piv::KEY_SLOTS
+    );
+
+    let status = |slot| slots.iter().find(|status| status.slot() == slot).unwrap();
+
+    let r1 = status(SlotId::Retired(RetiredSlotId::R1));
+    assert_eq!(r1.is_present(), Some(true));
+    assert_eq!(r1.key().unwrap().algorithm(), AlgorithmId::EccP384);
+    assert_eq!(r1.key().unwrap().origin(), Some(Origin::Generated));
+    assert_eq!(r1.has_default_policies(), Some(false));
+    assert!(r1.certificate().is_none());
+    assert_eq!(r1.certificate_matches(), None);
+
+    let authentication = status(SlotId::Authentication);
+    assert_eq!(authentication.is_present(), Some(true));
+    assert_eq!(authentication.has_default_policies(), Some(true));
+    assert_eq!(authentication.certificate_matches(), Some(true));
+
+    let r2 = status(SlotId::Retired(RetiredSlotId::R2));
+    assert_eq!(r2.is_present(), Some(false));
+    assert!(r2.key().is_none());
+    assert_eq!(r2.certificate_matches(), Some(false));
+
+    let r3 = status(SlotId::Retired(RetiredSlotId::R3));
+    assert_eq!(r3.is_present(), Some(true));
+    assert_eq!(r3.certificate_matches(), Some(false));
+
+    // The attestation key is present out of the box
+    let attestation = status(SlotId::Attestation);
+    assert_eq!(attestation.is_present(), Some(true));
+    assert_eq!(attestation.certificate_matches(), Some(true));
+
+    let empty = status(SlotId::Signature);
+    assert_eq!(empty.is_present(), Some(false));
+    assert!(empty.certificate().is_none());
+    assert_eq!(empty.has_default_policies(), None);
+}
+
+#[test]
+fn test_slot_status_without_metadata() {
+    // No metadata before firmware 5.3
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 2, 7])).unwrap();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+
+    let bob = Certificate::from_bytes(std::fs::read("tests/assets/Bob.der").unwrap()).unwrap();
+    bob.write(&mut yubikey, slot, CertInfo::Uncompressed)
+        .unwrap();
+
+    let slots = piv::SlotStatus::list(&mut yubikey).unwrap();
+    assert_eq!(slots.len(), piv::KEY_SLOTS.len());
+
+    for status in &slots {
+        assert_eq!(status.is_present(), None);
+        assert!(status.key().is_none());
+        assert_eq!(status.certificate_matches(), None);
+    }
+
+    let r1 = slots.iter().find(|status| status.slot() == slot).unwrap();
+    assert_eq!(r1.certificate().unwrap().cert, bob.cert);
+}
+
+#[test]
+fn test_slot_status_with_invalid_metadata() {
+    let card = emulator();
+    let r1 = SlotId::Retired(RetiredSlotId::R1);
+    let r2 = SlotId::Retired(RetiredSlotId::R2);
+
+    let mut yubikey = authenticated(&card);
+    for slot in [r1, r2] {
+        piv::generate(
+            &mut yubikey,
+            slot,
+            AlgorithmId::EccP256,
+            PinPolicy::Default,
+            TouchPolicy::Default,
+        )
+        .unwrap();
+    }
+    drop(yubikey);
+
+    let mut yubikey = YubiKey::open_transport(NoPublicKey(card, r1.into()), "test").unwrap();
+    let slots = piv::SlotStatus::list(&mut yubikey).unwrap();
+    assert_eq!(slots.len(), piv::KEY_SLOTS.len());
+
+    let status = |slot| slots.iter().find(|status| status.slot() == slot).unwrap();
+
+    assert_eq!(status(r1).is_present(), Some(true));
+    assert!(status(r1).key().is_none());
+    assert_eq!(status(r1).key_error(), Some(Error::InvalidObject));
+
+    assert_eq!(status(r2).is_present(), Some(true));
+    assert_eq!(status(r2).key().unwrap().algorithm(), AlgorithmId::EccP256);
+    assert_eq!(status(r2).key_error(), None);
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_slot_status_with_invalid_certificates() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let r1 = SlotId::Retired(RetiredSlotId::R1);
+    let r2 = SlotId::Retired(RetiredSlotId::R2);
+    let r3 = SlotId::Retired(RetiredSlotId::R3);
+
+    // Gzip-compressed certificate (certinfo 0x01) in R1, garbage in R2
+    let mut compressed = [
+        0x70, 0x0a, 0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x71, 0x01, 0x01,
+        0xfe, 0x00,
+    ];
+    assert!(yubikey.save_object(0x005f_c10d, &mut compressed).is_ok());
+    let mut garbage = [0x70, 0x03, 0x01, 0x02, 0x03, 0xfe, 0x00];
+    assert!(yubikey.save_object(0x005f_c10e, &mut garbage).is_ok());
+
+    let bob = Certificate::from_bytes(std::fs::read("tests/assets/Bob.der").unwrap()).unwrap();
+    bob.write(&mut yubikey, r3, CertInfo::Uncompressed).unwrap();
+
+    let slots = piv::SlotStatus::list(&mut yubikey).unwrap();
+    assert_eq!(slots.len(), piv::KEY_SLOTS.len());
+
+    let status = |slot| slots.iter().find(|status| status.slot() == slot).unwrap();
+
+    for slot in [r1, r2] {
+        assert!(status(slot).certificate().is_none());
+        assert_eq!(status(slot).certificate_error(), Some(Error::InvalidObject));
+        assert_eq!(status(slot).certificate_matches(), None);
+    }
+
+    assert_eq!(status(r3).certificate().unwrap().cert, bob.cert);
+    assert_eq!(status(r3).certificate_error(), None);
+}
+
+#[test]
+fn test_move_key() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let r1 = SlotId::Retired(RetiredSlotId::R1);
+    let r2 = SlotId::Retired(RetiredSlotId::R2);
+    let r3 = SlotId::Retired(RetiredSlotId::R3);
+
+    let generated = piv::generate(
+        &mut yubikey,
+        r1,
+        AlgorithmId::EccP256,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+    Certificate::generate_self_signed::<_, p256::NistP256>(
+        &mut yubikey,
+        r1,
+        SerialNumber::new(&[0x01]).unwrap(),
+        Validity::from_now(Duration::new(500000, 0)).unwrap(),
+        Name::from_str("CN=moved").unwrap(),
+        generated.clone(),
+        |_builder| Ok(()),
+    )
+    .unwrap();
+
+    // With its certificate
+    piv::move_key_and_certificate(&mut yubikey, r1, r2).unwrap();
+    assert!(matches!(
+        piv::metadata(&mut yubikey, r1),
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(
+        Certificate::read(&mut yubikey, r1),
+        Err(Error::InvalidObject)
+    ));
+
+    let metadata = piv::metadata(&mut yubikey, r2).unwrap();
+    assert_eq!(metadata.public, Some(generated.clone()));
+    assert_eq!(
+        metadata.policy,
+        Some((PinPolicy::Never, TouchPolicy::Never))
+    );
+    assert_eq!(
+        Certificate::read(&mut yubikey, r2).unwrap().subject(),
+        "CN=moved"
+    );
+
+    let digest = Sha256::digest(b"moved");
+    let signature = piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, r2).unwrap();
+    VerifyingKey::try_from(generated.owned_to_ref())
+        .unwrap()
+        .verify_prehash(
+            &digest,
+            &DerSignature::try_from(signature.as_slice()).unwrap(),
+        )
+        .unwrap();
+
+    // Keys can't be moved over another key
+    piv::generate(
+        &mut yubikey,
+        r3,
+        AlgorithmId::EccP384,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    assert_eq!(
+        piv::move_key_and_certificate(&mut yubikey, r2, r3),
+        Err(Error::SlotOccupied)
+    );
+    assert_eq!(
+        piv::metadata(&mut yubikey, r2).unwrap().public,
+        Some(generated.clone())
+    );
+    assert_eq!(
+        piv::metadata(&mut yubikey, r3).unwrap().algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::EccP384)
+    );
+    assert_eq!(
+        Certificate::read(&mut yubikey, r2).unwrap().subject(),
+        "CN=moved"
+    );
+    piv::delete_key(&mut yubikey, r3).unwrap();
+
+    // Without its certificate
+    piv::move_key(&mut yubikey, r2, r3).unwrap();
+    assert_eq!(
+        piv::metadata(&mut yubikey, r3).unwrap().public,
+        Some(generated)
+    );
+    assert!(Certificate::read(&mut yubikey, r2).is_ok());
+
+    piv::delete_key(&mut yubikey, r3).unwrap();
+    assert!(matches!(
+        piv::metadata(&mut yubikey, r3),
+        Err(Error::NotFound)
+    ));
+    assert_eq!(piv::delete_key(&mut yubikey, r3), Err(Error::NotFound));
+    assert_eq!(piv::move_key(&mut yubikey, r3, r1), Err(Error::NotFound));
+
+    // The attestation key can't be moved
+    assert_eq!(
+        piv::move_key(&mut yubikey, SlotId::Attestation, r1),
+        Err(Error::KeyError)
+    );
+
+    // Requires the management key
+    let mut yubikey = card.open().unwrap();
+    assert_eq!(
+        piv::delete_key(&mut yubikey, SlotId::Attestation),
+        Err(Error::AuthenticationError)
+    );
+}
+
+#[test]
+fn test_move_key_unsupported() {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 4, 3])).unwrap();
+    let mut yubikey = authenticated(&card);
+
+    assert_eq!(
+        piv::delete_key(&mut yubikey, SlotId::Attestation),
+        Err(Error::NotSupported)
+    );
+}
+
+#[test]
+fn test_write_certificate() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R5);
+
+    let bob = Certificate::from_bytes(std::fs::read("tests/assets/Bob.der").unwrap()).unwrap();
+    assert!(bob
+        .write(&mut yubikey, slot, CertInfo::Uncompressed)
+        .is_ok());
+    assert_eq!(
+        Certificate::read(&mut yubikey, slot).unwrap().cert,
+        bob.cert
+    );
+
+    // Attestation certificate is present out of the box
+    let attestation = Certificate::read(&mut yubikey, SlotId::Attestation).unwrap();
+    assert_eq!(attestation.subject(), "CN=Yubico PIV Attestation");
+}
+
+#[test]
+fn test_sessions_share_state() {
+    let card = emulator();
+
+    {
+        let mut yubikey = card.open().unwrap();
+        assert!(yubikey.verify_pin(b"000000").is_err());
+    }
+
+    let mut yubikey = card.clone().open().unwrap();
+    assert_eq!(yubikey.get_pin_retries(), Ok(2));
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_change_and_unblock_pin() {
+    let card = emulator();
+    let mut yubikey = card.open().unwrap();
+
+    assert!(yubikey.change_pin(b"123456", b"654321").is_ok());
+    assert!(yubikey.verify_pin(b"654321").is_ok());
+
+    for _ in 0..3 {
+        assert!(yubikey.verify_pin(b"000000").is_err());
+    }
+    assert_eq!(
+        yubikey.verify_pin(b"654321"),
+        Err(Error::WrongPin { tries: 0 })
+    );
+
+    assert!(yubikey.unblock_pin(b"12345678", b"111111").is_ok());
+    assert!(yubikey.verify_pin(b"111111").is_ok());
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_attest() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Signature;
+
+    let generated = piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::Ed25519,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+
+    let attestation = Certificate::from_bytes(piv::attest(&mut yubikey, slot).unwrap()).unwrap();
+    assert_eq!(attestation.issuer(), "CN=Yubico PIV Attestation");
+    assert_eq!(attestation.subject(), "CN=YubiKey PIV Attestation 9c");
+    assert_eq!(attestation.subject_pki(), generated.owned_to_ref());
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_objects() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let object_id = 0x005f_c10d;
+
+    assert_eq!(
+        yubikey.fetch_object(object_id).unwrap_err(),
+        Error::NotFound
+    );
+
+    let mut data = [0x01, 0x02, 0x03];
+    assert!(yubikey.save_object(object_id, &mut data).is_ok());
+    assert_eq!(
+        yubikey.fetch_object(object_id).unwrap().as_slice(),
+        &[0x01, 0x02, 0x03]
+    );
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_set_mgmkey() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let default_key = MgmKey::get_default(&yubikey).unwrap();
+
+    let manual = MgmKey::generate_for(&yubikey, &mut rand::rng()).unwrap();
+    assert!(manual.set_manual(&mut yubikey, false).is_ok());
+    assert!(yubikey.authenticate(&default_key).is_err());
+    assert!(yubikey.authenticate(&manual).is_ok());
+
+    let mgm = piv::metadata(
+        &mut yubikey,
+        SlotId::Management(ManagementSlotId::Management),
+    )
+    .unwrap();
+    assert_eq!(mgm.default, Some(false));
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_reset() {
+    let card = emulator();
+    let mut yubikey = authenticated(&card);
+    let slot = SlotId::Retired(RetiredSlotId::R1);
+
+    assert!(piv::generate(
+        &mut yubikey,
+        slot,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .is_ok());
+
+    // Reset requires both the PIN and PUK to be blocked
+    assert!(yubikey.reset_device().is_err());
+
+    for _ in 0..3 {
+        assert!(yubikey.verify_pin(b"000000").is_err());
+    }
+    assert!(yubikey.block_puk().is_ok());
+    assert!(yubikey.reset_device().is_ok());
+
+    let mut yubikey = card.open().unwrap();
+    assert_eq!(yubikey.get_pin_retries(), Ok(3));
+    assert_eq!(
+        piv::metadata(&mut yubikey, slot).unwrap_err(),
+        Error::NotFound
+    );
+    assert!(Certificate::read(&mut yubikey, SlotId::Attestation).is_ok());
+}
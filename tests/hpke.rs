@@ -0,0 +1,179 @@
+//! Tests for HPKE receiver contexts, using the software PIV emulator
+
+#![cfg(all(feature = "hpke", feature = "untested"))]
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use yubikey::{
+    emulator::VirtualYubiKey,
+    hpke::{Aead, Kdf, Kem, Receiver, Suite},
+    piv::{self, AlgorithmId, SlotId},
+    Error, MgmKey, PinPolicy, Serial, TouchPolicy, Version, YubiKey,
+};
+
+const SLOT: SlotId = SlotId::KeyManagement;
+
+/// RFC 9180 test vector A.1.1: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256,
+/// AES-128-GCM, Base mode
+mod base_vector {
+    pub const SK_RM: &str = "4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8";
+    pub const ENC: &str = "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431";
+    pub const INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    pub const PT: &str = "4265617574792069732074727574682c20747275746820626561757479";
+
+    /// (aad, ciphertext) of the first two messages
+    pub const MESSAGES: [(&str, &str); 2] = [
+        (
+            "436f756e742d30",
+            "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d8770ac83d07bea87e13c512a",
+        ),
+        (
+            "436f756e742d31",
+            "af2d7e9ac9ae7e270f46ba1f975be53c09f8d875bdc8535458c2494e8a6eab251c03d0c22a56b8ca42c2063b84",
+        ),
+    ];
+
+    /// (exporter context, exported value) with a length of 32 bytes
+    pub const EXPORTS: [(&str, &str); 3] = [
+        (
+            "",
+            "3853fe2b4035195a573ffc53856e77058e15d9ea064de3e59f4961d0095250ee",
+        ),
+        (
+            "00",
+            "2e8f0b54673c7029649d4eb9d5e33bf1872cf76d623ff164ac185da9e88c21a5",
+        ),
+        (
+            "54657374436f6e74657874",
+            "e9e43065102c3836401bed8c3c3c75ae46be1639869391d62c61f1ec7af54931",
+        ),
+    ];
+}
+
+/// RFC 9180 test vector A.1.3: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256,
+/// AES-128-GCM, Auth mode
+mod auth_vector {
+    pub const SK_RM: &str = "fdea67cf831f1ca98d8e27b1f6abeb5b7745e9d35348b80fa407ff6958f9137e";
+    pub const PK_SM: &str = "8b0c70873dc5aecb7f9ee4e62406a397b350e57012be45cf53b7105ae731790b";
+    pub const ENC: &str = "23fb952571a14a25e3d678140cd0e5eb47a0961bb18afcf85896e5453c312e76";
+    pub const AAD: &str = "436f756e742d30";
+    pub const CT: &str = "5fd92cc9d46dbf8943e72a07e42f363ed5f721212cd90bcfd072bfd9f44e06b80fd17824947496e21b680c141b";
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    hex::lower::decode_vec(s).unwrap()
+}
+
+/// Open a session with the X25519 key `sk_rm` in [`SLOT`].
+fn setup(sk_rm: &str) -> YubiKey {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let mut yubikey = card.open().unwrap();
+
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey
+        .authenticate(&MgmKey::get_default(&yubikey).unwrap())
+        .unwrap();
+    piv::import_cv_key(
+        &mut yubikey,
+        SLOT,
+        AlgorithmId::X25519,
+        &unhex(sk_rm),
+        TouchPolicy::Never,
+        PinPolicy::Never,
+    )
+    .unwrap();
+
+    yubikey
+}
+
+fn suite() -> Suite {
+    Suite::new(Kem::X25519HkdfSha256, Kdf::HkdfSha256, Aead::Aes128Gcm)
+}
+
+#[test]
+fn test_base_vector() {
+    let mut yubikey = setup(base_vector::SK_RM);
+    let mut receiver = Receiver::setup_base(
+        &mut yubikey,
+        SLOT,
+        suite(),
+        &unhex(base_vector::ENC),
+        &unhex(base_vector::INFO),
+    )
+    .unwrap();
+
+    for (aad, ciphertext) in base_vector::MESSAGES {
+        let plaintext = receiver.open(&unhex(aad), &unhex(ciphertext)).unwrap();
+        assert_eq!(plaintext.as_slice(), unhex(base_vector::PT));
+    }
+
+    for (context, exported) in base_vector::EXPORTS {
+        let secret = receiver.export(&unhex(context), 32).unwrap();
+        assert_eq!(secret.as_slice(), unhex(exported));
+    }
+}
+
+#[test]
+fn test_auth_vector() {
+    let mut yubikey = setup(auth_vector::SK_RM);
+    let mut receiver = Receiver::setup_auth(
+        &mut yubikey,
+        SLOT,
+        suite(),
+        &unhex(auth_vector::ENC),
+        &unhex(base_vector::INFO),
+        &unhex(auth_vector::PK_SM),
+    )
+    .unwrap();
+
+    let plaintext = receiver
+        .open(&unhex(auth_vector::AAD), &unhex(auth_vector::CT))
+        .unwrap();
+    assert_eq!(plaintext.as_slice(), unhex(base_vector::PT));
+}
+
+#[test]
+fn test_open_errors() {
+    let mut yubikey = setup(base_vector::SK_RM);
+    let enc = unhex(base_vector::ENC);
+    let info = unhex(base_vector::INFO);
+    let mut receiver = Receiver::setup_base(&mut yubikey, SLOT, suite(), &enc, &info).unwrap();
+
+    // Wrong associated data, or message out of order
+    let (aad, ciphertext) = base_vector::MESSAGES[0];
+    let (_, next) = base_vector::MESSAGES[1];
+    assert_eq!(
+        receiver.open(b"", &unhex(ciphertext)),
+        Err(Error::DecryptionError)
+    );
+    assert_eq!(
+        receiver.open(&unhex(aad), &unhex(next)),
+        Err(Error::DecryptionError)
+    );
+
+    // Failures don't advance the context
+    receiver.open(&unhex(aad), &unhex(ciphertext)).unwrap();
+
+    // Wrong info
+    let mut receiver = Receiver::setup_base(&mut yubikey, SLOT, suite(), &enc, b"").unwrap();
+    assert_eq!(
+        receiver.open(&unhex(aad), &unhex(ciphertext)),
+        Err(Error::DecryptionError)
+    );
+
+    // Malformed encapsulated key
+    assert_eq!(
+        Receiver::setup_base(&mut yubikey, SLOT, suite(), &enc[1..], &info).err(),
+        Some(Error::KeyError)
+    );
+
+    // KEM not matching the key in the slot
+    let suite = Suite::new(Kem::P256HkdfSha256, Kdf::HkdfSha256, Aead::Aes128Gcm);
+    let enc = unhex(
+        "04a92719c6195d5085104f469a8b9814d5838ff72b60501e2c4466e5e67b325ac98536d7b61a1af4b78e5b7f951c0900be863c403ce65c9bfcb9382657222d18c4",
+    );
+    assert_eq!(
+        Receiver::setup_base(&mut yubikey, SLOT, suite, &enc, &info).err(),
+        Some(Error::AlgorithmError)
+    );
+}
@@ -0,0 +1,369 @@
+//! Tests for importing private keys from standard encodings, using the
+//! software PIV emulator
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use der::referenced::OwnedToRef;
+use p256::ecdsa::{signature::hazmat::PrehashVerifier, DerSignature, VerifyingKey};
+use p256::pkcs8::DecodePrivateKey;
+use rsa::{Pkcs1v15Encrypt, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use yubikey::{
+    certificate::{CertInfo, Certificate},
+    emulator::VirtualYubiKey,
+    piv::{self, AlgorithmId, ManagementSlotId, Origin, RetiredSlotId, SlotAlgorithmId, SlotId},
+    Error, MgmKey, PinPolicy, Serial, TouchPolicy, Version, YubiKey,
+};
+
+const SLOT: SlotId = SlotId::Retired(RetiredSlotId::R1);
+const PASSPHRASE: &[u8] = b"yubikey";
+
+fn key(name: &str) -> Vec<u8> {
+    std::fs::read(format!("tests/assets/keys/{name}")).unwrap()
+}
+
+/// Open a session which has authenticated with the default management key.
+fn authenticated(version: [u8; 3]) -> YubiKey {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new(version)).unwrap();
+    let mut yubikey = card.open().unwrap();
+
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey
+        .authenticate(&MgmKey::get_default(&yubikey).unwrap())
+        .unwrap();
+    yubikey
+}
+
+fn import(yubikey: &mut YubiKey, name: &str, passphrase: Option<&[u8]>) -> yubikey::Result<()> {
+    piv::import_key(
+        yubikey,
+        SLOT,
+        &key(name),
+        passphrase,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .map(|_| ())
+}
+
+#[test]
+fn test_import_sec1() {
+    let mut yubikey = authenticated([5, 7, 2]);
+
+    let public = piv::import_key(
+        &mut yubikey,
+        SLOT,
+        &key("p256-sec1.pem"),
+        None,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    let metadata = piv::metadata(&mut yubikey, SLOT).unwrap();
+    assert_eq!(
+        metadata.algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::EccP256)
+    );
+    assert_eq!(metadata.origin, Some(Origin::Imported));
+    assert_eq!(metadata.policy, Some((PinPolicy::Once, TouchPolicy::Never)));
+    assert_eq!(metadata.public, Some(public.clone()));
+
+    let digest = Sha256::digest(b"imported");
+    let signature = piv::sign_data(&mut yubikey, &digest, AlgorithmId::EccP256, SLOT).unwrap();
+    VerifyingKey::try_from(public.owned_to_ref())
+        .unwrap()
+        .verify_prehash(
+            &digest,
+            &DerSignature::try_from(signature.as_slice()).unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn test_import_pkcs8() {
+    let mut yubikey = authenticated([5, 7, 2]);
+
+    // Encrypted DER, with PBKDF2-HMAC-SHA256 and AES-256-CBC
+    let public = piv::import_key(
+        &mut yubikey,
+        SLOT,
+        &key("p384-encrypted.der"),
+        Some(PASSPHRASE),
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+    assert_eq!(
+        piv::metadata(&mut yubikey, SLOT).unwrap().algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::EccP384)
+    );
+    assert!(p384::PublicKey::try_from(public.owned_to_ref()).is_ok());
+
+    // Encrypted PEM, with PBKDF2-HMAC-SHA1 and AES-128-CBC
+    let public = piv::import_key(
+        &mut yubikey,
+        SLOT,
+        &key("rsa1024-encrypted.pem"),
+        Some(PASSPHRASE),
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+
+    let ciphertext = RsaPublicKey::try_from(public.owned_to_ref())
+        .unwrap()
+        .encrypt(&mut rand::rng(), Pkcs1v15Encrypt, b"imported")
+        .unwrap();
+    let decrypted =
+        piv::decrypt_pkcs1v15(&mut yubikey, &ciphertext, AlgorithmId::Rsa1024, SLOT).unwrap();
+    assert_eq!(decrypted.as_slice(), b"imported");
+
+    // Plain DER
+    import(&mut yubikey, "rsa2048.der", None).unwrap();
+    assert_eq!(
+        piv::metadata(&mut yubikey, SLOT).unwrap().algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::Rsa2048)
+    );
+
+    // Plain PEM
+    let ed25519 = std::str::from_utf8(&key("ed25519.pem"))
+        .map(ed25519_dalek::SigningKey::from_pkcs8_pem)
+        .unwrap()
+        .unwrap();
+    let public = piv::import_key(
+        &mut yubikey,
+        SLOT,
+        &key("ed25519.pem"),
+        None,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+    )
+    .unwrap();
+    assert_eq!(
+        public.subject_public_key.raw_bytes(),
+        ed25519.verifying_key().as_bytes()
+    );
+
+    import(&mut yubikey, "x25519.pem", None).unwrap();
+    assert_eq!(
+        piv::metadata(&mut yubikey, SLOT).unwrap().algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::X25519)
+    );
+}
+
+#[test]
+fn test_import_errors() {
+    let mut yubikey = authenticated([5, 7, 2]);
+
+    // Missing or wrong passphrase
+    assert_eq!(
+        import(&mut yubikey, "p384-encrypted.der", None),
+        Err(Error::ArgumentError)
+    );
+    assert_eq!(
+        import(&mut yubikey, "p384-encrypted.der", Some(b"wrong")),
+        Err(Error::DecryptionError)
+    );
+
+    // Unsupported encryption (scrypt) and public exponent
+    assert_eq!(
+        import(&mut yubikey, "p256-scrypt.pem", Some(PASSPHRASE)),
+        Err(Error::AlgorithmError)
+    );
+    assert_eq!(
+        import(&mut yubikey, "rsa1024-e3.pem", None),
+        Err(Error::AlgorithmError)
+    );
+
+    // Primes of unequal lengths (1040 and 1008 bits) don't fit the card's
+    // fixed-size parameters
+    assert_eq!(
+        import(&mut yubikey, "rsa2048-unbalanced.pem", None),
+        Err(Error::AlgorithmError)
+    );
+
+    // Not a private key
+    assert_eq!(
+        piv::import_key(
+            &mut yubikey,
+            SLOT,
+            b"not a key",
+            None,
+            PinPolicy::Never,
+            TouchPolicy::Never,
+        ),
+        Err(Error::ParseError)
+    );
+
+    // Management slot
+    assert_eq!(
+        piv::import_key(
+            &mut yubikey,
+            SlotId::Management(ManagementSlotId::Management),
+            &key("p256-sec1.pem"),
+            None,
+            PinPolicy::Never,
+            TouchPolicy::Never,
+        ),
+        Err(Error::KeyError)
+    );
+
+    // Nothing was imported
+    assert!(matches!(
+        piv::metadata(&mut yubikey, SLOT),
+        Err(Error::NotFound)
+    ));
+
+    // Requires the management key
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let mut yubikey = card.open().unwrap();
+    assert_eq!(
+        import(&mut yubikey, "p256-sec1.pem", None),
+        Err(Error::AuthenticationError)
+    );
+}
+
+#[test]
+fn test_import_firmware_support() {
+    let mut yubikey = authenticated([5, 4, 3]);
+
+    assert_eq!(
+        import(&mut yubikey, "ed25519.pem", None),
+        Err(Error::NotSupported)
+    );
+    import(&mut yubikey, "p256-sec1.pem", None).unwrap();
+
+    // No metadata before firmware 5.3
+    let mut yubikey = authenticated([5, 2, 7]);
+    import(&mut yubikey, "rsa2048.der", None).unwrap();
+}
+
+fn import_pkcs12(
+    yubikey: &mut YubiKey,
+    name: &str,
+    password: &str,
+    chain: &[RetiredSlotId],
+) -> yubikey::Result<Certificate> {
+    piv::import_pkcs12(
+        yubikey,
+        SLOT,
+        &key(name),
+        password,
+        PinPolicy::Never,
+        TouchPolicy::Never,
+        chain,
+    )
+}
+
+#[test]
+fn test_import_pkcs12() {
+    let mut yubikey = authenticated([5, 7, 2]);
+
+    // PBES2 with AES-256-CBC, and an HMAC-SHA256 MAC
+    let leaf = import_pkcs12(
+        &mut yubikey,
+        "p256-chain.p12",
+        "yubikey",
+        &[RetiredSlotId::R2],
+    )
+    .unwrap();
+    assert_eq!(leaf.subject(), "CN=Test User");
+    assert_eq!(
+        piv::metadata(&mut yubikey, SLOT).unwrap().public.as_ref(),
+        Some(leaf.cert.tbs_certificate().subject_public_key_info())
+    );
+    assert_eq!(
+        Certificate::read(&mut yubikey, SLOT).unwrap().subject(),
+        "CN=Test User"
+    );
+
+    // Only the intermediate is stored, not the root
+    let intermediate = Certificate::read(&mut yubikey, SlotId::Retired(RetiredSlotId::R2)).unwrap();
+    assert_eq!(intermediate.subject(), "CN=Test Intermediate CA");
+    assert_eq!(intermediate.issuer(), "CN=Test Root CA");
+
+    // 3DES, with an HMAC-SHA1 MAC
+    let leaf = import_pkcs12(&mut yubikey, "rsa2048-3des.p12", "yubikey", &[]).unwrap();
+    assert_eq!(leaf.subject(), "CN=Test RSA");
+    assert_eq!(
+        piv::metadata(&mut yubikey, SLOT).unwrap().algorithm,
+        SlotAlgorithmId::Asymmetric(AlgorithmId::Rsa2048)
+    );
+
+    // Neither encrypted nor authenticated
+    let leaf = import_pkcs12(&mut yubikey, "p256-plain.p12", "", &[]).unwrap();
+    assert_eq!(leaf.subject(), "CN=Test User");
+}
+
+#[test]
+fn test_import_pkcs12_errors() {
+    let mut yubikey = authenticated([5, 7, 2]);
+
+    assert_eq!(
+        import_pkcs12(&mut yubikey, "p256-chain.p12", "wrong", &[]).err(),
+        Some(Error::DecryptionError)
+    );
+    assert_eq!(
+        import_pkcs12(&mut yubikey, "mismatch.p12", "yubikey", &[]).err(),
+        Some(Error::KeyError)
+    );
+    assert_eq!(
+        import_pkcs12(&mut yubikey, "rsa-legacy.p12", "yubikey", &[]).err(),
+        Some(Error::AlgorithmError)
+    );
+    assert_eq!(
+        import_pkcs12(&mut yubikey, "rsa2048.der", "yubikey", &[]).err(),
+        Some(Error::ParseError)
+    );
+
+    // The intermediate would overwrite the leaf
+    assert_eq!(
+        import_pkcs12(
+            &mut yubikey,
+            "p256-chain.p12",
+            "yubikey",
+            &[RetiredSlotId::R1]
+        )
+        .err(),
+        Some(Error::ArgumentError)
+    );
+
+    // The intermediate would overwrite a key or a certificate
+    piv::generate(
+        &mut yubikey,
+        SlotId::Retired(RetiredSlotId::R3),
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let bob = Certificate::from_bytes(std::fs::read("tests/assets/Bob.der").unwrap()).unwrap();
+    bob.write(
+        &mut yubikey,
+        SlotId::Retired(RetiredSlotId::R4),
+        CertInfo::Uncompressed,
+    )
+    .unwrap();
+
+    for retired in [RetiredSlotId::R3, RetiredSlotId::R4] {
+        assert_eq!(
+            import_pkcs12(&mut yubikey, "p256-chain.p12", "yubikey", &[retired]).err(),
+            Some(Error::SlotOccupied)
+        );
+    }
+    assert_eq!(
+        Certificate::read(&mut yubikey, SlotId::Retired(RetiredSlotId::R4))
+            .unwrap()
+            .cert,
+        bob.cert
+    );
+
+    // Nothing was written
+    assert!(matches!(
+        piv::metadata(&mut yubikey, SLOT),
+        Err(Error::NotFound)
+    ));
+    assert!(Certificate::read(&mut yubikey, SLOT).is_err());
+}
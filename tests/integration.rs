@@ -3,7 +3,9 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
 
-use cipher::common::{getrandom::SysRng, Generate};
+#[cfg(feature = "untested")]
+use cipher::common::getrandom::SysRng;
+use cipher::common::Generate;
 use log::trace;
 use once_cell::sync::Lazy;
 use rsa::{pkcs1v15, RsaPublicKey};
@@ -14,7 +16,9 @@ use x509_cert::{der::Encode, name::Name, serial_number::SerialNumber, time::Vali
 use yubikey::{
     certificate::{yubikey_signer, Certificate},
     piv::{self, AlgorithmId, Key, ManagementSlotId, RetiredSlotId, SlotId},
-    Error, MgmKey, PinPolicy, Serial, TouchPolicy, YubiKey,
+    reader::{Context, Event, Watcher},
+    transport::{Disposition, ShareMode, Transport, TransportTransaction},
+    Error, MgmKey, OpenOptions, PinPolicy, Serial, TouchPolicy, YubiKey,
 };
 
 static YUBIKEY: Lazy<Mutex<YubiKey>> = Lazy::new(|| {
@@ -36,6 +40,143 @@ static YUBIKEY: Lazy<Mutex<YubiKey>> = Lazy::new(|| {
     Mutex::new(yubikey)
 });
 
+//
+// Transport support
+//
+
+/// Minimal stand-in transport which answers the commands issued when opening
+/// a session and verifying the PIN.
+struct StandInTransport;
+
+struct StandInTransaction;
+
+impl Transport for StandInTransport {
+    fn begin_transaction(&mut self) -> yubikey::Result<Box<dyn TransportTransaction + '_>> {
+        Ok(Box::new(StandInTransaction))
+    }
+
+    fn reconnect(&mut self, _disposition: Disposition) -> yubikey::Result<()> {
+        Ok(())
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        _disposition: Disposition,
+    ) -> Result<(), (Box<dyn Transport>, Error)> {
+        Ok(())
+    }
+}
+
+impl TransportTransaction for StandInTransaction {
+    fn transmit(&self, send_buffer: &[u8], _recv_len: usize) -> yubikey::Result<Vec<u8>> {
+        Ok(match send_buffer[1] {
+            // SELECT
+            0xa4 => vec![0x90, 0x00],
+            // GET VERSION
+            0xfd => vec![5, 4, 3, 0x90, 0x00],
+            // GET SERIAL
+            0xf8 => vec![0x00, 0xbc, 0x61, 0x4e, 0x90, 0x00],
+            // VERIFY
+            0x20 if send_buffer[5..13] == *b"123456\xff\xff" => vec![0x90, 0x00],
+            0x20 => vec![0x63, 0xc2],
+            _ => vec![0x6d, 0x00],
+        })
+    }
+
+    fn end(self: Box<Self>, _disposition: Disposition) -> yubikey::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_open_transport() {
+    let mut yubikey = YubiKey::open_transport(StandInTransport, "stand-in").unwrap();
+    assert_eq!(yubikey.name(), "stand-in");
+    assert_eq!(yubikey.version().to_string(), "5.4.3");
+    assert_eq!(yubikey.serial(), Serial(12345678));
+
+    assert_eq!(
+        yubikey.verify_pin(b"000000"),
+        Err(Error::WrongPin { tries: 2 })
+    );
+    assert!(yubikey.verify_pin(b"123456").is_ok());
+    assert!(yubikey.disconnect(Disposition::LeaveCard).is_ok());
+}
+
+//
+// Reader watcher support
+//
+
+#[test]
+#[ignore]
+fn test_watcher() {
+    let serial = match YUBIKEY.lock() {
+        Ok(yubikey) => yubikey.serial(),
+        Err(poison) => poison.into_inner().serial(),
+    };
+
+    // Readers and cards already present are reported first
+    let mut watcher = Watcher::new().unwrap();
+    let mut events = vec![];
+
+    while let Some(event) = watcher.wait(Some(Duration::from_secs(1))).unwrap() {
+        trace!("event: {:?}", event);
+        events.push(event);
+    }
+
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, Event::ReaderAttached { .. })));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        Event::CardInserted { serial: Some(s), .. } if *s == serial
+    )));
+}
+
+#[test]
+#[ignore]
+fn test_probe() {
+    let (serial, version) = match YUBIKEY.lock() {
+        Ok(yubikey) => (yubikey.serial(), yubikey.version()),
+        Err(poison) => {
+            let yubikey = poison.into_inner();
+            (yubikey.serial(), yubikey.version())
+        }
+    };
+
+    let mut context = Context::open().unwrap();
+    let summary = context
+        .probe()
+        .unwrap()
+        .filter_map(Result::ok)
+        .find(|summary| summary.serial == Some(serial))
+        .unwrap();
+
+    trace!("device: {:?}", summary);
+    assert!(summary.piv);
+    assert_eq!(summary.version, Some(version));
+}
+
+#[test]
+#[ignore]
+fn test_open_exclusive() {
+    let serial = match YUBIKEY.lock() {
+        Ok(yubikey) => yubikey.serial(),
+        Err(poison) => poison.into_inner().serial(),
+    };
+
+    // The shared session held by the other tests prevents exclusive access
+    assert_eq!(
+        OpenOptions::new()
+            .share_mode(ShareMode::Exclusive)
+            .open_by_serial(serial)
+            .unwrap_err(),
+        Error::PcscError {
+            inner: Some(pcsc::Error::SharingViolation)
+        }
+    );
+}
+
 //
 // CCCID support
 //
@@ -0,0 +1,125 @@
+//! Tests for recovering sessions after the card was reset or removed, using
+//! the software PIV emulator
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use yubikey::{
+    emulator::VirtualYubiKey,
+    piv::{self, AlgorithmId, RetiredSlotId, SlotId},
+    transport::Disposition,
+    Error, MgmKey, OpenOptions, PinPolicy, Recovery, Serial, TouchPolicy, Version, YubiKey,
+};
+
+const SLOT: SlotId = SlotId::Retired(RetiredSlotId::R3);
+const DIGEST: [u8; 32] = [0x42; 32];
+
+/// Open a session with a PIN-protected key in [`SLOT`], returning the
+/// default management key.
+fn setup() -> (VirtualYubiKey, YubiKey, MgmKey) {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let mut yubikey = card.open().unwrap();
+    let mgm_key = MgmKey::get_default(&yubikey).unwrap();
+
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey.authenticate(&mgm_key).unwrap();
+    generate(&mut yubikey).unwrap();
+
+    (card, yubikey, mgm_key)
+}
+
+fn generate(yubikey: &mut YubiKey) -> yubikey::Result<()> {
+    piv::generate(
+        yubikey,
+        SLOT,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )
+    .map(drop)
+}
+
+fn sign(yubikey: &mut YubiKey) -> yubikey::Result<()> {
+    piv::sign_data(yubikey, &DIGEST, AlgorithmId::EccP256, SLOT).map(drop)
+}
+
+#[test]
+fn test_recovery_disabled() {
+    let (card, mut yubikey, _) = setup();
+    assert!(!yubikey.recovery().is_enabled());
+
+    card.simulate_reset().unwrap();
+    assert_eq!(
+        sign(&mut yubikey),
+        Err(Error::PcscError {
+            inner: Some(pcsc::Error::ResetCard)
+        })
+    );
+}
+
+#[test]
+fn test_recover_after_reset() {
+    let (card, mut yubikey, _) = setup();
+    yubikey.set_recovery(Recovery::reconnect());
+
+    // The PIN is re-verified, and the operation retried
+    card.simulate_reset().unwrap();
+    sign(&mut yubikey).unwrap();
+
+    // ...but the management key isn't re-authenticated
+    card.simulate_reset().unwrap();
+    piv::metadata(&mut yubikey, SLOT).unwrap();
+    assert!(generate(&mut yubikey).is_err());
+}
+
+#[test]
+fn test_recover_mgm_key() {
+    let (card, mut yubikey, mgm_key) = setup();
+    yubikey.set_recovery(Recovery::reconnect().mgm_key(mgm_key));
+
+    card.simulate_reset().unwrap();
+    piv::metadata(&mut yubikey, SLOT).unwrap();
+    generate(&mut yubikey).unwrap();
+    sign(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_card_removed() {
+    let (card, mut yubikey, _) = setup();
+    yubikey.set_recovery(Recovery::reconnect());
+
+    card.remove().unwrap();
+    assert_eq!(sign(&mut yubikey), Err(Error::SessionLost));
+    assert_eq!(sign(&mut yubikey), Err(Error::SessionLost));
+
+    // Recovery is attempted again once the card is back
+    card.insert().unwrap();
+    sign(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_reconnect() {
+    let (_card, mut yubikey, _) = setup();
+
+    yubikey.reconnect().unwrap();
+    sign(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_open_options() {
+    let (card, _, _) = setup();
+
+    let mut yubikey = OpenOptions::new()
+        .reconnect_disposition(Disposition::LeaveCard)
+        .recovery(Recovery::reconnect())
+        .open_transport(card.clone(), "options")
+        .unwrap();
+    assert!(yubikey.recovery().is_enabled());
+
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey.reconnect().unwrap();
+    sign(&mut yubikey).unwrap();
+
+    card.simulate_reset().unwrap();
+    sign(&mut yubikey).unwrap();
+}
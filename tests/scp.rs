@@ -0,0 +1,395 @@
+//! Tests for SCP03 and SCP11b secure channels, using the software PIV
+//! emulator
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use cipher::common::getrandom::SysRng;
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use yubikey::{
+    certificate::{CertInfo, Certificate},
+    emulator::VirtualYubiKey,
+    piv::{self, AlgorithmId, RetiredSlotId, SlotId},
+    scp::{ScpKeyParams, StaticKeys},
+    transcript::{Recorder, Transcript},
+    transport::{Disposition, Transport, TransportTransaction},
+    Error, MgmKey, OpenOptions, PinPolicy, Recovery, Serial, TouchPolicy, Version, YubiKey,
+};
+
+const SLOT: SlotId = SlotId::Retired(RetiredSlotId::R4);
+const DIGEST: [u8; 32] = [0x42; 32];
+
+/// Transcript output shared with the test.
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transport forwarding to an emulated card, without reporting an ATR (so
+/// that only short APDUs are used). It records the commands sent, and can
+/// corrupt the R-MAC of a response.
+#[derive(Clone)]
+struct Wire {
+    card: VirtualYubiKey,
+    commands: Arc<Mutex<Vec<Vec<u8>>>>,
+
+    /// Instruction of the wrapped command whose response gets corrupted
+    corrupt: Arc<Mutex<Option<u8>>>,
+}
+
+impl Wire {
+    fn new(card: VirtualYubiKey) -> Self {
+        Self {
+            card,
+            commands: Arc::default(),
+            corrupt: Arc::default(),
+        }
+    }
+
+    /// Corrupt the next authenticated response to a wrapped command with
+    /// instruction `ins`.
+    fn corrupt(&self, ins: u8) {
+        *self.corrupt.lock().unwrap() = Some(ins);
+    }
+}
+
+impl Transport for Wire {
+    fn begin_transaction(&mut self) -> yubikey::Result<Box<dyn TransportTransaction + '_>> {
+        Ok(Box::new(WireTransaction {
+            inner: self.card.begin_transaction()?,
+            commands: &self.commands,
+            corrupt: &self.corrupt,
+        }))
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> yubikey::Result<()> {
+        self.card.reconnect(disposition)
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> Result<(), (Box<dyn Transport>, Error)> {
+        Box::new(self.card.clone()).disconnect(disposition)
+    }
+}
+
+struct WireTransaction<'a> {
+    inner: Box<dyn TransportTransaction + 'a>,
+    commands: &'a Mutex<Vec<Vec<u8>>>,
+    corrupt: &'a Mutex<Option<u8>>,
+}
+
+impl TransportTransaction for WireTransaction<'_> {
+    fn transmit(&self, send_buffer: &[u8], recv_len: usize) -> yubikey::Result<Vec<u8>> {
+        self.commands.lock().unwrap().push(send_buffer.to_vec());
+
+        let mut response = self.inner.transmit(send_buffer, recv_len)?;
+
+        let mut corrupt = self.corrupt.lock().unwrap();
+        if corrupt.is_some_and(|ins| send_buffer[0] & 0x04 != 0 && send_buffer[1] == ins)
+            && response.len() > 10
+            && response.ends_with(&[0x90, 0x00])
+        {
+            let mac = response.len() - 3;
+            response[mac] ^= 0x01;
+            *corrupt = None;
+        }
+
+        Ok(response)
+    }
+
+    fn end(self: Box<Self>, disposition: Disposition) -> yubikey::Result<()> {
+        self.inner.end(disposition)
+    }
+}
+
+/// Is this command (CLA and INS) one of SELECT, or the SCP03 handshake,
+/// which are sent in clear?
+fn is_handshake(command: &[u8]) -> bool {
+    matches!(command[1], 0xa4 | 0x50 | 0x82)
+}
+
+fn card() -> VirtualYubiKey {
+    VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap()
+}
+
+/// Verify the PIN, authenticate, and generate and sign with a key.
+fn session(yubikey: &mut YubiKey) -> yubikey::Result<()> {
+    let mgm_key = MgmKey::get_default(yubikey)?;
+
+    yubikey.verify_pin(b"123456")?;
+    yubikey.authenticate(&mgm_key)?;
+
+    piv::generate(
+        yubikey,
+        SLOT,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        TouchPolicy::Never,
+    )?;
+    piv::sign_data(yubikey, &DIGEST, AlgorithmId::EccP256, SLOT)?;
+    Ok(())
+}
+
+#[test]
+fn test_scp03() {
+    let card = card();
+    let output = SharedOutput::default();
+    let mut yubikey =
+        YubiKey::open_transport(Recorder::new(card.clone(), output.clone()), "recorder").unwrap();
+
+    yubikey
+        .open_secure_channel(ScpKeyParams::scp03_default())
+        .unwrap();
+    session(&mut yubikey).unwrap();
+    assert_eq!(yubikey.get_pin_retries().unwrap(), 3);
+
+    // Everything after the handshake is wrapped, except for selecting the PIV
+    // application (which closes the secure channel) and opening the secure
+    // channel again right after it
+    let transcript =
+        Transcript::from_str(&String::from_utf8(output.0.lock().unwrap().clone()).unwrap())
+            .unwrap();
+    let commands: Vec<_> = transcript
+        .iter()
+        .map(|exchange| exchange.command()[..2].to_vec())
+        .skip_while(|command| command != &[0x84, 0x82])
+        .skip(1)
+        .filter(|command| !is_handshake(command))
+        .collect();
+
+    assert!(commands.contains(&vec![0x04, 0x20]));
+    assert!(commands.iter().all(|command| command[0] == 0x04));
+
+    // get_pin_retries selects the PIV application before VERIFY
+    assert!(transcript
+        .iter()
+        .any(|exchange| exchange.command()[..2] == [0x00, 0xa4]));
+    assert_eq!(commands.last().unwrap(), &vec![0x04, 0x20]);
+}
+
+#[test]
+fn test_scp03_corrupted_response() {
+    let wire = Wire::new(card());
+    let mut yubikey = YubiKey::open_transport(wire.clone(), "wire").unwrap();
+    yubikey
+        .open_secure_channel(ScpKeyParams::scp03_default())
+        .unwrap();
+
+    let mgm_key = MgmKey::get_default(&yubikey).unwrap();
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey.authenticate(&mgm_key).unwrap();
+    mgm_key.set_protected(&mut yubikey).unwrap();
+
+    // Corrupt the R-MAC of the protected data read back before it is updated
+    let new_key = MgmKey::generate_for(&yubikey, &mut SysRng).unwrap();
+    wire.corrupt(0xcb);
+    wire.commands.lock().unwrap().clear();
+
+    assert_eq!(
+        new_key.set_protected(&mut yubikey),
+        Err(Error::SecureChannelClosed)
+    );
+
+    // The transaction was aborted instead of writing the key in clear
+    let commands = wire.commands.lock().unwrap().clone();
+    assert!(commands.iter().all(|command| command[0] & 0x04 != 0));
+    assert_eq!(commands.last().unwrap()[1], 0xcb);
+
+    // The next transaction opens the secure channel again
+    wire.commands.lock().unwrap().clear();
+    assert_eq!(yubikey.get_pin_retries().unwrap(), 3);
+
+    let commands = wire.commands.lock().unwrap().clone();
+    assert!(commands
+        .iter()
+        .filter(|command| !is_handshake(command))
+        .all(|command| command[0] & 0x04 != 0));
+}
+
+#[test]
+fn test_scp03_short_apdus() {
+    let mut yubikey = YubiKey::open_transport(Wire::new(card()), "wire").unwrap();
+    yubikey
+        .open_secure_channel(ScpKeyParams::scp03_default())
+        .unwrap();
+
+    let mgm_key = MgmKey::get_default(&yubikey).unwrap();
+    yubikey.authenticate(&mgm_key).unwrap();
+
+    // Responses of more than 256 bytes are collected with GET RESPONSE
+    let bob = Certificate::from_bytes(std::fs::read("tests/assets/Bob.der").unwrap()).unwrap();
+    bob.write(&mut yubikey, SLOT, CertInfo::Uncompressed)
+        .unwrap();
+
+    let read = Certificate::read(&mut yubikey, SLOT).unwrap();
+    assert_eq!(read.cert, bob.cert);
+}
+
+#[test]
+fn test_scp03_wrong_keys() {
+    let card = card();
+    let mut yubikey = card.open().unwrap();
+
+    let keys = StaticKeys::new([0x01; 16], [0x02; 16]);
+    assert_eq!(
+        yubikey.open_secure_channel(ScpKeyParams::scp03(0xff, keys)),
+        Err(Error::AuthenticationError)
+    );
+
+    // The session still works without a secure channel
+    session(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_scp11b() {
+    let card = card();
+    let mut yubikey = OpenOptions::new()
+        .secure_channel(ScpKeyParams::scp11b(0x01, card.scp11b_ca().unwrap()))
+        .open_transport(card.clone(), "scp11b")
+        .unwrap();
+
+    session(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_scp11b_untrusted() {
+    let card = card();
+    let mut yubikey = card.open().unwrap();
+
+    let other_ca = self::card().scp11b_ca().unwrap();
+    assert_eq!(
+        yubikey.open_secure_channel(ScpKeyParams::scp11b(0x01, other_ca)),
+        Err(Error::AuthenticationError)
+    );
+
+    assert_eq!(
+        yubikey.open_secure_channel(ScpKeyParams::scp11b(0x02, card.scp11b_ca().unwrap())),
+        Err(Error::NotFound)
+    );
+}
+
+#[test]
+fn test_scp11b_certificates() {
+    let mut card = card();
+    let txn = card.begin_transaction().unwrap();
+    let get_certificates = [
+        0x80, 0xca, 0xbf, 0x21, 0x06, 0xa6, 0x04, 0x83, 0x02, 0x13, 0x01, 0x00,
+    ];
+
+    // The certificates are only available from the Security Domain
+    let select_piv = [0x00, 0xa4, 0x04, 0x00, 0x05, 0xa0, 0x00, 0x00, 0x03, 0x08];
+    assert!(txn
+        .transmit(&select_piv, 0x100)
+        .unwrap()
+        .ends_with(&[0x90, 0x00]));
+    assert_eq!(
+        txn.transmit(&get_certificates, 0x1000).unwrap(),
+        [0x6d, 0x00]
+    );
+
+    let select_sd = [
+        0x00, 0xa4, 0x04, 0x00, 0x08, 0xa0, 0x00, 0x00, 0x01, 0x51, 0x00, 0x00, 0x00,
+    ];
+    assert!(txn
+        .transmit(&select_sd, 0x100)
+        .unwrap()
+        .ends_with(&[0x90, 0x00]));
+
+    // The chain starts with the CA certificate (a DER sequence)
+    let response = txn.transmit(&get_certificates, 0x1000).unwrap();
+    assert_eq!(response[..2], [0x30, 0x82]);
+}
+
+#[test]
+fn test_not_supported() {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 4, 3])).unwrap();
+    let mut yubikey = card.open().unwrap();
+
+    assert_eq!(
+        yubikey.open_secure_channel(ScpKeyParams::scp03_default()),
+        Err(Error::NotSupported)
+    );
+}
+
+#[test]
+fn test_recovery() {
+    let card = card();
+    let mut yubikey = card.open().unwrap();
+    yubikey.set_recovery(Recovery::reconnect());
+    yubikey
+        .open_secure_channel(ScpKeyParams::scp03_default())
+        .unwrap();
+    session(&mut yubikey).unwrap();
+
+    // The secure channel is opened again before the PIN is re-verified
+    yubikey.reconnect().unwrap();
+    piv::sign_data(&mut yubikey, &DIGEST, AlgorithmId::EccP256, SLOT).unwrap();
+
+    card.simulate_reset().unwrap();
+    piv::sign_data(&mut yubikey, &DIGEST, AlgorithmId::EccP256, SLOT).unwrap();
+}
+
+#[test]
+fn test_large_objects() {
+    let card = card();
+    let mut yubikey = card.open().unwrap();
+    yubikey
+        .open_secure_channel(ScpKeyParams::scp11b(0x01, card.scp11b_ca().unwrap()))
+        .unwrap();
+    session(&mut yubikey).unwrap();
+
+    // Certificates span several (chained) commands and responses
+    let cert = Certificate::read(&mut yubikey, SlotId::Attestation).unwrap();
+    cert.write(&mut yubikey, SLOT, CertInfo::Uncompressed)
+        .unwrap();
+
+    let read = Certificate::read(&mut yubikey, SLOT).unwrap();
+    assert_eq!(read.cert, cert.cert);
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_manager() {
+    use yubikey::mgm::{Capability, Manager};
+
+    let wire = Wire::new(card());
+    let mut yubikey = YubiKey::open_transport(wire.clone(), "wire").unwrap();
+    yubikey
+        .open_secure_channel(ScpKeyParams::scp03_default())
+        .unwrap();
+    wire.commands.lock().unwrap().clear();
+
+    let mut manager = Manager::new(yubikey).unwrap();
+    let info = manager.read_config().unwrap();
+    assert!(info.config.usb_enabled_apps.contains(Capability::PIV));
+    assert!(info.config.nfc_enabled());
+
+    manager.disable_nfc().unwrap();
+    assert!(!manager.read_config().unwrap().config.nfc_enabled());
+
+    // The secure channel is opened again after selecting the management
+    // application, and again after selecting the PIV application
+    let mut yubikey = manager.into_inner().unwrap();
+    assert_eq!(yubikey.get_pin_retries().unwrap(), 3);
+
+    let commands = wire.commands.lock().unwrap().clone();
+    assert!(commands.iter().any(|command| command[..2] == [0x04, 0x1c]));
+    assert!(commands
+        .iter()
+        .filter(|command| !is_handshake(command))
+        .all(|command| command[0] & 0x04 != 0));
+}
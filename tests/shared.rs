@@ -0,0 +1,95 @@
+//! Tests for sharing a YubiKey between threads, using the software PIV emulator
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use std::thread;
+use yubikey::{
+    emulator::VirtualYubiKey,
+    piv::{AlgorithmId, RetiredSlotId, SlotId},
+    shared::SharedYubiKey,
+    MgmKey, PinPolicy, Serial, TouchPolicy, Version,
+};
+
+const SLOT: SlotId = SlotId::Retired(RetiredSlotId::R2);
+
+fn open() -> SharedYubiKey {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    SharedYubiKey::new(card.open().unwrap())
+}
+
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedYubiKey>();
+}
+
+#[test]
+fn test_concurrent_signing() {
+    let yubikey = open();
+    let mgm_key = yubikey
+        .with(|yubikey| MgmKey::get_default(yubikey))
+        .unwrap();
+
+    // Authentication state is kept across operations from different threads
+    thread::scope(|scope| {
+        scope
+            .spawn(|| yubikey.verify_pin(b"123456"))
+            .join()
+            .unwrap()?;
+        scope
+            .spawn(|| yubikey.clone().authenticate(&mgm_key))
+            .join()
+            .unwrap()
+    })
+    .unwrap();
+
+    yubikey
+        .generate(
+            SLOT,
+            AlgorithmId::EccP256,
+            PinPolicy::Once,
+            TouchPolicy::Never,
+        )
+        .unwrap();
+
+    let workers: Vec<_> = (0..8u8)
+        .map(|i| {
+            let yubikey = yubikey.clone();
+            thread::spawn(move || {
+                (0..4)
+                    .map(|_| yubikey.sign_data(&[i; 32], AlgorithmId::EccP256, SLOT))
+                    .collect::<yubikey::Result<Vec<_>>>()
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        assert_eq!(worker.join().unwrap().unwrap().len(), 4);
+    }
+
+    assert_eq!(yubikey.get_pin_retries().unwrap(), 3);
+}
+
+#[test]
+fn test_panicking_operation() {
+    let yubikey = open();
+
+    let clone = yubikey.clone();
+    let result = thread::spawn(move || clone.with(|_| panic!("operation failed"))).join();
+    assert!(result.is_err());
+
+    // The queue moves on to the next operation
+    assert_eq!(yubikey.get_pin_retries().unwrap(), 3);
+    assert_eq!(yubikey.serial(), Serial(12345678));
+}
+
+#[test]
+fn test_reconnect() {
+    let yubikey = open();
+    yubikey.verify_pin(b"123456").unwrap();
+
+    yubikey.reconnect().unwrap();
+    assert_eq!(yubikey.serial(), Serial(12345678));
+    assert_eq!(yubikey.get_pin_retries().unwrap(), 3);
+}
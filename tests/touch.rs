@@ -0,0 +1,271 @@
+//! Tests for timeouts and cancellation of touch-gated operations, using the
+//! software PIV emulator
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use yubikey::{
+    emulator::VirtualYubiKey,
+    piv::{self, AlgorithmId, RetiredSlotId, SlotId},
+    touch::{CancelHandle, Request},
+    transport::{Disposition, Interrupt, Transport, TransportTransaction},
+    Error, MgmKey, PinPolicy, Serial, TouchPolicy, Version, YubiKey,
+};
+
+const SLOT: SlotId = SlotId::Retired(RetiredSlotId::R5);
+const DIGEST: [u8; 32] = [0x42; 32];
+
+/// Open a session with a card requiring touch, with a key in [`SLOT`].
+fn setup(touch_policy: TouchPolicy) -> (VirtualYubiKey, YubiKey) {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let mut yubikey = card.open().unwrap();
+
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey
+        .authenticate(&MgmKey::get_default(&yubikey).unwrap())
+        .unwrap();
+    piv::generate(
+        &mut yubikey,
+        SLOT,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        touch_policy,
+    )
+    .unwrap();
+
+    card.set_touch_required(true);
+    (card, yubikey)
+}
+
+fn sign(yubikey: &mut YubiKey) -> yubikey::Result<()> {
+    piv::sign_data(yubikey, &DIGEST, AlgorithmId::EccP256, SLOT).map(|_| ())
+}
+
+/// Record the requests reported to the touch callback.
+fn record_requests(yubikey: &mut YubiKey) -> Arc<Mutex<Vec<Request>>> {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = Arc::clone(&requests);
+    yubikey.set_touch_callback(move |request| recorded.lock().unwrap().push(request));
+    requests
+}
+
+/// Wait until the card waits for a touch.
+fn wait_for_prompt(card: &VirtualYubiKey) {
+    while !card.is_waiting_for_touch() {
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Touch the card once it waits for a touch, from another thread.
+fn touch_later(card: &VirtualYubiKey) -> thread::JoinHandle<()> {
+    let card = card.clone();
+    thread::spawn(move || {
+        wait_for_prompt(&card);
+        assert!(card.touch());
+    })
+}
+
+/// Cancel the operation once the card waits for a touch, from another thread.
+fn cancel_later(card: &VirtualYubiKey, cancel: CancelHandle) -> thread::JoinHandle<()> {
+    let card = card.clone();
+    thread::spawn(move || {
+        wait_for_prompt(&card);
+        assert!(cancel.is_pending());
+        cancel.cancel();
+    })
+}
+
+#[test]
+fn test_touch() {
+    let (card, mut yubikey) = setup(TouchPolicy::Always);
+    yubikey.set_touch_timeout(Some(Duration::from_secs(10)));
+
+    let toucher = touch_later(&card);
+    sign(&mut yubikey).unwrap();
+    toucher.join().unwrap();
+
+    // Not touched: nothing to do
+    assert!(!card.touch());
+}
+
+#[test]
+fn test_cached() {
+    let (card, mut yubikey) = setup(TouchPolicy::Cached);
+
+    let toucher = touch_later(&card);
+    sign(&mut yubikey).unwrap();
+    toucher.join().unwrap();
+
+    // The touch is cached for 15 seconds
+    sign(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_cancel() {
+    let (card, mut yubikey) = setup(TouchPolicy::Always);
+    assert!(!yubikey.cancel_handle().is_pending());
+
+    let canceller = cancel_later(&card, yubikey.cancel_handle());
+    assert_eq!(sign(&mut yubikey), Err(Error::TouchNotReceived));
+    canceller.join().unwrap();
+
+    // The session was restored (the PIN was verified again), and cancelling
+    // doesn't affect later operations
+    yubikey.cancel_handle().cancel();
+    let toucher = touch_later(&card);
+    sign(&mut yubikey).unwrap();
+    toucher.join().unwrap();
+}
+
+#[test]
+fn test_timeout() {
+    let (card, mut yubikey) = setup(TouchPolicy::Always);
+    yubikey.set_touch_timeout(Some(Duration::from_millis(100)));
+    assert_eq!(yubikey.touch_timeout(), Some(Duration::from_millis(100)));
+
+    assert_eq!(sign(&mut yubikey), Err(Error::TouchNotReceived));
+    assert!(!card.is_waiting_for_touch());
+
+    card.set_touch_required(false);
+    sign(&mut yubikey).unwrap();
+}
+
+#[test]
+fn test_mgm_key_timeout() {
+    let (card, mut yubikey) = setup(TouchPolicy::Never);
+    let mgm_key = MgmKey::generate_for(&yubikey, &mut rand::rng()).unwrap();
+
+    card.set_touch_required(false);
+    mgm_key.set_manual(&mut yubikey, true).unwrap();
+    card.set_touch_required(true);
+
+    yubikey.set_touch_timeout(Some(Duration::from_millis(100)));
+    assert_eq!(yubikey.authenticate(&mgm_key), Err(Error::TouchNotReceived));
+
+    // Signing doesn't require touch
+    sign(&mut yubikey).unwrap();
+
+    yubikey.set_touch_timeout(None);
+    let toucher = touch_later(&card);
+    yubikey.authenticate(&mgm_key).unwrap();
+    toucher.join().unwrap();
+}
+
+#[test]
+fn test_callback() {
+    let (card, mut yubikey) = setup(TouchPolicy::Always);
+    let requests = record_requests(&mut yubikey);
+
+    // The callback is called before the card waits for a touch
+    let prompted = Arc::clone(&requests);
+    let touched = card.clone();
+    let toucher = thread::spawn(move || {
+        wait_for_prompt(&touched);
+        assert_eq!(*prompted.lock().unwrap(), [Request::Sign(SLOT)]);
+        assert!(touched.touch());
+    });
+
+    sign(&mut yubikey).unwrap();
+    toucher.join().unwrap();
+
+    yubikey.clear_touch_callback();
+    let toucher = touch_later(&card);
+    sign(&mut yubikey).unwrap();
+    toucher.join().unwrap();
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_callback_policies() {
+    let (card, mut yubikey) = setup(TouchPolicy::Never);
+    card.set_touch_required(false);
+    let requests = record_requests(&mut yubikey);
+
+    sign(&mut yubikey).unwrap();
+    assert!(requests.lock().unwrap().is_empty());
+
+    // Cached touches only prompt once
+    piv::generate(
+        &mut yubikey,
+        SLOT,
+        AlgorithmId::EccP256,
+        PinPolicy::Once,
+        TouchPolicy::Cached,
+    )
+    .unwrap();
+    sign(&mut yubikey).unwrap();
+    sign(&mut yubikey).unwrap();
+    assert_eq!(*requests.lock().unwrap(), [Request::Sign(SLOT)]);
+
+    // Management key requiring touch
+    let mgm_key = MgmKey::generate_for(&yubikey, &mut rand::rng()).unwrap();
+    mgm_key.set_manual(&mut yubikey, true).unwrap();
+    yubikey.authenticate(&mgm_key).unwrap();
+    assert_eq!(
+        *requests.lock().unwrap(),
+        [Request::Sign(SLOT), Request::Authenticate]
+    );
+}
+
+/// Transport counting how often its interrupter is fetched.
+struct Interrupters(VirtualYubiKey, Arc<AtomicUsize>);
+
+impl Transport for Interrupters {
+    fn begin_transaction(&mut self) -> yubikey::Result<Box<dyn TransportTransaction + '_>> {
+        self.0.begin_transaction()
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> yubikey::Result<()> {
+        self.0.reconnect(disposition)
+    }
+
+    fn interrupter(&self) -> Option<Arc<dyn Interrupt>> {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        self.0.interrupter()
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> Result<(), (Box<dyn Transport>, Error)> {
+        Box::new(self.0).disconnect(disposition)
+    }
+}
+
+#[test]
+fn test_unwatched_operations() {
+    let (card, yubikey) = setup(TouchPolicy::Never);
+    card.set_touch_required(false);
+    drop(yubikey);
+
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let mut yubikey =
+        YubiKey::open_transport(Interrupters(card, Arc::clone(&fetched)), "test").unwrap();
+    yubikey.verify_pin(b"123456").unwrap();
+
+    // Without a timeout or a cancel handle, operations can't be aborted
+    sign(&mut yubikey).unwrap();
+    assert_eq!(fetched.load(Ordering::SeqCst), 0);
+
+    let cancel = yubikey.cancel_handle();
+    sign(&mut yubikey).unwrap();
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+    drop(cancel);
+
+    // Keys known not to require touch aren't watched
+    yubikey.set_touch_timeout(Some(Duration::from_secs(10)));
+    sign(&mut yubikey).unwrap();
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+    let _requests = record_requests(&mut yubikey);
+    sign(&mut yubikey).unwrap();
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,57 @@
+//! Tests for the APDU trace, using the software PIV emulator
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::Mutex;
+use yubikey::{emulator::VirtualYubiKey, MgmAlgorithmId, MgmKey, Serial, Version};
+
+/// Logger capturing the APDU trace, along with its structured data.
+struct Capture(Mutex<Vec<String>>);
+
+impl Log for Capture {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == "yubikey::apdu" && metadata.level() <= Level::Trace
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.enabled(record.metadata()) {
+            let data = record.key_values().get("data".into());
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {:?}", record.args(), data));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+#[test]
+fn test_protected_mgm_key_is_redacted() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let mut yubikey = card.open().unwrap();
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey
+        .authenticate(&MgmKey::get_default(&yubikey).unwrap())
+        .unwrap();
+
+    let mgm_key = MgmKey::from_bytes([0xa5; 24], Some(MgmAlgorithmId::Aes192)).unwrap();
+    mgm_key.set_protected(&mut yubikey).unwrap();
+    assert_eq!(
+        MgmKey::get_protected(&mut yubikey).unwrap().as_ref(),
+        mgm_key.as_ref()
+    );
+
+    let lines = CAPTURE.0.lock().unwrap();
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("> PutData") && line.contains("data=[redacted]")));
+    assert!(!lines.iter().any(|line| line.contains("a5a5a5a5")));
+}
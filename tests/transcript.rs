@@ -0,0 +1,271 @@
+//! Tests for recording and replaying APDU transcripts
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs, rust_2018_idioms, trivial_casts, unused_qualifications)]
+
+use der::referenced::OwnedToRef;
+use p256::elliptic_curve::Generate;
+use rsa::{Pkcs1v15Encrypt, RsaPublicKey};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+use yubikey::{
+    emulator::VirtualYubiKey,
+    piv::{self, AlgorithmId, RetiredSlotId, SlotId},
+    transcript::{Recorder, Replay, Transcript},
+    transport::{Disposition, Transport, TransportTransaction},
+    Buffer, Error, MgmAlgorithmId, MgmKey, PinPolicy, Serial, TouchPolicy, Version, YubiKey,
+};
+
+const SLOT: SlotId = SlotId::Retired(RetiredSlotId::R1);
+const DIGEST: [u8; 32] = [0x42; 32];
+
+/// Transcript output shared with the test.
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    fn transcript(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Session exercised by the tests: returns the generated public key.
+fn session(yubikey: &mut YubiKey) -> yubikey::Result<Vec<u8>> {
+    let default_key = MgmKey::get_default(yubikey)?;
+
+    yubikey.verify_pin(b"123456")?;
+    yubikey.authenticate(&default_key)?;
+
+    let public = piv::generate(
+        yubikey,
+        SLOT,
+        AlgorithmId::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )?;
+
+    Ok(public.subject_public_key.raw_bytes().to_vec())
+}
+
+fn sign(yubikey: &mut YubiKey, digest: &[u8]) -> yubikey::Result<Buffer> {
+    piv::sign_data(yubikey, digest, AlgorithmId::EccP256, SLOT)
+}
+
+/// Default management key of the emulated (firmware 5.7) card.
+fn default_mgm_key() -> MgmKey {
+    MgmKey::from_bytes(
+        [1, 2, 3, 4, 5, 6, 7, 8].repeat(3),
+        Some(MgmAlgorithmId::Aes192),
+    )
+    .unwrap()
+}
+
+/// Record the test session followed by a signature, returning the
+/// transcript and the generated public key.
+fn record() -> (String, Vec<u8>) {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let output = SharedOutput::default();
+
+    let mut yubikey =
+        YubiKey::open_transport(Recorder::new(card, output.clone()), "recorded").unwrap();
+    let public = session(&mut yubikey).unwrap();
+    sign(&mut yubikey, &DIGEST).unwrap();
+    drop(yubikey);
+
+    (output.transcript(), public)
+}
+
+#[test]
+fn test_record_and_replay() {
+    let (recorded, expected) = record();
+    let transcript = recorded.parse::<Transcript>().unwrap();
+    assert!(!transcript.is_empty());
+    assert_eq!(transcript.to_string(), recorded);
+
+    let replay = Replay::new(transcript).mgm_key(default_mgm_key());
+    let mut yubikey = YubiKey::open_transport(replay.clone(), "replayed").unwrap();
+    assert_eq!(yubikey.serial(), Serial(12345678));
+    assert_eq!(session(&mut yubikey).unwrap(), expected);
+
+    // The results of GENERAL AUTHENTICATE aren't recorded
+    assert_eq!(sign(&mut yubikey, &DIGEST), Err(Error::SizeError));
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn test_secrets_are_redacted() {
+    let (recorded, _) = record();
+
+    // PIN "123456", and the default management key
+    assert!(!recorded.contains("313233343536"));
+    assert!(!recorded.contains("0102030405060708"));
+
+    let transcript = recorded.parse::<Transcript>().unwrap();
+    let verify = transcript
+        .iter()
+        .find(|exchange| exchange.command()[1] == 0x20)
+        .unwrap();
+    assert!(verify.is_redacted());
+    assert_eq!(verify.command(), &[0x00, 0x20, 0x00, 0x80]);
+}
+
+#[test]
+fn test_secret_results_are_redacted() {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let output = SharedOutput::default();
+
+    // Short APDUs, so that RSA results are split across GET RESPONSE
+    let mut yubikey =
+        YubiKey::open_transport(Recorder::new(NoAtr(card), output.clone()), "recorded").unwrap();
+    session(&mut yubikey).unwrap();
+
+    // Shared secret
+    let ephemeral = p256::ecdh::EphemeralSecret::generate_from_rng(&mut rand::rng());
+    let shared = piv::ecdh(&mut yubikey, SLOT, &ephemeral.public_key()).unwrap();
+
+    // Decrypted message
+    let rsa_slot = SlotId::Retired(RetiredSlotId::R2);
+    let public = piv::generate(
+        &mut yubikey,
+        rsa_slot,
+        AlgorithmId::Rsa2048,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .unwrap();
+    let ciphertext = RsaPublicKey::try_from(public.owned_to_ref())
+        .unwrap()
+        .encrypt(&mut rand::rng(), Pkcs1v15Encrypt, b"transcript secret")
+        .unwrap();
+    let message =
+        piv::decrypt_pkcs1v15(&mut yubikey, &ciphertext, AlgorithmId::Rsa2048, rsa_slot).unwrap();
+    assert_eq!(message.as_slice(), b"transcript secret");
+
+    // PIN-protected management key
+    let mgm_key = MgmKey::from_bytes([0xa5; 24], Some(MgmAlgorithmId::Aes192)).unwrap();
+    mgm_key.set_protected(&mut yubikey).unwrap();
+    drop(yubikey);
+
+    let recorded = output.transcript();
+    assert!(!recorded.contains(&hex(&shared)));
+    assert!(!recorded.contains(&hex(b"transcript secret")));
+    assert!(!recorded.contains("a5a5a5a5"));
+
+    // The decryption result and its remainder are redacted
+    let transcript = recorded.parse::<Transcript>().unwrap();
+    let exchanges: Vec<_> = transcript.iter().collect();
+    let decrypt = exchanges
+        .iter()
+        .rposition(|exchange| exchange.command()[1..3] == [0x87, 0x07])
+        .unwrap();
+    let exchanges = &exchanges[decrypt..decrypt + 2];
+    assert_eq!(exchanges[1].command()[1], 0xc0);
+    assert!(exchanges.iter().all(|exchange| exchange.is_redacted()));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[test]
+fn test_replay_mismatch() {
+    let (recorded, _) = record();
+    let replay = Replay::new(recorded.parse().unwrap()).mgm_key(default_mgm_key());
+    let mut yubikey = YubiKey::open_transport(replay.clone(), "replayed").unwrap();
+    session(&mut yubikey).unwrap();
+
+    assert_eq!(sign(&mut yubikey, &[0x24; 32]), Err(Error::GenericError));
+    assert!(!replay.is_finished());
+}
+
+#[test]
+fn test_parse_errors() {
+    assert!("> 00a40400\n".parse::<Transcript>().is_err());
+    assert!("< 9000\n".parse::<Transcript>().is_err());
+    assert!("> zz\n< 9000\n".parse::<Transcript>().is_err());
+    assert_eq!(
+        "# comment\n\n> 00200080 [redacted]\n< 9000\n"
+            .parse::<Transcript>()
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+/// Wrapper hiding the ATR of the transport it wraps.
+struct NoAtr<T>(T);
+
+impl<T: Transport> Transport for NoAtr<T> {
+    fn begin_transaction(&mut self) -> yubikey::Result<Box<dyn TransportTransaction + '_>> {
+        self.0.begin_transaction()
+    }
+
+    fn reconnect(&mut self, disposition: Disposition) -> yubikey::Result<()> {
+        self.0.reconnect(disposition)
+    }
+
+    fn disconnect(
+        self: Box<Self>,
+        disposition: Disposition,
+    ) -> Result<(), (Box<dyn Transport>, Error)> {
+        Box::new(self.0).disconnect(disposition)
+    }
+}
+
+/// Write and read back a large object, returning the recorded transcript.
+#[cfg(feature = "untested")]
+fn transfer_large_object(transport: impl Transport + 'static) -> Transcript {
+    let output = SharedOutput::default();
+    let mut yubikey =
+        YubiKey::open_transport(Recorder::new(transport, output.clone()), "recorded").unwrap();
+    let mut object = vec![0x5a; 3000];
+
+    yubikey.verify_pin(b"123456").unwrap();
+    yubikey
+        .authenticate(&MgmKey::get_default(&yubikey).unwrap())
+        .unwrap();
+    yubikey.save_object(0x005f_c10d, &mut object).unwrap();
+    assert_eq!(
+        yubikey.fetch_object(0x005f_c10d).unwrap().as_slice(),
+        &object[..]
+    );
+    drop(yubikey);
+
+    output.transcript().parse().unwrap()
+}
+
+#[cfg(feature = "untested")]
+#[test]
+fn test_extended_apdus() {
+    let card = VirtualYubiKey::new(Serial(12345678), Version::new([5, 7, 2])).unwrap();
+    let count = |transcript: &Transcript, ins: u8| {
+        transcript
+            .iter()
+            .filter(|exchange| exchange.command()[1] == ins)
+            .count()
+    };
+
+    // The emulated card advertises extended length support in its ATR
+    let transcript = transfer_large_object(card.clone());
+    assert!(transcript.atr().is_some());
+    assert_eq!(count(&transcript, 0xdb), 1);
+    assert_eq!(count(&transcript, 0xc0), 0);
+
+    // Without an ATR, command chaining and GET RESPONSE are used instead
+    let transcript = transfer_large_object(NoAtr(card));
+    assert!(transcript.atr().is_none());
+    assert!(count(&transcript, 0xdb) > 1);
+    assert!(count(&transcript, 0xc0) > 1);
+}